@@ -1,87 +1,233 @@
 use crate::chunker::Chunk;
 use crate::merkle::Node;
+use crate::scanner::path_length_problem;
+use crate::server::http::find_node;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 
 pub const MAGIC: &[u8; 8] = b"WEBPUB\0\0";
-pub const VERSION: u8 = 1;
+pub const VERSION: u8 = 4;
 
-/// Header size: magic (8) + version (1) + index_offset (8) + index_size (8) = 25 bytes
-const HEADER_SIZE: u64 = 25;
+/// Header size: magic (8) + version (1) + index_offset (8) + index_size (8)
+/// + index_hash (32) = 57 bytes. Version 1 headers have no index_hash (25 bytes).
+const HEADER_SIZE: u64 = 57;
+
+/// First version ending with a checksum trailer: the BLAKE3 hash of the
+/// chunk region, between the header and the index.
+const CHECKSUM_VERSION: u8 = 4;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("not a webpub archive (invalid magic)")]
+    BadMagic,
+    #[error("unsupported archive version {0}")]
+    UnsupportedVersion(u8),
+    #[error("archive index corrupted")]
+    CorruptIndex,
+    #[error("archive is missing chunk {}", hex::encode(.0))]
+    MissingChunk([u8; 32]),
+    #[error("{0} not found in archive")]
+    NotFound(String),
+    #[error("{0} is a directory in archive")]
+    IsDirectory(String),
+    #[error("archive is {actual} bytes but its header says {expected}; truncated or appended to")]
+    WrongSize { expected: u64, actual: u64 },
+    #[error("archive checksum doesn't match its chunks")]
+    ChecksumMismatch,
+    #[error("archive chunk {} doesn't match its hash", hex::encode(.0))]
+    CorruptChunk([u8; 32]),
+    #[error("archive index entry for chunk {} is out of bounds", hex::encode(.0))]
+    BadChunkEntry([u8; 32]),
+    #[error("archive entry {0:?} is not a plain file name")]
+    UnsafeName(String),
+    #[error("{} already exists", .0.display())]
+    AlreadyExists(PathBuf),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, ArchiveError>;
+
+/// zstd level used for archive chunks
+const ZSTD_LEVEL: i32 = 3;
+
+/// Largest decompressed chunk an index entry may claim; chunk sizes are u32
+const MAX_RAW_SIZE: u64 = u32::MAX as u64;
+
+/// How a chunk's bytes are stored in the archive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    #[default]
+    Zstd,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "unknown compression '{}' (expected none or zstd)",
+                s
+            )),
+        }
+    }
+}
+
+/// Location and encoding of a chunk in the archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkEntry {
+    pub offset: u64,
+    /// Size as stored in the archive
+    pub size: u64,
+    pub compression: Compression,
+    /// Size after decompression
+    pub raw_size: u64,
+}
 
 /// Archive index stored at the end of the file.
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ArchiveIndex {
     pub tree: Node,
-    pub chunk_offsets: HashMap<[u8; 32], (u64, u64)>, // hash -> (offset, size)
+    pub chunks: HashMap<[u8; 32], ChunkEntry>,
+}
+
+/// Index layout of version 1 and 2 archives, with uncompressed chunks.
+#[derive(Deserialize)]
+struct LegacyIndex {
+    tree: Node,
+    chunk_offsets: HashMap<[u8; 32], (u64, u64)>, // hash -> (offset, size)
 }
 
-/// Write an archive file.
-pub fn write_archive(path: &Path, tree: &Node, chunks: &[Chunk]) -> io::Result<()> {
+impl From<LegacyIndex> for ArchiveIndex {
+    fn from(legacy: LegacyIndex) -> Self {
+        let chunks = legacy
+            .chunk_offsets
+            .into_iter()
+            .map(|(hash, (offset, size))| {
+                let entry = ChunkEntry {
+                    offset,
+                    size,
+                    compression: Compression::None,
+                    raw_size: size,
+                };
+                (hash, entry)
+            })
+            .collect();
+        ArchiveIndex {
+            tree: legacy.tree,
+            chunks,
+        }
+    }
+}
+
+/// Write an archive file, compressing chunks with zstd.
+pub fn write_archive(path: &Path, tree: &Node, chunks: &[Chunk]) -> Result<()> {
+    write_archive_with(path, tree, chunks, Compression::default())
+}
+
+/// Write an archive file with the given chunk compression. Chunks that
+/// don't shrink when compressed are stored as-is.
+pub fn write_archive_with(
+    path: &Path,
+    tree: &Node,
+    chunks: &[Chunk],
+    compression: Compression,
+) -> Result<()> {
     let file = File::create(path)?;
     let mut writer = BufWriter::new(file);
 
     // Write placeholder header
     writer.write_all(MAGIC)?;
     writer.write_all(&[VERSION])?;
-    writer.write_all(&[0u8; 16])?; // placeholder for index_offset and index_size
+    writer.write_all(&[0u8; 48])?; // placeholder for index_offset, index_size, index_hash
 
     // Write chunks, tracking offsets (deduplicate by hash)
-    let mut chunk_offsets: HashMap<[u8; 32], (u64, u64)> = HashMap::new();
+    let mut entries: HashMap<[u8; 32], ChunkEntry> = HashMap::new();
     let mut offset = HEADER_SIZE;
+    let mut checksum = blake3::Hasher::new();
 
     for chunk in chunks {
-        if chunk_offsets.contains_key(&chunk.hash) {
+        if entries.contains_key(&chunk.hash) {
             continue; // Skip duplicate
         }
 
-        writer.write_all(&chunk.data)?;
-        chunk_offsets.insert(chunk.hash, (offset, chunk.data.len() as u64));
-        offset += chunk.data.len() as u64;
+        let compressed = match compression {
+            Compression::Zstd => Some(zstd::bulk::compress(&chunk.data, ZSTD_LEVEL)?)
+                .filter(|data| data.len() < chunk.data.len()),
+            Compression::None => None,
+        };
+        let (data, chunk_compression) = match &compressed {
+            Some(data) => (data.as_slice(), Compression::Zstd),
+            None => (chunk.data.as_slice(), Compression::None),
+        };
+
+        writer.write_all(data)?;
+        checksum.update(data);
+        entries.insert(
+            chunk.hash,
+            ChunkEntry {
+                offset,
+                size: data.len() as u64,
+                compression: chunk_compression,
+                raw_size: chunk.data.len() as u64,
+            },
+        );
+        offset += data.len() as u64;
     }
 
-    // Write index
+    // Write index, without mtimes so the same content makes the same
+    // archive and older versions can read it
+    let mut tree = tree.clone();
+    tree.clear_mtimes();
     let index = ArchiveIndex {
-        tree: tree.clone(),
-        chunk_offsets,
+        tree,
+        chunks: entries,
     };
     let index_bytes = rmp_serde::to_vec(&index).map_err(io::Error::other)?;
     let index_offset = offset;
     let index_size = index_bytes.len() as u64;
     writer.write_all(&index_bytes)?;
+    writer.write_all(checksum.finalize().as_bytes())?;
 
     // Seek back and write actual header
     writer.flush()?;
-    let mut file = writer.into_inner()?;
+    let mut file = writer.into_inner().map_err(|e| e.into_error())?;
     file.seek(SeekFrom::Start(9))?; // After magic + version
     file.write_all(&index_offset.to_le_bytes())?;
     file.write_all(&index_size.to_le_bytes())?;
+    file.write_all(blake3::hash(&index_bytes).as_bytes())?;
 
     Ok(())
 }
 
-/// Read and extract an archive file.
-pub fn read_archive(archive_path: &Path, output_path: &Path) -> io::Result<()> {
-    let file = File::open(archive_path)?;
-    let mut reader = BufReader::new(file);
+/// Where an archive's chunk region ends, and the checksum of that region
+/// for archives that have one.
+struct Layout {
+    index_offset: u64,
+    checksum: Option<[u8; 32]>,
+}
 
+/// Read the header, index and checksum trailer of an archive. An archive
+/// with a trailer must end right after it, so truncation is caught here.
+fn read_index_from<R: Read + Seek>(reader: &mut R) -> Result<(ArchiveIndex, Layout)> {
     // Read and verify header
     let mut magic = [0u8; 8];
     reader.read_exact(&mut magic)?;
     if &magic != MAGIC {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid magic"));
+        return Err(ArchiveError::BadMagic);
     }
 
     let mut version = [0u8; 1];
     reader.read_exact(&mut version)?;
-    if version[0] != VERSION {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "unsupported version",
-        ));
+    if version[0] == 0 || version[0] > VERSION {
+        return Err(ArchiveError::UnsupportedVersion(version[0]));
     }
 
     let mut offset_bytes = [0u8; 8];
@@ -92,46 +238,329 @@ pub fn read_archive(archive_path: &Path, output_path: &Path) -> io::Result<()> {
     reader.read_exact(&mut size_bytes)?;
     let index_size = u64::from_le_bytes(size_bytes);
 
-    // Read index
+    // Version 1 archives have no index checksum
+    let mut index_hash = None;
+    if version[0] >= 2 {
+        let mut hash = [0u8; 32];
+        reader.read_exact(&mut hash)?;
+        index_hash = Some(hash);
+    }
+
+    let mut checksum = None;
+    if version[0] >= CHECKSUM_VERSION {
+        let expected = index_offset.saturating_add(index_size).saturating_add(32);
+        let actual = reader.seek(SeekFrom::End(0))?;
+        if actual != expected {
+            return Err(ArchiveError::WrongSize { expected, actual });
+        }
+        let mut hash = [0u8; 32];
+        reader.seek(SeekFrom::Start(expected - 32))?;
+        reader.read_exact(&mut hash)?;
+        checksum = Some(hash);
+    }
+    let layout = Layout {
+        index_offset,
+        checksum,
+    };
+
+    // Read index, which must lie within the file
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    let in_file = index_offset
+        .checked_add(index_size)
+        .is_some_and(|end| end <= file_size);
+    if !in_file {
+        return Err(ArchiveError::CorruptIndex);
+    }
     reader.seek(SeekFrom::Start(index_offset))?;
     let mut index_bytes = vec![0u8; index_size as usize];
     reader.read_exact(&mut index_bytes)?;
 
-    let index: ArchiveIndex = rmp_serde::from_slice(&index_bytes)
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if let Some(expected) = index_hash {
+        if blake3::hash(&index_bytes).as_bytes() != &expected {
+            return Err(ArchiveError::CorruptIndex);
+        }
+    }
 
-    // Extract tree
-    fs::create_dir_all(output_path)?;
-    extract_node(&index.tree, output_path, &mut reader, &index.chunk_offsets)?;
+    let index: ArchiveIndex = if version[0] < 3 {
+        let legacy: LegacyIndex =
+            rmp_serde::from_slice(&index_bytes).map_err(|_| ArchiveError::CorruptIndex)?;
+        legacy.into()
+    } else {
+        rmp_serde::from_slice(&index_bytes).map_err(|_| ArchiveError::CorruptIndex)?
+    };
 
-    Ok(())
+    // Entries are trusted for allocation sizes later, so bound them here
+    for (hash, entry) in &index.chunks {
+        let in_region = entry
+            .offset
+            .checked_add(entry.size)
+            .is_some_and(|end| end <= index_offset);
+        let sized = match entry.compression {
+            Compression::None => entry.raw_size == entry.size,
+            Compression::Zstd => entry.raw_size <= MAX_RAW_SIZE,
+        };
+        if !in_region || !sized {
+            return Err(ArchiveError::BadChunkEntry(*hash));
+        }
+    }
+    Ok((index, layout))
+}
+
+/// BLAKE3 hash of the chunk region of an archive, read in one pass.
+fn hash_chunk_region<R: Read + Seek>(reader: &mut R, layout: &Layout) -> Result<[u8; 32]> {
+    reader.seek(SeekFrom::Start(HEADER_SIZE))?;
+    let mut hasher = blake3::Hasher::new();
+    let mut region = reader.take(layout.index_offset.saturating_sub(HEADER_SIZE));
+    io::copy(&mut region, &mut hasher)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Read only the index of an archive, without touching chunk data.
+pub fn read_index(archive_path: &Path) -> Result<ArchiveIndex> {
+    let file = File::open(archive_path)?;
+    let mut reader = BufReader::new(file);
+    Ok(read_index_from(&mut reader)?.0)
+}
+
+/// Read one file's contents from an archive whose index was already read,
+/// seeking to just the chunks it needs.
+pub fn read_file_data(
+    archive_path: &Path,
+    index: &ArchiveIndex,
+    file_chunks: &[[u8; 32]],
+) -> Result<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(archive_path)?);
+    let mut data = Vec::new();
+    for hash in file_chunks {
+        let entry = index
+            .chunks
+            .get(hash)
+            .ok_or(ArchiveError::MissingChunk(*hash))?;
+        data.extend_from_slice(&read_chunk(&mut reader, entry)?);
+    }
+    Ok(data)
+}
+
+/// A file or directory in an archive listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// Path relative to the archive root, e.g. "css/style.css"
+    pub path: String,
+    /// Nesting depth, 0 for top-level entries
+    pub depth: usize,
+    pub is_dir: bool,
+    /// File size in bytes (0 for directories)
+    pub size: u64,
+    pub permissions: u32,
+    /// Number of chunks (0 for directories)
+    pub chunks: usize,
+}
+
+/// List an archive's files and directories in tree order, reading only
+/// the header and index.
+pub fn list_archive(archive_path: &Path) -> Result<Vec<EntryInfo>> {
+    let index = read_index(archive_path)?;
+    let mut entries = Vec::new();
+    if let Node::Directory { children, .. } = &index.tree {
+        for child in children {
+            list_node(child, "", 0, &mut entries);
+        }
+    }
+    Ok(entries)
+}
+
+fn list_node(node: &Node, prefix: &str, depth: usize, entries: &mut Vec<EntryInfo>) {
+    let path = format!("{}{}", prefix, node.name());
+    match node {
+        Node::File {
+            size,
+            permissions,
+            chunks,
+            ..
+        } => entries.push(EntryInfo {
+            path,
+            depth,
+            is_dir: false,
+            size: *size,
+            permissions: *permissions,
+            chunks: chunks.len(),
+        }),
+        Node::Directory {
+            permissions,
+            children,
+            ..
+        } => {
+            entries.push(EntryInfo {
+                path: path.clone(),
+                depth,
+                is_dir: true,
+                size: 0,
+                permissions: *permissions,
+                chunks: 0,
+            });
+            let prefix = format!("{}/", path);
+            for child in children {
+                list_node(child, &prefix, depth + 1, entries);
+            }
+        }
+    }
+}
+
+/// An open archive whose index has been read, for reading files and chunks
+/// from it repeatedly without parsing it again.
+pub struct ArchiveReader {
+    reader: BufReader<File>,
+    index: ArchiveIndex,
+    layout: Layout,
+}
+
+impl ArchiveReader {
+    /// Open an archive, reading its header and index.
+    pub fn open(archive_path: &Path) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(archive_path)?);
+        let (index, layout) = read_index_from(&mut reader)?;
+        Ok(ArchiveReader {
+            reader,
+            index,
+            layout,
+        })
+    }
+
+    /// The archive's root directory.
+    pub fn tree(&self) -> &Node {
+        &self.index.tree
+    }
+
+    pub fn index(&self) -> &ArchiveIndex {
+        &self.index
+    }
+
+    /// Check the chunk region against the archive's checksum trailer,
+    /// reading every chunk once. Archives from before checksums have
+    /// nothing to check, and pass.
+    pub fn verify_checksum(&mut self) -> Result<()> {
+        match self.layout.checksum {
+            Some(expected) if hash_chunk_region(&mut self.reader, &self.layout)? != expected => {
+                Err(ArchiveError::ChecksumMismatch)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the archive has a checksum trailer.
+    pub fn has_checksum(&self) -> bool {
+        self.layout.checksum.is_some()
+    }
+
+    /// A chunk's contents, checked against its hash.
+    pub fn chunk(&mut self, hash: &[u8; 32]) -> Result<Vec<u8>> {
+        let entry = self
+            .index
+            .chunks
+            .get(hash)
+            .ok_or(ArchiveError::MissingChunk(*hash))?;
+        let data = read_chunk(&mut self.reader, entry)?;
+        if blake3::hash(&data).as_bytes() != hash {
+            return Err(ArchiveError::CorruptChunk(*hash));
+        }
+        Ok(data)
+    }
+
+    /// The contents of the file at `path`, relative to the archive root,
+    /// e.g. "css/style.css".
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>> {
+        let chunks = match find_node(&self.index.tree, path) {
+            Some(Node::File { chunks, .. }) => chunks.clone(),
+            Some(Node::Directory { .. }) => {
+                return Err(ArchiveError::IsDirectory(path.to_string()))
+            }
+            None => return Err(ArchiveError::NotFound(path.to_string())),
+        };
+        let mut data = Vec::new();
+        for hash in &chunks {
+            data.extend_from_slice(&self.chunk(hash)?);
+        }
+        Ok(data)
+    }
+
+    /// Extract a file or directory into `output_path`, or the whole archive
+    /// when `inner_path` is empty. The node keeps its name under the output.
+    /// Existing files are left alone, failing with `AlreadyExists`.
+    pub fn extract(&mut self, inner_path: &str, output_path: &Path) -> Result<()> {
+        self.extract_with(inner_path, output_path, &ExtractOptions::default())
+    }
+
+    /// Like `extract`, with control over existing files.
+    pub fn extract_with(
+        &mut self,
+        inner_path: &str,
+        output_path: &Path,
+        options: &ExtractOptions,
+    ) -> Result<()> {
+        let node = if inner_path.trim_matches('/').is_empty() {
+            &self.index.tree
+        } else {
+            find_node(&self.index.tree, inner_path)
+                .ok_or_else(|| ArchiveError::NotFound(inner_path.to_string()))?
+        };
+
+        fs::create_dir_all(output_path)?;
+        extract_node(
+            node,
+            output_path,
+            &mut self.reader,
+            &self.index.chunks,
+            options,
+        )
+    }
+}
+
+/// How an archive is extracted.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// Replace files and symlinks already in the output directory instead
+    /// of failing. Existing directories are always merged into.
+    pub overwrite: bool,
+}
+
+/// Read and extract an archive file.
+pub fn read_archive(archive_path: &Path, output_path: &Path) -> Result<()> {
+    ArchiveReader::open(archive_path)?.extract("", output_path)
+}
+
+/// Extract a single file or directory from an archive into `output_path`,
+/// reading only the chunks it needs. `inner_path` is relative to the archive
+/// root, e.g. "css/style.css"; the node keeps its name under the output.
+pub fn extract_path(archive_path: &Path, inner_path: &str, output_path: &Path) -> Result<()> {
+    ArchiveReader::open(archive_path)?.extract(inner_path, output_path)
 }
 
 fn extract_node(
     node: &Node,
     base_path: &Path,
     reader: &mut BufReader<File>,
-    chunk_offsets: &HashMap<[u8; 32], (u64, u64)>,
-) -> io::Result<()> {
+    chunks: &HashMap<[u8; 32], ChunkEntry>,
+    options: &ExtractOptions,
+) -> Result<()> {
     match node {
         Node::File {
             name,
-            chunks,
+            chunks: file_chunks,
             permissions,
             ..
         } => {
-            let file_path = base_path.join(name);
-            let mut file = File::create(&file_path)?;
-
-            for hash in chunks {
-                let (offset, size) = chunk_offsets
-                    .get(hash)
-                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing chunk"))?;
-
-                reader.seek(SeekFrom::Start(*offset))?;
-                let mut data = vec![0u8; *size as usize];
-                reader.read_exact(&mut data)?;
-                file.write_all(&data)?;
+            let file_path = checked_join(base_path, name)?;
+            make_way(&file_path, false, options)?;
+            // create_new never follows a symlink swapped in after make_way
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&file_path)
+                .map_err(|e| with_path(e, &file_path))?;
+
+            for hash in file_chunks {
+                let entry = chunks.get(hash).ok_or(ArchiveError::MissingChunk(*hash))?;
+                file.write_all(&read_chunk(reader, entry)?)?;
             }
 
             // Set permissions
@@ -150,13 +579,15 @@ fn extract_node(
             let dir_path = if name.is_empty() {
                 base_path.to_path_buf()
             } else {
-                base_path.join(name)
+                let dir_path = checked_join(base_path, name)?;
+                make_way(&dir_path, true, options)?;
+                dir_path
             };
 
-            fs::create_dir_all(&dir_path)?;
+            fs::create_dir_all(&dir_path).map_err(|e| with_path(e, &dir_path))?;
 
             for child in children {
-                extract_node(child, &dir_path, reader, chunk_offsets)?;
+                extract_node(child, &dir_path, reader, chunks, options)?;
             }
 
             // Set permissions
@@ -169,3 +600,354 @@ fn extract_node(
     }
     Ok(())
 }
+
+/// Clear the way for extracting to `path`. A real directory can take a
+/// directory; anything else in the way is an error, or removed when
+/// overwriting. Symlinks are never followed, so an archive can't write
+/// through one to outside the output directory.
+fn make_way(path: &Path, is_dir: bool, options: &ExtractOptions) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(with_path(e, path).into()),
+    };
+    if is_dir && metadata.is_dir() {
+        return Ok(());
+    }
+    if !options.overwrite || metadata.is_dir() {
+        return Err(ArchiveError::AlreadyExists(path.to_path_buf()));
+    }
+    fs::remove_file(path).map_err(|e| with_path(e, path))?;
+    Ok(())
+}
+
+/// Write an archive's contents as a tar stream, preserving names and
+/// permissions, without creating any files on disk.
+pub fn to_tar<W: Write>(archive_path: &Path, writer: W) -> Result<()> {
+    let ArchiveReader {
+        mut reader, index, ..
+    } = ArchiveReader::open(archive_path)?;
+
+    let mut builder = tar::Builder::new(writer);
+    if let Node::Directory { children, .. } = &index.tree {
+        for child in children {
+            tar_node(child, "", &mut builder, &mut reader, &index.chunks)?;
+        }
+    }
+    builder.finish()?;
+    Ok(())
+}
+
+fn tar_node<W: Write>(
+    node: &Node,
+    prefix: &str,
+    builder: &mut tar::Builder<W>,
+    reader: &mut BufReader<File>,
+    chunks: &HashMap<[u8; 32], ChunkEntry>,
+) -> Result<()> {
+    check_name(node.name())?;
+    let path = format!("{}{}", prefix, node.name());
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(node.permissions() & 0o7777);
+    header.set_mtime(0);
+
+    match node {
+        Node::File {
+            chunks: file_chunks,
+            size,
+            ..
+        } => {
+            let entries = file_chunks
+                .iter()
+                .map(|hash| chunks.get(hash).ok_or(ArchiveError::MissingChunk(*hash)))
+                .collect::<Result<Vec<_>>>()?;
+
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(*size);
+            let contents = FileContents {
+                reader,
+                entries,
+                next: 0,
+                buffer: io::Cursor::new(Vec::new()),
+            };
+            builder.append_data(&mut header, &path, contents)?;
+        }
+        Node::Directory { children, .. } => {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+            builder.append_data(&mut header, format!("{}/", path), io::empty())?;
+
+            let prefix = format!("{}/", path);
+            for child in children {
+                tar_node(child, &prefix, builder, reader, chunks)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a file's contents chunk by chunk from the archive.
+struct FileContents<'a, R> {
+    reader: &'a mut R,
+    entries: Vec<&'a ChunkEntry>,
+    next: usize,
+    buffer: io::Cursor<Vec<u8>>,
+}
+
+impl<R: Read + Seek> Read for FileContents<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.buffer.read(out)?;
+            if n > 0 || out.is_empty() || self.next == self.entries.len() {
+                return Ok(n);
+            }
+            let data = read_chunk(self.reader, self.entries[self.next])?;
+            self.buffer = io::Cursor::new(data);
+            self.next += 1;
+        }
+    }
+}
+
+/// A problem found by [`verify_archive`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Corruption {
+    /// Chunk bytes at `offset` don't hash to the chunk's key
+    Chunk {
+        offset: u64,
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    /// Compressed chunk at `offset` can't be decompressed
+    Undecodable { offset: u64, expected: [u8; 32] },
+    /// A file's hash doesn't match its chunk hashes
+    File {
+        path: String,
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    /// A file references a chunk that isn't in the archive
+    MissingChunk { path: String, hash: [u8; 32] },
+    /// The chunk region doesn't match the checksum trailer
+    Checksum {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+}
+
+impl std::fmt::Display for Corruption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Corruption::Chunk {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "chunk at offset {}: expected hash {}, got {}",
+                offset,
+                hex::encode(expected),
+                hex::encode(actual)
+            ),
+            Corruption::Undecodable { offset, expected } => write!(
+                f,
+                "chunk at offset {} ({}) cannot be decompressed",
+                offset,
+                hex::encode(expected)
+            ),
+            Corruption::File {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "file {}: expected hash {}, got {}",
+                path,
+                hex::encode(expected),
+                hex::encode(actual)
+            ),
+            Corruption::MissingChunk { path, hash } => {
+                write!(f, "file {}: missing chunk {}", path, hex::encode(hash))
+            }
+            Corruption::Checksum { expected, actual } => write!(
+                f,
+                "archive checksum: expected {}, got {}",
+                hex::encode(expected),
+                hex::encode(actual)
+            ),
+        }
+    }
+}
+
+/// Outcome of [`verify_archive`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub chunks_checked: usize,
+    pub files_checked: usize,
+    /// Whether the archive had a checksum trailer to check
+    pub checksum_checked: bool,
+    /// The first problem found, in file order; None if the archive is intact
+    pub corruption: Option<Corruption>,
+}
+
+/// Check an archive's integrity: every chunk must hash to its key, every
+/// file's hash must match its chunk hashes, and the chunk region must match
+/// the checksum trailer if the archive has one. Stops at the first problem.
+pub fn verify_archive(archive_path: &Path) -> Result<VerifyReport> {
+    let file = File::open(archive_path)?;
+    let mut reader = BufReader::new(file);
+    let (index, layout) = read_index_from(&mut reader)?;
+    let mut report = VerifyReport::default();
+
+    let mut entries: Vec<(&[u8; 32], &ChunkEntry)> = index.chunks.iter().collect();
+    entries.sort_by_key(|(_, entry)| entry.offset);
+
+    for (hash, entry) in entries {
+        report.chunks_checked += 1;
+        let data = match read_chunk(&mut reader, entry) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(e.into()),
+            Err(_) => {
+                report.corruption = Some(Corruption::Undecodable {
+                    offset: entry.offset,
+                    expected: *hash,
+                });
+                return Ok(report);
+            }
+        };
+        let actual = *blake3::hash(&data).as_bytes();
+        if actual != *hash {
+            report.corruption = Some(Corruption::Chunk {
+                offset: entry.offset,
+                expected: *hash,
+                actual,
+            });
+            return Ok(report);
+        }
+    }
+
+    report.corruption = verify_node(&index.tree, "", &index.chunks, &mut report.files_checked);
+    if report.corruption.is_some() {
+        return Ok(report);
+    }
+
+    // The checksum also covers bytes between chunks that no entry points at
+    if let Some(expected) = layout.checksum {
+        report.checksum_checked = true;
+        let actual = hash_chunk_region(&mut reader, &layout)?;
+        if actual != expected {
+            report.corruption = Some(Corruption::Checksum { expected, actual });
+        }
+    }
+    Ok(report)
+}
+
+fn verify_node(
+    node: &Node,
+    prefix: &str,
+    chunks: &HashMap<[u8; 32], ChunkEntry>,
+    files_checked: &mut usize,
+) -> Option<Corruption> {
+    let path = format!("{}/{}", prefix, node.name());
+    match node {
+        Node::File {
+            chunks: file_chunks,
+            hash,
+            ..
+        } => {
+            *files_checked += 1;
+            if let Some(missing) = file_chunks.iter().find(|h| !chunks.contains_key(*h)) {
+                return Some(Corruption::MissingChunk {
+                    path,
+                    hash: *missing,
+                });
+            }
+            // File hash = BLAKE3(concatenated chunk hashes)
+            let mut hasher = blake3::Hasher::new();
+            for chunk_hash in file_chunks {
+                hasher.update(chunk_hash);
+            }
+            let actual = *hasher.finalize().as_bytes();
+            (actual != *hash).then_some(Corruption::File {
+                path,
+                expected: *hash,
+                actual,
+            })
+        }
+        Node::Directory { children, .. } => {
+            let prefix = if node.name().is_empty() {
+                String::new()
+            } else {
+                path
+            };
+            children
+                .iter()
+                .find_map(|child| verify_node(child, &prefix, chunks, files_checked))
+        }
+    }
+}
+
+/// Read a chunk's bytes from the archive, decompressing if needed.
+pub fn read_chunk<R: Read + Seek>(reader: &mut R, entry: &ChunkEntry) -> io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(entry.offset))?;
+    let stored = reader.take(entry.size);
+
+    // Grow the buffer as bytes arrive rather than trusting the sizes up front
+    let mut data = Vec::new();
+    match entry.compression {
+        Compression::None => stored.take(entry.raw_size).read_to_end(&mut data)?,
+        Compression::Zstd => zstd::stream::read::Decoder::new(stored)?
+            .take(entry.raw_size.saturating_add(1))
+            .read_to_end(&mut data)?,
+    };
+    if data.len() as u64 != entry.raw_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "archive chunk is not its recorded size",
+        ));
+    }
+    Ok(data)
+}
+
+/// Check that a node name is a single plain path component. Archives come
+/// from elsewhere, and a name like "..", "/etc" or "a/../../b" would place a
+/// file outside the output directory.
+fn check_name(name: &str) -> Result<()> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(part)), None) if part == name => Ok(()),
+        _ => Err(ArchiveError::UnsafeName(name.to_string())),
+    }
+}
+
+/// Join a node name onto the output path, rejecting names that would escape
+/// it, and names and paths that are too long to create with a descriptive
+/// error instead of an opaque OS one.
+fn checked_join(base_path: &Path, name: &str) -> Result<PathBuf> {
+    check_name(name)?;
+    let path = base_path.join(name);
+
+    if let Some(problem) = path_length_problem(name, path.as_os_str().len()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot extract {}: {}", path.display(), problem),
+        )
+        .into());
+    }
+
+    // Windows long-path prefix lifts the 260 character MAX_PATH limit
+    #[cfg(windows)]
+    if path.as_os_str().len() >= 260 {
+        let absolute = std::path::absolute(&path)?;
+        if !absolute.to_string_lossy().starts_with(r"\\?\") {
+            let mut prefixed = std::ffi::OsString::from(r"\\?\");
+            prefixed.push(absolute.as_os_str());
+            return Ok(PathBuf::from(prefixed));
+        }
+    }
+
+    Ok(path)
+}
+
+fn with_path(e: io::Error, path: &Path) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
@@ -1,4 +1,8 @@
-use fastcdc::v2020::FastCDC;
+use fastcdc::v2020::{
+    FastCDC, AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN,
+};
+use std::io::{self, Write};
+use thiserror::Error;
 
 /// A content-addressed chunk of data.
 #[derive(Debug, Clone)]
@@ -7,21 +11,225 @@ pub struct Chunk {
     pub data: Vec<u8>,
 }
 
-/// Chunk sizes: min 16KB, avg 32KB, max 64KB
-const MIN_SIZE: u32 = 16 * 1024;
-const AVG_SIZE: u32 = 32 * 1024;
-const MAX_SIZE: u32 = 64 * 1024;
+/// Default chunk sizes: min 16KB, avg 32KB, max 64KB
+pub const MIN_SIZE: u32 = 16 * 1024;
+pub const AVG_SIZE: u32 = 32 * 1024;
+pub const MAX_SIZE: u32 = 64 * 1024;
 
-/// Chunk data using FastCDC algorithm, yielding chunks with BLAKE3 hashes.
-pub fn chunk_data(data: &[u8]) -> impl Iterator<Item = Chunk> + '_ {
-    let chunker = FastCDC::new(data, MIN_SIZE, AVG_SIZE, MAX_SIZE);
+/// How data is split into chunks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChunkStrategy {
+    /// Content-defined boundaries found by FastCDC, so an edit only changes
+    /// the chunks around it
+    #[default]
+    FastCdc,
+    /// Blocks of exactly `max` bytes, the last one shorter. Boundaries
+    /// depend only on offsets, which makes chunks easy to predict in tests,
+    /// but an insertion changes every later chunk.
+    Fixed,
+}
+
+/// Chunk size bounds, in bytes, and the strategy using them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkConfig {
+    pub min: u32,
+    pub avg: u32,
+    pub max: u32,
+    pub strategy: ChunkStrategy,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        ChunkConfig {
+            min: MIN_SIZE,
+            avg: AVG_SIZE,
+            max: MAX_SIZE,
+            strategy: ChunkStrategy::FastCdc,
+        }
+    }
+}
+
+/// Invalid chunk size bounds.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ChunkConfigError {
+    #[error("chunk sizes must satisfy min <= avg <= max, got {min}/{avg}/{max}")]
+    Order { min: u32, avg: u32, max: u32 },
+    #[error("{name} chunk size {value} is outside the supported range {low}..={high}")]
+    OutOfRange {
+        name: &'static str,
+        value: u32,
+        low: u32,
+        high: u32,
+    },
+}
+
+impl ChunkConfig {
+    /// Chunk size bounds, checked against each other and FastCDC's limits.
+    pub fn new(min: u32, avg: u32, max: u32) -> Result<Self, ChunkConfigError> {
+        let config = ChunkConfig {
+            min,
+            avg,
+            max,
+            strategy: ChunkStrategy::FastCdc,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Fixed-size chunks of `size` bytes, at most FastCDC's largest maximum.
+    pub fn fixed(size: u32) -> Result<Self, ChunkConfigError> {
+        let config = ChunkConfig {
+            min: size,
+            avg: size,
+            max: size,
+            strategy: ChunkStrategy::Fixed,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check that min <= avg <= max and each is within FastCDC's range.
+    /// Fixed-size chunking only uses `max`, which must be non-zero.
+    pub fn validate(&self) -> Result<(), ChunkConfigError> {
+        if self.strategy == ChunkStrategy::Fixed {
+            if !(1..=MAXIMUM_MAX).contains(&self.max) {
+                return Err(ChunkConfigError::OutOfRange {
+                    name: "fixed",
+                    value: self.max,
+                    low: 1,
+                    high: MAXIMUM_MAX,
+                });
+            }
+            return Ok(());
+        }
+        let limits = [
+            ("min", self.min, MINIMUM_MIN, MINIMUM_MAX),
+            ("avg", self.avg, AVERAGE_MIN, AVERAGE_MAX),
+            ("max", self.max, MAXIMUM_MIN, MAXIMUM_MAX),
+        ];
+        for (name, value, low, high) in limits {
+            if !(low..=high).contains(&value) {
+                return Err(ChunkConfigError::OutOfRange {
+                    name,
+                    value,
+                    low,
+                    high,
+                });
+            }
+        }
+        if self.min > self.avg || self.avg > self.max {
+            return Err(ChunkConfigError::Order {
+                min: self.min,
+                avg: self.avg,
+                max: self.max,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Chunk data using the config's strategy, FastCDC by default, yielding
+/// chunks with BLAKE3 hashes. The config must be valid; see
+/// [`ChunkConfig::validate`].
+///
+/// Data shorter than `config.min` is emitted as a single chunk without
+/// running FastCDC, which would produce the same chunk anyway. Empty data
+/// has no chunks, so an empty file's chunk list is empty.
+pub fn chunk_data<'a>(data: &'a [u8], config: &ChunkConfig) -> impl Iterator<Item = Chunk> + 'a {
+    let fixed = config.strategy == ChunkStrategy::Fixed;
+    let blocks = fixed.then(|| data.chunks(config.max as usize));
 
-    chunker.map(|chunk| {
-        let chunk_data = data[chunk.offset..chunk.offset + chunk.length].to_vec();
-        let hash = *blake3::hash(&chunk_data).as_bytes();
-        Chunk {
-            hash,
-            data: chunk_data,
+    let small = !fixed && !data.is_empty() && data.len() < config.min as usize;
+    let whole = small.then_some(data);
+    let large = !fixed && data.len() >= config.min as usize;
+    let chunker = large.then(|| FastCDC::new(data, config.min, config.avg, config.max));
+
+    blocks
+        .into_iter()
+        .flatten()
+        .chain(whole)
+        .chain(
+            chunker
+                .into_iter()
+                .flatten()
+                .map(|chunk| &data[chunk.offset..chunk.offset + chunk.length]),
+        )
+        .map(new_chunk)
+}
+
+fn new_chunk(data: &[u8]) -> Chunk {
+    Chunk {
+        hash: *blake3::hash(data).as_bytes(),
+        data: data.to_vec(),
+    }
+}
+
+/// Chunk data with the default chunk sizes.
+pub fn chunk_data_default(data: &[u8]) -> impl Iterator<Item = Chunk> + '_ {
+    chunk_data(data, &ChunkConfig::default())
+}
+
+/// Chunks data written to it as it arrives, for streams that shouldn't be
+/// held in memory whole. Each chunk is passed to `on_chunk` once its
+/// boundary is known; call [`StreamingChunker::finish`] at the end of the
+/// stream for the rest. The chunks are the same as [`chunk_data`] gives
+/// for all the data at once.
+///
+/// At most about `config.max` bytes plus the last write are buffered.
+/// `flush` doesn't cut a chunk, since that would move boundaries.
+pub struct StreamingChunker<F: FnMut(Chunk) -> io::Result<()>> {
+    config: ChunkConfig,
+    buffer: Vec<u8>,
+    on_chunk: F,
+}
+
+impl<F: FnMut(Chunk) -> io::Result<()>> StreamingChunker<F> {
+    /// A chunker passing chunks to `on_chunk`. The config must be valid;
+    /// see [`ChunkConfig::validate`].
+    pub fn new(config: ChunkConfig, on_chunk: F) -> Self {
+        StreamingChunker {
+            config,
+            buffer: Vec::new(),
+            on_chunk,
+        }
+    }
+
+    /// Chunk the data still buffered, ending the stream.
+    pub fn finish(mut self) -> io::Result<()> {
+        for chunk in chunk_data(&self.buffer, &self.config) {
+            (self.on_chunk)(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: FnMut(Chunk) -> io::Result<()>> Write for StreamingChunker<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+
+        // FastCDC looks at most `max` bytes ahead for a boundary, so with
+        // that much buffered the next chunk is final
+        let max = self.config.max as usize;
+        let mut start = 0;
+        while self.buffer.len() - start >= max {
+            let rest = &self.buffer[start..];
+            let length = match self.config.strategy {
+                ChunkStrategy::Fixed => max,
+                ChunkStrategy::FastCdc => {
+                    FastCDC::new(rest, self.config.min, self.config.avg, self.config.max)
+                        .next()
+                        .map_or(max, |chunk| chunk.length)
+                }
+            };
+            let chunk = new_chunk(&rest[..length]);
+            start += length;
+            (self.on_chunk)(chunk)?;
         }
-    })
+        self.buffer.drain(..start);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
@@ -0,0 +1,50 @@
+use crate::client::{connect, recv, send};
+use crate::protocol::{ClientMessage, ServerMessage, FILE_PROTOCOL_VERSION};
+use std::io::Write;
+
+/// Write the bytes the server holds for a path in a site's current snapshot
+/// to `out`, as they arrive. A directory gives its index.html. Returns the
+/// number of bytes written.
+pub async fn cat(
+    server_url: &str,
+    hostname: &str,
+    path: &str,
+    token: &str,
+    out: &mut impl Write,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let (mut ws, version) = connect(server_url, token).await?;
+    if version < FILE_PROTOCOL_VERSION {
+        return Err(format!(
+            "Server speaks protocol version {}, cat needs version {}",
+            version, FILE_PROTOCOL_VERSION
+        )
+        .into());
+    }
+
+    send(
+        &mut ws,
+        &ClientMessage::GetFile {
+            hostname: hostname.to_string(),
+            path: path.to_string(),
+        },
+    )
+    .await?;
+
+    let mut written = 0;
+    loop {
+        match recv(&mut ws).await? {
+            ServerMessage::FileData { data, done } => {
+                out.write_all(&data)?;
+                written += data.len() as u64;
+                if done {
+                    out.flush()?;
+                    return Ok(written);
+                }
+            }
+            ServerMessage::GetFileFailed { reason } => {
+                return Err(format!("{}: {}", path, reason).into())
+            }
+            _ => return Err("Unexpected response".into()),
+        }
+    }
+}
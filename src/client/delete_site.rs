@@ -0,0 +1,34 @@
+use crate::client::{connect, recv, send};
+use crate::protocol::{ClientMessage, ServerMessage, DELETE_SITE_PROTOCOL_VERSION};
+
+/// Remove a site and all of its snapshots from the server.
+pub async fn delete_site(
+    server_url: &str,
+    hostname: &str,
+    token: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut ws, version) = connect(server_url, token).await?;
+    if version < DELETE_SITE_PROTOCOL_VERSION {
+        return Err(format!(
+            "Server speaks protocol version {}, deleting a site needs version {}",
+            version, DELETE_SITE_PROTOCOL_VERSION
+        )
+        .into());
+    }
+
+    send(
+        &mut ws,
+        &ClientMessage::DeleteSite {
+            hostname: hostname.to_string(),
+        },
+    )
+    .await?;
+
+    match recv(&mut ws).await? {
+        ServerMessage::SiteDeleted => Ok(()),
+        ServerMessage::DeleteSiteFailed { reason } => {
+            Err(format!("Delete failed: {}", reason).into())
+        }
+        _ => Err("Unexpected response".into()),
+    }
+}
@@ -0,0 +1,70 @@
+use crate::client::list::list_snapshots;
+use crate::client::{connect, fetch_tree, Connection};
+use crate::merkle::{self, DiffEntry};
+use crate::protocol::TREE_PROTOCOL_VERSION;
+
+/// The changes between two snapshots of a site.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    pub old_id: u64,
+    pub new_id: u64,
+    pub changes: Vec<DiffEntry>,
+}
+
+/// Compare two snapshots of a site. `new` defaults to the current snapshot
+/// and `old` to the snapshot deployed before `new`.
+pub async fn diff(
+    server_url: &str,
+    hostname: &str,
+    token: &str,
+    old: Option<u64>,
+    new: Option<u64>,
+) -> Result<SnapshotDiff, Box<dyn std::error::Error>> {
+    let (mut ws, version) = connect(server_url, token).await?;
+    if version < TREE_PROTOCOL_VERSION {
+        return Err(format!(
+            "Server speaks protocol version {}, diff needs version {}",
+            version, TREE_PROTOCOL_VERSION
+        )
+        .into());
+    }
+
+    let (new_id, new_tree) = fetch_tree(&mut ws, hostname, new)
+        .await?
+        .ok_or_else(|| not_found(hostname, new))?;
+    let old_id = match old {
+        Some(id) => id,
+        None => previous_snapshot(&mut ws, hostname, new_id).await?,
+    };
+    let (old_id, old_tree) = fetch_tree(&mut ws, hostname, Some(old_id))
+        .await?
+        .ok_or_else(|| not_found(hostname, Some(old_id)))?;
+
+    Ok(SnapshotDiff {
+        old_id,
+        new_id,
+        changes: merkle::diff(&old_tree, &new_tree),
+    })
+}
+
+fn not_found(hostname: &str, snapshot_id: Option<u64>) -> String {
+    match snapshot_id {
+        Some(id) => format!("Snapshot {} not found", id),
+        None => format!("No current snapshot for {}", hostname),
+    }
+}
+
+/// The newest snapshot older than `snapshot_id`.
+async fn previous_snapshot(
+    ws: &mut Connection,
+    hostname: &str,
+    snapshot_id: u64,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    list_snapshots(ws, hostname)
+        .await?
+        .into_iter()
+        .map(|(id, _, _, _)| id)
+        .filter(|&id| id < snapshot_id)
+        .max()
+        .ok_or_else(|| format!("No snapshot before {} to compare with", snapshot_id).into())
+}
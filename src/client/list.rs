@@ -1,46 +1,70 @@
-use crate::protocol::{ClientMessage, ServerMessage};
-use futures_util::{SinkExt, StreamExt};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use crate::client::{connect, recv, send, Connection};
+use crate::protocol::{ClientMessage, ServerMessage, SITES_PROTOCOL_VERSION};
+use crate::timestamp::parse_utc;
+use std::collections::HashMap;
 
+/// List a site's snapshots as (ID, creation time in seconds since the Unix
+/// epoch, is current, tag).
 pub async fn list(
     server_url: &str,
     hostname: &str,
     token: &str,
-) -> Result<Vec<(u64, String, bool)>, Box<dyn std::error::Error>> {
-    let (mut ws, _) = connect_async(server_url).await?;
-
-    // Authenticate
-    let auth_msg = rmp_serde::to_vec(&ClientMessage::Auth {
-        token: token.to_string(),
-    })?;
-    ws.send(Message::Binary(auth_msg)).await?;
+) -> Result<Vec<(u64, i64, bool, Option<String>)>, Box<dyn std::error::Error>> {
+    let (mut ws, _) = connect(server_url, token).await?;
+    list_snapshots(&mut ws, hostname).await
+}
 
-    let response = ws.next().await.ok_or("Connection closed")??;
-    let server_msg: ServerMessage = match response {
-        Message::Binary(data) => rmp_serde::from_slice(&data)?,
-        _ => return Err("Expected binary message".into()),
-    };
+/// Request a site's snapshots on an open connection. Creation times sent as
+/// strings by older servers are parsed, or zero if unreadable.
+pub(crate) async fn list_snapshots(
+    ws: &mut Connection,
+    hostname: &str,
+) -> Result<Vec<(u64, i64, bool, Option<String>)>, Box<dyn std::error::Error>> {
+    send(
+        ws,
+        &ClientMessage::ListSnapshots {
+            hostname: hostname.to_string(),
+        },
+    )
+    .await?;
 
-    match server_msg {
-        ServerMessage::AuthOk => {}
-        ServerMessage::AuthFailed => return Err("Authentication failed".into()),
-        _ => return Err("Unexpected response".into()),
+    match recv(ws).await? {
+        ServerMessage::Snapshots { snapshots, tags } => {
+            let mut tags: HashMap<u64, String> = tags.into_iter().collect();
+            Ok(snapshots
+                .into_iter()
+                .map(|(id, created_at, is_current)| (id, created_at, is_current, tags.remove(&id)))
+                .collect())
+        }
+        ServerMessage::SnapshotList { snapshots } => Ok(snapshots
+            .into_iter()
+            .map(|(id, created_at, is_current)| {
+                (id, parse_utc(&created_at).unwrap_or(0), is_current, None)
+            })
+            .collect()),
+        _ => Err("Unexpected response".into()),
     }
+}
 
-    // Request list
-    let list_msg = rmp_serde::to_vec(&ClientMessage::ListSnapshots {
-        hostname: hostname.to_string(),
-    })?;
-    ws.send(Message::Binary(list_msg)).await?;
+/// List every site on the server as (hostname, snapshot count, current
+/// snapshot ID).
+pub async fn list_sites(
+    server_url: &str,
+    token: &str,
+) -> Result<Vec<(String, u64, Option<u64>)>, Box<dyn std::error::Error>> {
+    let (mut ws, version) = connect(server_url, token).await?;
+    if version < SITES_PROTOCOL_VERSION {
+        return Err(format!(
+            "Server speaks protocol version {}, listing sites needs version {}",
+            version, SITES_PROTOCOL_VERSION
+        )
+        .into());
+    }
 
-    let response = ws.next().await.ok_or("Connection closed")??;
-    let server_msg: ServerMessage = match response {
-        Message::Binary(data) => rmp_serde::from_slice(&data)?,
-        _ => return Err("Expected binary message".into()),
-    };
+    send(&mut ws, &ClientMessage::ListSites).await?;
 
-    match server_msg {
-        ServerMessage::SnapshotList { snapshots } => Ok(snapshots),
+    match recv(&mut ws).await? {
+        ServerMessage::SiteList { sites } => Ok(sites),
         _ => Err("Unexpected response".into()),
     }
 }
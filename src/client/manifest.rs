@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the push manifest written to the root of the source directory
+/// by a push with `--resume`. It is never scanned, so it doesn't change the
+/// tree being pushed or archived.
+pub const MANIFEST_FILE: &str = ".webpub-push";
+
+/// Record of the chunks a server has acked during a push, so a retry with
+/// `--resume` can skip them. The first line holds a key derived from the
+/// server, hostname and tree hash; each following line is an acked chunk
+/// hash in hex. A manifest for a different key is discarded.
+///
+/// Without `--resume` acks are only kept in memory, so a push never writes
+/// to the source directory unless asked to.
+pub struct PushManifest {
+    path: PathBuf,
+    /// The manifest file, when resuming
+    file: Option<File>,
+    acked: HashSet<[u8; 32]>,
+}
+
+/// Key identifying one push: the same tree to the same site on the same server.
+pub fn manifest_key(server_url: &str, hostname: &str, tree_hash: &[u8; 32]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(server_url.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(hostname.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(tree_hash);
+    hasher.finalize().to_hex().to_string()
+}
+
+impl PushManifest {
+    /// Open the manifest in `dir` for `key`. With `resume`, chunks acked by
+    /// an earlier push of the same key are kept and new acks are appended,
+    /// creating the file if needed; otherwise it starts empty and nothing
+    /// is written.
+    pub fn open(dir: &Path, key: &str, resume: bool) -> io::Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        if !resume {
+            return Ok(PushManifest {
+                path,
+                file: None,
+                acked: HashSet::new(),
+            });
+        }
+
+        let acked = read_acked(&path, key)?;
+        let file = match acked {
+            Some(_) => OpenOptions::new().append(true).open(&path)?,
+            None => {
+                let mut file = File::create(&path)?;
+                writeln!(file, "{}", key)?;
+                file
+            }
+        };
+
+        Ok(PushManifest {
+            path,
+            file: Some(file),
+            acked: acked.unwrap_or_default(),
+        })
+    }
+
+    /// Whether an earlier push already got an ack for this chunk.
+    pub fn is_acked(&self, hash: &[u8; 32]) -> bool {
+        self.acked.contains(hash)
+    }
+
+    /// Number of chunks recorded as acked.
+    pub fn acked_count(&self) -> usize {
+        self.acked.len()
+    }
+
+    /// Append newly acked chunks.
+    pub fn record(&mut self, hashes: &[[u8; 32]]) -> io::Result<()> {
+        let mut lines = String::new();
+        for hash in hashes {
+            if self.acked.insert(*hash) {
+                lines.push_str(&hex::encode(hash));
+                lines.push('\n');
+            }
+        }
+        match &mut self.file {
+            Some(file) => file.write_all(lines.as_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    /// Delete the manifest once the push has committed.
+    pub fn remove(self) -> io::Result<()> {
+        match self.file {
+            Some(file) => {
+                drop(file);
+                fs::remove_file(&self.path)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Acked hashes from an existing manifest, or None if it is missing or
+/// belongs to a different push. A torn last line is skipped.
+fn read_acked(path: &Path, key: &str) -> io::Result<Option<HashSet<[u8; 32]>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut lines = BufReader::new(file).lines();
+    if lines.next().transpose()?.as_deref() != Some(key) {
+        return Ok(None);
+    }
+
+    let mut acked = HashSet::new();
+    for line in lines {
+        let mut hash = [0u8; 32];
+        if hex::decode_to_slice(line?.trim(), &mut hash).is_ok() {
+            acked.insert(hash);
+        }
+    }
+    Ok(Some(acked))
+}
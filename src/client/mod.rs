@@ -1,3 +1,103 @@
+pub mod cat;
+pub mod delete_site;
+pub mod diff;
 pub mod list;
+pub mod manifest;
+mod progress;
 pub mod push;
 pub mod rollback;
+
+use crate::protocol::{self, ClientMessage, ServerMessage};
+use crate::Node;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+pub(crate) type Connection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Connect to a sync server and authenticate with a token, returning the
+/// connection and the protocol version both sides speak.
+pub(crate) async fn connect(
+    server_url: &str,
+    token: &str,
+) -> Result<(Connection, u32), Box<dyn std::error::Error>> {
+    let (mut ws, _) = connect_async(server_url).await?;
+
+    send(
+        &mut ws,
+        &ClientMessage::Auth {
+            token: token.to_string(),
+            protocol_version: protocol::PROTOCOL_VERSION,
+        },
+    )
+    .await?;
+
+    let response = ws.next().await.ok_or("Connection closed")??;
+    let envelope = match response {
+        Message::Binary(data) => protocol::decode_envelope::<ServerMessage>(&data)?,
+        Message::Close(Some(frame)) => {
+            return Err(format!("Server closed the connection: {}", frame.reason).into())
+        }
+        _ => return Err("Expected binary message".into()),
+    };
+
+    match envelope.message {
+        ServerMessage::AuthOk => Ok((ws, envelope.version.min(protocol::PROTOCOL_VERSION))),
+        ServerMessage::AuthFailed => Err("Authentication failed".into()),
+        ServerMessage::VersionMismatch { server_version } => Err(format!(
+            "Protocol version mismatch: client speaks version {}, server speaks version {}",
+            protocol::PROTOCOL_VERSION,
+            server_version
+        )
+        .into()),
+        _ => Err("Unexpected response".into()),
+    }
+}
+
+/// Send a message to the server.
+pub(crate) async fn send(
+    ws: &mut Connection,
+    msg: &ClientMessage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    ws.send(Message::Binary(protocol::encode(msg)?)).await?;
+    Ok(())
+}
+
+/// Wait for the next message from the server.
+pub(crate) async fn recv(ws: &mut Connection) -> Result<ServerMessage, Box<dyn std::error::Error>> {
+    let response = ws.next().await.ok_or("Connection closed")??;
+    let msg = match response {
+        Message::Binary(data) => protocol::decode(&data)?,
+        Message::Close(Some(frame)) => {
+            return Err(format!("Server closed the connection: {}", frame.reason).into())
+        }
+        _ => return Err("Expected binary message".into()),
+    };
+    match msg {
+        ServerMessage::Denied { reason } => Err(format!("Permission denied: {}", reason).into()),
+        msg => Ok(msg),
+    }
+}
+
+/// Fetch a snapshot's tree with its ID, or the current snapshot's when no ID
+/// is given. None if the site has no such snapshot.
+pub(crate) async fn fetch_tree(
+    ws: &mut Connection,
+    hostname: &str,
+    snapshot_id: Option<u64>,
+) -> Result<Option<(u64, Node)>, Box<dyn std::error::Error>> {
+    send(
+        ws,
+        &ClientMessage::GetSnapshotTree {
+            hostname: hostname.to_string(),
+            snapshot_id,
+        },
+    )
+    .await?;
+
+    match recv(ws).await? {
+        ServerMessage::SnapshotTree { snapshot_id, tree } => Ok(Some((snapshot_id, tree))),
+        ServerMessage::SnapshotTreeFailed { .. } => Ok(None),
+        _ => Err("Unexpected response".into()),
+    }
+}
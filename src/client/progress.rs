@@ -0,0 +1,112 @@
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use std::io::IsTerminal;
+use std::time::{Duration, Instant};
+
+/// How often line-based progress is printed when stdout isn't a terminal.
+const LINE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether to draw progress bars, which only make sense on a terminal.
+fn interactive() -> bool {
+    std::io::stdout().is_terminal()
+}
+
+/// A spinner for a phase of unknown length, such as scanning and hashing.
+/// Without a terminal the message is printed once as a plain line.
+pub(crate) struct Phase {
+    spinner: Option<ProgressBar>,
+}
+
+impl Phase {
+    pub(crate) fn start(message: String) -> Self {
+        if !interactive() {
+            println!("{}", message);
+            return Phase { spinner: None };
+        }
+        let spinner = ProgressBar::new_spinner().with_message(message);
+        spinner.enable_steady_tick(Duration::from_millis(100));
+        Phase {
+            spinner: Some(spinner),
+        }
+    }
+
+    pub(crate) fn finish(self) {
+        if let Some(spinner) = self.spinner {
+            spinner.finish_and_clear();
+        }
+    }
+}
+
+/// Progress of a chunk upload, advanced as the server acks each chunk.
+/// Draws a bar with chunks, bytes and ETA on a terminal, and prints a line
+/// every few seconds otherwise so CI logs stay readable.
+pub(crate) struct UploadProgress {
+    bar: Option<ProgressBar>,
+    total_chunks: u64,
+    total_bytes: u64,
+    chunks: u64,
+    bytes: u64,
+    started: Instant,
+    last_line: Instant,
+}
+
+impl UploadProgress {
+    pub(crate) fn new(total_chunks: u64, total_bytes: u64) -> Self {
+        let bar = interactive().then(|| {
+            let bar = ProgressBar::new(total_bytes);
+            bar.set_style(
+                ProgressStyle::with_template(
+                    "{bar:40} {bytes}/{total_bytes} {msg} ({bytes_per_sec}, ETA {eta})",
+                )
+                .unwrap(),
+            );
+            bar.set_message(format!("0/{} chunks", total_chunks));
+            bar
+        });
+        let now = Instant::now();
+        UploadProgress {
+            bar,
+            total_chunks,
+            total_bytes,
+            chunks: 0,
+            bytes: 0,
+            started: now,
+            last_line: now,
+        }
+    }
+
+    /// Record acked chunks totalling the given size.
+    pub(crate) fn chunks_done(&mut self, count: u64, size: u64) {
+        self.chunks += count;
+        self.bytes += size;
+        match &self.bar {
+            Some(bar) => {
+                bar.set_position(self.bytes);
+                bar.set_message(format!("{}/{} chunks", self.chunks, self.total_chunks));
+            }
+            None if self.last_line.elapsed() >= LINE_INTERVAL => {
+                self.last_line = Instant::now();
+                println!("  {}", self.summary());
+            }
+            None => {}
+        }
+    }
+
+    pub(crate) fn finish(self) {
+        match self.bar {
+            Some(bar) => bar.finish_and_clear(),
+            None if self.total_chunks > 0 => println!("  {}", self.summary()),
+            None => {}
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "Uploaded {}/{} chunks, {}/{} in {:.0?}",
+            self.chunks,
+            self.total_chunks,
+            HumanBytes(self.bytes),
+            HumanBytes(self.total_bytes),
+            self.started.elapsed()
+        )
+    }
+}
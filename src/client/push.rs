@@ -1,112 +1,438 @@
-use crate::protocol::{ClientMessage, ServerMessage};
-use crate::{build_tree, scan_directory, Chunk};
-use futures_util::{SinkExt, StreamExt};
-use std::path::Path;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use crate::chunker::{chunk_data, Chunk, ChunkConfig};
+use crate::client::manifest::{manifest_key, PushManifest};
+use crate::client::progress::{Phase, UploadProgress};
+use crate::client::{connect, fetch_tree, recv, send, Connection};
+use crate::merkle::build_tree_with_config_stats;
+use crate::protocol::{
+    ClientMessage, ServerMessage, BATCH_PROTOCOL_VERSION, DEPLOY_BATCH_PROTOCOL_VERSION,
+    MTIME_PROTOCOL_VERSION, SIZES_PROTOCOL_VERSION, TREE_PROTOCOL_VERSION,
+};
+use crate::scanner::{scan_directory_incremental, scan_directory_with, ScanOptions};
+use crate::Node;
+use indicatif::HumanBytes;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Frames of chunks sent ahead of their acks by default.
+pub const DEFAULT_CONCURRENCY: usize = 32;
+
+/// Chunks are grouped into `ChunkBatch` frames of about this many bytes
+/// when the server supports batching.
+pub const BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// Options for [`push`].
+#[derive(Debug, Clone)]
+pub struct PushOptions {
+    /// How the source directory is scanned
+    pub scan: ScanOptions,
+    /// Chunk sizes used to split files
+    pub chunking: ChunkConfig,
+    /// Maximum number of chunk frames sent before their acks arrive
+    pub concurrency: usize,
+    /// Skip chunks acked by an earlier, interrupted push of the same tree
+    pub resume: bool,
+    /// Fetch the site's current tree first and don't read files whose size
+    /// and mtime match it. Ignored unless `chunking` is the default, since
+    /// the current tree's chunk lists may have been split differently.
+    pub incremental: bool,
+}
+
+impl Default for PushOptions {
+    fn default() -> Self {
+        PushOptions {
+            scan: ScanOptions::default(),
+            chunking: ChunkConfig::default(),
+            concurrency: DEFAULT_CONCURRENCY,
+            resume: false,
+            incremental: true,
+        }
+    }
+}
 
 pub async fn push(
     dir: &Path,
     server_url: &str,
     hostname: &str,
     token: &str,
+    options: &PushOptions,
 ) -> Result<u64, Box<dyn std::error::Error>> {
-    // Scan directory and build tree
-    println!("Scanning {}...", dir.display());
-    let entry = scan_directory(dir)?
-        .next()
-        .ok_or("Failed to scan directory")?;
-    let (tree, chunks) = build_tree(entry);
+    // Connect to server
+    println!("Connecting to {}...", server_url);
+    let (mut ws, version) = connect(server_url, token).await?;
+    let batching = version >= BATCH_PROTOCOL_VERSION;
+    println!("Authenticated");
 
-    println!("  Files: {} chunks", chunks.len());
-    println!("  Root hash: {}", hex::encode(tree.hash()));
+    let (tree, manifest) =
+        upload_site(&mut ws, version, dir, server_url, hostname, options).await?;
 
-    // Connect to server
+    // Commit tree. Chunks the server turns out not to have, e.g. ones
+    // skipped on resume or collected since it was asked, are read again
+    // from the files, uploaded, and the commit retried once.
+    println!("Committing...");
+    let mut retried = false;
+    loop {
+        send(
+            &mut ws,
+            &ClientMessage::CommitTree {
+                hostname: hostname.to_string(),
+                tree: tree.clone(),
+            },
+        )
+        .await?;
+
+        match recv(&mut ws).await? {
+            ServerMessage::CommitOk {
+                snapshot_id,
+                unchanged,
+            } => {
+                manifest.remove()?;
+                if unchanged {
+                    println!("No changes, snapshot {} is still current", snapshot_id);
+                } else {
+                    println!("Deployed snapshot {}", snapshot_id);
+                }
+                return Ok(snapshot_id);
+            }
+            ServerMessage::CommitFailed { missing, .. } if !missing.is_empty() && !retried => {
+                println!(
+                    "Server is missing {} chunks, uploading them again...",
+                    missing.len()
+                );
+                let chunks = reread_chunks(dir, &tree, &missing, &options.chunking)?;
+                upload_chunks(&mut ws, chunks, batching).await?;
+                retried = true;
+            }
+            ServerMessage::CommitFailed { reason, .. } => {
+                return Err(format!("Commit failed: {}", reason).into())
+            }
+            _ => return Err("Unexpected response".into()),
+        }
+    }
+}
+
+/// Deploy several sites at once, given as hostname and source directory
+/// pairs: every site's chunks are uploaded first, then all the trees are
+/// committed in one batch, so the sites switch to their new snapshots
+/// together or not at all. Returns each site's new snapshot ID.
+pub async fn push_sites(
+    sites: &[(String, PathBuf)],
+    server_url: &str,
+    token: &str,
+    options: &PushOptions,
+) -> Result<Vec<(String, u64)>, Box<dyn std::error::Error>> {
     println!("Connecting to {}...", server_url);
-    let (mut ws, _) = connect_async(server_url).await?;
-
-    // Authenticate
-    let auth_msg = rmp_serde::to_vec(&ClientMessage::Auth {
-        token: token.to_string(),
-    })?;
-    ws.send(Message::Binary(auth_msg)).await?;
-
-    let response = ws.next().await.ok_or("Connection closed")??;
-    let server_msg: ServerMessage = match response {
-        Message::Binary(data) => rmp_serde::from_slice(&data)?,
-        _ => return Err("Expected binary message".into()),
-    };
+    let (mut ws, version) = connect(server_url, token).await?;
+    if version < DEPLOY_BATCH_PROTOCOL_VERSION {
+        return Err(format!(
+            "Server speaks protocol version {}, deploying several sites at once needs version {}",
+            version, DEPLOY_BATCH_PROTOCOL_VERSION
+        )
+        .into());
+    }
+    println!("Authenticated");
+
+    let mut staged = Vec::with_capacity(sites.len());
+    for (hostname, dir) in sites {
+        println!("Uploading {}...", hostname);
+        let (tree, manifest) =
+            upload_site(&mut ws, version, dir, server_url, hostname, options).await?;
+        staged.push((hostname, tree, manifest));
+    }
 
-    match server_msg {
-        ServerMessage::AuthOk => println!("Authenticated"),
-        ServerMessage::AuthFailed => return Err("Authentication failed".into()),
+    println!("Committing {} sites...", staged.len());
+    send(&mut ws, &ClientMessage::BeginBatch).await?;
+    match recv(&mut ws).await? {
+        ServerMessage::BatchStarted => {}
         _ => return Err("Unexpected response".into()),
     }
+    for (hostname, tree, _) in &staged {
+        send(
+            &mut ws,
+            &ClientMessage::CommitTree {
+                hostname: hostname.to_string(),
+                tree: tree.clone(),
+            },
+        )
+        .await?;
+        match recv(&mut ws).await? {
+            ServerMessage::TreeStaged { .. } => {}
+            ServerMessage::CommitFailed { reason, .. } => {
+                return Err(format!("Commit of {} failed: {}", hostname, reason).into())
+            }
+            _ => return Err("Unexpected response".into()),
+        }
+    }
+
+    send(&mut ws, &ClientMessage::CommitBatch).await?;
+    match recv(&mut ws).await? {
+        ServerMessage::BatchCommitted { snapshots } => {
+            for (_, _, manifest) in staged {
+                manifest.remove()?;
+            }
+            for (hostname, snapshot_id) in &snapshots {
+                println!("Deployed {} snapshot {}", hostname, snapshot_id);
+            }
+            Ok(snapshots)
+        }
+        ServerMessage::CommitFailed { reason, .. } => {
+            Err(format!("Commit failed: {}", reason).into())
+        }
+        _ => Err("Unexpected response".into()),
+    }
+}
+
+/// Scan a site and upload the chunks the server is missing, returning the
+/// tree to commit and the manifest of acked chunks.
+async fn upload_site(
+    ws: &mut Connection,
+    version: u32,
+    dir: &Path,
+    server_url: &str,
+    hostname: &str,
+    options: &PushOptions,
+) -> Result<(Node, PushManifest), Box<dyn std::error::Error>> {
+    let batching = version >= BATCH_PROTOCOL_VERSION;
+
+    // The current tree lets files unchanged since the last deploy be
+    // skipped; their chunks are already on the server. Trees don't record
+    // how they were chunked, so its chunk lists are only trusted to match
+    // the default config.
+    let default_chunking = options.chunking == ChunkConfig::default();
+    if options.incremental && !default_chunking {
+        println!("Custom chunking: reading every file instead of reusing the current tree");
+    }
+    let previous = if options.incremental && default_chunking && version >= TREE_PROTOCOL_VERSION {
+        fetch_tree(ws, hostname, None).await?
+    } else {
+        None
+    };
+
+    // Scan directory and build tree
+    let phase = Phase::start(format!("Scanning and hashing {}...", dir.display()));
+    let scan = &options.scan;
+    let entry = match &previous {
+        Some((_, tree)) => scan_directory_incremental(dir, scan, tree)?.next(),
+        None => scan_directory_with(dir, scan)?.next(),
+    }
+    .ok_or("Failed to scan directory")?;
+    let (mut tree, mut chunks, stats) = build_tree_with_config_stats(entry, &options.chunking);
+    phase.finish();
+
+    if let Some((snapshot_id, _)) = previous {
+        println!(
+            "  Unchanged since snapshot {}: {} files",
+            snapshot_id, stats.files_unchanged
+        );
+    }
+    println!(
+        "  Files: {} in {} chunks",
+        HumanBytes(stats.file_bytes),
+        chunks.len()
+    );
+    println!("  Root hash: {}", hex::encode(tree.hash()));
+
+    // Servers before mtimes can't decode trees carrying them
+    if version < MTIME_PROTOCOL_VERSION {
+        tree.clear_mtimes();
+    }
+
+    // With --resume, chunks acked so far are recorded in a manifest so an
+    // interrupted push can be resumed, and those chunks are not offered again.
+    let key = manifest_key(server_url, hostname, tree.hash());
+    let mut manifest = PushManifest::open(dir, &key, options.resume)?;
+    if manifest.acked_count() > 0 {
+        chunks.retain(|c| !manifest.is_acked(&c.hash));
+        println!(
+            "Resuming: {} chunks already uploaded",
+            manifest.acked_count()
+        );
+    }
 
     // Send chunk hashes in batches
+    let offered = chunks.len();
     const BATCH_SIZE: usize = 100;
-    let mut chunks_to_send: Vec<&Chunk> = Vec::new();
+    let mut needed: HashSet<[u8; 32]> = HashSet::new();
 
     for batch in chunks.chunks(BATCH_SIZE) {
         let hashes: Vec<[u8; 32]> = batch.iter().map(|c| c.hash).collect();
-        let msg = rmp_serde::to_vec(&ClientMessage::HaveChunks { hashes })?;
-        ws.send(Message::Binary(msg)).await?;
-
-        // Get response
-        let response = ws.next().await.ok_or("Connection closed")??;
-        let server_msg: ServerMessage = match response {
-            Message::Binary(data) => rmp_serde::from_slice(&data)?,
-            _ => return Err("Expected binary message".into()),
+        let sizes = match version >= SIZES_PROTOCOL_VERSION {
+            true => batch.iter().map(|c| c.data.len() as u32).collect(),
+            false => Vec::new(),
         };
+        send(ws, &ClientMessage::HaveChunks { hashes, sizes }).await?;
 
-        match server_msg {
-            ServerMessage::NeedChunks { hashes: needed } => {
-                for chunk in batch {
-                    if needed.contains(&chunk.hash) {
-                        chunks_to_send.push(chunk);
-                    }
-                }
+        match recv(ws).await? {
+            ServerMessage::NeedChunks { hashes } => needed.extend(hashes),
+            ServerMessage::CommitFailed { reason, .. } => {
+                return Err(format!("Deploy rejected: {}", reason).into())
             }
             _ => return Err("Unexpected response".into()),
         }
     }
 
-    // Send needed chunks
-    println!("Sending {} chunks...", chunks_to_send.len());
-    for chunk in chunks_to_send {
-        let msg = rmp_serde::to_vec(&ClientMessage::ChunkData {
-            hash: chunk.hash,
-            data: chunk.data.clone(),
-        })?;
-        ws.send(Message::Binary(msg)).await?;
-
-        // Wait for ack
-        let response = ws.next().await.ok_or("Connection closed")??;
-        let _: ServerMessage = match response {
-            Message::Binary(data) => rmp_serde::from_slice(&data)?,
-            _ => return Err("Expected binary message".into()),
+    // Send needed chunks, moving each chunk's data into its message
+    // rather than copying it. Removing from `needed` also skips duplicates.
+    // Chunks go out in `ChunkBatch` frames of about `BATCH_BYTES`, or one
+    // `ChunkData` each for servers without batching. Up to `concurrency`
+    // frames are in flight; acks are collected as the window fills, and all
+    // of them before committing.
+    let to_send: Vec<Chunk> = chunks
+        .into_iter()
+        .filter(|c| needed.remove(&c.hash))
+        .collect();
+    let total_bytes = to_send.iter().map(|c| c.data.len() as u64).sum();
+    println!(
+        "Server already has {} of {} chunks, sending {} ({})...",
+        offered - to_send.len(),
+        offered,
+        to_send.len(),
+        HumanBytes(total_bytes)
+    );
+    let mut progress = UploadProgress::new(to_send.len() as u64, total_bytes);
+
+    let window = options.concurrency.max(1);
+    let mut in_flight: HashMap<[u8; 32], u64> = HashMap::new();
+    let mut frames = 0;
+    let mut batch: Vec<([u8; 32], Vec<u8>)> = Vec::new();
+    let mut batch_bytes = 0;
+    let mut to_send = to_send.into_iter().peekable();
+    while let Some(chunk) = to_send.next() {
+        in_flight.insert(chunk.hash, chunk.data.len() as u64);
+        batch_bytes += chunk.data.len();
+        batch.push((chunk.hash, chunk.data));
+        if batching && batch_bytes < BATCH_BYTES && to_send.peek().is_some() {
+            continue;
+        }
+
+        if frames >= window {
+            let (hashes, size) = recv_ack(ws, &mut in_flight).await?;
+            progress.chunks_done(hashes.len() as u64, size);
+            manifest.record(&hashes)?;
+            frames -= 1;
+        }
+        let message = if batching {
+            ClientMessage::ChunkBatch {
+                chunks: std::mem::take(&mut batch),
+            }
+        } else {
+            let (hash, data) = batch.pop().unwrap();
+            ClientMessage::ChunkData { hash, data }
         };
+        batch_bytes = 0;
+        send(ws, &message).await?;
+        frames += 1;
+    }
+    for _ in 0..frames {
+        let (hashes, size) = recv_ack(ws, &mut in_flight).await?;
+        progress.chunks_done(hashes.len() as u64, size);
+        manifest.record(&hashes)?;
     }
+    progress.finish();
 
-    // Commit tree
-    println!("Committing...");
-    let msg = rmp_serde::to_vec(&ClientMessage::CommitTree {
-        hostname: hostname.to_string(),
-        tree,
-    })?;
-    ws.send(Message::Binary(msg)).await?;
-
-    let response = ws.next().await.ok_or("Connection closed")??;
-    let server_msg: ServerMessage = match response {
-        Message::Binary(data) => rmp_serde::from_slice(&data)?,
-        _ => return Err("Expected binary message".into()),
-    };
+    Ok((tree, manifest))
+}
 
-    match server_msg {
-        ServerMessage::CommitOk { snapshot_id } => {
-            println!("Deployed snapshot {}", snapshot_id);
-            Ok(snapshot_id)
+/// Chunk again the files under `dir` that reference any of `hashes`,
+/// returning those chunks. Fails if a file no longer produces them.
+fn reread_chunks(
+    dir: &Path,
+    tree: &Node,
+    hashes: &[[u8; 32]],
+    config: &ChunkConfig,
+) -> Result<Vec<Chunk>, Box<dyn std::error::Error>> {
+    let mut wanted: HashSet<[u8; 32]> = hashes.iter().copied().collect();
+    let mut found = Vec::new();
+    find_chunks(dir, tree, config, &mut wanted, &mut found)?;
+    if !wanted.is_empty() {
+        return Err(format!(
+            "{} missing chunks are no longer in the files on disk",
+            wanted.len()
+        )
+        .into());
+    }
+    Ok(found)
+}
+
+fn find_chunks(
+    path: &Path,
+    node: &Node,
+    config: &ChunkConfig,
+    wanted: &mut HashSet<[u8; 32]>,
+    found: &mut Vec<Chunk>,
+) -> std::io::Result<()> {
+    match node {
+        Node::File { chunks, .. } => {
+            if chunks.iter().any(|hash| wanted.contains(hash)) {
+                let data = fs::read(path)?;
+                found.extend(chunk_data(&data, config).filter(|c| wanted.remove(&c.hash)));
+            }
         }
-        ServerMessage::CommitFailed { reason } => Err(format!("Commit failed: {}", reason).into()),
-        _ => Err("Unexpected response".into()),
+        Node::Directory { children, .. } => {
+            for child in children {
+                find_chunks(&path.join(child.name()), child, config, wanted, found)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Upload chunks one frame at a time, waiting for each frame's ack.
+async fn upload_chunks(
+    ws: &mut Connection,
+    chunks: Vec<Chunk>,
+    batching: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut in_flight: HashMap<[u8; 32], u64> = HashMap::new();
+    let mut batch: Vec<([u8; 32], Vec<u8>)> = Vec::new();
+    let mut batch_bytes = 0;
+    let mut chunks = chunks.into_iter().peekable();
+    while let Some(chunk) = chunks.next() {
+        in_flight.insert(chunk.hash, chunk.data.len() as u64);
+        batch_bytes += chunk.data.len();
+        batch.push((chunk.hash, chunk.data));
+        if batching && batch_bytes < BATCH_BYTES && chunks.peek().is_some() {
+            continue;
+        }
+
+        let message = if batching {
+            ClientMessage::ChunkBatch {
+                chunks: std::mem::take(&mut batch),
+            }
+        } else {
+            let (hash, data) = batch.pop().unwrap();
+            ClientMessage::ChunkData { hash, data }
+        };
+        batch_bytes = 0;
+        send(ws, &message).await?;
+        recv_ack(ws, &mut in_flight).await?;
+    }
+    Ok(())
+}
+
+/// Wait for the ack of one in-flight frame and stop tracking its chunks,
+/// returning the acked hashes and their total size.
+async fn recv_ack(
+    ws: &mut Connection,
+    in_flight: &mut HashMap<[u8; 32], u64>,
+) -> Result<(Vec<[u8; 32]>, u64), Box<dyn std::error::Error>> {
+    let hashes = match recv(ws).await? {
+        ServerMessage::ChunkAck { hash } => vec![hash],
+        ServerMessage::BatchAck { hashes } => hashes,
+        ServerMessage::ChunkRejected { hash, reason } => {
+            return Err(format!("Chunk {} rejected: {}", hex::encode(hash), reason).into())
+        }
+        ServerMessage::CommitFailed { reason, .. } => {
+            return Err(format!("Deploy rejected: {}", reason).into())
+        }
+        _ => return Err("Unexpected response".into()),
+    };
+
+    let mut size = 0;
+    for hash in &hashes {
+        size += in_flight
+            .remove(hash)
+            .ok_or_else(|| format!("Unexpected ack for chunk {}", hex::encode(hash)))?;
     }
+    Ok((hashes, size))
 }
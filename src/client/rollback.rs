@@ -1,47 +1,36 @@
-use crate::protocol::{ClientMessage, ServerMessage};
-use futures_util::{SinkExt, StreamExt};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use crate::client::{connect, recv, send};
+use crate::protocol::{ClientMessage, ServerMessage, TAG_PROTOCOL_VERSION};
 
+/// Roll a site back to a snapshot given by ID or tag, or to the previous
+/// snapshot when neither is given.
 pub async fn rollback(
     server_url: &str,
     hostname: &str,
     token: &str,
     snapshot_id: Option<u64>,
+    tag: Option<&str>,
 ) -> Result<u64, Box<dyn std::error::Error>> {
-    let (mut ws, _) = connect_async(server_url).await?;
-
-    // Authenticate
-    let auth_msg = rmp_serde::to_vec(&ClientMessage::Auth {
-        token: token.to_string(),
-    })?;
-    ws.send(Message::Binary(auth_msg)).await?;
-
-    let response = ws.next().await.ok_or("Connection closed")??;
-    let server_msg: ServerMessage = match response {
-        Message::Binary(data) => rmp_serde::from_slice(&data)?,
-        _ => return Err("Expected binary message".into()),
-    };
-
-    match server_msg {
-        ServerMessage::AuthOk => {}
-        ServerMessage::AuthFailed => return Err("Authentication failed".into()),
-        _ => return Err("Unexpected response".into()),
+    let (mut ws, version) = connect(server_url, token).await?;
+    if tag.is_some() && version < TAG_PROTOCOL_VERSION {
+        return Err(format!(
+            "Server speaks protocol version {}, rollback by tag needs version {}",
+            version, TAG_PROTOCOL_VERSION
+        )
+        .into());
     }
 
     // Request rollback
-    let rollback_msg = rmp_serde::to_vec(&ClientMessage::Rollback {
-        hostname: hostname.to_string(),
-        snapshot_id,
-    })?;
-    ws.send(Message::Binary(rollback_msg)).await?;
-
-    let response = ws.next().await.ok_or("Connection closed")??;
-    let server_msg: ServerMessage = match response {
-        Message::Binary(data) => rmp_serde::from_slice(&data)?,
-        _ => return Err("Expected binary message".into()),
-    };
+    send(
+        &mut ws,
+        &ClientMessage::Rollback {
+            hostname: hostname.to_string(),
+            snapshot_id,
+            tag: tag.map(str::to_string),
+        },
+    )
+    .await?;
 
-    match server_msg {
+    match recv(&mut ws).await? {
         ServerMessage::RollbackOk { snapshot_id } => Ok(snapshot_id),
         ServerMessage::RollbackFailed { reason } => {
             Err(format!("Rollback failed: {}", reason).into())
@@ -49,3 +38,37 @@ pub async fn rollback(
         _ => Err("Unexpected response".into()),
     }
 }
+
+/// Tag a snapshot of a site, moving the tag off any other snapshot.
+pub async fn tag(
+    server_url: &str,
+    hostname: &str,
+    token: &str,
+    snapshot_id: u64,
+    tag: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut ws, version) = connect(server_url, token).await?;
+    if version < TAG_PROTOCOL_VERSION {
+        return Err(format!(
+            "Server speaks protocol version {}, tagging needs version {}",
+            version, TAG_PROTOCOL_VERSION
+        )
+        .into());
+    }
+
+    send(
+        &mut ws,
+        &ClientMessage::TagSnapshot {
+            hostname: hostname.to_string(),
+            snapshot_id,
+            tag: tag.to_string(),
+        },
+    )
+    .await?;
+
+    match recv(&mut ws).await? {
+        ServerMessage::SnapshotTagged => Ok(()),
+        ServerMessage::TagFailed { reason } => Err(format!("Tag failed: {}", reason).into()),
+        _ => Err("Unexpected response".into()),
+    }
+}
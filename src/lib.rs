@@ -5,7 +5,10 @@ pub mod merkle;
 pub mod protocol;
 pub mod scanner;
 pub mod server;
+pub mod timestamp;
 
-pub use chunker::Chunk;
-pub use merkle::{build_tree, Node};
-pub use scanner::{scan_directory, ScannedEntry};
+pub use chunker::{Chunk, ChunkConfig};
+pub use merkle::{build_tree, build_tree_with, Node};
+pub use scanner::{
+    scan_directory, scan_directory_incremental, scan_directory_with, ScanOptions, ScannedEntry,
+};
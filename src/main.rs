@@ -1,8 +1,33 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use indicatif::HumanBytes;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::net::TcpListener;
-use webpub::{archive, build_tree, scan_directory, server::storage::Storage};
+use tokio_util::sync::CancellationToken;
+use webpub::chunker::{ChunkConfig, ChunkConfigError, AVG_SIZE, MAX_SIZE, MIN_SIZE};
+use webpub::client::push::{PushOptions, DEFAULT_CONCURRENCY};
+use webpub::merkle::{self, build_tree_with_config_stats, DiffEntry};
+use webpub::server::access_log::{init_logging, LogFormat};
+use webpub::server::admin::admin_router;
+use webpub::server::auth::TokenAuthenticator;
+use webpub::server::file_cache::DEFAULT_CACHE_SIZE;
+use webpub::server::http::{create_router_with, RouterOptions};
+use webpub::server::metrics::{metrics_router, Metrics};
+use webpub::server::pool::DEFAULT_POOL_SIZE;
+use webpub::server::preview_link::preview_path;
+use webpub::server::rate_limit::{AuthLimiter, DEFAULT_AUTH_FAILURES_PER_MINUTE};
+use webpub::server::sync::{
+    PermissionPolicy, SyncState, DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_MESSAGE_SIZE,
+};
+use webpub::server::tls;
+use webpub::timestamp::{format_expiry, format_utc};
+use webpub::{
+    archive, scan_directory_with,
+    server::storage::{Storage, StorageOptions, Synchronous},
+    ScanOptions,
+};
 
 #[derive(Parser)]
 #[command(name = "webpub")]
@@ -20,28 +45,149 @@ enum Commands {
         dir: PathBuf,
         /// Output archive file
         output: PathBuf,
+        /// Chunk compression: none or zstd
+        #[arg(long, default_value = "zstd")]
+        compression: archive::Compression,
+        /// Skip paths matching a gitignore-style pattern (repeatable)
+        #[arg(long = "ignore", value_name = "PATTERN")]
+        ignore: Vec<String>,
+        /// Follow symlinks instead of skipping them
+        #[arg(long)]
+        follow_symlinks: bool,
+        /// Record files as 0644 and directories as 0755 regardless of source permissions
+        #[arg(long = "normalize-perms")]
+        normalize_permissions: bool,
+        /// Skip files larger than this, e.g. 50MB or 1GiB
+        #[arg(long, value_parser = parse_size, value_name = "SIZE")]
+        max_file_size: Option<u64>,
+        /// Fail instead of skipping files over --max-file-size
+        #[arg(long, requires = "max_file_size")]
+        fail_on_large_files: bool,
+        #[command(flatten)]
+        chunking: ChunkArgs,
     },
     /// Extract archive to directory
     Extract {
         /// Archive file
         archive: PathBuf,
         /// Output directory
-        output: PathBuf,
+        #[arg(required_unless_present = "tar", conflicts_with = "tar")]
+        output: Option<PathBuf>,
+        /// Extract only this file or directory, e.g. css/style.css
+        #[arg(long, conflicts_with = "tar")]
+        path: Option<String>,
+        /// Write a tar stream to this file instead, or "-" for stdout
+        #[arg(long, value_name = "DEST")]
+        tar: Option<PathBuf>,
+        /// Check the archive's checksum before extracting
+        #[arg(long)]
+        verify: bool,
+        /// Overwrite files already in the output directory
+        #[arg(long, conflicts_with = "tar")]
+        force: bool,
+    },
+    /// List the contents of an archive without extracting it
+    ListArchive {
+        /// Archive file
+        archive: PathBuf,
+    },
+    /// Check an archive's chunks and file hashes for corruption
+    Verify {
+        /// Archive file
+        archive: PathBuf,
+    },
+    /// Compare the contents of two archives
+    DiffArchive {
+        /// First archive file
+        a: PathBuf,
+        /// Second archive file
+        b: PathBuf,
+    },
+    /// Preview an archive over HTTP, without a data directory or sync server
+    ServeArchive {
+        /// Archive file
+        archive: PathBuf,
+        /// HTTP port
+        #[arg(long, default_value = "8080")]
+        port: u16,
     },
     /// Run the server
     Serve {
-        /// HTTP port for serving websites
-        #[arg(long, default_value = "8080")]
-        http_port: u16,
-        /// Sync port for WebSocket deployments
-        #[arg(long, default_value = "9000")]
-        sync_port: u16,
+        /// HTTP port for serving websites [default: 8080, or 443 with TLS]
+        #[arg(long)]
+        http_port: Option<u16>,
+        /// IP, or IP and port, to serve websites on [default: 0.0.0.0]
+        #[arg(long, value_parser = parse_bind_addr, value_name = "ADDR")]
+        http_addr: Option<BindAddr>,
+        /// Sync port for WebSocket deployments [default: 9000]
+        #[arg(long)]
+        sync_port: Option<u16>,
+        /// IP, or IP and port, to accept deployments on [default: 0.0.0.0]
+        #[arg(long, value_parser = parse_bind_addr, value_name = "ADDR")]
+        sync_addr: Option<BindAddr>,
         /// Data directory for storage
         #[arg(long, default_value = "./data")]
         data: PathBuf,
         /// Number of snapshots to keep per site
         #[arg(long, default_value = "5")]
         keep: usize,
+        /// Reject deploys containing setuid, setgid, or world-writable files
+        #[arg(long)]
+        strict_permissions: bool,
+        /// Free space in bytes to keep on the data disk; deploys that would use it are rejected
+        #[arg(long, default_value = "0")]
+        min_free_space: u64,
+        /// Seconds a sync connection may go without a message before it is closed
+        #[arg(long, default_value_t = DEFAULT_IDLE_TIMEOUT.as_secs())]
+        sync_idle_timeout: u64,
+        /// Largest message in bytes a sync client may send
+        #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+        sync_max_message_size: usize,
+        /// Failed sync authentications allowed per client IP per minute; 0 disables the limit
+        #[arg(long, default_value_t = DEFAULT_AUTH_FAILURES_PER_MINUTE)]
+        auth_failures_per_minute: u32,
+        /// Serve websites over HTTPS with this PEM certificate chain
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// PEM private key for --tls-cert
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Also accept plain HTTP on this port (e.g. 80) of the HTTP address's IP, redirecting to HTTPS
+        #[arg(long, requires = "tls_cert")]
+        redirect_port: Option<u16>,
+        /// Accept wss:// deployments on the sync port with this PEM certificate chain
+        #[arg(long, requires = "sync_tls_key")]
+        sync_tls_cert: Option<PathBuf>,
+        /// PEM private key for --sync-tls-cert
+        #[arg(long, requires = "sync_tls_cert")]
+        sync_tls_key: Option<PathBuf>,
+        /// Answer /healthz on this host with server health instead of site content
+        #[arg(long)]
+        health_host: Option<String>,
+        /// Serve Prometheus metrics at /metrics and the admin API at /api on this port
+        #[arg(long)]
+        metrics_port: Option<u16>,
+        /// IP, or IP and port, for metrics and the admin API [default: the HTTP address's IP]
+        #[arg(long, value_parser = parse_bind_addr, value_name = "ADDR")]
+        metrics_addr: Option<BindAddr>,
+        /// Log format, for access logs and request errors: text or json
+        #[arg(long, default_value = "text")]
+        log_format: LogFormat,
+        /// Bytes of reassembled files to cache in memory; 0 disables the cache
+        #[arg(long, default_value_t = DEFAULT_CACHE_SIZE)]
+        cache_size: u64,
+        /// SQLite connections each chunk database, and index reads, may use at once
+        #[arg(long, default_value_t = DEFAULT_POOL_SIZE)]
+        db_pool_size: usize,
+        /// SQLite synchronous level: off, normal, or full/extra to survive power loss
+        #[arg(long, default_value = "normal")]
+        db_synchronous: Synchronous,
+        /// Bytes of each SQLite database to memory-map for reads
+        #[arg(long)]
+        db_mmap_size: Option<u64>,
+        /// Let signed /_preview/ links show a site's other snapshots
+        #[arg(long)]
+        preview: bool,
     },
     /// Manage authentication tokens
     Token {
@@ -51,12 +197,61 @@ enum Commands {
         #[arg(long, default_value = "./data")]
         data: PathBuf,
     },
+    /// Import an archive into server storage as a new snapshot of a site
+    Import {
+        /// Archive file
+        archive: PathBuf,
+        /// Hostname
+        #[arg(long)]
+        host: String,
+        /// Data directory for storage
+        #[arg(long, default_value = "./data")]
+        data: PathBuf,
+    },
+    /// Export a site's current snapshot to an archive
+    Export {
+        /// Hostname
+        hostname: String,
+        /// Output archive file
+        output: PathBuf,
+        /// Data directory for storage
+        #[arg(long, default_value = "./data")]
+        data: PathBuf,
+    },
+    /// Print the preview link of one of a site's snapshots, for `serve --preview`
+    PreviewLink {
+        /// Hostname
+        hostname: String,
+        /// Snapshot ID
+        snapshot_id: i64,
+        /// Data directory for storage
+        #[arg(long, default_value = "./data")]
+        data: PathBuf,
+    },
     /// Garbage collect unused chunks
     Gc {
         /// Data directory for storage
         #[arg(long, default_value = "./data")]
         data: PathBuf,
     },
+    /// Merge chunk shards of a small store, or split them again once it grows
+    Compact {
+        /// Data directory for storage
+        #[arg(long, default_value = "./data")]
+        data: PathBuf,
+        /// Largest chunk count kept in a single database
+        #[arg(long, default_value = "10000")]
+        threshold: u64,
+    },
+    /// Check storage consistency
+    Doctor {
+        /// Data directory for storage
+        #[arg(long, default_value = "./data")]
+        data: PathBuf,
+        /// Repair any problems found
+        #[arg(long)]
+        fix: bool,
+    },
     /// Push directory to server
     Push {
         /// Source directory
@@ -66,6 +261,69 @@ enum Commands {
         /// Hostname to publish as
         #[arg(long)]
         host: String,
+        /// Skip paths matching a gitignore-style pattern (repeatable)
+        #[arg(long = "ignore", value_name = "PATTERN")]
+        ignore: Vec<String>,
+        /// Follow symlinks instead of skipping them
+        #[arg(long)]
+        follow_symlinks: bool,
+        /// Record files as 0644 and directories as 0755 regardless of source permissions
+        #[arg(long = "normalize-perms")]
+        normalize_permissions: bool,
+        /// Skip files larger than this, e.g. 50MB or 1GiB
+        #[arg(long, value_parser = parse_size, value_name = "SIZE")]
+        max_file_size: Option<u64>,
+        /// Fail instead of skipping files over --max-file-size
+        #[arg(long, requires = "max_file_size")]
+        fail_on_large_files: bool,
+        #[command(flatten)]
+        chunking: ChunkArgs,
+        /// Chunks to upload before waiting for the server's acks
+        #[arg(long, default_value_t = DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+        /// Skip chunks uploaded by an earlier, interrupted push of the same tree
+        #[arg(long)]
+        resume: bool,
+        /// Read every file, instead of skipping files whose size and mtime
+        /// match the current snapshot
+        #[arg(long)]
+        full_scan: bool,
+    },
+    /// Deploy several sites together, switching all of them to their new
+    /// snapshots at once
+    PushSites {
+        /// Server WebSocket URL
+        server: String,
+        /// Site to deploy as HOSTNAME=DIR (repeatable)
+        #[arg(long = "site", value_name = "HOSTNAME=DIR", value_parser = parse_site, required = true)]
+        sites: Vec<(String, PathBuf)>,
+        /// Skip paths matching a gitignore-style pattern (repeatable)
+        #[arg(long = "ignore", value_name = "PATTERN")]
+        ignore: Vec<String>,
+        /// Follow symlinks instead of skipping them
+        #[arg(long)]
+        follow_symlinks: bool,
+        /// Record files as 0644 and directories as 0755 regardless of source permissions
+        #[arg(long = "normalize-perms")]
+        normalize_permissions: bool,
+        /// Skip files larger than this, e.g. 50MB or 1GiB
+        #[arg(long, value_parser = parse_size, value_name = "SIZE")]
+        max_file_size: Option<u64>,
+        /// Fail instead of skipping files over --max-file-size
+        #[arg(long, requires = "max_file_size")]
+        fail_on_large_files: bool,
+        #[command(flatten)]
+        chunking: ChunkArgs,
+        /// Chunks to upload before waiting for the server's acks
+        #[arg(long, default_value_t = DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+        /// Skip chunks uploaded by an earlier, interrupted push of the same tree
+        #[arg(long)]
+        resume: bool,
+        /// Read every file, instead of skipping files whose size and mtime
+        /// match the current snapshot
+        #[arg(long)]
+        full_scan: bool,
     },
     /// List snapshots for a site
     List {
@@ -82,128 +340,813 @@ enum Commands {
         /// Hostname
         #[arg(long)]
         host: String,
-        /// Specific snapshot ID (default: previous)
+        /// Specific snapshot ID or tag (default: previous)
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// Tag a snapshot, e.g. as stable, to roll back to it by name
+    Tag {
+        /// Server WebSocket URL
+        server: String,
+        /// Hostname
+        #[arg(long)]
+        host: String,
+        /// Snapshot ID
+        snapshot_id: u64,
+        /// Tag, moved off any other snapshot of the site
+        tag: String,
+    },
+    /// Write a file of a site's current snapshot to stdout
+    Cat {
+        /// Server WebSocket URL
+        server: String,
+        /// Hostname
+        #[arg(long)]
+        host: String,
+        /// Path in the site, e.g. /css/style.css; a directory gives its index.html
+        path: String,
+    },
+    /// List the sites on a server
+    Sites {
+        /// Server WebSocket URL
+        server: String,
+    },
+    /// Remove a site with all of its snapshots
+    DeleteSite {
+        /// Server WebSocket URL
+        server: String,
+        /// Hostname
+        #[arg(long)]
+        host: String,
+    },
+    /// Show files changed between two snapshots of a site
+    Diff {
+        /// Server WebSocket URL
+        server: String,
+        /// Hostname
         #[arg(long)]
-        to: Option<u64>,
+        host: String,
+        /// Older snapshot ID (default: the one before <NEW>)
+        old: Option<u64>,
+        /// Newer snapshot ID (default: current)
+        new: Option<u64>,
     },
 }
 
 #[derive(Subcommand)]
 enum TokenAction {
     /// Add a new token
-    Add,
-    /// List all tokens
+    Add {
+        /// Unique name identifying the token's holder, e.g. github-ci
+        #[arg(long)]
+        label: Option<String>,
+        /// Expire after this long (e.g. 30d, 12h), overriding the default TTL policy
+        #[arg(long, value_parser = parse_duration, conflicts_with = "expires_never")]
+        ttl: Option<Duration>,
+        /// Never expire, overriding the default TTL policy
+        #[arg(long)]
+        expires_never: bool,
+    },
+    /// Show or set the default TTL for new tokens
+    Policy {
+        /// Default TTL for new tokens (e.g. 90d, 12h)
+        #[arg(long, value_parser = parse_duration, conflicts_with = "no_default_ttl")]
+        default_ttl: Option<Duration>,
+        /// Make new tokens non-expiring by default
+        #[arg(long)]
+        no_default_ttl: bool,
+    },
+    /// List all tokens with their expiry
     List,
     /// Revoke a token
     Revoke {
-        /// Token to revoke
+        /// Token to revoke, or its label
         token: String,
     },
 }
 
+/// How long `serve` waits for requests and deploys in flight after a
+/// shutdown signal before closing them.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// Wait for Ctrl-C, or SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => eprintln!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        eprintln!("Failed to listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Chunking flags shared by the commands that build trees.
+#[derive(Args)]
+struct ChunkArgs {
+    /// Minimum chunk size in bytes
+    #[arg(long, default_value_t = MIN_SIZE)]
+    min_chunk: u32,
+    /// Average chunk size in bytes
+    #[arg(long, default_value_t = AVG_SIZE)]
+    avg_chunk: u32,
+    /// Maximum chunk size in bytes
+    #[arg(long, default_value_t = MAX_SIZE)]
+    max_chunk: u32,
+    /// Split files into blocks of exactly this many bytes instead of using FastCDC
+    #[arg(long, value_name = "BYTES", conflicts_with_all = ["min_chunk", "avg_chunk", "max_chunk"])]
+    fixed_chunk: Option<u32>,
+}
+
+impl ChunkArgs {
+    /// The chunk config the flags describe.
+    fn config(&self) -> Result<ChunkConfig, ChunkConfigError> {
+        match self.fixed_chunk {
+            Some(size) => ChunkConfig::fixed(size),
+            None => ChunkConfig::new(self.min_chunk, self.avg_chunk, self.max_chunk),
+        }
+    }
+}
+
+/// Parse a duration such as `90d`, `12h`, `30m`, `45s` or `2w`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in '{}' (use s, m, h, d or w)", s))?;
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", s))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown unit '{}' (use s, m, h, d or w)", unit)),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+/// Parse a size in bytes with an optional unit: KB, MB and GB are powers of
+/// 1000, KiB, MiB and GiB powers of 1024.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000 * 1000,
+        "gb" => 1000 * 1000 * 1000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "unknown unit '{}' (use B, KB, MB, GB, KiB, MiB or GiB)",
+                unit
+            ))
+        }
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+/// An address for a server to listen on: an IP, taking its port from a
+/// separate flag, or a full socket address.
+#[derive(Debug, Clone, Copy)]
+enum BindAddr {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+}
+
+/// Parse a `--*-addr` value such as `127.0.0.1`, `::1` or `[::1]:8080`.
+fn parse_bind_addr(s: &str) -> Result<BindAddr, String> {
+    if let Ok(addr) = s.parse() {
+        return Ok(BindAddr::Socket(addr));
+    }
+    s.parse()
+        .map(BindAddr::Ip)
+        .map_err(|_| format!("invalid address '{}' (use an IP or IP:PORT)", s))
+}
+
+/// The socket address for a `--<name>-addr` and `--<name>-port` pair,
+/// listening on all interfaces by default.
+fn bind_addr(
+    name: &str,
+    addr: Option<BindAddr>,
+    port: Option<u16>,
+    default_port: u16,
+) -> Result<SocketAddr, String> {
+    match (addr, port) {
+        (Some(BindAddr::Socket(_)), Some(_)) => Err(format!(
+            "--{}-addr includes a port, so --{}-port can't be given too",
+            name, name
+        )),
+        (Some(BindAddr::Socket(addr)), None) => Ok(addr),
+        (Some(BindAddr::Ip(ip)), port) => Ok(SocketAddr::new(ip, port.unwrap_or(default_port))),
+        (None, port) => Ok(SocketAddr::from((
+            [0, 0, 0, 0],
+            port.unwrap_or(default_port),
+        ))),
+    }
+}
+
+/// Parse a `HOSTNAME=DIR` site for `push-sites`.
+fn parse_site(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((hostname, dir)) if !hostname.is_empty() && !dir.is_empty() => {
+            Ok((hostname.to_string(), PathBuf::from(dir)))
+        }
+        _ => Err(format!("expected HOSTNAME=DIR, got '{}'", s)),
+    }
+}
+
+/// Format a duration in the largest whole unit accepted by `parse_duration`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    for (unit, size) in [("w", 604_800), ("d", 86_400), ("h", 3_600), ("m", 60)] {
+        if secs > 0 && secs.is_multiple_of(size) {
+            return format!("{}{}", secs / size, unit);
+        }
+    }
+    format!("{}s", secs)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Archive { dir, output } => {
-            let entry = scan_directory(&dir)?
+        Commands::Archive {
+            dir,
+            output,
+            ignore,
+            follow_symlinks,
+            normalize_permissions,
+            max_file_size,
+            fail_on_large_files,
+            chunking,
+            compression,
+        } => {
+            let chunking = chunking.config()?;
+            let options = ScanOptions {
+                ignore,
+                follow_symlinks,
+                normalize_permissions,
+                max_file_size,
+                fail_on_large_files,
+            };
+            let entry = scan_directory_with(&dir, &options)?
                 .next()
                 .ok_or("Failed to scan directory")?;
-            let (tree, chunks) = build_tree(entry);
-            archive::write_archive(&output, &tree, &chunks)?;
+            let (tree, chunks, stats) = build_tree_with_config_stats(entry, &chunking);
+            archive::write_archive_with(&output, &tree, &chunks, compression)?;
             println!("Created archive: {}", output.display());
             println!("  Tree hash: {}", hex::encode(tree.hash()));
             println!("  Chunks: {}", chunks.len());
+            println!(
+                "  Files: {}, distinct chunks: {}{}",
+                HumanBytes(stats.file_bytes),
+                HumanBytes(stats.chunk_bytes),
+                stats
+                    .dedup_ratio()
+                    .map(|ratio| format!(" ({:.2}x dedup)", ratio))
+                    .unwrap_or_default()
+            );
+            println!(
+                "  Archive size: {}",
+                HumanBytes(std::fs::metadata(&output)?.len())
+            );
         }
         Commands::Extract {
             archive: archive_path,
             output,
+            path,
+            tar,
+            verify,
+            force,
+        } => {
+            if verify {
+                let mut reader = archive::ArchiveReader::open(&archive_path)?;
+                if !reader.has_checksum() {
+                    eprintln!("Warning: archive predates checksums, not verified");
+                }
+                reader.verify_checksum()?;
+            }
+            match (tar, output) {
+                (Some(dest), _) if dest.as_os_str() == "-" => {
+                    archive::to_tar(&archive_path, std::io::stdout().lock())?;
+                }
+                (Some(dest), _) => {
+                    archive::to_tar(&archive_path, std::fs::File::create(&dest)?)?;
+                    eprintln!("Wrote tar to: {}", dest.display());
+                }
+                (None, Some(output)) => {
+                    let options = archive::ExtractOptions { overwrite: force };
+                    archive::ArchiveReader::open(&archive_path)?.extract_with(
+                        path.as_deref().unwrap_or(""),
+                        &output,
+                        &options,
+                    )?;
+                    println!("Extracted to: {}", output.display());
+                }
+                (None, None) => unreachable!("clap requires an output directory without --tar"),
+            }
+        }
+        Commands::ListArchive {
+            archive: archive_path,
+        } => {
+            for entry in archive::list_archive(&archive_path)? {
+                let indent = "  ".repeat(entry.depth);
+                let name = entry.path.rsplit('/').next().unwrap_or(&entry.path);
+                if entry.is_dir {
+                    println!(
+                        "{:04o}            {}{}/",
+                        entry.permissions & 0o7777,
+                        indent,
+                        name
+                    );
+                } else {
+                    println!(
+                        "{:04o} {:>10} {}{} ({} chunks)",
+                        entry.permissions & 0o7777,
+                        entry.size,
+                        indent,
+                        name,
+                        entry.chunks
+                    );
+                }
+            }
+        }
+        Commands::Verify {
+            archive: archive_path,
         } => {
-            archive::read_archive(&archive_path, &output)?;
-            println!("Extracted to: {}", output.display());
+            let report = archive::verify_archive(&archive_path)?;
+            match report.corruption {
+                Some(corruption) => {
+                    eprintln!("Corrupted: {}", corruption);
+                    std::process::exit(1);
+                }
+                None => println!(
+                    "OK ({} chunks, {} files{})",
+                    report.chunks_checked,
+                    report.files_checked,
+                    if report.checksum_checked {
+                        ", checksum"
+                    } else {
+                        ""
+                    }
+                ),
+            }
+        }
+        Commands::DiffArchive { a, b } => {
+            let index_a = archive::read_index(&a)?;
+            let index_b = archive::read_index(&b)?;
+
+            let changes = merkle::diff(&index_a.tree, &index_b.tree);
+            let chunks_differ = index_a.chunks.len() != index_b.chunks.len()
+                || index_a
+                    .chunks
+                    .keys()
+                    .any(|hash| !index_b.chunks.contains_key(hash));
+
+            print_changes(&changes);
+            if chunks_differ {
+                println!("Chunk sets differ");
+            }
+
+            if changes.is_empty() && !chunks_differ {
+                println!("Archives are identical");
+            } else {
+                std::process::exit(1);
+            }
+        }
+        Commands::ServeArchive { archive, port } => {
+            let router = webpub::server::preview::archive_router(&archive)?;
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let listener = TcpListener::bind(addr).await?;
+            println!("Serving {} at http://{}", archive.display(), addr);
+            axum::serve(listener, router)
+                .with_graceful_shutdown(shutdown_signal())
+                .await?;
         }
         Commands::Serve {
             http_port,
+            http_addr,
             sync_port,
+            sync_addr,
             data,
             keep,
+            strict_permissions,
+            min_free_space,
+            sync_idle_timeout,
+            sync_max_message_size,
+            auth_failures_per_minute,
+            tls_cert,
+            tls_key,
+            redirect_port,
+            sync_tls_cert,
+            sync_tls_key,
+            health_host,
+            metrics_port,
+            metrics_addr,
+            log_format,
+            cache_size,
+            db_pool_size,
+            db_synchronous,
+            db_mmap_size,
+            preview,
         } => {
-            let storage = Arc::new(Storage::open(&data)?);
+            let storage = Arc::new(Storage::open_with(
+                &data,
+                StorageOptions {
+                    pool_size: db_pool_size,
+                    synchronous: db_synchronous,
+                    mmap_size: db_mmap_size,
+                },
+            )?);
+            let shutdown = CancellationToken::new();
+            let _log_guard = init_logging(log_format)?;
 
-            // Create HTTP server
-            let http_router = webpub::server::http::create_router(storage.clone());
-            let http_addr = format!("0.0.0.0:{}", http_port);
-            let http_listener = TcpListener::bind(&http_addr).await?;
-            println!("HTTP server listening on {}", http_addr);
-
-            // Create sync server
-            let sync_addr = format!("0.0.0.0:{}", sync_port);
-            let sync_listener = TcpListener::bind(&sync_addr).await?;
-            println!("Sync server listening on {}", sync_addr);
-
-            // Run both servers concurrently
-            let http_server = async {
-                axum::serve(http_listener, http_router).await.unwrap();
+            // Create HTTP server, terminating TLS itself when given a certificate
+            let metrics = Arc::new(Metrics::default());
+            let options = RouterOptions {
+                health_host,
+                metrics: metrics.clone(),
+                access_log: true,
+                cache_size,
+                preview_secret: if preview {
+                    Some(storage.preview_secret()?)
+                } else {
+                    None
+                },
+            };
+            let http_router = create_router_with(storage.clone(), options);
+            let default_port = if tls_cert.is_some() { 443 } else { 8080 };
+            let http_addr = bind_addr("http", http_addr, http_port, default_port)?;
+            let sync_addr = bind_addr("sync", sync_addr, sync_port, 9000)?;
+            let metrics_addr = match (metrics_addr, metrics_port) {
+                (None, None) => None,
+                (Some(BindAddr::Ip(_)), None) => {
+                    return Err("--metrics-addr without a port needs --metrics-port".into())
+                }
+                // Metrics and the admin API share the HTTP server's interface by default
+                (addr, port) => {
+                    let addr = addr.unwrap_or(BindAddr::Ip(http_addr.ip()));
+                    Some(bind_addr("metrics", Some(addr), port, 0)?)
+                }
+            };
+            let http_server = match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => {
+                    let config = tls::load_config(&cert, &key).await?;
+                    tls::reload_on_sighup(config.clone(), cert, key)?;
+                    println!("HTTPS server listening on {}", http_addr);
+                    let handle = axum_server::Handle::new();
+                    let signal = shutdown.clone();
+                    let shutdown_handle = handle.clone();
+                    tokio::spawn(async move {
+                        signal.cancelled().await;
+                        shutdown_handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+                    });
+                    tokio::spawn(
+                        axum_server::bind_rustls(http_addr, config)
+                            .handle(handle)
+                            .serve(http_router.into_make_service()),
+                    )
+                }
+                _ => {
+                    let http_listener = TcpListener::bind(http_addr).await?;
+                    println!("HTTP server listening on {}", http_listener.local_addr()?);
+                    let signal = shutdown.clone().cancelled_owned();
+                    tokio::spawn(async move {
+                        axum::serve(http_listener, http_router)
+                            .with_graceful_shutdown(signal)
+                            .await
+                    })
+                }
             };
 
-            let sync_storage = storage.clone();
-            let sync_server = async move {
-                loop {
-                    match sync_listener.accept().await {
-                        Ok((stream, addr)) => {
-                            println!("Sync connection from {}", addr);
-                            let storage = sync_storage.clone();
-                            tokio::spawn(webpub::server::sync::handle_connection(
-                                stream, storage, keep,
-                            ));
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to accept sync connection: {}", e);
-                        }
+            if let Some(port) = redirect_port {
+                let redirect_listener = TcpListener::bind((http_addr.ip(), port)).await?;
+                println!("Redirecting HTTP on port {} to HTTPS", port);
+                let signal = shutdown.clone().cancelled_owned();
+                let https_port = http_addr.port();
+                tokio::spawn(async move {
+                    if let Err(e) = axum::serve(redirect_listener, tls::redirect_router(https_port))
+                        .with_graceful_shutdown(signal)
+                        .await
+                    {
+                        eprintln!("Redirect server failed: {}", e);
+                    }
+                });
+            }
+
+            if let Some(metrics_addr) = metrics_addr {
+                let metrics_listener = TcpListener::bind(metrics_addr).await?;
+                println!(
+                    "Metrics and admin API listening on {}",
+                    metrics_listener.local_addr()?
+                );
+                let authenticator = Arc::new(TokenAuthenticator::new(storage.clone()));
+                let router = metrics_router(metrics.clone(), storage.clone())
+                    .merge(admin_router(storage.clone(), authenticator));
+                let signal = shutdown.clone().cancelled_owned();
+                tokio::spawn(async move {
+                    if let Err(e) = axum::serve(metrics_listener, router)
+                        .with_graceful_shutdown(signal)
+                        .await
+                    {
+                        eprintln!("Metrics server failed: {}", e);
                     }
+                });
+            }
+
+            // Create sync server
+            let sync_listener = TcpListener::bind(sync_addr).await?;
+            let sync_addr = sync_listener.local_addr()?;
+            let sync_tls = match (sync_tls_cert, sync_tls_key) {
+                (Some(cert), Some(key)) => {
+                    let config = tls::load_config(&cert, &key).await?;
+                    tls::reload_on_sighup(config.clone(), cert, key)?;
+                    println!("Sync server listening on {} (wss)", sync_addr);
+                    Some(config)
+                }
+                _ => {
+                    println!("Sync server listening on {}", sync_addr);
+                    None
                 }
             };
 
-            tokio::select! {
-                _ = http_server => {},
-                _ = sync_server => {},
+            // Run both servers concurrently until a shutdown signal
+            let mut sync_state = SyncState::new(storage.clone(), keep);
+            sync_state.min_free_space = min_free_space;
+            sync_state.idle_timeout = Duration::from_secs(sync_idle_timeout);
+            sync_state.max_message_size = sync_max_message_size;
+            sync_state.auth_limiter = AuthLimiter::per_minute(auth_failures_per_minute);
+            sync_state.metrics = metrics;
+            if strict_permissions {
+                sync_state.permission_policy = PermissionPolicy::Strict;
             }
+            let sync_server = tokio::spawn(webpub::server::sync::serve(
+                sync_listener,
+                Arc::new(sync_state),
+                sync_tls,
+                shutdown.clone(),
+                SHUTDOWN_GRACE,
+            ));
+
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                println!("Shutting down, finishing requests in flight");
+                shutdown.cancel();
+            });
+
+            let (http_result, sync_result) = tokio::join!(http_server, sync_server);
+            http_result??;
+            sync_result?;
+
+            // The databases close with the last handle to storage: here, or
+            // with any session still open past the grace period
+            drop(storage);
+            println!("Shut down cleanly");
         }
         Commands::Token { action, data } => {
             let storage = Storage::open(&data)?;
 
             match action {
-                TokenAction::Add => {
-                    let token = storage.add_token()?;
+                TokenAction::Add {
+                    label,
+                    ttl,
+                    expires_never,
+                } => {
+                    let ttl = if expires_never || ttl.is_some() {
+                        ttl
+                    } else {
+                        storage.default_token_ttl()?
+                    };
+                    let token = match label {
+                        Some(label) => storage.add_labeled_token(&label, ttl)?,
+                        None => storage.add_token_with_ttl(ttl)?,
+                    };
                     println!("{}", token);
                 }
+                TokenAction::Policy {
+                    default_ttl,
+                    no_default_ttl,
+                } => {
+                    if no_default_ttl {
+                        storage.set_default_token_ttl(None)?;
+                    } else if default_ttl.is_some() {
+                        storage.set_default_token_ttl(default_ttl)?;
+                    }
+                    match storage.default_token_ttl()? {
+                        Some(ttl) => println!("Default token TTL: {}", format_duration(ttl)),
+                        None => println!("Default token TTL: never expires"),
+                    }
+                }
                 TokenAction::List => {
                     let tokens = storage.list_tokens()?;
                     if tokens.is_empty() {
                         println!("No tokens found");
                     } else {
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+                        let width = tokens
+                            .iter()
+                            .filter_map(|t| t.label.as_ref().map(|l| l.len()))
+                            .max()
+                            .unwrap_or(0)
+                            .max("LABEL".len());
+                        println!(
+                            "{:<11}  {:<width$}  {:<19}  EXPIRES",
+                            "TOKEN", "LABEL", "CREATED"
+                        );
                         for token in tokens {
-                            println!("{}", token);
+                            let expiry = match token.expires_at {
+                                Some(expires_at) => format_expiry(expires_at, now),
+                                None => "never expires".to_string(),
+                            };
+                            println!(
+                                "{:<11}  {:<width$}  {:<19}  {}",
+                                format!("{}...", token.prefix),
+                                token.label.as_deref().unwrap_or("-"),
+                                token.created_at,
+                                expiry
+                            );
                         }
                     }
                 }
                 TokenAction::Revoke { token } => {
-                    storage.revoke_token(&token)?;
+                    if !storage.revoke_token(&token)? {
+                        return Err(format!("No token or label matching '{}'", token).into());
+                    }
                     println!("Token revoked");
                 }
             }
         }
-        Commands::Gc { data: _ } => {
-            println!("Garbage collection not yet implemented");
+        Commands::Import {
+            archive,
+            host,
+            data,
+        } => {
+            let storage = Storage::open(&data)?;
+            let commit = storage.import_archive(&archive, &host)?;
+            if commit.unchanged {
+                println!(
+                    "No changes, snapshot {} is still current",
+                    commit.snapshot_id
+                );
+            } else {
+                println!("Imported {} as snapshot {}", host, commit.snapshot_id);
+            }
         }
-        Commands::Push { dir, server, host } => {
+        Commands::Export {
+            hostname,
+            output,
+            data,
+        } => {
+            let storage = Storage::open(&data)?;
+            let snapshot_id = storage
+                .export_archive(&hostname, &output)?
+                .ok_or_else(|| format!("No current snapshot for {}", hostname))?;
+            println!(
+                "Exported {} snapshot {} to {}",
+                hostname,
+                snapshot_id,
+                output.display()
+            );
+        }
+        Commands::PreviewLink {
+            hostname,
+            snapshot_id,
+            data,
+        } => {
+            let storage = Storage::open(&data)?;
+            if !storage.has_snapshot(&hostname, snapshot_id)? {
+                return Err(format!("{} has no snapshot {}", hostname, snapshot_id).into());
+            }
+            let secret = storage.preview_secret()?;
+            println!("{}", preview_path(&secret, &hostname, snapshot_id));
+        }
+        Commands::Gc { data } => {
+            let storage = Storage::open(&data)?;
+            let stats = storage.gc()?;
+            println!(
+                "Deleted {} chunks, freed {} bytes",
+                stats.chunks_deleted, stats.bytes_freed
+            );
+        }
+        Commands::Compact { data, threshold } => {
+            let storage = Storage::open(&data)?;
+            let count = storage.chunk_count()?;
+            if storage.compact(threshold)? {
+                println!("Merged {} chunks into a single database", count);
+            } else if count > threshold && storage.rebalance()? {
+                println!("Split {} chunks into shard databases", count);
+            } else {
+                println!("Chunk layout unchanged ({} chunks)", count);
+            }
+        }
+        Commands::Doctor { data, fix } => {
+            let storage = Storage::open(&data)?;
+
+            let broken = storage.find_broken_current()?;
+            if broken.is_empty() {
+                println!("No problems found");
+            }
+            for (hostname, count) in broken {
+                println!("{}: {} current snapshots (expected 1)", hostname, count);
+                if fix && storage.repair_current(&hostname)? {
+                    println!("  Repaired: latest snapshot is now current");
+                }
+            }
+        }
+        Commands::Push {
+            dir,
+            server,
+            host,
+            ignore,
+            follow_symlinks,
+            normalize_permissions,
+            max_file_size,
+            fail_on_large_files,
+            chunking,
+            concurrency,
+            resume,
+            full_scan,
+        } => {
+            let chunking = chunking.config()?;
             let token = std::env::var("WEBPUB_TOKEN")
                 .map_err(|_| "WEBPUB_TOKEN environment variable not set")?;
 
-            let snapshot_id = webpub::client::push::push(&dir, &server, &host, &token).await?;
+            let options = PushOptions {
+                scan: ScanOptions {
+                    ignore,
+                    follow_symlinks,
+                    normalize_permissions,
+                    max_file_size,
+                    fail_on_large_files,
+                },
+                chunking,
+                concurrency,
+                resume,
+                incremental: !full_scan,
+            };
+            let snapshot_id =
+                webpub::client::push::push(&dir, &server, &host, &token, &options).await?;
             println!("Successfully deployed snapshot {}", snapshot_id);
         }
+        Commands::PushSites {
+            server,
+            sites,
+            ignore,
+            follow_symlinks,
+            normalize_permissions,
+            max_file_size,
+            fail_on_large_files,
+            chunking,
+            concurrency,
+            resume,
+            full_scan,
+        } => {
+            let chunking = chunking.config()?;
+            let token = std::env::var("WEBPUB_TOKEN")
+                .map_err(|_| "WEBPUB_TOKEN environment variable not set")?;
+
+            let options = PushOptions {
+                scan: ScanOptions {
+                    ignore,
+                    follow_symlinks,
+                    normalize_permissions,
+                    max_file_size,
+                    fail_on_large_files,
+                },
+                chunking,
+                concurrency,
+                resume,
+                incremental: !full_scan,
+            };
+            let snapshots =
+                webpub::client::push::push_sites(&sites, &server, &token, &options).await?;
+            println!("Successfully deployed {} sites", snapshots.len());
+        }
         Commands::List { server, host } => {
             let token = std::env::var("WEBPUB_TOKEN")
                 .map_err(|_| "WEBPUB_TOKEN environment variable not set")?;
@@ -213,21 +1156,107 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("No snapshots for {}", host);
             } else {
                 println!("Snapshots for {}:", host);
-                for (id, created_at, is_current) in snapshots {
+                for (id, created_at, is_current, tag) in snapshots {
+                    let tag = tag.map(|tag| format!(" [{}]", tag)).unwrap_or_default();
                     let current_marker = if is_current { " (current)" } else { "" };
-                    println!("  {} - {}{}", id, created_at, current_marker);
+                    println!(
+                        "  {}{} - {}{}",
+                        id,
+                        tag,
+                        format_utc(created_at),
+                        current_marker
+                    );
                 }
             }
         }
+        Commands::Cat { server, host, path } => {
+            let token = std::env::var("WEBPUB_TOKEN")
+                .map_err(|_| "WEBPUB_TOKEN environment variable not set")?;
+
+            let mut stdout = std::io::stdout().lock();
+            webpub::client::cat::cat(&server, &host, &path, &token, &mut stdout).await?;
+        }
+        Commands::Sites { server } => {
+            let token = std::env::var("WEBPUB_TOKEN")
+                .map_err(|_| "WEBPUB_TOKEN environment variable not set")?;
+
+            let sites = webpub::client::list::list_sites(&server, &token).await?;
+            if sites.is_empty() {
+                println!("No sites");
+            }
+            for (hostname, count, current) in sites {
+                let current = match current {
+                    Some(id) => format!("current {}", id),
+                    None => "none current".to_string(),
+                };
+                println!("  {} - {} snapshots, {}", hostname, count, current);
+            }
+        }
         Commands::Rollback { server, host, to } => {
             let token = std::env::var("WEBPUB_TOKEN")
                 .map_err(|_| "WEBPUB_TOKEN environment variable not set")?;
 
+            // Tags are never numbers, so a number is an ID
+            let (id, tag) = match to.as_deref().map(|to| (to, to.parse::<u64>())) {
+                Some((_, Ok(id))) => (Some(id), None),
+                Some((tag, Err(_))) => (None, Some(tag)),
+                None => (None, None),
+            };
             let snapshot_id =
-                webpub::client::rollback::rollback(&server, &host, &token, to).await?;
+                webpub::client::rollback::rollback(&server, &host, &token, id, tag).await?;
             println!("Rolled back {} to snapshot {}", host, snapshot_id);
         }
+        Commands::Tag {
+            server,
+            host,
+            snapshot_id,
+            tag,
+        } => {
+            let token = std::env::var("WEBPUB_TOKEN")
+                .map_err(|_| "WEBPUB_TOKEN environment variable not set")?;
+
+            webpub::client::rollback::tag(&server, &host, &token, snapshot_id, &tag).await?;
+            println!("Tagged {} snapshot {} as {}", host, snapshot_id, tag);
+        }
+        Commands::DeleteSite { server, host } => {
+            let token = std::env::var("WEBPUB_TOKEN")
+                .map_err(|_| "WEBPUB_TOKEN environment variable not set")?;
+
+            webpub::client::delete_site::delete_site(&server, &host, &token).await?;
+            println!("Deleted {}", host);
+        }
+        Commands::Diff {
+            server,
+            host,
+            old,
+            new,
+        } => {
+            let token = std::env::var("WEBPUB_TOKEN")
+                .map_err(|_| "WEBPUB_TOKEN environment variable not set")?;
+
+            let diff = webpub::client::diff::diff(&server, &host, &token, old, new).await?;
+            if diff.changes.is_empty() {
+                println!(
+                    "No changes from snapshot {} to {}",
+                    diff.old_id, diff.new_id
+                );
+            } else {
+                println!("Changes from snapshot {} to {}:", diff.old_id, diff.new_id);
+                print_changes(&diff.changes);
+            }
+        }
     }
 
     Ok(())
 }
+
+/// Print a changelist, one `A`dded, `D`eleted or `M`odified path per line.
+fn print_changes(changes: &[DiffEntry]) {
+    for change in changes {
+        match change {
+            DiffEntry::Added(path) => println!("  A {}", path),
+            DiffEntry::Removed(path) => println!("  D {}", path),
+            DiffEntry::Modified(path) => println!("  M {}", path),
+        }
+    }
+}
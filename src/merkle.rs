@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use crate::chunker::{chunk_data, Chunk};
+use crate::chunker::{chunk_data, Chunk, ChunkConfig};
 use crate::scanner::ScannedEntry;
 
 /// A node in the merkle tree representing a file or directory.
@@ -11,7 +12,18 @@ pub enum Node {
         permissions: u32,
         size: u64,
         chunks: Vec<[u8; 32]>,
+        /// BLAKE3 of the concatenated chunk hashes, used in the merkle tree
         hash: [u8; 32],
+        /// BLAKE3 of the file contents, independent of how it was chunked.
+        /// All zeros in trees serialized before this field existed.
+        #[serde(default)]
+        content_hash: [u8; 32],
+        /// Modification time in nanoseconds since the Unix epoch, letting
+        /// the next scan skip the file while it's unchanged. 0 if unknown or
+        /// too recent to trust. Not part of the hash, and left out when 0 so
+        /// such trees still read in versions before this field.
+        #[serde(default, skip_serializing_if = "is_zero")]
+        mtime: u64,
     },
     Directory {
         name: String,
@@ -29,77 +41,331 @@ impl Node {
         }
     }
 
+    /// Forget the modification times of every file, for trees sent to
+    /// peers that predate them or written where they'd be noise.
+    pub fn clear_mtimes(&mut self) {
+        match self {
+            Node::File { mtime, .. } => *mtime = 0,
+            Node::Directory { children, .. } => children.iter_mut().for_each(Node::clear_mtimes),
+        }
+    }
+
     pub fn hash(&self) -> &[u8; 32] {
         match self {
             Node::File { hash, .. } => hash,
             Node::Directory { hash, .. } => hash,
         }
     }
+
+    pub fn permissions(&self) -> u32 {
+        match self {
+            Node::File { permissions, .. } => *permissions,
+            Node::Directory { permissions, .. } => *permissions,
+        }
+    }
+}
+
+/// A change between two trees, identified by its path from the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffEntry {
+    Added(String),
+    Removed(String),
+    Modified(String),
+}
+
+impl DiffEntry {
+    pub fn path(&self) -> &str {
+        match self {
+            DiffEntry::Added(path) => path,
+            DiffEntry::Removed(path) => path,
+            DiffEntry::Modified(path) => path,
+        }
+    }
+}
+
+/// Compare two trees, returning the paths that were added, removed, or modified.
+/// Subtrees with equal hashes are skipped without being walked. An added or
+/// removed directory is reported once rather than file by file.
+pub fn diff(old: &Node, new: &Node) -> Vec<DiffEntry> {
+    let mut entries = Vec::new();
+    diff_children(old, new, "", &mut entries);
+    entries
+}
+
+fn diff_children(old: &Node, new: &Node, prefix: &str, entries: &mut Vec<DiffEntry>) {
+    let (Node::Directory { children: old, .. }, Node::Directory { children: new, .. }) = (old, new)
+    else {
+        return;
+    };
+
+    let mut pairs: BTreeMap<&str, (Option<&Node>, Option<&Node>)> = BTreeMap::new();
+    for child in old {
+        pairs.entry(child.name()).or_default().0 = Some(child);
+    }
+    for child in new {
+        pairs.entry(child.name()).or_default().1 = Some(child);
+    }
+
+    for (name, pair) in pairs {
+        let path = format!("{}{}", prefix, name);
+        match pair {
+            (Some(_), None) => entries.push(DiffEntry::Removed(path)),
+            (None, Some(_)) => entries.push(DiffEntry::Added(path)),
+            (Some(a), Some(b)) => match (a, b) {
+                (Node::Directory { .. }, Node::Directory { .. }) => {
+                    if a.permissions() != b.permissions() {
+                        entries.push(DiffEntry::Modified(path.clone()));
+                    }
+                    if a.hash() != b.hash() {
+                        diff_children(a, b, &format!("{}/", path), entries);
+                    }
+                }
+                (Node::File { .. }, Node::File { .. }) => {
+                    if a.hash() != b.hash() || a.permissions() != b.permissions() {
+                        entries.push(DiffEntry::Modified(path));
+                    }
+                }
+                _ => {
+                    // File replaced by directory or vice versa
+                    entries.push(DiffEntry::Removed(path.clone()));
+                    entries.push(DiffEntry::Added(path));
+                }
+            },
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+/// File hash = BLAKE3(concatenated chunk hashes). An empty file has no
+/// chunks, so its hash is BLAKE3 of nothing, the same as its content hash.
+fn file_hash(chunks: &[[u8; 32]]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    for hash in chunks {
+        hasher.update(hash);
+    }
+    *hasher.finalize().as_bytes()
+}
+
+/// Directory hash = BLAKE3(sorted children's (name, permissions, hash) tuples)
+fn directory_hash(children: &[Node]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    for child in children {
+        hasher.update(child.name().as_bytes());
+        hasher.update(&child.permissions().to_le_bytes());
+        hasher.update(child.hash());
+    }
+    *hasher.finalize().as_bytes()
+}
+
+/// A node whose stored hash doesn't match the one recomputed from its
+/// contents.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("hash mismatch at {path}")]
+pub struct VerifyError {
+    /// Path from the root, e.g. `/css/site.css`; the root itself is `/`
+    pub path: String,
+}
+
+/// Recompute every file hash from its chunk hashes and every directory hash
+/// from its children, confirming each matches the stored `hash`. Returns the
+/// first mismatch, deepest first, so a tree from an untrusted client can be
+/// checked before it's stored.
+pub fn verify_tree(node: &Node) -> Result<(), VerifyError> {
+    verify_node(node, "")
+}
+
+fn verify_node(node: &Node, prefix: &str) -> Result<(), VerifyError> {
+    let path = format!("{}/{}", prefix, node.name());
+    let expected = match node {
+        Node::File { chunks, .. } => file_hash(chunks),
+        Node::Directory { children, .. } => {
+            let prefix = path.trim_end_matches('/');
+            for child in children {
+                verify_node(child, prefix)?;
+            }
+            directory_hash(children)
+        }
+    };
+    if &expected != node.hash() {
+        return Err(VerifyError { path });
+    }
+    Ok(())
+}
+
+/// Counters describing the work done while building a tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildStats {
+    /// Files that were run through the chunker
+    pub files_chunked: usize,
+    /// Files whose chunk list was reused from an identical earlier file
+    pub files_reused: usize,
+    /// Files an incremental scan found unchanged, taken from the previous
+    /// tree without reading them
+    pub files_unchanged: usize,
+    /// Total size of every file in the tree
+    pub file_bytes: u64,
+    /// Total size of the distinct chunks returned
+    pub chunk_bytes: u64,
+}
+
+impl BuildStats {
+    /// How many times larger the files are than their distinct chunks, or
+    /// None without chunks. Only meaningful for a full build: unchanged
+    /// files count towards `file_bytes` but add no chunks.
+    pub fn dedup_ratio(&self) -> Option<f64> {
+        (self.chunk_bytes > 0).then(|| self.file_bytes as f64 / self.chunk_bytes as f64)
+    }
 }
 
 /// Build a merkle tree from a scanned entry, returning the tree and all chunks.
 pub fn build_tree(entry: ScannedEntry) -> (Node, Vec<Chunk>) {
-    let mut all_chunks = Vec::new();
-    let node = build_node(entry, &mut all_chunks);
-    (node, all_chunks)
-}
-
-fn build_node(entry: ScannedEntry, all_chunks: &mut Vec<Chunk>) -> Node {
-    match entry {
-        ScannedEntry::File {
-            name,
-            permissions,
-            size,
-            data,
-        } => {
-            let chunks: Vec<Chunk> = chunk_data(&data).collect();
-            let chunk_hashes: Vec<[u8; 32]> = chunks.iter().map(|c| c.hash).collect();
-
-            // File hash = BLAKE3(concatenated chunk hashes)
-            let mut hasher = blake3::Hasher::new();
-            for hash in &chunk_hashes {
-                hasher.update(hash);
-            }
-            let hash = *hasher.finalize().as_bytes();
+    build_tree_with(entry, &ChunkConfig::default())
+}
+
+/// Build a merkle tree, splitting files with the given chunk sizes.
+pub fn build_tree_with(entry: ScannedEntry, config: &ChunkConfig) -> (Node, Vec<Chunk>) {
+    let (node, chunks, _) = build_tree_with_config_stats(entry, config);
+    (node, chunks)
+}
 
-            all_chunks.extend(chunks);
+/// Build a merkle tree, also returning counters about the build.
+pub fn build_tree_with_stats(entry: ScannedEntry) -> (Node, Vec<Chunk>, BuildStats) {
+    build_tree_with_config_stats(entry, &ChunkConfig::default())
+}
 
-            Node::File {
+/// Build a merkle tree with the given chunk sizes, also returning counters
+/// about the build. The other builders all call this one.
+///
+/// The returned chunks are distinct: a chunk shared by several files, or
+/// repeated within one, appears once. Files with identical content are
+/// chunked only once; later copies reuse the first copy's chunk list and
+/// add no chunks of their own. Files an incremental scan found unchanged
+/// add no chunks either: their chunks are already on the server that sent
+/// the previous tree.
+pub fn build_tree_with_config_stats(
+    entry: ScannedEntry,
+    config: &ChunkConfig,
+) -> (Node, Vec<Chunk>, BuildStats) {
+    let mut builder = TreeBuilder::new(*config);
+    let node = builder.build_node(entry);
+    (node, builder.all_chunks, builder.stats)
+}
+
+struct TreeBuilder {
+    config: ChunkConfig,
+    all_chunks: Vec<Chunk>,
+    /// Hashes of the chunks in `all_chunks`
+    chunk_hashes: HashSet<[u8; 32]>,
+    /// Content hash -> chunk hashes of files already chunked
+    known_files: HashMap<[u8; 32], Vec<[u8; 32]>>,
+    stats: BuildStats,
+}
+
+impl TreeBuilder {
+    fn new(config: ChunkConfig) -> Self {
+        TreeBuilder {
+            config,
+            all_chunks: Vec::new(),
+            chunk_hashes: HashSet::new(),
+            known_files: HashMap::new(),
+            stats: BuildStats::default(),
+        }
+    }
+
+    fn build_node(&mut self, entry: ScannedEntry) -> Node {
+        match entry {
+            ScannedEntry::File {
                 name,
                 permissions,
                 size,
-                chunks: chunk_hashes,
-                hash,
+                mtime,
+                data,
+            } => {
+                let content_hash = *blake3::hash(&data).as_bytes();
+                self.stats.file_bytes += size;
+                let chunk_hashes = match self.known_files.get(&content_hash) {
+                    Some(chunk_hashes) => {
+                        self.stats.files_reused += 1;
+                        chunk_hashes.clone()
+                    }
+                    None => {
+                        let chunks: Vec<Chunk> = chunk_data(&data, &self.config).collect();
+                        let chunk_hashes: Vec<[u8; 32]> = chunks.iter().map(|c| c.hash).collect();
+                        for chunk in chunks {
+                            if self.chunk_hashes.insert(chunk.hash) {
+                                self.stats.chunk_bytes += chunk.data.len() as u64;
+                                self.all_chunks.push(chunk);
+                            }
+                        }
+                        self.known_files.insert(content_hash, chunk_hashes.clone());
+                        self.stats.files_chunked += 1;
+                        chunk_hashes
+                    }
+                };
+
+                let hash = file_hash(&chunk_hashes);
+
+                Node::File {
+                    name,
+                    permissions,
+                    size,
+                    chunks: chunk_hashes,
+                    hash,
+                    content_hash,
+                    mtime,
+                }
             }
-        }
-        ScannedEntry::Directory {
-            name,
-            permissions,
-            children,
-        } => {
-            let child_nodes: Vec<Node> = children
-                .into_iter()
-                .map(|c| build_node(c, all_chunks))
-                .collect();
-
-            // Directory hash = BLAKE3(sorted children's (name, permissions, hash) tuples)
-            let mut hasher = blake3::Hasher::new();
-            for child in &child_nodes {
-                hasher.update(child.name().as_bytes());
-                hasher.update(&match child {
-                    Node::File { permissions, .. } => permissions.to_le_bytes(),
-                    Node::Directory { permissions, .. } => permissions.to_le_bytes(),
-                });
-                hasher.update(child.hash());
+            ScannedEntry::Unchanged {
+                name,
+                permissions,
+                node,
+            } => {
+                self.stats.files_unchanged += 1;
+                let Node::File {
+                    size,
+                    chunks,
+                    hash,
+                    content_hash,
+                    mtime,
+                    ..
+                } = node
+                else {
+                    unreachable!("scanner only reuses file nodes")
+                };
+                self.stats.file_bytes += size;
+                self.known_files
+                    .entry(content_hash)
+                    .or_insert_with(|| chunks.clone());
+                Node::File {
+                    name,
+                    permissions,
+                    size,
+                    chunks,
+                    hash,
+                    content_hash,
+                    mtime,
+                }
             }
-            let hash = *hasher.finalize().as_bytes();
-
-            Node::Directory {
+            ScannedEntry::Directory {
                 name,
                 permissions,
-                children: child_nodes,
-                hash,
+                children,
+            } => {
+                let child_nodes: Vec<Node> =
+                    children.into_iter().map(|c| self.build_node(c)).collect();
+                let hash = directory_hash(&child_nodes);
+
+                Node::Directory {
+                    name,
+                    permissions,
+                    children: child_nodes,
+                    hash,
+                }
             }
         }
     }
 }
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
@@ -1,18 +1,134 @@
 use crate::Node;
+use serde::de::{DeserializeOwned, IgnoredAny};
 use serde::{Deserialize, Serialize};
 
+/// Protocol version stamped on every message.
+pub const PROTOCOL_VERSION: u32 = 14;
+
+/// First protocol version with `ChunkBatch`/`BatchAck`.
+pub const BATCH_PROTOCOL_VERSION: u32 = 3;
+
+/// First protocol version with `GetSnapshotTree`/`SnapshotTree`.
+pub const TREE_PROTOCOL_VERSION: u32 = 4;
+
+/// First protocol version whose trees may carry file mtimes.
+pub const MTIME_PROTOCOL_VERSION: u32 = 5;
+
+/// First protocol version whose `CommitFailed` may list missing chunks.
+pub const MISSING_CHUNKS_PROTOCOL_VERSION: u32 = 6;
+
+/// First protocol version with `BeginBatch`/`CommitBatch`.
+pub const DEPLOY_BATCH_PROTOCOL_VERSION: u32 = 7;
+
+/// First protocol version with `DeleteSite`.
+pub const DELETE_SITE_PROTOCOL_VERSION: u32 = 8;
+
+/// First protocol version with `ListSites`/`SiteList`.
+pub const SITES_PROTOCOL_VERSION: u32 = 9;
+
+/// First protocol version whose `CommitOk` may report an unchanged tree.
+pub const UNCHANGED_PROTOCOL_VERSION: u32 = 10;
+
+/// First protocol version answering `ListSnapshots` with `Snapshots`.
+pub const TIMESTAMP_PROTOCOL_VERSION: u32 = 11;
+
+/// First protocol version with `TagSnapshot` and rollback by tag.
+pub const TAG_PROTOCOL_VERSION: u32 = 12;
+
+/// First protocol version with `GetFile`/`FileData`.
+pub const FILE_PROTOCOL_VERSION: u32 = 13;
+
+/// First protocol version whose `HaveChunks` carries chunk sizes.
+pub const SIZES_PROTOCOL_VERSION: u32 = 14;
+
+/// Most missing chunk hashes a `CommitFailed` lists; beyond this only the
+/// count in its reason is reported.
+pub const MAX_MISSING_CHUNKS: usize = 1024;
+
+/// Oldest client protocol version the server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Whether a peer speaking `version` can talk to this build.
+pub fn is_compatible(version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+/// Version assumed for clients that predate the `protocol_version` field.
+fn legacy_version() -> u32 {
+    1
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Wire wrapper carrying the protocol version alongside each message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub version: u32,
+    pub message: T,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    /// A well-formed envelope holding a message this side doesn't know
+    #[error("unsupported message (protocol version {version})")]
+    Unsupported { version: u32 },
+    #[error("malformed message: {0}")]
+    Malformed(#[from] rmp_serde::decode::Error),
+    #[error("failed to encode message: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+}
+
+/// Serialize a message inside a versioned envelope.
+pub fn encode<T: Serialize>(message: T) -> Result<Vec<u8>, ProtocolError> {
+    Ok(rmp_serde::to_vec(&Envelope {
+        version: PROTOCOL_VERSION,
+        message,
+    })?)
+}
+
+/// Deserialize an enveloped message, keeping the sender's protocol version.
+pub fn decode_envelope<T: DeserializeOwned>(bytes: &[u8]) -> Result<Envelope<T>, ProtocolError> {
+    match rmp_serde::from_slice::<Envelope<T>>(bytes) {
+        Ok(envelope) => Ok(envelope),
+        Err(e) => match rmp_serde::from_slice::<Envelope<IgnoredAny>>(bytes) {
+            Ok(envelope) => Err(ProtocolError::Unsupported {
+                version: envelope.version,
+            }),
+            Err(_) => Err(ProtocolError::Malformed(e)),
+        },
+    }
+}
+
+/// Deserialize an enveloped message. An envelope whose message isn't
+/// recognized yields `ProtocolError::Unsupported` rather than a decode error.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ProtocolError> {
+    Ok(decode_envelope(bytes)?.message)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMessage {
     Auth {
         token: String,
+        /// Highest protocol version the client speaks
+        #[serde(default = "legacy_version")]
+        protocol_version: u32,
     },
     HaveChunks {
         hashes: Vec<[u8; 32]>,
+        /// Size of each chunk in `hashes`, for the server's disk space check
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        sizes: Vec<u32>,
     },
     ChunkData {
         hash: [u8; 32],
         data: Vec<u8>,
     },
+    /// Several chunks in one frame, acked together with `BatchAck`
+    ChunkBatch {
+        chunks: Vec<([u8; 32], Vec<u8>)>,
+    },
     CommitTree {
         hostname: String,
         tree: Node,
@@ -23,6 +139,35 @@ pub enum ClientMessage {
     Rollback {
         hostname: String,
         snapshot_id: Option<u64>,
+        /// Roll back to the snapshot with this tag instead of by ID
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tag: Option<String>,
+    },
+    /// Fetch a snapshot's tree; the current one when no ID is given
+    GetSnapshotTree {
+        hostname: String,
+        snapshot_id: Option<u64>,
+    },
+    /// Stage the following `CommitTree`s instead of committing each one
+    BeginBatch,
+    /// Commit every staged tree in one transaction
+    CommitBatch,
+    /// Remove a site with all of its snapshots
+    DeleteSite {
+        hostname: String,
+    },
+    ListSites,
+    /// Name a snapshot, moving the tag off any other snapshot of the site
+    TagSnapshot {
+        hostname: String,
+        snapshot_id: u64,
+        tag: String,
+    },
+    /// Read one file of a site's current snapshot, resolved as HTTP serving
+    /// would: a directory gives its index.html
+    GetFile {
+        hostname: String,
+        path: String,
     },
 }
 
@@ -30,11 +175,91 @@ pub enum ClientMessage {
 pub enum ServerMessage {
     AuthOk,
     AuthFailed,
-    NeedChunks { hashes: Vec<[u8; 32]> },
-    ChunkAck { hash: [u8; 32] },
-    CommitOk { snapshot_id: u64 },
-    CommitFailed { reason: String },
-    SnapshotList { snapshots: Vec<(u64, String, bool)> }, // (id, created_at, is_current)
-    RollbackOk { snapshot_id: u64 },
-    RollbackFailed { reason: String },
+    NeedChunks {
+        hashes: Vec<[u8; 32]>,
+    },
+    ChunkAck {
+        hash: [u8; 32],
+    },
+    BatchAck {
+        hashes: Vec<[u8; 32]>,
+    },
+    /// A chunk whose data doesn't hash to its claimed hash; nothing was stored
+    ChunkRejected {
+        hash: [u8; 32],
+        reason: String,
+    },
+    CommitOk {
+        snapshot_id: u64,
+        /// The tree matched the current snapshot, so nothing was added and
+        /// `snapshot_id` is the current one
+        #[serde(default, skip_serializing_if = "is_false")]
+        unchanged: bool,
+    },
+    CommitFailed {
+        reason: String,
+        /// Chunks the tree references that the server doesn't have, for the
+        /// client to upload before retrying; empty when not known
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        missing: Vec<[u8; 32]>,
+    },
+    SnapshotList {
+        snapshots: Vec<(u64, String, bool)>,
+    }, // (id, created_at, is_current)
+    RollbackOk {
+        snapshot_id: u64,
+    },
+    RollbackFailed {
+        reason: String,
+    },
+    SnapshotTree {
+        snapshot_id: u64,
+        tree: Node,
+    },
+    SnapshotTreeFailed {
+        reason: String,
+    },
+    Denied {
+        reason: String,
+    },
+    /// The client's protocol version isn't supported; sent instead of an auth reply
+    VersionMismatch {
+        server_version: u32,
+    },
+    BatchStarted,
+    /// A tree passed its checks and waits for `CommitBatch`
+    TreeStaged {
+        hostname: String,
+    },
+    /// The new snapshot of each staged site, in staging order
+    BatchCommitted {
+        snapshots: Vec<(String, u64)>,
+    },
+    SiteDeleted,
+    DeleteSiteFailed {
+        reason: String,
+    },
+    SiteList {
+        sites: Vec<(String, u64, Option<u64>)>,
+    }, // (hostname, snapshot count, current snapshot id)
+    /// Replaces `SnapshotList`, with creation times in seconds since the
+    /// Unix epoch
+    Snapshots {
+        snapshots: Vec<(u64, i64, bool)>,
+        /// (id, tag) of the tagged snapshots
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tags: Vec<(u64, String)>,
+    }, // (id, created_at, is_current)
+    SnapshotTagged,
+    TagFailed {
+        reason: String,
+    },
+    /// Part of a file's contents, in order; the last part has `done` set
+    FileData {
+        data: Vec<u8>,
+        done: bool,
+    },
+    GetFileFailed {
+        reason: String,
+    },
 }
@@ -1,6 +1,38 @@
+use crate::client::manifest::MANIFEST_FILE;
+use crate::merkle::Node;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Longest file name, in bytes, accepted by common filesystems.
+pub const MAX_NAME_LEN: usize = 255;
+/// Longest path, in bytes, that can be created on common systems (Linux PATH_MAX).
+pub const MAX_PATH_LEN: usize = 4096;
+
+/// Modification times this close to the start of a scan aren't recorded:
+/// the file may still change within the filesystem's timestamp granularity
+/// (2s on FAT) without its mtime moving, so a later scan must read it.
+const MTIME_MARGIN: Duration = Duration::from_secs(2);
+
+/// Describe why a name or path is too long to be extracted portably, if it is.
+pub fn path_length_problem(name: &str, path_len: usize) -> Option<String> {
+    if name.len() > MAX_NAME_LEN {
+        Some(format!(
+            "file name is {} bytes, over the {} byte limit",
+            name.len(),
+            MAX_NAME_LEN
+        ))
+    } else if path_len > MAX_PATH_LEN {
+        Some(format!(
+            "path is {} bytes, over the {} byte limit",
+            path_len, MAX_PATH_LEN
+        ))
+    } else {
+        None
+    }
+}
 
 /// A scanned filesystem entry.
 #[derive(Debug)]
@@ -9,8 +41,17 @@ pub enum ScannedEntry {
         name: String,
         permissions: u32,
         size: u64,
+        /// Nanoseconds since the Unix epoch; 0 if unknown or too recent
+        mtime: u64,
         data: Vec<u8>,
     },
+    /// A file whose size and mtime match the previous tree of an
+    /// incremental scan, carrying that tree's node instead of its contents
+    Unchanged {
+        name: String,
+        permissions: u32,
+        node: Node,
+    },
     Directory {
         name: String,
         permissions: u32,
@@ -22,73 +63,306 @@ impl ScannedEntry {
     pub fn name(&self) -> &str {
         match self {
             ScannedEntry::File { name, .. } => name,
+            ScannedEntry::Unchanged { name, .. } => name,
             ScannedEntry::Directory { name, .. } => name,
         }
     }
 }
 
+/// Name of the optional ignore file read from the scan root.
+pub const IGNORE_FILE: &str = ".webpubignore";
+
+/// Options controlling which entries a scan includes.
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Gitignore-style patterns of paths to skip, applied in addition to
+    /// any `.webpubignore` file at the scan root
+    pub ignore: Vec<String>,
+    /// Scan the targets of symlinks as regular files and directories
+    /// instead of skipping them. Cycles and broken links are skipped.
+    pub follow_symlinks: bool,
+    /// Record every file as 0644 and directory as 0755 instead of the
+    /// source permissions, so the tree hash doesn't depend on them
+    pub normalize_permissions: bool,
+    /// Skip files larger than this many bytes, with a warning
+    pub max_file_size: Option<u64>,
+    /// Fail the scan on a file over `max_file_size` instead of skipping it
+    pub fail_on_large_files: bool,
+}
+
 /// Scan a directory recursively, returning entries sorted by name.
-/// Ignores symlinks and special files.
+/// Ignores symlinks and special files, and skips (with a warning) entries whose
+/// name or path is too long to be extracted portably.
 /// Returns an iterator yielding the root entry as a tree structure.
 pub fn scan_directory(path: &Path) -> io::Result<impl Iterator<Item = ScannedEntry>> {
-    let entry = scan_entry(path, "")?;
+    scan_directory_with(path, &ScanOptions::default())
+}
+
+/// Scan a directory with options. Paths matching the ignore patterns or a
+/// `.webpubignore` file at the root are skipped, as is a push manifest
+/// at the root. Patterns follow gitignore
+/// rules: `*` and `**` wildcards, a trailing `/` matches only directories,
+/// and a leading `!` re-includes a path. Symlinks are skipped unless
+/// `follow_symlinks` is set. Files over `max_file_size` are skipped, or
+/// fail the scan with [`io::ErrorKind::FileTooLarge`] naming the file if
+/// `fail_on_large_files` is set.
+pub fn scan_directory_with(
+    path: &Path,
+    options: &ScanOptions,
+) -> io::Result<impl Iterator<Item = ScannedEntry>> {
+    let scanner = Scanner::new(path, options)?;
+    let entry = scanner.scan_entry(path, "", "", &mut Vec::new(), None)?;
     Ok(std::iter::once(entry))
 }
 
-fn scan_entry(path: &Path, name: &str) -> io::Result<ScannedEntry> {
-    let metadata = fs::metadata(path)?;
-
-    #[cfg(unix)]
-    let permissions = {
-        use std::os::unix::fs::PermissionsExt;
-        metadata.permissions().mode()
-    };
-    #[cfg(not(unix))]
-    let permissions = if metadata.permissions().readonly() {
-        0o444
-    } else {
-        0o644
-    };
-
-    if metadata.is_file() {
-        let data = fs::read(path)?;
-        Ok(ScannedEntry::File {
-            name: name.to_string(),
-            permissions,
-            size: metadata.len(),
-            data,
+/// Scan a directory with options, reusing the nodes of files unchanged
+/// since `previous` was built. A file is unchanged when a file node at the
+/// same path has the same size and a recorded mtime equal to the file's;
+/// such files are returned as [`ScannedEntry::Unchanged`] without being
+/// read. Anything else, including nodes without an mtime or content hash,
+/// is read as in a full scan.
+pub fn scan_directory_incremental(
+    path: &Path,
+    options: &ScanOptions,
+    previous: &Node,
+) -> io::Result<impl Iterator<Item = ScannedEntry>> {
+    let scanner = Scanner::new(path, options)?;
+    let entry = scanner.scan_entry(path, "", "", &mut Vec::new(), Some(previous))?;
+    Ok(std::iter::once(entry))
+}
+
+struct Scanner {
+    ignore: Gitignore,
+    follow_symlinks: bool,
+    normalize_permissions: bool,
+    max_file_size: Option<u64>,
+    fail_on_large_files: bool,
+    /// Modification times from here on aren't recorded
+    mtime_cutoff: u64,
+}
+
+impl Scanner {
+    fn new(root: &Path, options: &ScanOptions) -> io::Result<Self> {
+        let mut builder = GitignoreBuilder::new(root);
+        // A manifest left by an interrupted push is never part of the site
+        builder
+            .add_line(None, &format!("/{}", MANIFEST_FILE))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let ignore_file = root.join(IGNORE_FILE);
+        if ignore_file.is_file() {
+            if let Some(e) = builder.add(&ignore_file) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+            }
+        }
+        for pattern in &options.ignore {
+            builder
+                .add_line(None, pattern)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+
+        let ignore = builder
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Scanner {
+            ignore,
+            follow_symlinks: options.follow_symlinks,
+            normalize_permissions: options.normalize_permissions,
+            max_file_size: options.max_file_size,
+            fail_on_large_files: options.fail_on_large_files,
+            mtime_cutoff: unix_nanos(SystemTime::now() - MTIME_MARGIN),
         })
-    } else if metadata.is_dir() {
+    }
+
+    fn is_ignored(&self, rel_path: &str, is_dir: bool) -> bool {
+        self.ignore
+            .matched(rel_path.trim_start_matches('/'), is_dir)
+            .is_ignore()
+    }
+
+    /// Scan one entry. `ancestors` holds the canonical paths of the
+    /// directories being scanned above it, to detect symlink cycles, and
+    /// `previous` the node at the same path in an incremental scan's tree.
+    fn scan_entry(
+        &self,
+        path: &Path,
+        name: &str,
+        rel_path: &str,
+        ancestors: &mut Vec<PathBuf>,
+        previous: Option<&Node>,
+    ) -> io::Result<ScannedEntry> {
+        let metadata = fs::metadata(path)?;
+
+        #[cfg(unix)]
+        let permissions = {
+            use std::os::unix::fs::PermissionsExt;
+            metadata.permissions().mode()
+        };
+        #[cfg(not(unix))]
+        let permissions = if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        };
+        let permissions = match (self.normalize_permissions, metadata.is_dir()) {
+            (false, _) => permissions,
+            (true, false) => 0o644,
+            (true, true) => 0o755,
+        };
+
+        if metadata.is_file() {
+            if let Some(limit) = self.max_file_size.filter(|&limit| metadata.len() > limit) {
+                let problem = format!("{} bytes, over the {} byte limit", metadata.len(), limit);
+                if self.fail_on_large_files {
+                    return Err(io::Error::new(
+                        io::ErrorKind::FileTooLarge,
+                        format!("{} is too large: {}", rel_path, problem),
+                    ));
+                }
+                eprintln!("Skipping {}: {}", rel_path, problem);
+                return Err(io::Error::other("file too large"));
+            }
+
+            let mtime = metadata
+                .modified()
+                .map(unix_nanos)
+                .ok()
+                .filter(|&mtime| mtime < self.mtime_cutoff)
+                .unwrap_or(0);
+            if let Some(node) = previous.filter(|node| is_unchanged(node, metadata.len(), mtime)) {
+                return Ok(ScannedEntry::Unchanged {
+                    name: name.to_string(),
+                    permissions,
+                    node: node.clone(),
+                });
+            }
+
+            let data = fs::read(path)?;
+            Ok(ScannedEntry::File {
+                name: name.to_string(),
+                permissions,
+                size: metadata.len(),
+                mtime,
+                data,
+            })
+        } else if metadata.is_dir() {
+            // A directory reached again below itself is a symlink cycle
+            let canonical = if self.follow_symlinks {
+                let canonical = fs::canonicalize(path)?;
+                if ancestors.contains(&canonical) {
+                    eprintln!("Skipping {}: symlink cycle", rel_path);
+                    return Err(io::Error::other("symlink cycle"));
+                }
+                Some(canonical)
+            } else {
+                None
+            };
+
+            if let Some(canonical) = &canonical {
+                ancestors.push(canonical.clone());
+            }
+            let previous = previous.and_then(|node| match node {
+                Node::Directory { children, .. } => Some(children.as_slice()),
+                Node::File { .. } => None,
+            });
+            let children = self.scan_children(path, rel_path, ancestors, previous);
+            if canonical.is_some() {
+                ancestors.pop();
+            }
+            let mut children = children?;
+
+            // Sort by name for determinism
+            children.sort_by(|a, b| a.name().cmp(b.name()));
+
+            Ok(ScannedEntry::Directory {
+                name: name.to_string(),
+                permissions,
+                children,
+            })
+        } else {
+            // Special file - treat as empty directory to skip
+            Err(io::Error::other("special file"))
+        }
+    }
+
+    fn scan_children(
+        &self,
+        path: &Path,
+        rel_path: &str,
+        ancestors: &mut Vec<PathBuf>,
+        previous: Option<&[Node]>,
+    ) -> io::Result<Vec<ScannedEntry>> {
         let mut children = Vec::new();
 
         for entry in fs::read_dir(path)? {
             let entry = entry?;
             let file_type = entry.file_type()?;
 
-            // Skip symlinks and special files
-            if file_type.is_symlink() {
-                continue;
-            }
+            // Skip symlinks unless following them; broken links are
+            // skipped below when their metadata can't be read
+            let is_dir = if file_type.is_symlink() {
+                if !self.follow_symlinks {
+                    continue;
+                }
+                entry.path().is_dir()
+            } else {
+                file_type.is_dir()
+            };
 
             let child_name = entry.file_name().to_string_lossy().to_string();
             let child_path = entry.path();
+            let child_rel = format!("{}/{}", rel_path, child_name);
+
+            if self.is_ignored(&child_rel, is_dir) {
+                continue;
+            }
+
+            if let Some(problem) = path_length_problem(&child_name, child_rel.len()) {
+                eprintln!("Skipping {}: {}", child_rel, problem);
+                continue;
+            }
+
+            // Tree children are sorted by name
+            let child_previous = previous.and_then(|nodes| {
+                nodes
+                    .binary_search_by(|node| node.name().cmp(&child_name))
+                    .ok()
+                    .map(|i| &nodes[i])
+            });
 
             // Skip if we can't read metadata (broken symlink, permission denied, etc.)
-            if let Ok(child_entry) = scan_entry(&child_path, &child_name) {
-                children.push(child_entry);
+            match self.scan_entry(
+                &child_path,
+                &child_name,
+                &child_rel,
+                ancestors,
+                child_previous,
+            ) {
+                Ok(child_entry) => children.push(child_entry),
+                Err(e) if e.kind() == io::ErrorKind::FileTooLarge => return Err(e),
+                Err(_) => {}
             }
         }
 
-        // Sort by name for determinism
-        children.sort_by(|a, b| a.name().cmp(b.name()));
+        Ok(children)
+    }
+}
 
-        Ok(ScannedEntry::Directory {
-            name: name.to_string(),
-            permissions,
-            children,
-        })
-    } else {
-        // Special file - treat as empty directory to skip
-        Err(io::Error::other("special file"))
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
+
+/// Whether a previous tree's node can stand in for a file of this size
+/// and mtime without reading it.
+fn is_unchanged(node: &Node, len: u64, file_mtime: u64) -> bool {
+    match node {
+        Node::File {
+            size,
+            mtime,
+            content_hash,
+            ..
+        } => *mtime != 0 && *mtime == file_mtime && *size == len && *content_hash != [0u8; 32],
+        Node::Directory { .. } => false,
     }
 }
@@ -0,0 +1,132 @@
+use axum::{
+    body::HttpBody,
+    extract::Request,
+    http::header,
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use std::time::Instant;
+use tracing::subscriber::SetGlobalDefaultError;
+use tracing::Subscriber;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// How `serve` writes log events, one line each, to stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `<time>  INFO webpub::access: request host=example.com method=GET ...`
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format '{}' (expected text or json)",
+                s
+            )),
+        }
+    }
+}
+
+/// Target of access log events, so collectors can tell them from errors.
+pub const ACCESS_TARGET: &str = "webpub::access";
+
+/// One served request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessEntry {
+    pub host: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// Response body bytes, if known up front
+    pub bytes: u64,
+    pub duration_ms: f64,
+}
+
+impl AccessEntry {
+    /// Emit the entry as an info event with one field per attribute.
+    pub fn record(&self) {
+        tracing::info!(
+            target: ACCESS_TARGET,
+            host = %self.host,
+            method = %self.method,
+            path = %self.path,
+            status = self.status,
+            bytes = self.bytes,
+            duration_ms = self.duration_ms,
+            "request"
+        );
+    }
+}
+
+/// A subscriber writing events to `writer`, one line each in `format`.
+/// JSON lines carry the event's fields at the top level.
+pub fn subscriber<W>(format: LogFormat, writer: W) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_ansi(false);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .flatten_event(true)
+                .with_span_list(false)
+                .finish(),
+        ),
+    }
+}
+
+/// Send log events to stdout in `format` for the rest of the process.
+/// Lines are written by a background thread, so a slow stdout never blocks
+/// a request; if it falls too far behind, lines are dropped instead. The
+/// returned guard flushes what is queued when dropped.
+pub fn init_logging(format: LogFormat) -> Result<WorkerGuard, SetGlobalDefaultError> {
+    let (writer, guard) = tracing_appender::non_blocking(std::io::stdout());
+    tracing::subscriber::set_global_default(subscriber(format, writer))?;
+    Ok(guard)
+}
+
+/// Middleware recording an access log event for every response of a router.
+pub async fn log_requests(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+
+    let response = next.run(request).await;
+
+    AccessEntry {
+        host,
+        method,
+        path,
+        status: response.status().as_u16(),
+        bytes: response.body().size_hint().exact().unwrap_or(0),
+        // Whole microseconds
+        duration_ms: started.elapsed().as_micros() as f64 / 1000.0,
+    }
+    .record();
+    response
+}
+
+/// Add access logging to a router.
+pub fn log_access(router: Router) -> Router {
+    router.layer(middleware::from_fn(log_requests))
+}
@@ -0,0 +1,178 @@
+use crate::server::auth::{AuthError, Authenticator, SCOPE_DEPLOY, SCOPE_READ};
+use crate::server::preview_link::preview_path;
+use crate::server::storage::Storage;
+use crate::server::sync::rollback_target;
+use crate::timestamp::format_utc;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+struct AdminState {
+    storage: Arc<Storage>,
+    authenticator: Arc<dyn Authenticator>,
+}
+
+/// Body of a rollback request. Without an ID or tag the site goes back to
+/// the snapshot before its newest.
+#[derive(Debug, Default, Deserialize)]
+struct RollbackRequest {
+    snapshot_id: Option<u64>,
+    tag: Option<String>,
+}
+
+/// A router serving the JSON admin API under `/api`, for the admin port.
+/// Requests authenticate with `Authorization: Bearer <token>`, checked by
+/// the same authenticator as sync connections:
+///
+/// - `GET /api/sites` lists sites (read scope)
+/// - `GET /api/sites/:host/snapshots` lists a site's snapshots with their
+///   preview links (read scope)
+/// - `POST /api/sites/:host/rollback` makes another snapshot current, taking
+///   an optional `{"snapshot_id": ...}` or `{"tag": ...}` body (deploy scope)
+pub fn admin_router(storage: Arc<Storage>, authenticator: Arc<dyn Authenticator>) -> Router {
+    Router::new()
+        .route("/api/sites", get(handle_sites))
+        .route("/api/sites/:host/snapshots", get(handle_snapshots))
+        .route("/api/sites/:host/rollback", post(handle_rollback))
+        .with_state(Arc::new(AdminState {
+            storage,
+            authenticator,
+        }))
+}
+
+async fn handle_sites(State(state): State<Arc<AdminState>>, headers: HeaderMap) -> Response {
+    if let Some(refusal) = refuse(&state, &headers, SCOPE_READ).await {
+        return refusal;
+    }
+    match state.storage.list_sites() {
+        Ok(sites) => Json(
+            sites
+                .into_iter()
+                .map(|(hostname, snapshots, current)| {
+                    json!({
+                        "hostname": hostname,
+                        "snapshots": snapshots,
+                        "current_snapshot_id": current,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn handle_snapshots(
+    State(state): State<Arc<AdminState>>,
+    headers: HeaderMap,
+    Path(host): Path<String>,
+) -> Response {
+    if let Some(refusal) = refuse(&state, &headers, SCOPE_READ).await {
+        return refusal;
+    }
+    let listed = state
+        .storage
+        .list_snapshots(&host)
+        .and_then(|snapshots| Ok((snapshots, state.storage.preview_secret()?)));
+    match listed {
+        Ok((snapshots, secret)) => Json(
+            snapshots
+                .into_iter()
+                .map(|(id, is_current, created_at, tag)| {
+                    json!({
+                        "id": id,
+                        "created_at": format_utc(created_at),
+                        "current": is_current,
+                        "tag": tag,
+                        "preview_path": preview_path(&secret, &host, id),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+async fn handle_rollback(
+    State(state): State<Arc<AdminState>>,
+    headers: HeaderMap,
+    Path(host): Path<String>,
+    body: Bytes,
+) -> Response {
+    if let Some(refusal) = refuse(&state, &headers, SCOPE_DEPLOY).await {
+        return refusal;
+    }
+    // A malformed body must not fall back to rolling back to the previous snapshot
+    let request: RollbackRequest = if body.is_empty() {
+        RollbackRequest::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => return error(StatusCode::BAD_REQUEST, &format!("Invalid body: {}", e)),
+        }
+    };
+
+    let storage = &state.storage;
+    let target_id =
+        match rollback_target(storage, &host, request.snapshot_id, request.tag.as_deref()) {
+            Ok(Ok(id)) => id,
+            Ok(Err(reason)) => return error(StatusCode::NOT_FOUND, &reason),
+            Err(e) => return internal_error(e),
+        };
+    match storage.set_current_snapshot(&host, target_id) {
+        Ok(true) => {
+            println!("Rolled back {} to snapshot {}", host, target_id);
+            Json(json!({ "snapshot_id": target_id })).into_response()
+        }
+        Ok(false) => error(StatusCode::NOT_FOUND, "Snapshot not found"),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// The response refusing a request, unless its bearer token is valid and
+/// grants `scope`.
+async fn refuse(state: &AdminState, headers: &HeaderMap, scope: &str) -> Option<Response> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let Some(token) = token else {
+        return Some(unauthorized());
+    };
+    match state.authenticator.authenticate(token.trim()).await {
+        Ok(auth) if auth.has_scope(scope) => None,
+        Ok(_) => Some(error(
+            StatusCode::FORBIDDEN,
+            &format!("{} scope required", scope),
+        )),
+        Err(AuthError::Denied) => Some(unauthorized()),
+        Err(e) => Some(internal_error(e)),
+    }
+}
+
+fn unauthorized() -> Response {
+    let mut response = error(StatusCode::UNAUTHORIZED, "Invalid or missing token");
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+    response
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+/// A 500 that logs the error rather than sending it to the client.
+fn internal_error(error: impl std::fmt::Display) -> Response {
+    eprintln!("Admin API error: {}", error);
+    self::error(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+}
@@ -0,0 +1,71 @@
+use crate::server::storage::Storage;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Scope required to upload chunks, commit trees, and roll back.
+pub const SCOPE_DEPLOY: &str = "deploy";
+/// Scope required to list snapshots and read site contents.
+pub const SCOPE_READ: &str = "read";
+/// Scope required for destructive site management.
+pub const SCOPE_ADMIN: &str = "admin";
+
+/// Identity and permissions of an authenticated client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthContext {
+    /// Who authenticated, for logging
+    pub subject: String,
+    /// Granted scopes; `*` grants everything
+    pub scopes: Vec<String>,
+}
+
+impl AuthContext {
+    /// A context granting every scope.
+    pub fn full(subject: impl Into<String>) -> Self {
+        AuthContext {
+            subject: subject.into(),
+            scopes: vec!["*".to_string()],
+        }
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope || s == "*")
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("invalid credential")]
+    Denied,
+    #[error("authentication backend error: {0}")]
+    Backend(#[from] Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Verifies client credentials. Implement this to plug in an external
+/// identity system (OIDC, JWT, an internal service) in place of tokens.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, credential: &str) -> Result<AuthContext, AuthError>;
+}
+
+/// Default authenticator backed by the tokens table in storage.
+/// Valid tokens are granted every scope.
+pub struct TokenAuthenticator {
+    storage: Arc<Storage>,
+}
+
+impl TokenAuthenticator {
+    pub fn new(storage: Arc<Storage>) -> Self {
+        TokenAuthenticator { storage }
+    }
+}
+
+#[async_trait]
+impl Authenticator for TokenAuthenticator {
+    async fn authenticate(&self, credential: &str) -> Result<AuthContext, AuthError> {
+        match self.storage.verify_token(credential) {
+            Ok(true) => Ok(AuthContext::full("token")),
+            Ok(false) => Err(AuthError::Denied),
+            Err(e) => Err(AuthError::Backend(Box::new(e))),
+        }
+    }
+}
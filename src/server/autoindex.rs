@@ -0,0 +1,66 @@
+use crate::server::storage::DirectoryEntry;
+
+/// Render an HTML listing of a directory's entries. `path` is the request
+/// path; links are relative to it, so they work with or without a trailing
+/// slash.
+pub fn render_listing(path: &str, entries: &[DirectoryEntry]) -> String {
+    let dir = format!("{}/", path.trim_end_matches('/'));
+    // Without a trailing slash, relative links resolve against the parent
+    let base = if path.ends_with('/') {
+        String::new()
+    } else {
+        let last = path.rsplit('/').next().unwrap_or("");
+        format!("{}/", encode_segment(last))
+    };
+
+    let title = format!("Index of {}", escape_html(&dir));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n\
+         <body>\n<h1>{title}</h1>\n<table>\n"
+    );
+    if dir != "/" {
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}../\">../</a></td><td></td></tr>\n",
+            base
+        ));
+    }
+    for entry in entries {
+        let (suffix, size) = if entry.is_dir {
+            ("/", "-".to_string())
+        } else {
+            ("", entry.size.to_string())
+        };
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}{}{}\">{}{}</a></td><td>{}</td></tr>\n",
+            base,
+            encode_segment(&entry.name),
+            suffix,
+            escape_html(&entry.name),
+            suffix,
+            size
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Percent-encode a path segment, keeping only unreserved characters.
+fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::new();
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
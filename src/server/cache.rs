@@ -0,0 +1,76 @@
+use mime_guess::{mime, Mime};
+use serde::Deserialize;
+
+/// Cache-Control for fingerprinted assets, whose content never changes
+/// under the same name.
+pub const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Cache-Control for HTML pages: always revalidate, using the ETag.
+pub const REVALIDATE: &str = "no-cache";
+
+/// A `cache_control` rule from `webpub.json`: files matching `glob` are
+/// served with `value` as their Cache-Control header. An empty value sends
+/// no header.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CacheRule {
+    pub glob: String,
+    pub value: String,
+}
+
+/// The Cache-Control header for a file. The first configured rule matching
+/// the path wins; otherwise HTML is revalidated, fingerprinted assets are
+/// cached for a year and anything else gets no header.
+pub fn cache_control<'a>(rules: &'a [CacheRule], path: &str, mime: &Mime) -> Option<&'a str> {
+    let value = match rules.iter().find(|rule| glob_match(&rule.glob, path)) {
+        Some(rule) => rule.value.as_str(),
+        None if mime.subtype() == mime::HTML => REVALIDATE,
+        None if is_fingerprinted(path) => IMMUTABLE,
+        None => return None,
+    };
+    (!value.is_empty()).then_some(value)
+}
+
+/// Match a path against a glob. `*` matches within a path segment, `**`
+/// across segments and `?` a single character. A glob without a `/` is
+/// matched against the file name only; otherwise against the whole path,
+/// where the leading `/` is optional.
+pub fn glob_match(glob: &str, path: &str) -> bool {
+    let path = path.trim_start_matches('/');
+    let (glob, path) = match glob.contains('/') {
+        true => (glob.trim_start_matches('/'), path),
+        false => (glob, path.rsplit('/').next().unwrap_or(path)),
+    };
+    match_from(glob.as_bytes(), path.as_bytes())
+}
+
+fn match_from(glob: &[u8], path: &[u8]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            let rest = rest.strip_prefix(b"/").unwrap_or(rest);
+            (0..=path.len()).any(|i| match_from(rest, &path[i..]))
+        }
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+            (0..=segment).any(|i| match_from(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => matches!(path, [c, tail @ ..] if *c != b'/' && match_from(rest, tail)),
+        [c, rest @ ..] => matches!(path, [p, tail @ ..] if p == c && match_from(rest, tail)),
+    }
+}
+
+/// Whether a file name carries a content hash, as bundlers emit for
+/// `app.3f2a9c1b.js` or `index-BxK3z9aQ.css`: a part of the name, split on
+/// `.` and `-`, of at least 8 characters mixing letters and digits.
+pub fn is_fingerprinted(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let Some((stem, _extension)) = name.rsplit_once('.') else {
+        return false;
+    };
+    stem.split(['.', '-']).skip(1).any(|part| {
+        part.len() >= 8
+            && part.bytes().all(|c| c.is_ascii_alphanumeric())
+            && part.bytes().any(|c| c.is_ascii_digit())
+            && part.bytes().any(|c| c.is_ascii_alphabetic())
+    })
+}
@@ -0,0 +1,89 @@
+use axum::{
+    body::Body,
+    http::{header, response::Builder, HeaderMap, StatusCode},
+    response::Response,
+};
+use serde::Deserialize;
+
+/// Cross-origin settings from the `cors` key of `webpub.json`. Sites without
+/// it send no CORS headers.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins allowed to read responses; `*` allows any
+    pub origins: Vec<String>,
+    /// Methods allowed in preflight responses
+    pub methods: Vec<String>,
+    /// Request headers allowed in preflight responses
+    pub headers: Vec<String>,
+    /// Seconds browsers may cache a preflight response
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            origins: Vec::new(),
+            methods: vec!["GET".to_string(), "HEAD".to_string()],
+            headers: Vec::new(),
+            max_age: None,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// The Access-Control-Allow-Origin value for a request's `Origin`, if
+    /// it's allowed: `*` when any origin is, otherwise the origin itself.
+    pub fn allow_origin<'a>(&'a self, origin: &'a str) -> Option<&'a str> {
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            Some("*")
+        } else {
+            self.origins
+                .iter()
+                .any(|allowed| allowed == origin)
+                .then_some(origin)
+        }
+    }
+
+    /// Add the CORS headers for a request to a response.
+    pub fn apply(&self, request: &HeaderMap, mut response: Builder) -> Builder {
+        let origin = request
+            .get(header::ORIGIN)
+            .and_then(|value| value.to_str().ok());
+        if let Some(allowed) = origin.and_then(|origin| self.allow_origin(origin)) {
+            response = response.header(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+        }
+        // The response depends on the Origin unless every origin is allowed
+        if !self.origins.iter().any(|allowed| allowed == "*") {
+            response = response.header(header::VARY, "origin");
+        }
+        response
+    }
+
+    /// Answer an OPTIONS preflight request with 204. Disallowed origins get
+    /// no Access-Control-* headers, so the browser blocks the request.
+    pub fn preflight(&self, request: &HeaderMap) -> Response {
+        let mut response = self.apply(request, Response::builder().status(StatusCode::NO_CONTENT));
+        let allowed = request
+            .get(header::ORIGIN)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|origin| self.allow_origin(origin))
+            .is_some();
+        if allowed {
+            response = response.header(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                self.methods.join(", "),
+            );
+            if !self.headers.is_empty() {
+                response = response.header(
+                    header::ACCESS_CONTROL_ALLOW_HEADERS,
+                    self.headers.join(", "),
+                );
+            }
+            if let Some(max_age) = self.max_age {
+                response = response.header(header::ACCESS_CONTROL_MAX_AGE, max_age);
+            }
+        }
+        response.body(Body::empty()).unwrap()
+    }
+}
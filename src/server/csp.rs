@@ -0,0 +1,105 @@
+/// HTML split at the points where a `nonce` attribute must be inserted into
+/// `<script>` and `<style>` tags. Parsed once per file, rendered per request.
+#[derive(Debug)]
+pub struct NonceTemplate {
+    parts: Vec<Vec<u8>>,
+}
+
+impl NonceTemplate {
+    /// Find every script/style open tag without a nonce attribute.
+    /// Comments and the bodies of script/style elements are skipped so that
+    /// tag-like text inside them is left alone.
+    pub fn parse(html: &[u8]) -> Self {
+        let lower = html.to_ascii_lowercase();
+        let len = lower.len();
+        let mut parts = Vec::new();
+        let mut start = 0;
+        let mut pos = 0;
+
+        while let Some(offset) = find(&lower[pos..], b"<") {
+            let i = pos + offset;
+            let rest = &lower[i..];
+
+            if rest.starts_with(b"<!--") {
+                pos = find(rest, b"-->").map(|e| i + e + 3).unwrap_or(len);
+                continue;
+            }
+
+            let tag: &[u8] = if rest.starts_with(b"<script") {
+                b"script"
+            } else if rest.starts_with(b"<style") {
+                b"style"
+            } else {
+                pos = i + 1;
+                continue;
+            };
+
+            // Tag name must end here, e.g. not <scripts> or <styled-thing>
+            let name_end = i + 1 + tag.len();
+            match lower.get(name_end) {
+                Some(c) if c.is_ascii_whitespace() || *c == b'>' || *c == b'/' => {}
+                _ => {
+                    pos = i + 1;
+                    continue;
+                }
+            }
+
+            let tag_end = find(&lower[name_end..], b">")
+                .map(|e| name_end + e)
+                .unwrap_or(len);
+            if !has_nonce(&lower[name_end..tag_end]) {
+                parts.push(html[start..name_end].to_vec());
+                start = name_end;
+            }
+
+            // Skip the element body
+            let mut close = b"</".to_vec();
+            close.extend_from_slice(tag);
+            pos = find(&lower[tag_end..], &close)
+                .map(|e| tag_end + e + close.len())
+                .unwrap_or(len);
+        }
+
+        parts.push(html[start..].to_vec());
+        NonceTemplate { parts }
+    }
+
+    /// Number of tags that receive the nonce.
+    pub fn slots(&self) -> usize {
+        self.parts.len() - 1
+    }
+
+    /// Produce the HTML with the nonce attribute inserted into each tag.
+    pub fn render(&self, nonce: &str) -> Vec<u8> {
+        let attr = format!(" nonce=\"{}\"", nonce);
+        let mut out = Vec::with_capacity(
+            self.parts.iter().map(|p| p.len()).sum::<usize>() + attr.len() * self.slots(),
+        );
+        for (i, part) in self.parts.iter().enumerate() {
+            if i > 0 {
+                out.extend_from_slice(attr.as_bytes());
+            }
+            out.extend_from_slice(part);
+        }
+        out
+    }
+}
+
+/// Generate a random nonce suitable for a CSP `'nonce-...'` source.
+pub fn generate_nonce() -> String {
+    use rand::Rng;
+
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    hex::encode(bytes)
+}
+
+fn has_nonce(attrs: &[u8]) -> bool {
+    attrs
+        .windows(5)
+        .enumerate()
+        .any(|(i, w)| w == b"nonce" && i > 0 && attrs[i - 1].is_ascii_whitespace())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
@@ -0,0 +1,77 @@
+use crate::server::http::accepts_encoding;
+use axum::http::HeaderMap;
+use flate2::write::GzEncoder;
+use mime_guess::{mime, Mime};
+use std::io::{self, Write};
+
+/// Bodies smaller than this are sent uncompressed; the savings don't cover
+/// the encoding overhead.
+pub const MIN_COMPRESS_SIZE: usize = 256;
+
+/// A response body compression supported by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The Content-Encoding token for this encoding.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Compress a response body.
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                    writer.write_all(data)?;
+                }
+                Ok(out)
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Pick the encoding for a response: brotli if the client accepts it,
+/// then gzip, otherwise none.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    [Encoding::Brotli, Encoding::Gzip]
+        .into_iter()
+        .find(|encoding| accepts_encoding(headers, encoding.as_str()))
+}
+
+/// Whether a content type benefits from compression. Text formats do;
+/// images, video, audio, fonts such as woff2 and archives are already
+/// compressed, so only a known list of types is compressed.
+pub fn is_compressible(mime: &Mime) -> bool {
+    if mime.type_() == mime::TEXT {
+        return true;
+    }
+    if let Some(suffix) = mime.suffix() {
+        if suffix == mime::XML || suffix == mime::JSON {
+            return true;
+        }
+    }
+    matches!(
+        (mime.type_().as_str(), mime.subtype().as_str()),
+        ("application", "javascript")
+            | ("application", "json")
+            | ("application", "xml")
+            | ("application", "wasm")
+            | ("font", "ttf")
+            | ("font", "otf")
+            | ("application", "vnd.ms-fontobject")
+    )
+}
@@ -0,0 +1,90 @@
+use axum::body::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Default total size of reassembled files kept in memory: 64MB.
+pub const DEFAULT_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Reassembled file contents keyed by the file's merkle hash, evicting the
+/// least recently used files beyond a total size. Entries never go stale
+/// since the key names the content. Files larger than the whole cache are
+/// not kept.
+pub struct FileCache {
+    capacity: u64,
+    lru: Mutex<Lru>,
+}
+
+#[derive(Default)]
+struct Lru {
+    /// Contents and last-use tick of each file
+    entries: HashMap<[u8; 32], (Bytes, u64)>,
+    /// Files by last-use tick, oldest first
+    order: BTreeMap<u64, [u8; 32]>,
+    tick: u64,
+    size: u64,
+}
+
+impl FileCache {
+    /// A cache holding up to `capacity` bytes; zero disables it.
+    pub fn new(capacity: u64) -> Self {
+        FileCache {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// A file's contents, if cached, marking it as recently used.
+    pub fn get(&self, hash: &[u8; 32]) -> Option<Bytes> {
+        let mut guard = self.lru.lock().unwrap();
+        let lru = &mut *guard;
+        lru.tick += 1;
+        let tick = lru.tick;
+        let (data, used) = lru.entries.get_mut(hash)?;
+        let (data, previous) = (data.clone(), std::mem::replace(used, tick));
+        lru.order.remove(&previous);
+        lru.order.insert(tick, *hash);
+        Some(data)
+    }
+
+    /// Cache a file's contents, evicting older files to make room.
+    pub fn insert(&self, hash: [u8; 32], data: Bytes) {
+        let len = data.len() as u64;
+        if len > self.capacity {
+            return;
+        }
+
+        let mut guard = self.lru.lock().unwrap();
+        let lru = &mut *guard;
+        lru.tick += 1;
+        let tick = lru.tick;
+        if let Some((old, used)) = lru.entries.insert(hash, (data, tick)) {
+            lru.size -= old.len() as u64;
+            lru.order.remove(&used);
+        }
+        lru.order.insert(tick, hash);
+        lru.size += len;
+
+        while lru.size > self.capacity {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = lru.entries.remove(&oldest) {
+                lru.size -= evicted.len() as u64;
+            }
+        }
+    }
+
+    /// Total bytes of the cached files.
+    pub fn size(&self) -> u64 {
+        self.lru.lock().unwrap().size
+    }
+
+    /// Number of cached files.
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,104 @@
+use crate::server::redirects::PathPattern;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+
+/// Netlify-style custom response headers, read from the root of the
+/// deployed snapshot.
+pub const HEADERS_FILE: &str = "_headers";
+
+/// Headers describing how the body is framed or encoded, which the server
+/// sets itself and a `_headers` file may not override.
+const RESERVED: [HeaderName; 4] = [
+    header::CONTENT_LENGTH,
+    header::CONTENT_ENCODING,
+    header::CONTENT_RANGE,
+    header::TRANSFER_ENCODING,
+];
+
+/// A block of a `_headers` file: a path pattern followed by indented
+/// `Name: value` lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderRule {
+    path: PathPattern,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Parse a `_headers` file:
+///
+/// ```text
+/// /*
+///   X-Frame-Options: DENY
+/// /assets/*
+///   Cache-Control: public, max-age=31536000
+/// ```
+///
+/// Blank lines and `#` comments are skipped, as are invalid lines, with a
+/// warning.
+pub fn parse_headers(text: &str) -> Vec<HeaderRule> {
+    let mut rules: Vec<HeaderRule> = Vec::new();
+    // Whether header lines belong to the last rule, rather than to an
+    // invalid path or to no path at all
+    let mut in_rule = false;
+    for (number, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if !line.starts_with(char::is_whitespace) {
+            in_rule = trimmed.starts_with('/');
+            if in_rule {
+                rules.push(HeaderRule {
+                    path: PathPattern::parse(trimmed),
+                    headers: Vec::new(),
+                });
+            } else {
+                warn(number, &format!("path {} must start with /", trimmed));
+            }
+            continue;
+        }
+
+        let Some(rule) = rules.last_mut().filter(|_| in_rule) else {
+            warn(number, "header without a valid path");
+            continue;
+        };
+        match parse_header(trimmed) {
+            Ok(header) => rule.headers.push(header),
+            Err(e) => warn(number, &e),
+        }
+    }
+    rules
+}
+
+fn parse_header(line: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = line
+        .split_once(':')
+        .ok_or_else(|| "expected `Name: value`".to_string())?;
+    let name = HeaderName::try_from(name.trim()).map_err(|e| e.to_string())?;
+    if RESERVED.contains(&name) {
+        return Err(format!("{} is set by the server", name));
+    }
+    let value = HeaderValue::try_from(value.trim()).map_err(|e| e.to_string())?;
+    Ok((name, value))
+}
+
+fn warn(number: usize, message: &str) {
+    eprintln!("Ignoring {} line {}: {}", HEADERS_FILE, number + 1, message);
+}
+
+/// Add the headers of every rule matching a request path to a response.
+/// Rules apply in file order, and a header set by a rule replaces any value
+/// the server or an earlier rule gave it, so the last matching rule wins.
+/// A header repeated within one rule is sent with each of its values.
+pub fn apply_headers(rules: &[HeaderRule], path: &str, headers: &mut HeaderMap) {
+    for rule in rules.iter().filter(|rule| rule.path.matches(path)) {
+        let mut set: Vec<&HeaderName> = Vec::new();
+        for (name, value) in &rule.headers {
+            if set.contains(&name) {
+                headers.append(name, value.clone());
+            } else {
+                headers.insert(name, value.clone());
+                set.push(name);
+            }
+        }
+    }
+}
@@ -1,95 +1,828 @@
-use crate::server::storage::Storage;
+use crate::server::access_log::log_access;
+use crate::server::autoindex::render_listing;
+use crate::server::cache::cache_control;
+use crate::server::csp;
+use crate::server::encoding::{is_compressible, negotiate, MIN_COMPRESS_SIZE};
+use crate::server::file_cache::{FileCache, DEFAULT_CACHE_SIZE};
+use crate::server::headers::apply_headers;
+use crate::server::metrics::{instrument, Metrics};
+use crate::server::preview_link::{
+    cookie, verify_preview_key, PREVIEW_COOKIE, PREVIEW_EXIT, PREVIEW_PREFIX,
+};
+use crate::server::range::{parse_range, RangeRequest};
+use crate::server::redirects::find_redirect;
+use crate::server::site::{Site, SiteCache};
+use crate::server::sniff::sniff_content_type;
+use crate::server::storage::{SnapshotEntry, Storage};
 use crate::Node;
 use axum::{
-    body::Body,
-    extract::{Host, Path, State},
-    http::{header, StatusCode},
+    body::{Body, Bytes},
+    extract::{Host, State},
+    http::{header, response::Builder, HeaderMap, HeaderValue, Method, StatusCode, Uri},
     response::{IntoResponse, Response},
     routing::get,
-    Router,
+    Json, Router,
 };
+use flate2::read::GzDecoder;
+use futures_util::stream;
+use std::convert::Infallible;
+use std::io::Read;
 use std::sync::Arc;
+use std::time::Instant;
+
+/// Page served for paths that can't be resolved, if the site has one.
+pub const NOT_FOUND_PAGE: &str = "404.html";
+
+/// Path of the health endpoint.
+pub const HEALTH_PATH: &str = "/healthz";
+
+/// Response header naming the snapshot a preview response came from.
+pub const PREVIEW_HEADER: &str = "x-webpub-preview";
+
+/// Server-wide settings for the HTTP router.
+#[derive(Debug, Clone)]
+pub struct RouterOptions {
+    /// Host answering `/healthz` with the server's health instead of site
+    /// content. Requests without a Host header always get it.
+    pub health_host: Option<String>,
+    /// Counters every response is recorded in
+    pub metrics: Arc<Metrics>,
+    /// Record an access log event per request, written by whatever
+    /// subscriber is installed
+    pub access_log: bool,
+    /// Total bytes of reassembled files kept in memory; zero disables caching
+    pub cache_size: u64,
+    /// Key that snapshot preview links are signed with. When set,
+    /// `/_preview/<key>/` links let a browser view one of a site's other
+    /// snapshots; when unset, those paths are ordinary site content.
+    pub preview_secret: Option<[u8; 32]>,
+}
+
+impl Default for RouterOptions {
+    fn default() -> Self {
+        RouterOptions {
+            health_host: None,
+            metrics: Arc::default(),
+            access_log: false,
+            cache_size: DEFAULT_CACHE_SIZE,
+            preview_secret: None,
+        }
+    }
+}
 
 pub struct AppState {
     pub storage: Arc<Storage>,
+    pub sites: SiteCache,
+    /// Reassembled files shared by all requests
+    pub files: FileCache,
+    pub options: RouterOptions,
+    pub started: Instant,
 }
 
 pub fn create_router(storage: Arc<Storage>) -> Router {
-    let state = AppState { storage };
+    create_router_with(storage, RouterOptions::default())
+}
 
-    Router::new()
-        .route("/", get(handle_request))
-        .route("/*path", get(handle_request))
-        .with_state(Arc::new(state))
+pub fn create_router_with(storage: Arc<Storage>, options: RouterOptions) -> Router {
+    let metrics = options.metrics.clone();
+    let access_log = options.access_log;
+    let state = AppState {
+        storage,
+        sites: SiteCache::default(),
+        files: FileCache::new(options.cache_size),
+        options,
+        started: Instant::now(),
+    };
+
+    let router = Router::new()
+        .route(HEALTH_PATH, get(handle_health).head(handle_health))
+        .route(
+            "/",
+            get(handle_request)
+                .head(handle_request)
+                .options(handle_preflight)
+                .fallback(handle_method_not_allowed),
+        )
+        .route(
+            "/*path",
+            get(handle_request)
+                .head(handle_request)
+                .options(handle_preflight)
+                .fallback(handle_method_not_allowed),
+        )
+        .with_state(Arc::new(state));
+    let router = instrument(router, metrics);
+    if access_log {
+        log_access(router)
+    } else {
+        router
+    }
+}
+
+/// Report health for requests without a Host or to the configured health
+/// host; for any other host `/healthz` is ordinary site content.
+async fn handle_health(
+    State(state): State<Arc<AppState>>,
+    host: Option<Host>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    let health_host = state.options.health_host.as_deref();
+    let site_host = host
+        .map(|Host(host)| host)
+        .filter(|host| Some(host.split(':').next().unwrap_or(host)) != health_host);
+    match site_host {
+        Some(host) => {
+            let uri = Uri::from_static(HEALTH_PATH);
+            handle_request(State(state), Host(host), method, headers, uri).await
+        }
+        None => health(&state),
+    }
 }
 
+/// Uptime and the number of sites served, as JSON. Fails with 503 when the
+/// index database can't be read.
+fn health(state: &AppState) -> Response {
+    match state.storage.count_sites() {
+        Ok(sites) => Json(serde_json::json!({
+            "status": "ok",
+            "uptime_seconds": state.started.elapsed().as_secs(),
+            "sites": sites,
+        }))
+        .into_response(),
+        Err(e) => {
+            tracing::error!(error = %e, "health check failed");
+            (StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable").into_response()
+        }
+    }
+}
+
+/// Serve GET and HEAD requests. HEAD resolves the path the same way and
+/// sends the same headers, but never reads the file's chunks. Errors are
+/// logged within a span naming the request's host and path.
+#[tracing::instrument(name = "request", skip_all, fields(host = %host, path = %uri.path()))]
 async fn handle_request(
     State(state): State<Arc<AppState>>,
     Host(host): Host,
-    path: Option<Path<String>>,
+    method: Method,
+    headers: HeaderMap,
+    uri: Uri,
 ) -> Response {
-    let path_str = path
-        .map(|p| format!("/{}", p.0))
-        .unwrap_or_else(|| "/".to_string());
+    let path_str = match request_path(&uri) {
+        Ok(path) => path,
+        Err(reason) => return (StatusCode::BAD_REQUEST, reason).into_response(),
+    };
 
     // Strip port from host if present
     let hostname = host.split(':').next().unwrap_or(&host);
 
+    if let Some(secret) = &state.options.preview_secret {
+        if let Some(rest) = path_str.strip_prefix(PREVIEW_PREFIX) {
+            return enter_preview(&state.storage, secret, hostname, rest, &uri);
+        }
+        match preview_site(&state.storage, secret, hostname, &headers) {
+            Ok(Some(site)) => {
+                let mut response = serve(&state, &site, &method, &headers, path_str.clone());
+                apply_headers(&site.headers, &path_str, response.headers_mut());
+                // Previews must not be cached where live visitors could get them
+                let response_headers = response.headers_mut();
+                response_headers.insert(
+                    header::CACHE_CONTROL,
+                    HeaderValue::from_static("private, no-store"),
+                );
+                response_headers.insert(PREVIEW_HEADER, HeaderValue::from(site.snapshot_id));
+                return response;
+            }
+            Ok(None) => {}
+            Err(e) => return internal_error(e),
+        }
+    }
+
     // Get current snapshot for this host
-    let snapshot = match state.storage.get_current_snapshot(hostname) {
-        Ok(Some((_, tree))) => tree,
+    let site = match state.sites.get(&state.storage, hostname) {
+        Ok(Some(site)) => site,
         Ok(None) => return (StatusCode::NOT_FOUND, "Site not found").into_response(),
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Err(e) => return internal_error(e),
     };
 
-    // Find the node for this path
-    let node = match find_node(&snapshot, &path_str) {
-        Some(n) => n,
-        None => return (StatusCode::NOT_FOUND, "Not found").into_response(),
+    // Headers from _headers apply to every response for the requested path
+    let mut response = serve(&state, &site, &method, &headers, path_str.clone());
+    apply_headers(&site.headers, &path_str, response.headers_mut());
+    response
+}
+
+/// Answer a request under `/_preview/`. A valid key for one of the site's
+/// snapshots sets the preview cookie and redirects to the rest of the path,
+/// so the site's own absolute links keep working; `exit` clears the cookie.
+fn enter_preview(
+    storage: &Storage,
+    secret: &[u8; 32],
+    hostname: &str,
+    rest: &str,
+    uri: &Uri,
+) -> Response {
+    let key = rest.split('/').next().unwrap_or_default();
+    let cookie = if key == PREVIEW_EXIT {
+        format!("{}=; Path=/; Max-Age=0", PREVIEW_COOKIE)
+    } else {
+        let found = match verify_preview_key(secret, hostname, key) {
+            Some(snapshot_id) => storage.has_snapshot(hostname, snapshot_id),
+            None => Ok(false),
+        };
+        match found {
+            Ok(true) => format!("{}={}; Path=/; HttpOnly; SameSite=Lax", PREVIEW_COOKIE, key),
+            Ok(false) => return (StatusCode::NOT_FOUND, "Preview not found").into_response(),
+            Err(e) => return internal_error(e),
+        }
+    };
+
+    // The rest of the raw path, still percent-encoded
+    let location = uri
+        .path()
+        .strip_prefix(PREVIEW_PREFIX)
+        .and_then(|rest| rest.find('/').map(|i| &rest[i..]))
+        .unwrap_or("/");
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .header(header::SET_COOKIE, cookie)
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// The snapshot a request's preview cookie selects, if it holds a valid key
+/// for one of the site's snapshots. Preview sites are loaded per request
+/// rather than cached.
+fn preview_site(
+    storage: &Storage,
+    secret: &[u8; 32],
+    hostname: &str,
+    headers: &HeaderMap,
+) -> crate::server::storage::Result<Option<Site>> {
+    let Some(snapshot_id) =
+        cookie(headers, PREVIEW_COOKIE).and_then(|key| verify_preview_key(secret, hostname, key))
+    else {
+        return Ok(None);
+    };
+    if !storage.has_snapshot(hostname, snapshot_id)? {
+        return Ok(None);
+    }
+    Site::load(storage, snapshot_id).map(Some)
+}
+
+/// Build the response for a path of a site.
+fn serve(
+    state: &AppState,
+    site: &Site,
+    method: &Method,
+    headers: &HeaderMap,
+    mut path_str: String,
+) -> Response {
+    // Rules from _redirects apply before any file is looked up
+    if let Some((location, status)) = find_redirect(&site.redirects, &path_str) {
+        return Response::builder()
+            .status(status)
+            .header(header::LOCATION, location)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    let lookup = |path: &str| state.storage.lookup_path(site.snapshot_id, path);
+
+    let mut entry = match lookup(&path_str) {
+        Ok(entry) => entry,
+        Err(e) => return internal_error(e),
+    };
+
+    // With clean URLs, /about is served from /about.html or /about/index.html
+    // under its original URL
+    if entry.is_none() && site.config.clean_urls && is_client_route(&path_str) {
+        let base = path_str.trim_end_matches('/');
+        for candidate in [format!("{}.html", base), format!("{}/index.html", base)] {
+            match lookup(&candidate) {
+                Ok(Some(found @ SnapshotEntry::File { .. })) => {
+                    path_str = candidate;
+                    entry = Some(found);
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => return internal_error(e),
+            }
+        }
+    }
+
+    // Otherwise fall back to a gzip-only variant, then to the root index.html
+    // for client-side routes of single-page apps
+    let mut gzipped = false;
+    let entry = match entry {
+        Some(entry) => entry,
+        None => match lookup(&format!("{}.gz", path_str)) {
+            Ok(Some(entry @ SnapshotEntry::File { .. })) => {
+                gzipped = true;
+                entry
+            }
+            Ok(_) if site.config.spa_fallback && is_client_route(&path_str) => {
+                match lookup("/index.html") {
+                    Ok(Some(entry @ SnapshotEntry::File { .. })) => {
+                        path_str = "/index.html".to_string();
+                        entry
+                    }
+                    Ok(_) => return not_found(&state.storage, site.snapshot_id),
+                    Err(e) => return internal_error(e),
+                }
+            }
+            Ok(_) => return not_found(&state.storage, site.snapshot_id),
+            Err(e) => return internal_error(e),
+        },
     };
 
     // Must be a file
-    let (chunks, name) = match node {
-        Node::File { chunks, name, .. } => (chunks, name),
-        Node::Directory { .. } => {
+    let (chunks, name, hash, size) = match entry {
+        SnapshotEntry::File {
+            chunks, hash, size, ..
+        } => (chunks, path_str.clone(), hash, size),
+        SnapshotEntry::Directory { .. } => {
             // Try index.html
             let index_path = if path_str.ends_with('/') {
                 format!("{}index.html", path_str)
             } else {
                 format!("{}/index.html", path_str)
             };
-            if let Some(Node::File { chunks, name, .. }) = find_node(&snapshot, &index_path) {
-                (chunks, name)
-            } else {
-                return (StatusCode::NOT_FOUND, "Not found").into_response();
+            match lookup(&index_path) {
+                Ok(Some(SnapshotEntry::File {
+                    chunks, hash, size, ..
+                })) => (chunks, index_path, hash, size),
+                Ok(_) if site.config.autoindex => {
+                    return directory_listing(&state.storage, site.snapshot_id, &path_str)
+                }
+                Ok(_) if path_str == "/" => {
+                    return custom_not_found(&state.storage, site.snapshot_id)
+                        .unwrap_or_else(|| missing_root_index(&state.storage, site.snapshot_id))
+                }
+                Ok(_) => return not_found(&state.storage, site.snapshot_id),
+                Err(e) => return internal_error(e),
             }
         }
     };
 
-    // Reassemble file from chunks
-    let mut data = Vec::new();
-    for hash in chunks {
-        match state.storage.get_chunk(hash) {
-            Ok(Some(chunk_data)) => data.extend(chunk_data),
-            Ok(None) => {
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Missing chunk").into_response()
+    // Guess content type from extension, using the requested name for .gz variants
+    let mut mime = if gzipped {
+        mime_guess::from_path(&path_str).first_or_octet_stream()
+    } else {
+        mime_guess::from_path(&name).first_or_octet_stream()
+    };
+
+    // Sniff the type of files the extension says nothing about, from their
+    // first chunk. The response then tells browsers not to sniff again.
+    let mut sniffed = false;
+    if site.config.sniff_mime && !gzipped && mime == mime_guess::mime::APPLICATION_OCTET_STREAM {
+        if let Some(first) = chunks.first() {
+            let sample = match state.storage.get_chunk(first) {
+                Ok(Some(sample)) => sample,
+                Ok(None) => return internal_error("Missing chunk"),
+                Err(e) => return internal_error(e),
+            };
+            if let Some(guess) = sniff_content_type(&sample, chunks.len() == 1) {
+                mime = guess.parse().unwrap();
+                sniffed = true;
             }
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
         }
     }
+    let cache_path = if gzipped { &path_str } else { &name };
+    let cache = cache_control(&site.config.cache_control, cache_path, &mime);
+    let content_type = mime.to_string();
+    let inject_nonce = site.config.csp_nonce && mime.subtype() == mime_guess::mime::HTML;
 
-    // Guess content type from extension
-    let content_type = mime_guess::from_path(name)
-        .first_or_octet_stream()
-        .to_string();
+    // Decide how the body will be encoded up front, so the ETag can name it
+    let gzip_passthrough = gzipped && accepts_encoding(headers, "gzip");
+    let compressible = !gzipped && size >= MIN_COMPRESS_SIZE as u64 && is_compressible(&mime);
+    let encoding = if compressible {
+        negotiate(headers)
+    } else {
+        None
+    };
 
-    Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, content_type)
+    // Pages with a fresh nonce differ on every request and get no ETag
+    let etag = (!inject_nonce).then(|| {
+        let coding = match encoding {
+            Some(encoding) => Some(encoding.as_str()),
+            None if gzipped && !gzip_passthrough => Some("identity"),
+            None => None,
+        };
+        entity_tag(&hash, coding)
+    });
+
+    let mut response = Response::builder().header(header::CONTENT_TYPE, content_type);
+    if sniffed {
+        response = response.header(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+    }
+    if let Some(cache) = cache {
+        response = response.header(header::CACHE_CONTROL, cache);
+    }
+    if let Some(cors) = &site.config.cors {
+        response = cors.apply(headers, response);
+    }
+    if gzipped || compressible {
+        response = response.header(header::VARY, "accept-encoding");
+    }
+
+    if let Some(etag) = &etag {
+        if if_none_match(headers, etag) {
+            return response
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag)
+                .body(Body::empty())
+                .unwrap();
+        }
+        response = response.header(header::ETAG, etag);
+    }
+
+    // Byte ranges are served for files sent exactly as stored, reading only
+    // the chunks that overlap the range
+    let ranged = !gzipped && !inject_nonce && encoding.is_none();
+    if ranged {
+        let range = headers
+            .get(header::RANGE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(RangeRequest::Full, |value| parse_range(value, size));
+        match range {
+            RangeRequest::Full => {}
+            RangeRequest::Partial(range) => {
+                let response = response
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::ACCEPT_RANGES, "bytes")
+                    .header(
+                        header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+                    );
+                if method == Method::HEAD {
+                    return head_response(response, Some(range.end - range.start));
+                }
+                // Slice a cached copy of the file, or read just the range
+                let cached = state.files.get(&hash);
+                state.options.metrics.record_file_cache(cached.is_some());
+                let data = match cached {
+                    Some(file) => file.slice(range.start as usize..range.end as usize),
+                    None => match state.storage.read_range(&chunks, range.clone()) {
+                        Ok(Some(data)) if data.len() as u64 != range.end - range.start => {
+                            return size_mismatch(&name, data.len() as u64, range.end - range.start)
+                        }
+                        Ok(Some(data)) => Bytes::from(data),
+                        Ok(None) => return internal_error("Missing chunk"),
+                        Err(e) => return internal_error(e),
+                    },
+                };
+                return response
+                    .header(header::CONTENT_LENGTH, data.len())
+                    .body(Body::from(data))
+                    .unwrap();
+            }
+            RangeRequest::Unsatisfiable => {
+                return Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                    .body(Body::empty())
+                    .unwrap();
+            }
+        }
+    }
+
+    response = response.status(StatusCode::OK);
+    if ranged {
+        response = response.header(header::ACCEPT_RANGES, "bytes");
+    }
+
+    // Describe the body GET would send. Its length is the file's size when
+    // sent as stored; transformed bodies are only measured by building them.
+    if method == Method::HEAD {
+        let mut length = Some(size);
+        if gzip_passthrough {
+            response = response.header(header::CONTENT_ENCODING, "gzip");
+        } else if gzipped {
+            length = None;
+        }
+        if inject_nonce && !gzip_passthrough {
+            let nonce = csp::generate_nonce();
+            response = response.header(
+                header::CONTENT_SECURITY_POLICY,
+                site.config.csp_header(&nonce),
+            );
+            length = None;
+        }
+        if let Some(encoding) = encoding {
+            response = response.header(header::CONTENT_ENCODING, encoding.as_str());
+            length = None;
+        }
+        return head_response(response, length);
+    }
+
+    // Reassemble file from chunks, unless it's cached. A file whose chunks
+    // don't add up to its size means storage is corrupt, and isn't cached.
+    let cached = state.files.get(&hash);
+    state.options.metrics.record_file_cache(cached.is_some());
+    let mut data = match cached {
+        Some(data) => data,
+        None => match state.storage.read_file(&chunks) {
+            Ok(Some(data)) if data.len() as u64 != size => {
+                return size_mismatch(&name, data.len() as u64, size)
+            }
+            Ok(Some(data)) => {
+                let data = Bytes::from(data);
+                state.files.insert(hash, data.clone());
+                data
+            }
+            Ok(None) => return internal_error("Missing chunk"),
+            Err(e) => return internal_error(e),
+        },
+    };
+
+    // Send gzip-only assets as-is to clients that accept gzip, decompress otherwise
+    let mut encoded = false;
+    if gzipped {
+        if gzip_passthrough {
+            response = response.header(header::CONTENT_ENCODING, "gzip");
+            encoded = true;
+        } else {
+            let mut decoded = Vec::new();
+            if let Err(e) = GzDecoder::new(&data[..]).read_to_end(&mut decoded) {
+                return internal_error(e);
+            }
+            data = decoded.into();
+        }
+    }
+
+    // Inject a fresh CSP nonce into HTML pages
+    if inject_nonce && !encoded {
+        let nonce = csp::generate_nonce();
+        data = site.nonce_template(&hash, &data).render(&nonce).into();
+        response = response.header(
+            header::CONTENT_SECURITY_POLICY,
+            site.config.csp_header(&nonce),
+        );
+    }
+
+    // Compress text responses for clients that accept it
+    if let Some(encoding) = encoding {
+        data = match encoding.compress(&data) {
+            Ok(compressed) => compressed.into(),
+            Err(e) => return internal_error(e),
+        };
+        response = response.header(header::CONTENT_ENCODING, encoding.as_str());
+    }
+
+    response
+        .header(header::CONTENT_LENGTH, data.len())
         .body(Body::from(data))
         .unwrap()
 }
 
+/// The response for a file whose reassembled contents don't match the size
+/// its snapshot records.
+fn size_mismatch(path: &str, actual: u64, expected: u64) -> Response {
+    tracing::error!(file = path, actual, expected, "file size mismatch");
+    (StatusCode::INTERNAL_SERVER_ERROR, "File size mismatch").into_response()
+}
+
+/// A 500 with a fixed body. The error itself may hold storage internals, so
+/// it's only logged for the operator, in the request's span.
+fn internal_error(error: impl std::fmt::Display) -> Response {
+    tracing::error!(error = %error, "internal error");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+}
+
+/// Answer CORS preflight requests for sites that configure `cors`. Other
+/// sites don't accept OPTIONS.
+#[tracing::instrument(name = "request", skip_all, fields(host = %host, path = %uri.path()))]
+async fn handle_preflight(
+    State(state): State<Arc<AppState>>,
+    Host(host): Host,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    let hostname = host.split(':').next().unwrap_or(&host);
+    match state.sites.get(&state.storage, hostname) {
+        Ok(Some(site)) => match &site.config.cors {
+            Some(cors) => cors.preflight(&headers),
+            None => method_not_allowed(false),
+        },
+        Ok(None) => (StatusCode::NOT_FOUND, "Site not found").into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Answer methods other than GET, HEAD and OPTIONS on site paths, listing
+/// the ones the site accepts.
+async fn handle_method_not_allowed(
+    State(state): State<Arc<AppState>>,
+    Host(host): Host,
+) -> Response {
+    let hostname = host.split(':').next().unwrap_or(&host);
+    let cors = matches!(
+        state.sites.get(&state.storage, hostname),
+        Ok(Some(site)) if site.config.cors.is_some()
+    );
+    method_not_allowed(cors)
+}
+
+/// A 405 whose `Allow` header includes OPTIONS only for sites with `cors`,
+/// the only ones that answer preflights.
+fn method_not_allowed(cors: bool) -> Response {
+    let allow = if cors {
+        "GET, HEAD, OPTIONS"
+    } else {
+        "GET, HEAD"
+    };
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(header::ALLOW, allow)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Finish a HEAD response with an empty body. Without a known length the
+/// body is a stream, so no `Content-Length: 0` is filled in for it.
+fn head_response(response: Builder, length: Option<u64>) -> Response {
+    match length {
+        Some(length) => response
+            .header(header::CONTENT_LENGTH, length)
+            .body(Body::empty()),
+        None => response.body(Body::from_stream(
+            stream::empty::<Result<Bytes, Infallible>>(),
+        )),
+    }
+    .unwrap()
+}
+
+/// Whether a missing path looks like a client-side route such as
+/// `/app/settings` rather than an asset: its last segment has no extension.
+fn is_client_route(path: &str) -> bool {
+    let last = path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    !last.contains('.')
+}
+
+/// Generate an HTML listing for a directory without an index.html.
+fn directory_listing(storage: &Storage, snapshot_id: i64, path: &str) -> Response {
+    match storage.list_directory(snapshot_id, path) {
+        Ok(entries) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(render_listing(path, &entries)))
+            .unwrap(),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Respond 404 with the site's custom page, or plain text if it has none.
+fn not_found(storage: &Storage, snapshot_id: i64) -> Response {
+    custom_not_found(storage, snapshot_id)
+        .unwrap_or_else(|| (StatusCode::NOT_FOUND, "Not found").into_response())
+}
+
+/// Serve the site's `404.html` with a 404 status, if the snapshot has one.
+fn custom_not_found(storage: &Storage, snapshot_id: i64) -> Option<Response> {
+    let chunks = match storage.lookup_path(snapshot_id, NOT_FOUND_PAGE) {
+        Ok(Some(SnapshotEntry::File { chunks, .. })) => chunks,
+        Ok(_) => return None,
+        Err(e) => return Some(internal_error(e)),
+    };
+    let response = match storage.read_file(&chunks) {
+        Ok(Some(data)) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header(header::CONTENT_TYPE, "text/html")
+            .body(Body::from(data))
+            .unwrap(),
+        Ok(None) => internal_error("Missing chunk"),
+        Err(e) => internal_error(e),
+    };
+    Some(response)
+}
+
+/// Explain a 404 for `/` on a site deployed without a root index.html,
+/// listing what the root does contain.
+fn missing_root_index(storage: &Storage, snapshot_id: i64) -> Response {
+    let entries: Vec<String> = match storage.list_directory(snapshot_id, "/") {
+        Ok(entries) => entries
+            .into_iter()
+            .map(|entry| {
+                if entry.is_dir {
+                    format!("{}/", entry.name)
+                } else {
+                    entry.name
+                }
+            })
+            .collect(),
+        Err(e) => return internal_error(e),
+    };
+
+    let message = format!(
+        "Site deployed but no index.html found at root; did you mean to deploy a subdirectory?\n\
+         Top-level entries: {}\n",
+        if entries.is_empty() {
+            "(none)".to_string()
+        } else {
+            entries.join(", ")
+        }
+    );
+    (StatusCode::NOT_FOUND, message).into_response()
+}
+
+/// A strong ETag derived from a file's content hash, so it is stable across
+/// restarts and deploys. Bodies sent with a different content coding than
+/// the stored bytes name that coding, since they are different bytes.
+fn entity_tag(hash: &[u8; 32], coding: Option<&str>) -> String {
+    match coding {
+        Some(coding) => format!("\"{}-{}\"", hex::encode(hash), coding),
+        None => format!("\"{}\"", hex::encode(hash)),
+    }
+}
+
+/// Check whether the request's If-None-Match lists the given ETag, using
+/// weak comparison as the header requires.
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Check whether the request's Accept-Encoding allows the given encoding.
+pub fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or("").trim();
+            let rejected = params.any(|p| {
+                p.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            (name.eq_ignore_ascii_case(encoding) || name == "*") && !rejected
+        })
+}
+
+/// The decoded and normalized path of a request, or why it's a bad request.
+/// Decoding comes first, so encoded dots are resolved too.
+pub fn request_path(uri: &Uri) -> Result<String, &'static str> {
+    let path = decode_path(uri.path()).ok_or("Invalid percent-encoding in path")?;
+    normalize_path(&path).ok_or("Path escapes the site root")
+}
+
+/// Percent-decode a request path. `+` is literal, as it only means a space
+/// in query strings. None if a `%` isn't followed by two hex digits or the
+/// bytes aren't UTF-8.
+pub fn decode_path(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Resolve the `.` and `..` segments of a decoded request path and drop
+/// empty ones, keeping a trailing slash when the path names a directory.
+/// None if `..` would climb above the root.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let last = path.rsplit('/').next().unwrap_or("");
+    let is_dir = matches!(last, "" | "." | "..");
+    Some(match (segments.is_empty(), is_dir) {
+        (true, _) => "/".to_string(),
+        (false, true) => format!("/{}/", segments.join("/")),
+        (false, false) => format!("/{}", segments.join("/")),
+    })
+}
+
 pub fn find_node<'a>(tree: &'a Node, path: &str) -> Option<&'a Node> {
     let path = path.trim_start_matches('/');
 
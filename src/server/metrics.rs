@@ -0,0 +1,171 @@
+use crate::server::storage::Storage;
+use axum::{
+    body::{Body, HttpBody},
+    extract::{Request, State},
+    http::header,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Counters exported in the Prometheus text format. One registry is shared
+/// by the HTTP router and the sync server; chunk reads are counted by
+/// [`Storage`] itself.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<u16, u64>>,
+    bytes: AtomicU64,
+    file_cache_hits: AtomicU64,
+    file_cache_misses: AtomicU64,
+    commits: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    /// Count an HTTP response and the bytes of its body.
+    pub fn record_request(&self, status: u16, bytes: u64) {
+        *self.requests.lock().unwrap().entry(status).or_default() += 1;
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a lookup in the reassembled file cache.
+    pub fn record_file_cache(&self, hit: bool) {
+        let counter = match hit {
+            true => &self.file_cache_hits,
+            false => &self.file_cache_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a snapshot committed by a deploy.
+    pub fn record_commit(&self, hostname: &str) {
+        *self
+            .commits
+            .lock()
+            .unwrap()
+            .entry(hostname.to_string())
+            .or_default() += 1;
+    }
+
+    /// All counters in the Prometheus text exposition format.
+    pub fn render(&self, storage: &Storage) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "webpub_http_requests_total",
+            "HTTP responses by status code",
+        );
+        for (status, count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "webpub_http_requests_total{{status=\"{}\"}} {}",
+                status, count
+            );
+        }
+
+        header(
+            &mut out,
+            "webpub_http_bytes_total",
+            "HTTP response body bytes sent",
+        );
+        let _ = writeln!(
+            out,
+            "webpub_http_bytes_total {}",
+            self.bytes.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "webpub_chunk_reads_total",
+            "Chunks read from storage",
+        );
+        let _ = writeln!(out, "webpub_chunk_reads_total {}", storage.chunk_reads());
+
+        header(
+            &mut out,
+            "webpub_file_cache_hits_total",
+            "File responses served from the reassembled file cache",
+        );
+        let _ = writeln!(
+            out,
+            "webpub_file_cache_hits_total {}",
+            self.file_cache_hits.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "webpub_file_cache_misses_total",
+            "File responses read from chunks",
+        );
+        let _ = writeln!(
+            out,
+            "webpub_file_cache_misses_total {}",
+            self.file_cache_misses.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "webpub_sync_commits_total",
+            "Snapshots committed by deploys",
+        );
+        for (hostname, count) in self.commits.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "webpub_sync_commits_total{{hostname=\"{}\"}} {}",
+                escape_label(hostname),
+                count
+            );
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}.", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+}
+
+/// Escape a label value: backslashes, quotes and newlines.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Middleware counting every response of a router. Body bytes are taken
+/// from the body's exact size, which every file response has.
+pub async fn track_requests(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    let bytes = response.body().size_hint().exact().unwrap_or(0);
+    metrics.record_request(response.status().as_u16(), bytes);
+    response
+}
+
+/// Add request counting to a router.
+pub fn instrument(router: Router, metrics: Arc<Metrics>) -> Router {
+    router.layer(middleware::from_fn_with_state(metrics, track_requests))
+}
+
+/// A router serving the metrics at `/metrics`, for the admin port.
+pub fn metrics_router(metrics: Arc<Metrics>, storage: Arc<Storage>) -> Router {
+    Router::new().route(
+        "/metrics",
+        get(move || async move {
+            (
+                [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                Body::from(metrics.render(&storage)),
+            )
+                .into_response()
+        }),
+    )
+}
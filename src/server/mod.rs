@@ -1,3 +1,23 @@
+pub mod access_log;
+pub mod admin;
+pub mod auth;
+pub mod autoindex;
+pub mod cache;
+pub mod cors;
+pub mod csp;
+pub mod encoding;
+pub mod file_cache;
+pub mod headers;
 pub mod http;
+pub mod metrics;
+pub mod pool;
+pub mod preview;
+pub mod preview_link;
+pub mod range;
+pub mod rate_limit;
+pub mod redirects;
+pub mod site;
+pub mod sniff;
 pub mod storage;
 pub mod sync;
+pub mod tls;
@@ -0,0 +1,109 @@
+use rusqlite::{Connection, Result};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+/// Default number of connections each pool may open.
+pub const DEFAULT_POOL_SIZE: usize = 4;
+
+/// Setup run on each newly opened connection, e.g. pragmas
+type Init = Box<dyn Fn(&Connection) -> Result<()> + Send + Sync>;
+
+/// Connections to one SQLite database file, opened on demand up to a fixed
+/// size and reused. Checking out a connection when all of them are in use
+/// waits for one to be returned.
+pub struct Pool {
+    path: PathBuf,
+    size: usize,
+    init: Init,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<Connection>,
+    /// Connections open, idle or checked out
+    open: usize,
+}
+
+impl Pool {
+    /// A pool for the database at `path`. No connection is opened, and no
+    /// file created, until the first checkout.
+    pub fn new(
+        path: &Path,
+        size: usize,
+        init: impl Fn(&Connection) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        Pool {
+            path: path.to_path_buf(),
+            size: size.max(1),
+            init: Box::new(init),
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Check out a connection, returned to the pool when dropped.
+    pub fn get(&self) -> Result<Pooled<'_>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(conn) = state.idle.pop() {
+                return Ok(Pooled {
+                    pool: self,
+                    conn: Some(conn),
+                });
+            }
+            if state.open < self.size {
+                state.open += 1;
+                break;
+            }
+            state = self.returned.wait(state).unwrap();
+        }
+        // Open outside the lock so other checkouts aren't held up
+        drop(state);
+
+        match Connection::open(&self.path).and_then(|conn| (self.init)(&conn).map(|_| conn)) {
+            Ok(conn) => Ok(Pooled {
+                pool: self,
+                conn: Some(conn),
+            }),
+            Err(e) => {
+                self.state.lock().unwrap().open -= 1;
+                self.returned.notify_one();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A connection checked out of a [`Pool`].
+pub struct Pooled<'a> {
+    pool: &'a Pool,
+    conn: Option<Connection>,
+}
+
+impl Deref for Pooled<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Pooled<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for Pooled<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.state.lock().unwrap().idle.push(conn);
+            self.pool.returned.notify_one();
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::archive::{self, ArchiveIndex};
+use crate::server::autoindex::render_listing;
+use crate::server::http::{find_node, request_path, NOT_FOUND_PAGE};
+use crate::server::storage::DirectoryEntry;
+use crate::Node;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// An archive being previewed: its index stays in memory and chunks are
+/// read from the file per request.
+struct Preview {
+    path: PathBuf,
+    index: ArchiveIndex,
+}
+
+/// A router serving the files of a `.webpub` archive, for previewing a
+/// build without a data directory or sync server. Paths resolve as on the
+/// server: directories serve their `index.html`, or a listing without one,
+/// and missing paths get the archive's `404.html`. HEAD is answered like
+/// GET without a body.
+pub fn archive_router(archive_path: &std::path::Path) -> archive::Result<Router> {
+    let preview = Preview {
+        path: archive_path.to_path_buf(),
+        index: archive::read_index(archive_path)?,
+    };
+    Ok(Router::new()
+        .route("/", get(handle_request))
+        .route("/*path", get(handle_request))
+        .with_state(Arc::new(preview)))
+}
+
+async fn handle_request(State(preview): State<Arc<Preview>>, uri: Uri) -> Response {
+    let path_str = match request_path(&uri) {
+        Ok(path) => path,
+        Err(reason) => return (StatusCode::BAD_REQUEST, reason).into_response(),
+    };
+
+    let (name, chunks) = match find_node(&preview.index.tree, &path_str) {
+        Some(Node::File { name, chunks, .. }) => (name, chunks),
+        Some(Node::Directory { children, .. }) => {
+            match children.iter().find(|c| c.name() == "index.html") {
+                Some(Node::File { name, chunks, .. }) => (name, chunks),
+                _ => return directory_listing(&path_str, children),
+            }
+        }
+        None => return not_found(&preview),
+    };
+
+    let mime = mime_guess::from_path(name).first_or_octet_stream();
+    file_response(&preview, StatusCode::OK, mime.as_ref(), chunks)
+}
+
+/// Reassemble a file from the archive's chunks.
+fn file_response(
+    preview: &Preview,
+    status: StatusCode,
+    content_type: &str,
+    chunks: &[[u8; 32]],
+) -> Response {
+    match archive::read_file_data(&preview.path, &preview.index, chunks) {
+        Ok(data) => Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, data.len())
+            .body(Body::from(data))
+            .unwrap(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// An HTML listing of a directory without an index.html.
+fn directory_listing(path: &str, children: &[Node]) -> Response {
+    let mut entries: Vec<DirectoryEntry> = children
+        .iter()
+        .map(|child| DirectoryEntry {
+            name: child.name().to_string(),
+            is_dir: matches!(child, Node::Directory { .. }),
+            size: match child {
+                Node::File { size, .. } => *size,
+                Node::Directory { .. } => 0,
+            },
+        })
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(render_listing(path, &entries)))
+        .unwrap()
+}
+
+/// Respond 404 with the archive's `404.html`, or plain text if it has none.
+fn not_found(preview: &Preview) -> Response {
+    match find_node(&preview.index.tree, NOT_FOUND_PAGE) {
+        Some(Node::File { chunks, .. }) => {
+            file_response(preview, StatusCode::NOT_FOUND, "text/html", chunks)
+        }
+        _ => (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
+}
@@ -0,0 +1,55 @@
+use axum::http::{header, HeaderMap};
+
+/// Path prefix of snapshot preview links, e.g. `/_preview/42-<signature>/`.
+pub const PREVIEW_PREFIX: &str = "/_preview/";
+
+/// Path under the prefix that leaves preview mode.
+pub const PREVIEW_EXIT: &str = "exit";
+
+/// Cookie holding the preview key while browsing a snapshot.
+pub const PREVIEW_COOKIE: &str = "webpub_preview";
+
+/// The key identifying a snapshot in its preview link: the snapshot ID and
+/// a signature over the hostname and ID, so links can't be guessed or
+/// enumerated from snapshot IDs.
+pub fn preview_key(secret: &[u8; 32], hostname: &str, snapshot_id: i64) -> String {
+    format!(
+        "{}-{}",
+        snapshot_id,
+        signature(secret, hostname, snapshot_id).to_hex()
+    )
+}
+
+/// The path of a snapshot's preview link.
+pub fn preview_path(secret: &[u8; 32], hostname: &str, snapshot_id: i64) -> String {
+    format!(
+        "{}{}/",
+        PREVIEW_PREFIX,
+        preview_key(secret, hostname, snapshot_id)
+    )
+}
+
+/// The snapshot ID in a preview key, if it was signed for `hostname`.
+pub fn verify_preview_key(secret: &[u8; 32], hostname: &str, key: &str) -> Option<i64> {
+    let (id, signature_hex) = key.split_once('-')?;
+    let snapshot_id = id.parse().ok()?;
+    let given = blake3::Hash::from_hex(signature_hex).ok()?;
+    // Hash equality is constant time
+    (given == signature(secret, hostname, snapshot_id)).then_some(snapshot_id)
+}
+
+/// The value of a request cookie.
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn signature(secret: &[u8; 32], hostname: &str, snapshot_id: i64) -> blake3::Hash {
+    blake3::keyed_hash(secret, format!("{}\0{}", hostname, snapshot_id).as_bytes())
+}
@@ -0,0 +1,94 @@
+use std::ops::Range;
+
+/// The part of one chunk that falls inside a requested byte range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkSlice {
+    /// Index of the chunk in the file's chunk list
+    pub index: usize,
+    /// Byte range within the chunk
+    pub range: Range<usize>,
+}
+
+/// Map a half-open byte range of a file onto its chunks, given the size of
+/// each chunk in order. Chunks entirely outside the range are left out and
+/// the first and last chunks are trimmed to the range. The range is clamped
+/// to the end of the file; an empty range yields no slices.
+pub fn chunk_slices(chunk_sizes: &[u64], range: Range<u64>) -> Vec<ChunkSlice> {
+    let mut slices = Vec::new();
+    let mut chunk_start = 0u64;
+
+    for (index, &size) in chunk_sizes.iter().enumerate() {
+        let chunk_end = chunk_start + size;
+        if chunk_start >= range.end {
+            break;
+        }
+
+        let start = range.start.max(chunk_start);
+        let end = range.end.min(chunk_end);
+        if start < end {
+            slices.push(ChunkSlice {
+                index,
+                range: (start - chunk_start) as usize..(end - chunk_start) as usize,
+            });
+        }
+
+        chunk_start = chunk_end;
+    }
+
+    slices
+}
+
+/// What to do with a request's `Range` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No usable range (absent, malformed, or multi-range): send the whole file
+    Full,
+    /// Send this half-open byte range with 206 Partial Content
+    Partial(Range<u64>),
+    /// The range lies outside the file: 416 Range Not Satisfiable
+    Unsatisfiable,
+}
+
+/// Interpret a `Range` header value for a file of `len` bytes. Only single
+/// byte ranges are supported; anything else falls back to the full file.
+pub fn parse_range(header: &str, len: u64) -> RangeRequest {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // Suffix range: the last `end` bytes
+        return match end.parse::<u64>() {
+            Ok(0) => RangeRequest::Unsatisfiable,
+            Ok(_) if len == 0 => RangeRequest::Unsatisfiable,
+            Ok(suffix) => RangeRequest::Partial(len.saturating_sub(suffix)..len),
+            Err(_) => RangeRequest::Full,
+        };
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return RangeRequest::Full;
+    };
+    // The end is inclusive in the header
+    let end = if end.is_empty() {
+        len
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => end.saturating_add(1).min(len),
+            _ => return RangeRequest::Full,
+        }
+    };
+
+    if start >= len {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(start..end)
+    }
+}
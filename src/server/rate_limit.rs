@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Default number of failed sync authentications allowed per IP per minute.
+pub const DEFAULT_AUTH_FAILURES_PER_MINUTE: u32 = 5;
+
+/// Limits failed authentications per client IP with a token bucket: each
+/// failure takes a token, and tokens refill evenly over `period`. An IP with
+/// no tokens left is refused until one refills.
+pub struct AuthLimiter {
+    capacity: u32,
+    period: Duration,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl AuthLimiter {
+    /// Allow `max_failures` failures per `period` from each IP; zero
+    /// disables the limit.
+    pub fn new(max_failures: u32, period: Duration) -> Self {
+        AuthLimiter {
+            capacity: max_failures,
+            period,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Allow `max_failures` failures per minute from each IP.
+    pub fn per_minute(max_failures: u32) -> Self {
+        Self::new(max_failures, Duration::from_secs(60))
+    }
+
+    /// Whether `ip` may attempt to authenticate. Otherwise, how long until
+    /// it may try again.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let buckets = self.buckets.lock().unwrap();
+        match buckets.get(&ip) {
+            Some(bucket) => {
+                let tokens = self.refilled(bucket, now);
+                if tokens >= 1.0 {
+                    Ok(())
+                } else {
+                    Err(self.period.mul_f64((1.0 - tokens) / self.capacity as f64))
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Count a failed authentication from `ip`.
+    pub fn record_failure(&self, ip: IpAddr) {
+        if self.capacity == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        // Forget IPs whose buckets have refilled, so the map stays small
+        buckets.retain(|_, bucket| self.refilled(bucket, now) < self.capacity as f64);
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.capacity as f64,
+            updated: now,
+        });
+        bucket.tokens = (self.refilled(bucket, now) - 1.0).max(0.0);
+        bucket.updated = now;
+    }
+
+    /// Tokens in a bucket once refilled up to `now`.
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        let rate = self.capacity as f64 / self.period.as_secs_f64();
+        (bucket.tokens + elapsed * rate).min(self.capacity as f64)
+    }
+}
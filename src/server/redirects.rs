@@ -0,0 +1,148 @@
+use axum::http::StatusCode;
+
+/// Netlify-style redirect rules, read from the root of the deployed snapshot.
+pub const REDIRECTS_FILE: &str = "_redirects";
+
+/// One segment of a path pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Placeholder(String),
+}
+
+/// A path pattern as used by `_redirects` and `_headers`: literal segments,
+/// `:name` placeholder segments matching any one segment, and an optional
+/// trailing `*` splat matching the rest of the path. Trailing slashes don't
+/// matter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPattern {
+    segments: Vec<Segment>,
+    splat: bool,
+}
+
+impl PathPattern {
+    pub fn parse(pattern: &str) -> Self {
+        let mut parts: Vec<&str> = split_path(pattern).collect();
+        let splat = parts.last() == Some(&"*");
+        if splat {
+            parts.pop();
+        }
+        let segments = parts
+            .into_iter()
+            .map(|part| match part.strip_prefix(':') {
+                Some(name) => Segment::Placeholder(name.to_string()),
+                None => Segment::Literal(part.to_string()),
+            })
+            .collect();
+        PathPattern { segments, splat }
+    }
+
+    /// The placeholder values of a matching path, with the splat as
+    /// `splat`, or None if the path doesn't match.
+    pub fn captures<'a>(&'a self, path: &str) -> Option<Vec<(&'a str, String)>> {
+        let parts: Vec<&str> = split_path(path).collect();
+        if parts.len() < self.segments.len() || (!self.splat && parts.len() != self.segments.len())
+        {
+            return None;
+        }
+
+        let mut values = Vec::new();
+        for (segment, part) in self.segments.iter().zip(&parts) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Literal(_) => return None,
+                Segment::Placeholder(name) => values.push((name.as_str(), part.to_string())),
+            }
+        }
+        values.push(("splat", parts[self.segments.len()..].join("/")));
+        Some(values)
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        self.captures(path).is_some()
+    }
+}
+
+/// A `from to [status]` line of a `_redirects` file. `to` may use the
+/// placeholders of `from` as `:name` and its splat as `:splat`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectRule {
+    from: PathPattern,
+    to: String,
+    status: StatusCode,
+}
+
+impl RedirectRule {
+    /// Parse one rule line. A `!` after the status is accepted and ignored,
+    /// since rules are always applied before looking up files.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (from, to, status) = match fields[..] {
+            [from, to] => (from, to, StatusCode::MOVED_PERMANENTLY),
+            [from, to, status] => {
+                let status = status
+                    .trim_end_matches('!')
+                    .parse::<u16>()
+                    .ok()
+                    .and_then(|code| StatusCode::from_u16(code).ok())
+                    .filter(|code| [301, 302, 303, 307, 308].contains(&code.as_u16()))
+                    .ok_or_else(|| format!("unsupported status {}", status))?;
+                (from, to, status)
+            }
+            _ => return Err("expected `from to [status]`".to_string()),
+        };
+        if !from.starts_with('/') {
+            return Err(format!("source {} must start with /", from));
+        }
+
+        Ok(RedirectRule {
+            from: PathPattern::parse(from),
+            to: to.to_string(),
+            status,
+        })
+    }
+
+    /// The redirect target for a request path, if the rule matches it.
+    pub fn apply(&self, path: &str) -> Option<String> {
+        let mut values = self.from.captures(path)?;
+
+        // Longest names first, so `:id` doesn't replace the start of `:identifier`
+        values.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        let mut to = self.to.clone();
+        for (name, value) in values {
+            to = to.replace(&format!(":{}", name), &value);
+        }
+        Some(to)
+    }
+}
+
+/// Non-empty segments of a path, so trailing slashes don't matter.
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|part| !part.is_empty())
+}
+
+/// Parse a `_redirects` file. Blank lines and `#` comments are skipped, as
+/// are invalid lines, with a warning.
+pub fn parse_redirects(text: &str) -> Vec<RedirectRule> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(number, line)| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            RedirectRule::parse(line)
+                .map_err(|e| {
+                    eprintln!("Ignoring {} line {}: {}", REDIRECTS_FILE, number + 1, e);
+                })
+                .ok()
+        })
+        .collect()
+}
+
+/// The first rule matching a path, as its target and status.
+pub fn find_redirect(rules: &[RedirectRule], path: &str) -> Option<(String, StatusCode)> {
+    rules
+        .iter()
+        .find_map(|rule| rule.apply(path).map(|to| (to, rule.status)))
+}
@@ -0,0 +1,140 @@
+use crate::server::cache::CacheRule;
+use crate::server::cors::CorsConfig;
+use crate::server::csp::NonceTemplate;
+use crate::server::headers::{parse_headers, HeaderRule, HEADERS_FILE};
+use crate::server::redirects::{parse_redirects, RedirectRule, REDIRECTS_FILE};
+use crate::server::storage::{Result, SnapshotEntry, Storage};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Per-site configuration file, read from the root of the deployed snapshot.
+pub const CONFIG_FILE: &str = "webpub.json";
+
+const DEFAULT_CSP_POLICY: &str =
+    "script-src 'nonce-{nonce}'; style-src 'nonce-{nonce}'; object-src 'none'; base-uri 'self'";
+
+/// Per-site settings from `webpub.json`. Missing fields use defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SiteConfig {
+    /// Inject a per-request nonce into the CSP header and HTML script/style tags
+    pub csp_nonce: bool,
+    /// CSP header value; `{nonce}` is replaced by the request nonce
+    pub csp_policy: Option<String>,
+    /// Guess the content type of files with no useful extension from their
+    /// first bytes, instead of serving them as application/octet-stream
+    pub sniff_mime: bool,
+    /// Serve the root index.html for missing paths without a file extension,
+    /// so single-page apps can route on the client
+    pub spa_fallback: bool,
+    /// Serve `/about` from `/about.html` or `/about/index.html` when it
+    /// doesn't exist itself
+    pub clean_urls: bool,
+    /// List the contents of directories that have no index.html instead of
+    /// returning 404
+    pub autoindex: bool,
+    /// Cache-Control rules by path glob, checked before the default rules
+    pub cache_control: Vec<CacheRule>,
+    /// Cross-origin access to the site's files; none when unset
+    pub cors: Option<CorsConfig>,
+}
+
+impl SiteConfig {
+    /// The Content-Security-Policy header value for a given nonce.
+    pub fn csp_header(&self, nonce: &str) -> String {
+        self.csp_policy
+            .as_deref()
+            .unwrap_or(DEFAULT_CSP_POLICY)
+            .replace("{nonce}", nonce)
+    }
+}
+
+/// A site's current snapshot, loaded once and shared between requests.
+/// Files are looked up in storage by path rather than through the tree.
+pub struct Site {
+    pub snapshot_id: i64,
+    pub config: SiteConfig,
+    /// Rules from the site's `_redirects` file, in order
+    pub redirects: Vec<RedirectRule>,
+    /// Rules from the site's `_headers` file, in order
+    pub headers: Vec<HeaderRule>,
+    nonce_templates: Mutex<HashMap<[u8; 32], Arc<NonceTemplate>>>,
+}
+
+impl Site {
+    /// Load a site from a snapshot, reading its configuration, redirects and headers.
+    pub fn load(storage: &Storage, snapshot_id: i64) -> Result<Self> {
+        let config = match read_root_file(storage, snapshot_id, CONFIG_FILE)? {
+            Some(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                eprintln!("Ignoring invalid {}: {}", CONFIG_FILE, e);
+                SiteConfig::default()
+            }),
+            None => SiteConfig::default(),
+        };
+        let redirects = match read_root_file(storage, snapshot_id, REDIRECTS_FILE)? {
+            Some(data) => parse_redirects(&String::from_utf8_lossy(&data)),
+            None => Vec::new(),
+        };
+        let headers = match read_root_file(storage, snapshot_id, HEADERS_FILE)? {
+            Some(data) => parse_headers(&String::from_utf8_lossy(&data)),
+            None => Vec::new(),
+        };
+
+        Ok(Site {
+            snapshot_id,
+            config,
+            redirects,
+            headers,
+            nonce_templates: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Get the parsed nonce template for an HTML file, parsing it on first use.
+    pub fn nonce_template(&self, hash: &[u8; 32], html: &[u8]) -> Arc<NonceTemplate> {
+        let mut templates = self.nonce_templates.lock().unwrap();
+        templates
+            .entry(*hash)
+            .or_insert_with(|| Arc::new(NonceTemplate::parse(html)))
+            .clone()
+    }
+}
+
+/// Contents of a file at the root of a snapshot, if it exists and all its
+/// chunks are present.
+fn read_root_file(storage: &Storage, snapshot_id: i64, name: &str) -> Result<Option<Vec<u8>>> {
+    match storage.lookup_path(snapshot_id, name)? {
+        Some(SnapshotEntry::File { chunks, .. }) => storage.read_file(&chunks),
+        _ => Ok(None),
+    }
+}
+
+/// Loaded sites keyed by hostname, refreshed when the current snapshot changes.
+#[derive(Default)]
+pub struct SiteCache {
+    sites: Mutex<HashMap<String, Arc<Site>>>,
+}
+
+impl SiteCache {
+    /// Get the current site for a hostname, loading it if the snapshot changed.
+    pub fn get(&self, storage: &Storage, hostname: &str) -> Result<Option<Arc<Site>>> {
+        let Some(snapshot_id) = storage.get_current_snapshot_id(hostname)? else {
+            self.sites.lock().unwrap().remove(hostname);
+            return Ok(None);
+        };
+
+        if let Some(site) = self.sites.lock().unwrap().get(hostname) {
+            if site.snapshot_id == snapshot_id {
+                return Ok(Some(site.clone()));
+            }
+        }
+
+        let site = Arc::new(Site::load(storage, snapshot_id)?);
+        self.sites
+            .lock()
+            .unwrap()
+            .insert(hostname.to_string(), site.clone());
+
+        Ok(Some(site))
+    }
+}
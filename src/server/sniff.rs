@@ -0,0 +1,69 @@
+/// Guess a content type from the first bytes of a file, for files whose
+/// extension says nothing about their type. `complete` is true when
+/// `sample` is the whole file rather than a prefix of it.
+///
+/// Returns None if the content isn't recognized.
+pub fn sniff_content_type(sample: &[u8], complete: bool) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x00asm", "application/wasm"),
+    ];
+    for (magic, content_type) in SIGNATURES {
+        if sample.starts_with(magic) {
+            return Some(content_type);
+        }
+    }
+    if sample.len() >= 12 && &sample[..4] == b"RIFF" && &sample[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    let text = sample.strip_prefix(b"\xef\xbb\xbf").unwrap_or(sample);
+    let trimmed = text.trim_ascii_start();
+    let lower = trimmed[..trimmed.len().min(16)].to_ascii_lowercase();
+    const HTML_TAGS: &[&[u8]] = &[
+        b"<!doctype html",
+        b"<html",
+        b"<head",
+        b"<body",
+        b"<script",
+        b"<!--",
+    ];
+    if HTML_TAGS.iter().any(|tag| lower.starts_with(tag)) {
+        return Some("text/html");
+    }
+    if lower.starts_with(b"<svg") {
+        return Some("image/svg+xml");
+    }
+    if lower.starts_with(b"<?xml") {
+        return Some("application/xml");
+    }
+
+    if !is_text(text, complete) {
+        return None;
+    }
+    if trimmed.starts_with(b"{") || trimmed.starts_with(b"[") {
+        match serde_json::from_slice::<serde::de::IgnoredAny>(trimmed) {
+            Ok(_) => return Some("application/json"),
+            Err(e) if e.is_eof() && !complete => return Some("application/json"),
+            Err(_) => {}
+        }
+    }
+    Some("text/plain")
+}
+
+/// Whether a sample is UTF-8 text without binary control characters. A
+/// truncated sample may end in the middle of a multi-byte character.
+fn is_text(sample: &[u8], complete: bool) -> bool {
+    let valid = match std::str::from_utf8(sample) {
+        Ok(_) => true,
+        Err(e) => !complete && e.error_len().is_none(),
+    };
+    valid
+        && !sample
+            .iter()
+            .any(|&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c))
+}
@@ -1,10 +1,19 @@
-use std::collections::HashMap;
-use std::fs;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use rusqlite::{params, Connection, OptionalExtension};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, TransactionBehavior};
 
+use crate::archive::{self, ArchiveError};
+use crate::chunker::Chunk;
+use crate::server::pool::{Pool, DEFAULT_POOL_SIZE};
+use crate::server::range::chunk_slices;
+use crate::timestamp::{format_utc, parse_utc};
 use crate::Node;
 
 /// Storage error type
@@ -13,6 +22,15 @@ pub enum StorageError {
     Io(std::io::Error),
     Sqlite(rusqlite::Error),
     Serialization(String),
+    /// Another token already has this label
+    LabelInUse(String),
+    /// A snapshot references these chunks, which aren't stored
+    MissingChunks(Vec<[u8; 32]>),
+    /// A snapshot's file at this path has a size other than the total
+    /// length of its stored chunks
+    SizeMismatch(String),
+    /// An archive being imported or exported couldn't be read or written
+    Archive(ArchiveError),
 }
 
 impl std::fmt::Display for StorageError {
@@ -21,6 +39,12 @@ impl std::fmt::Display for StorageError {
             StorageError::Io(e) => write!(f, "IO error: {}", e),
             StorageError::Sqlite(e) => write!(f, "SQLite error: {}", e),
             StorageError::Serialization(e) => write!(f, "Serialization error: {}", e),
+            StorageError::LabelInUse(label) => write!(f, "Token label already in use: {}", label),
+            StorageError::MissingChunks(hashes) => write!(f, "Missing {} chunks", hashes.len()),
+            StorageError::SizeMismatch(path) => {
+                write!(f, "File size doesn't match its chunks: {}", path)
+            }
+            StorageError::Archive(e) => write!(f, "Archive error: {}", e),
         }
     }
 }
@@ -39,19 +63,241 @@ impl From<rusqlite::Error> for StorageError {
     }
 }
 
+impl From<ArchiveError> for StorageError {
+    fn from(e: ArchiveError) -> Self {
+        StorageError::Archive(e)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, StorageError>;
 
+/// A snapshot as listed: (id, is_current, creation time in seconds since the
+/// Unix epoch, tag)
+pub type SnapshotRow = (i64, bool, i64, Option<String>);
+
+/// Outcome of committing a site's tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Commit {
+    pub snapshot_id: i64,
+    /// The tree was identical to the current snapshot's, which was kept
+    /// instead of adding a new one
+    pub unchanged: bool,
+}
+
+/// Outcome of a garbage collection pass
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub chunks_deleted: u64,
+    pub bytes_freed: u64,
+}
+
+/// A token as listed for auditing. Only a hash of each token is stored, so
+/// the secret itself can't be shown again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    /// First characters of the token, to tell tokens apart
+    pub prefix: String,
+    pub label: Option<String>,
+    pub created_at: String,
+    /// Seconds since the Unix epoch, or None if it never expires
+    pub expires_at: Option<i64>,
+}
+
+/// Characters of a token kept in plaintext for listing.
+const TOKEN_PREFIX_LEN: usize = 8;
+
+/// Most hashes bound in one `IN (...)` query, under SQLite's older limit of
+/// 999 parameters per statement.
+const MAX_QUERY_PARAMS: usize = 500;
+
+/// Bytes of chunk data `import_archive` stores per batch.
+const IMPORT_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// How chunks are spread across database files under `chunks/`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkLayout {
+    /// One database per first hash byte (`00.db` to `ff.db`)
+    Sharded,
+    /// All chunks in a single `chunks.db`, for small stores
+    Single,
+}
+
+impl ChunkLayout {
+    fn as_str(self) -> &'static str {
+        match self {
+            ChunkLayout::Sharded => "sharded",
+            ChunkLayout::Single => "single",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "single" => ChunkLayout::Single,
+            _ => ChunkLayout::Sharded,
+        }
+    }
+
+    /// The shard a chunk lives in
+    fn shard(self, hash: &[u8; 32]) -> u8 {
+        match self {
+            ChunkLayout::Sharded => hash[0],
+            ChunkLayout::Single => 0,
+        }
+    }
+}
+
+/// A file or directory in a snapshot, looked up by path without
+/// deserializing the snapshot's tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotEntry {
+    File {
+        permissions: u32,
+        size: u64,
+        chunks: Vec<[u8; 32]>,
+        hash: [u8; 32],
+    },
+    Directory {
+        permissions: u32,
+    },
+}
+
+/// An entry directly inside a snapshot directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// File size in bytes; 0 for directories
+    pub size: u64,
+}
+
+/// SQLite's `synchronous` setting: how often writes wait for the disk.
+/// In WAL mode `Normal` may lose the last commits on power loss but never
+/// corrupts the database; `Full` survives power loss at some write cost.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    #[default]
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn as_str(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+impl std::str::FromStr for Synchronous {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Synchronous::Off),
+            "normal" => Ok(Synchronous::Normal),
+            "full" => Ok(Synchronous::Full),
+            "extra" => Ok(Synchronous::Extra),
+            _ => Err(format!(
+                "unknown synchronous level '{}' (expected off, normal, full or extra)",
+                s
+            )),
+        }
+    }
+}
+
+/// Tuning for [`Storage::open_with`]
+#[derive(Debug, Clone, Copy)]
+pub struct StorageOptions {
+    /// Connections each chunk database, and index reads, may use at once
+    pub pool_size: usize,
+    /// Durability of writes, for every database
+    pub synchronous: Synchronous,
+    /// Bytes of each database to memory-map for reads; None leaves SQLite's
+    /// default (no mapping)
+    pub mmap_size: Option<u64>,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            pool_size: DEFAULT_POOL_SIZE,
+            synchronous: Synchronous::default(),
+            mmap_size: None,
+        }
+    }
+}
+
+impl StorageOptions {
+    /// Set up a new connection: WAL so readers and a writer don't block
+    /// each other, waiting up to 5s on locks instead of failing with
+    /// "database is locked", and the configured durability and mmap size.
+    fn configure(&self, conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute_batch(&format!(
+            "PRAGMA journal_mode=WAL;
+             PRAGMA busy_timeout=5000;
+             PRAGMA synchronous={};",
+            self.synchronous.as_str()
+        ))?;
+        if let Some(mmap_size) = self.mmap_size {
+            conn.execute_batch(&format!("PRAGMA mmap_size={};", mmap_size))?;
+        }
+        Ok(())
+    }
+}
+
+/// Chunk database connection pools for the current layout, one per shard
+struct ChunkDbs {
+    layout: ChunkLayout,
+    shards: Vec<Pool>,
+}
+
+impl ChunkDbs {
+    fn new(base_path: &Path, layout: ChunkLayout, options: StorageOptions) -> Self {
+        ChunkDbs {
+            layout,
+            shards: (0..=255)
+                .map(|shard| {
+                    Pool::new(
+                        &shard_path(base_path, layout, shard),
+                        options.pool_size,
+                        move |conn| init_chunk_db(conn, &options),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
 /// Server storage with sharded SQLite databases for chunks
 /// and a central index database for sites, snapshots, and tokens.
 pub struct Storage {
     base_path: PathBuf,
+    /// The connection every index write goes through, one at a time
     index: Mutex<Connection>,
-    chunk_dbs: Mutex<HashMap<u8, Connection>>,
+    /// Connections for index reads on the serving path, which WAL lets run
+    /// alongside a write
+    readers: Pool,
+    options: StorageOptions,
+    /// Read-locked by chunk operations, which check out a connection of one
+    /// shard at a time; write-locked only to change the layout
+    chunk_dbs: RwLock<ChunkDbs>,
+    tree_loads: AtomicU64,
+    chunk_reads: AtomicU64,
 }
 
 impl Storage {
     /// Open or create storage at the given path
     pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with(path, StorageOptions::default())
+    }
+
+    /// Open or create storage at the given path with custom tuning
+    pub fn open_with(path: &Path, options: StorageOptions) -> Result<Self> {
         // Create base directory if needed
         fs::create_dir_all(path)?;
 
@@ -61,15 +307,12 @@ impl Storage {
 
         // Open/create index database
         let index_path = path.join("index.db");
-        let index = Connection::open(&index_path)?;
+        let mut index = Connection::open(&index_path)?;
+        options.configure(&index)?;
 
-        // Enable WAL mode for better concurrent access from multiple processes
-        // Set busy timeout to wait for locks instead of failing immediately
-        index.execute_batch(
-            "PRAGMA journal_mode=WAL;
-             PRAGMA busy_timeout=5000;
-             PRAGMA synchronous=NORMAL;",
-        )?;
+        let had_chunk_refs = index
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'chunk_refs'")?
+            .exists([])?;
 
         // Initialize index schema
         index.execute_batch(
@@ -94,124 +337,330 @@ impl Storage {
                 token TEXT UNIQUE NOT NULL,
                 created_at TEXT DEFAULT CURRENT_TIMESTAMP
             );
+
+            CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+
+            -- Every node of each snapshot by path, for serving without the tree
+            CREATE TABLE IF NOT EXISTS files (
+                snapshot_id INTEGER NOT NULL REFERENCES snapshots(id),
+                path TEXT NOT NULL,
+                parent TEXT,
+                is_dir INTEGER NOT NULL,
+                permissions INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                hash BLOB NOT NULL,
+                chunks BLOB NOT NULL,
+                PRIMARY KEY (snapshot_id, path)
+            ) WITHOUT ROWID;
+
+            -- Number of snapshots referencing each chunk
+            CREATE TABLE IF NOT EXISTS chunk_refs (
+                hash BLOB PRIMARY KEY,
+                count INTEGER NOT NULL
+            ) WITHOUT ROWID;
             "#,
         )?;
 
+        // Columns added after the initial schema
+        ensure_column(&index, "tokens", "expires_at", "INTEGER")?;
+        ensure_column(&index, "tokens", "label", "TEXT")?;
+        ensure_column(&index, "tokens", "prefix", "TEXT")?;
+        ensure_column(&index, "snapshots", "tree_hash", "BLOB")?;
+        ensure_column(&index, "snapshots", "tag", "TEXT")?;
+        index
+            .execute_batch("CREATE UNIQUE INDEX IF NOT EXISTS idx_tokens_label ON tokens(label)")?;
+        index.execute_batch(
+            "CREATE UNIQUE INDEX IF NOT EXISTS idx_snapshots_tag ON snapshots(site_id, tag)",
+        )?;
+
+        // Tokens stored in plaintext before they were hashed at rest
+        hash_plaintext_tokens(&mut index)?;
+
+        // Snapshots created before the files table existed
+        index_unindexed_snapshots(&mut index)?;
+
+        // Snapshots created before chunks were reference counted
+        if !had_chunk_refs {
+            count_all_chunk_refs(&mut index)?;
+        }
+
+        let layout = index
+            .query_row(
+                "SELECT value FROM settings WHERE key = 'chunk_layout'",
+                [],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .map_or(ChunkLayout::Sharded, |v| ChunkLayout::parse(&v));
+
         Ok(Storage {
             base_path: path.to_path_buf(),
             index: Mutex::new(index),
-            chunk_dbs: Mutex::new(HashMap::new()),
+            readers: Pool::new(&index_path, options.pool_size, move |conn| {
+                options.configure(conn)
+            }),
+            options,
+            chunk_dbs: RwLock::new(ChunkDbs::new(path, layout, options)),
+            tree_loads: AtomicU64::new(0),
+            chunk_reads: AtomicU64::new(0),
         })
     }
 
-    /// Get the chunk database connection for a given hash prefix
-    fn get_chunk_db(&self, prefix: u8) -> Result<()> {
-        let mut dbs = self.chunk_dbs.lock().unwrap();
-        if let std::collections::hash_map::Entry::Vacant(e) = dbs.entry(prefix) {
-            let db_path = self
-                .base_path
-                .join("chunks")
-                .join(format!("{:02x}.db", prefix));
-            let conn = Connection::open(&db_path)?;
-            conn.execute(
-                r#"
-                CREATE TABLE IF NOT EXISTS chunks (
-                    hash BLOB PRIMARY KEY,
-                    data BLOB NOT NULL
-                )
-                "#,
-                [],
-            )?;
-            e.insert(conn);
-        }
-        Ok(())
+    /// Path of a chunk database file
+    fn shard_path(&self, layout: ChunkLayout, shard: u8) -> PathBuf {
+        shard_path(&self.base_path, layout, shard)
+    }
+
+    /// Run `f` on a connection checked out of a shard's pool
+    fn with_shard<T>(
+        &self,
+        dbs: &ChunkDbs,
+        shard: u8,
+        f: impl FnOnce(&mut Connection) -> Result<T>,
+    ) -> Result<T> {
+        let mut conn = dbs.shards[shard as usize].get()?;
+        f(&mut conn)
+    }
+
+    /// Run `f` on the chunk database holding a given hash
+    fn with_chunk_db<T>(
+        &self,
+        hash: &[u8; 32],
+        f: impl FnOnce(&Connection) -> Result<T>,
+    ) -> Result<T> {
+        let dbs = self.chunk_dbs.read().unwrap();
+        self.with_shard(&dbs, dbs.layout.shard(hash), |conn| f(conn))
+    }
+
+    /// Shards with an existing database file in the given layout
+    fn existing_shards(&self, layout: ChunkLayout) -> Vec<u8> {
+        let shards = match layout {
+            ChunkLayout::Sharded => 0..=255u8,
+            ChunkLayout::Single => 0..=0u8,
+        };
+        shards
+            .filter(|&shard| self.shard_path(layout, shard).exists())
+            .collect()
     }
 
     /// Store a chunk
     pub fn store_chunk(&self, hash: &[u8; 32], data: &[u8]) -> Result<()> {
-        let prefix = hash[0];
-        self.get_chunk_db(prefix)?;
-
-        let dbs = self.chunk_dbs.lock().unwrap();
-        let conn = dbs.get(&prefix).unwrap();
+        self.with_chunk_db(hash, |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO chunks (hash, data) VALUES (?1, ?2)",
+                params![hash.as_slice(), data],
+            )?;
+            Ok(())
+        })
+    }
 
-        conn.execute(
-            "INSERT OR REPLACE INTO chunks (hash, data) VALUES (?1, ?2)",
-            params![hash.as_slice(), data],
-        )?;
+    /// Store several chunks, in one transaction per chunk database
+    pub fn store_chunks(&self, chunks: &[([u8; 32], Vec<u8>)]) -> Result<()> {
+        let dbs = self.chunk_dbs.read().unwrap();
+        let mut by_shard: BTreeMap<u8, Vec<_>> = BTreeMap::new();
+        for chunk in chunks {
+            by_shard
+                .entry(dbs.layout.shard(&chunk.0))
+                .or_default()
+                .push(chunk);
+        }
 
+        for (shard, chunks) in by_shard {
+            self.with_shard(&dbs, shard, |conn| {
+                let tx = conn.transaction()?;
+                {
+                    let mut stmt = tx.prepare_cached(
+                        "INSERT OR REPLACE INTO chunks (hash, data) VALUES (?1, ?2)",
+                    )?;
+                    for (hash, data) in chunks {
+                        stmt.execute(params![hash.as_slice(), data])?;
+                    }
+                }
+                tx.commit()?;
+                Ok(())
+            })?;
+        }
         Ok(())
     }
 
     /// Get a chunk by hash
     pub fn get_chunk(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>> {
-        let prefix = hash[0];
-        self.get_chunk_db(prefix)?;
+        self.chunk_reads.fetch_add(1, Ordering::Relaxed);
+        self.with_chunk_db(hash, |conn| {
+            let result: Option<Vec<u8>> = conn
+                .query_row(
+                    "SELECT data FROM chunks WHERE hash = ?1",
+                    params![hash.as_slice()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(result)
+        })
+    }
+
+    /// Reassemble file contents from its chunks.
+    /// Returns None if any chunk is missing.
+    pub fn read_file(&self, chunks: &[[u8; 32]]) -> Result<Option<Vec<u8>>> {
+        let mut data = Vec::new();
+        for hash in chunks {
+            match self.get_chunk(hash)? {
+                Some(chunk_data) => data.extend(chunk_data),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(data))
+    }
 
-        let dbs = self.chunk_dbs.lock().unwrap();
-        let conn = dbs.get(&prefix).unwrap();
+    /// Get the size of a chunk without reading its data
+    pub fn chunk_size(&self, hash: &[u8; 32]) -> Result<Option<u64>> {
+        self.with_chunk_db(hash, |conn| {
+            let size: Option<i64> = conn
+                .query_row(
+                    "SELECT length(data) FROM chunks WHERE hash = ?1",
+                    params![hash.as_slice()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(size.map(|s| s as u64))
+        })
+    }
 
-        let result: Option<Vec<u8>> = conn
-            .query_row(
-                "SELECT data FROM chunks WHERE hash = ?1",
-                params![hash.as_slice()],
-                |row| row.get(0),
-            )
-            .optional()?;
+    /// Reassemble a byte range of a file, reading only the chunks that
+    /// overlap it. Returns None if any chunk is missing.
+    pub fn read_range(&self, chunks: &[[u8; 32]], range: Range<u64>) -> Result<Option<Vec<u8>>> {
+        let mut sizes = Vec::with_capacity(chunks.len());
+        for hash in chunks {
+            match self.chunk_size(hash)? {
+                Some(size) => sizes.push(size),
+                None => return Ok(None),
+            }
+        }
 
-        Ok(result)
+        let mut data = Vec::new();
+        for slice in chunk_slices(&sizes, range) {
+            match self.get_chunk(&chunks[slice.index])? {
+                Some(chunk_data) => data.extend_from_slice(&chunk_data[slice.range]),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(data))
     }
 
-    /// Check which chunks from a list exist in storage
+    /// Check which chunks from a list exist in storage, in input order
     pub fn has_chunks(&self, hashes: &[[u8; 32]]) -> Result<Vec<[u8; 32]>> {
-        let mut found = Vec::new();
+        let stored = self.chunk_sizes(hashes)?;
+
+        // Input order, including any repeated hashes
+        Ok(hashes
+            .iter()
+            .filter(|hash| stored.contains_key(*hash))
+            .copied()
+            .collect())
+    }
 
-        // Check each hash in order to maintain input order
+    /// Sizes of the chunks from a list that exist in storage, without
+    /// reading their data
+    pub fn chunk_sizes(&self, hashes: &[[u8; 32]]) -> Result<HashMap<[u8; 32], u64>> {
+        let dbs = self.chunk_dbs.read().unwrap();
+        let mut by_shard: BTreeMap<u8, Vec<&[u8; 32]>> = BTreeMap::new();
         for hash in hashes {
-            let prefix = hash[0];
-            self.get_chunk_db(prefix)?;
-
-            let dbs = self.chunk_dbs.lock().unwrap();
-            let conn = dbs.get(&prefix).unwrap();
-
-            let exists: bool = conn
-                .query_row(
-                    "SELECT 1 FROM chunks WHERE hash = ?1",
-                    params![hash.as_slice()],
-                    |_| Ok(true),
-                )
-                .optional()?
-                .unwrap_or(false);
+            by_shard
+                .entry(dbs.layout.shard(hash))
+                .or_default()
+                .push(hash);
+        }
 
-            if exists {
-                found.push(*hash);
-            }
+        // One query per shard, or per batch of a large shard
+        let mut sizes = HashMap::new();
+        for (shard, hashes) in by_shard {
+            self.with_shard(&dbs, shard, |conn| {
+                for batch in hashes.chunks(MAX_QUERY_PARAMS) {
+                    let placeholders = vec!["?"; batch.len()].join(", ");
+                    let mut stmt = conn.prepare_cached(&format!(
+                        "SELECT hash, length(data) FROM chunks WHERE hash IN ({})",
+                        placeholders
+                    ))?;
+                    let rows = stmt.query_map(
+                        params_from_iter(batch.iter().map(|hash| hash.as_slice())),
+                        |row| {
+                            let hash = blob_hash(0, &row.get::<_, Vec<u8>>(0)?)?;
+                            Ok((hash, row.get::<_, i64>(1)? as u64))
+                        },
+                    )?;
+                    for row in rows {
+                        let (hash, size) = row?;
+                        sizes.insert(hash, size);
+                    }
+                }
+                Ok(())
+            })?;
         }
+        Ok(sizes)
+    }
 
-        Ok(found)
+    /// Free space in bytes available to storage on its filesystem
+    pub fn available_space(&self) -> Result<u64> {
+        Ok(fs2::available_space(&self.base_path)?)
     }
 
-    /// Generate and add a new token
+    /// Generate and add a new token, expiring per the default TTL policy
     pub fn add_token(&self) -> Result<String> {
+        let ttl = self.default_token_ttl()?;
+        self.add_token_with_ttl(ttl)
+    }
+
+    /// Generate and add a new token that expires after `ttl`, or never if None
+    pub fn add_token_with_ttl(&self, ttl: Option<Duration>) -> Result<String> {
+        self.insert_token(None, ttl)
+    }
+
+    /// Generate and add a new token with a unique label identifying its
+    /// holder, expiring after `ttl` or never if None
+    pub fn add_labeled_token(&self, label: &str, ttl: Option<Duration>) -> Result<String> {
+        self.insert_token(Some(label), ttl)
+    }
+
+    fn insert_token(&self, label: Option<&str>, ttl: Option<Duration>) -> Result<String> {
         use rand::Rng;
 
         let mut rng = rand::thread_rng();
         let bytes: [u8; 32] = rng.gen();
         let token = hex::encode(bytes);
 
+        let expires_at = ttl.map(|ttl| unix_now() + ttl.as_secs() as i64);
+
         let index = self.index.lock().unwrap();
-        index.execute("INSERT INTO tokens (token) VALUES (?1)", params![&token])?;
+        if let Some(label) = label {
+            let taken = index
+                .prepare("SELECT 1 FROM tokens WHERE label = ?1")?
+                .exists(params![label])?;
+            if taken {
+                return Err(StorageError::LabelInUse(label.to_string()));
+            }
+        }
+        index.execute(
+            "INSERT INTO tokens (token, prefix, label, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                hash_token(&token),
+                &token[..TOKEN_PREFIX_LEN],
+                label,
+                expires_at
+            ],
+        )?;
 
         Ok(token)
     }
 
-    /// Verify if a token is valid
+    /// Verify if a token is valid and unexpired
     pub fn verify_token(&self, token: &str) -> Result<bool> {
-        let index = self.index.lock().unwrap();
+        let index = self.readers.get()?;
         let exists: bool = index
             .query_row(
-                "SELECT 1 FROM tokens WHERE token = ?1",
-                params![token],
+                "SELECT 1 FROM tokens WHERE token = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+                params![hash_token(token), unix_now()],
                 |_| Ok(true),
             )
             .optional()?
@@ -220,19 +669,113 @@ impl Storage {
         Ok(exists)
     }
 
-    /// Revoke a token
-    pub fn revoke_token(&self, token: &str) -> Result<()> {
+    /// Get a token's expiry as seconds since the Unix epoch (None if it never expires)
+    pub fn token_expires_at(&self, token: &str) -> Result<Option<i64>> {
+        let index = self.readers.get()?;
+        let expires_at: Option<Option<i64>> = index
+            .query_row(
+                "SELECT expires_at FROM tokens WHERE token = ?1",
+                params![hash_token(token)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(expires_at.flatten())
+    }
+
+    /// Get the TTL applied to new tokens by default (None means never expire)
+    pub fn default_token_ttl(&self) -> Result<Option<Duration>> {
+        let value = self.get_setting("default_token_ttl")?;
+        Ok(value
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(Duration::from_secs))
+    }
+
+    /// Set the TTL applied to new tokens by default (None means never expire).
+    /// Existing tokens keep their expiry.
+    pub fn set_default_token_ttl(&self, ttl: Option<Duration>) -> Result<()> {
+        match ttl {
+            Some(ttl) => self.set_setting("default_token_ttl", Some(&ttl.as_secs().to_string())),
+            None => self.set_setting("default_token_ttl", None),
+        }
+    }
+
+    /// Secret key that snapshot preview links are signed with, generated
+    /// on first use and kept in the index
+    pub fn preview_secret(&self) -> Result<[u8; 32]> {
+        use rand::Rng;
+
+        let value = match self.get_setting("preview_secret")? {
+            Some(value) => value,
+            None => {
+                // Another caller may have generated one first; theirs wins
+                let index = self.index.lock().unwrap();
+                let secret: [u8; 32] = rand::thread_rng().gen();
+                index.execute(
+                    "INSERT OR IGNORE INTO settings (key, value) VALUES ('preview_secret', ?1)",
+                    params![hex::encode(secret)],
+                )?;
+                index.query_row(
+                    "SELECT value FROM settings WHERE key = 'preview_secret'",
+                    [],
+                    |row| row.get(0),
+                )?
+            }
+        };
+        hex::decode(&value)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| StorageError::Serialization("invalid preview secret".to_string()))
+    }
+
+    fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        let index = self.readers.get()?;
+        let value = index
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    fn set_setting(&self, key: &str, value: Option<&str>) -> Result<()> {
         let index = self.index.lock().unwrap();
-        index.execute("DELETE FROM tokens WHERE token = ?1", params![token])?;
+        match value {
+            Some(value) => index.execute(
+                "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )?,
+            None => index.execute("DELETE FROM settings WHERE key = ?1", params![key])?,
+        };
         Ok(())
     }
 
-    /// List all tokens
-    pub fn list_tokens(&self) -> Result<Vec<String>> {
+    /// Revoke a token, given either the token itself or its label.
+    /// Returns false if no token matched.
+    pub fn revoke_token(&self, token_or_label: &str) -> Result<bool> {
         let index = self.index.lock().unwrap();
-        let mut stmt = index.prepare("SELECT token FROM tokens")?;
-        let tokens: Vec<String> = stmt
-            .query_map([], |row| row.get(0))?
+        let deleted = index.execute(
+            "DELETE FROM tokens WHERE token = ?1 OR label = ?2",
+            params![hash_token(token_or_label), token_or_label],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// List all tokens, oldest first
+    pub fn list_tokens(&self) -> Result<Vec<TokenInfo>> {
+        let index = self.readers.get()?;
+        let mut stmt = index
+            .prepare("SELECT prefix, label, created_at, expires_at FROM tokens ORDER BY id")?;
+        let tokens = stmt
+            .query_map([], |row| {
+                Ok(TokenInfo {
+                    prefix: row.get(0)?,
+                    label: row.get(1)?,
+                    created_at: row.get(2)?,
+                    expires_at: row.get(3)?,
+                })
+            })?
             .collect::<std::result::Result<Vec<_>, _>>()?;
         Ok(tokens)
     }
@@ -263,34 +806,198 @@ impl Storage {
         Ok(index.last_insert_rowid())
     }
 
-    /// Create a new snapshot for a site
+    /// Create a new snapshot for a site. A tree identical to the current
+    /// snapshot's, going by the root hash, adds nothing and returns the
+    /// current snapshot's ID.
     pub fn create_snapshot(&self, hostname: &str, tree: &Node) -> Result<i64> {
-        let site_id = self.get_or_create_site(hostname)?;
+        Ok(self.insert_snapshots(&[(hostname, tree)], false)?[0].snapshot_id)
+    }
 
-        // Serialize tree
-        let tree_data =
-            rmp_serde::to_vec(tree).map_err(|e| StorageError::Serialization(e.to_string()))?;
+    /// Import a `.webpub` archive as a new snapshot of a site. Its chunks
+    /// are read in file order, checked against their hashes and stored,
+    /// then the tree is committed like a pushed one with
+    /// [`Storage::commit_snapshot`]. Chunks stored before a failed commit
+    /// are left for `gc`.
+    pub fn import_archive(&self, archive_path: &Path, hostname: &str) -> Result<Commit> {
+        let index = archive::read_index(archive_path)?;
+        let mut entries: Vec<_> = index.chunks.iter().collect();
+        entries.sort_by_key(|(_, entry)| entry.offset);
+
+        let mut reader = BufReader::new(File::open(archive_path)?);
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        for (hash, entry) in entries {
+            let data = archive::read_chunk(&mut reader, entry)?;
+            if blake3::hash(&data).as_bytes() != hash {
+                return Err(ArchiveError::CorruptChunk(*hash).into());
+            }
+            batch_bytes += data.len();
+            batch.push((*hash, data));
+            if batch_bytes >= IMPORT_BATCH_BYTES {
+                self.store_chunks(&batch)?;
+                batch.clear();
+                batch_bytes = 0;
+            }
+        }
+        self.store_chunks(&batch)?;
 
-        let index = self.index.lock().unwrap();
+        self.commit_snapshot(hostname, &index.tree)
+    }
 
-        // Unset current for all existing snapshots of this site
-        index.execute(
-            "UPDATE snapshots SET is_current = 0 WHERE site_id = ?1",
-            params![site_id],
-        )?;
+    /// Write a site's current snapshot to a `.webpub` archive at `output`,
+    /// with every chunk it references. Returns the exported snapshot's ID,
+    /// or None if the site has no current snapshot. Fails with
+    /// [`StorageError::MissingChunks`] if a chunk isn't stored.
+    pub fn export_archive(&self, hostname: &str, output: &Path) -> Result<Option<i64>> {
+        let Some((snapshot_id, tree)) = self.get_current_snapshot(hostname)? else {
+            return Ok(None);
+        };
 
-        // Insert new snapshot as current
-        index.execute(
-            "INSERT INTO snapshots (site_id, tree_data, is_current) VALUES (?1, ?2, 1)",
-            params![site_id, tree_data],
-        )?;
+        let mut hashes = HashSet::new();
+        collect_chunks(&tree, &mut hashes);
+        let mut hashes: Vec<_> = hashes.into_iter().collect();
+        hashes.sort();
 
-        Ok(index.last_insert_rowid())
+        let mut chunks = Vec::with_capacity(hashes.len());
+        let mut missing = Vec::new();
+        for hash in hashes {
+            match self.get_chunk(&hash)? {
+                Some(data) => chunks.push(Chunk { hash, data }),
+                None => missing.push(hash),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(StorageError::MissingChunks(missing));
+        }
+
+        archive::write_archive(output, &tree, &chunks)?;
+        Ok(Some(snapshot_id))
+    }
+
+    /// Create a snapshot like [`Storage::create_snapshot`], first checking
+    /// that every chunk it references is stored and that each file's size
+    /// is the total length of its chunks. The check and the reference
+    /// counting happen under the index's write lock, which chunk deletion
+    /// and [`Storage::gc`] also take, even from another process, so neither
+    /// can remove a chunk in between.
+    /// Fails with [`StorageError::MissingChunks`] or
+    /// [`StorageError::SizeMismatch`] otherwise.
+    pub fn commit_snapshot(&self, hostname: &str, tree: &Node) -> Result<Commit> {
+        Ok(self.commit_snapshots(&[(hostname, tree)])?[0])
+    }
+
+    /// Commit a snapshot for each of several sites, checked like
+    /// [`Storage::commit_snapshot`], in one transaction: either all of them
+    /// become current or, if any check fails, none. Returns the commits in
+    /// input order.
+    pub fn commit_snapshots(&self, trees: &[(&str, &Node)]) -> Result<Vec<Commit>> {
+        self.insert_snapshots(trees, true)
+    }
+
+    fn insert_snapshots(&self, trees: &[(&str, &Node)], check_chunks: bool) -> Result<Vec<Commit>> {
+        let mut sites = Vec::with_capacity(trees.len());
+        for (hostname, tree) in trees {
+            let site_id = self.get_or_create_site(hostname)?;
+            let tree_data =
+                rmp_serde::to_vec(tree).map_err(|e| StorageError::Serialization(e.to_string()))?;
+            sites.push((site_id, tree_data));
+        }
+
+        let mut index = self.index.lock().unwrap();
+        let tx = index.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if check_chunks {
+            let mut chunks = HashSet::new();
+            for (_, tree) in trees {
+                collect_chunks(tree, &mut chunks);
+            }
+            let chunks: Vec<[u8; 32]> = chunks.into_iter().collect();
+            let sizes = self.chunk_sizes(&chunks)?;
+            let missing: Vec<[u8; 32]> = chunks
+                .into_iter()
+                .filter(|hash| !sizes.contains_key(hash))
+                .collect();
+            if !missing.is_empty() {
+                return Err(StorageError::MissingChunks(missing));
+            }
+            for (_, tree) in trees {
+                check_file_sizes(tree, "", &sizes)?;
+            }
+        }
+
+        let mut commits = Vec::with_capacity(trees.len());
+        for ((site_id, tree_data), (_, tree)) in sites.iter().zip(trees) {
+            // Redeploying the current tree keeps the current snapshot
+            let current: Option<i64> = tx
+                .query_row(
+                    "SELECT id FROM snapshots WHERE site_id = ?1 AND is_current = 1 AND tree_hash = ?2",
+                    params![site_id, tree.hash().as_slice()],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if let Some(snapshot_id) = current {
+                commits.push(Commit {
+                    snapshot_id,
+                    unchanged: true,
+                });
+                continue;
+            }
+
+            // Unset current for all existing snapshots of this site
+            tx.execute(
+                "UPDATE snapshots SET is_current = 0 WHERE site_id = ?1",
+                params![site_id],
+            )?;
+
+            // Insert new snapshot as current
+            tx.execute(
+                r#"
+                INSERT INTO snapshots (site_id, tree_data, tree_hash, created_at, is_current)
+                VALUES (?1, ?2, ?3, ?4, 1)
+                "#,
+                params![
+                    site_id,
+                    tree_data,
+                    tree.hash().as_slice(),
+                    format_utc(unix_now())
+                ],
+            )?;
+            let snapshot_id = tx.last_insert_rowid();
+
+            index_snapshot(&tx, snapshot_id, tree)?;
+            add_chunk_refs(&tx, tree, 1)?;
+            commits.push(Commit {
+                snapshot_id,
+                unchanged: false,
+            });
+        }
+        tx.commit()?;
+
+        Ok(commits)
+    }
+
+    /// Get the ID of the current snapshot for a site
+    pub fn get_current_snapshot_id(&self, hostname: &str) -> Result<Option<i64>> {
+        let index = self.readers.get()?;
+
+        let id: Option<i64> = index
+            .query_row(
+                r#"
+                SELECT s.id
+                FROM snapshots s
+                JOIN sites si ON s.site_id = si.id
+                WHERE si.hostname = ?1 AND s.is_current = 1
+                "#,
+                params![hostname],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(id)
     }
 
     /// Get the current snapshot for a site
     pub fn get_current_snapshot(&self, hostname: &str) -> Result<Option<(i64, Node)>> {
-        let index = self.index.lock().unwrap();
+        let index = self.readers.get()?;
 
         let result: Option<(i64, Vec<u8>)> = index
             .query_row(
@@ -306,22 +1013,170 @@ impl Storage {
             .optional()?;
 
         match result {
-            Some((id, tree_data)) => {
-                let tree: Node = rmp_serde::from_slice(&tree_data)
-                    .map_err(|e| StorageError::Serialization(e.to_string()))?;
-                Ok(Some((id, tree)))
-            }
+            Some((id, tree_data)) => Ok(Some((id, self.decode_tree(&tree_data)?))),
             None => Ok(None),
         }
     }
 
-    /// List all snapshots for a site
-    pub fn list_snapshots(&self, hostname: &str) -> Result<Vec<(i64, bool, String)>> {
-        let index = self.index.lock().unwrap();
+    /// Get one of a site's snapshots by ID; None if the site has no such
+    /// snapshot
+    pub fn get_snapshot(&self, hostname: &str, snapshot_id: i64) -> Result<Option<Node>> {
+        let index = self.readers.get()?;
+
+        let tree_data: Option<Vec<u8>> = index
+            .query_row(
+                r#"
+                SELECT s.tree_data
+                FROM snapshots s
+                JOIN sites si ON s.site_id = si.id
+                WHERE si.hostname = ?1 AND s.id = ?2
+                "#,
+                params![hostname, snapshot_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        tree_data.map(|data| self.decode_tree(&data)).transpose()
+    }
+
+    /// Whether a site has a snapshot with this ID, without loading its tree
+    pub fn has_snapshot(&self, hostname: &str, snapshot_id: i64) -> Result<bool> {
+        let index = self.readers.get()?;
+
+        let found: Option<i64> = index
+            .query_row(
+                r#"
+                SELECT s.id
+                FROM snapshots s
+                JOIN sites si ON s.site_id = si.id
+                WHERE si.hostname = ?1 AND s.id = ?2
+                "#,
+                params![hostname, snapshot_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Deserialize a snapshot tree, counting it in [`Storage::tree_loads`]
+    fn decode_tree(&self, tree_data: &[u8]) -> Result<Node> {
+        self.tree_loads.fetch_add(1, Ordering::Relaxed);
+        decode_tree(tree_data)
+    }
+
+    /// Number of snapshot trees deserialized since storage was opened
+    pub fn tree_loads(&self) -> u64 {
+        self.tree_loads.load(Ordering::Relaxed)
+    }
+
+    /// Number of chunks read since storage was opened
+    pub fn chunk_reads(&self) -> u64 {
+        self.chunk_reads.load(Ordering::Relaxed)
+    }
+
+    /// Look up a file or directory in a snapshot by path, e.g.
+    /// `/css/style.css`. The empty path and `/` are the root directory.
+    pub fn lookup_path(&self, snapshot_id: i64, path: &str) -> Result<Option<SnapshotEntry>> {
+        let index = self.readers.get()?;
+
+        let entry = index
+            .query_row(
+                r#"
+                SELECT is_dir, permissions, size, hash, chunks
+                FROM files
+                WHERE snapshot_id = ?1 AND path = ?2
+                "#,
+                params![snapshot_id, normalize_path(path)],
+                |row| {
+                    let permissions = row.get(1)?;
+                    if row.get(0)? {
+                        return Ok(SnapshotEntry::Directory { permissions });
+                    }
+                    let hash: Vec<u8> = row.get(3)?;
+                    let chunks: Vec<u8> = row.get(4)?;
+                    Ok(SnapshotEntry::File {
+                        permissions,
+                        size: row.get::<_, i64>(2)? as u64,
+                        chunks: chunks
+                            .chunks(32)
+                            .map(|c| blob_hash(4, c))
+                            .collect::<rusqlite::Result<_>>()?,
+                        hash: blob_hash(3, &hash)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(entry)
+    }
+
+    /// The entries directly inside a snapshot directory, sorted by name
+    pub fn list_directory(&self, snapshot_id: i64, path: &str) -> Result<Vec<DirectoryEntry>> {
+        let index = self.readers.get()?;
+        let dir = normalize_path(path);
+
+        let mut stmt = index.prepare(
+            r#"
+            SELECT path, is_dir, size FROM files
+            WHERE snapshot_id = ?1 AND parent = ?2
+            ORDER BY path
+            "#,
+        )?;
+        let entries = stmt
+            .query_map(params![snapshot_id, dir], |row| {
+                let path: String = row.get(0)?;
+                Ok(DirectoryEntry {
+                    name: path.rsplit('/').next().unwrap_or(&path).to_string(),
+                    is_dir: row.get(1)?,
+                    size: row.get::<_, i64>(2)? as u64,
+                })
+            })?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        Ok(entries)
+    }
+
+    /// Number of sites with a current snapshot being served.
+    pub fn count_sites(&self) -> Result<u64> {
+        let index = self.readers.get()?;
+        let count: i64 = index.query_row(
+            "SELECT COUNT(DISTINCT site_id) FROM snapshots WHERE is_current = 1",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count as u64)
+    }
+
+    /// All sites by hostname, each with its number of snapshots and the ID
+    /// of its current one
+    pub fn list_sites(&self) -> Result<Vec<(String, u64, Option<i64>)>> {
+        let index = self.readers.get()?;
+
+        let mut stmt = index.prepare(
+            r#"
+            SELECT si.hostname, COUNT(s.id), MAX(CASE WHEN s.is_current = 1 THEN s.id END)
+            FROM sites si
+            LEFT JOIN snapshots s ON s.site_id = si.id
+            GROUP BY si.id
+            ORDER BY si.hostname
+            "#,
+        )?;
+        let sites = stmt
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get::<_, i64>(1)? as u64, row.get(2)?))
+            })?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        Ok(sites)
+    }
+
+    /// List all snapshots for a site, newest first
+    pub fn list_snapshots(&self, hostname: &str) -> Result<Vec<SnapshotRow>> {
+        let index = self.readers.get()?;
 
         let mut stmt = index.prepare(
             r#"
-            SELECT s.id, s.is_current, s.created_at
+            SELECT s.id, s.is_current, s.created_at, s.tag
             FROM snapshots s
             JOIN sites si ON s.site_id = si.id
             WHERE si.hostname = ?1
@@ -329,15 +1184,79 @@ impl Storage {
             "#,
         )?;
 
-        let snapshots: Vec<(i64, bool, String)> = stmt
+        // Rows before explicit timestamps hold SQLite's CURRENT_TIMESTAMP,
+        // which parse_utc reads too
+        let snapshots: Vec<SnapshotRow> = stmt
             .query_map(params![hostname], |row| {
-                Ok((row.get(0)?, row.get::<_, i32>(1)? != 0, row.get(2)?))
+                let created_at: Option<String> = row.get(2)?;
+                Ok((
+                    row.get(0)?,
+                    row.get::<_, i32>(1)? != 0,
+                    created_at.as_deref().and_then(parse_utc).unwrap_or(0),
+                    row.get(3)?,
+                ))
             })?
             .collect::<std::result::Result<Vec<_>, _>>()?;
 
         Ok(snapshots)
     }
 
+    /// Tag a snapshot of a site. A tag names one snapshot per site, so it is
+    /// moved off any snapshot that had it. Returns false if the site has no
+    /// such snapshot.
+    pub fn tag_snapshot(&self, hostname: &str, snapshot_id: i64, tag: &str) -> Result<bool> {
+        let mut index = self.index.lock().unwrap();
+        let tx = index.transaction()?;
+
+        let site_id: Option<i64> = tx
+            .query_row(
+                r#"
+                SELECT s.site_id
+                FROM snapshots s
+                JOIN sites si ON s.site_id = si.id
+                WHERE si.hostname = ?1 AND s.id = ?2
+                "#,
+                params![hostname, snapshot_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(site_id) = site_id else {
+            return Ok(false);
+        };
+
+        tx.execute(
+            "UPDATE snapshots SET tag = NULL WHERE site_id = ?1 AND tag = ?2",
+            params![site_id, tag],
+        )?;
+        tx.execute(
+            "UPDATE snapshots SET tag = ?1 WHERE id = ?2",
+            params![tag, snapshot_id],
+        )?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    /// The ID of a site's snapshot with the given tag
+    pub fn find_tagged_snapshot(&self, hostname: &str, tag: &str) -> Result<Option<i64>> {
+        let index = self.readers.get()?;
+
+        let id: Option<i64> = index
+            .query_row(
+                r#"
+                SELECT s.id
+                FROM snapshots s
+                JOIN sites si ON s.site_id = si.id
+                WHERE si.hostname = ?1 AND s.tag = ?2
+                "#,
+                params![hostname, tag],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(id)
+    }
+
     /// Set a specific snapshot as current
     pub fn set_current_snapshot(&self, hostname: &str, snapshot_id: i64) -> Result<bool> {
         let index = self.index.lock().unwrap();
@@ -383,4 +1302,686 @@ impl Storage {
 
         Ok(true)
     }
+
+    /// Delete a snapshot. The current snapshot of a site is never deleted.
+    /// Chunks no other snapshot references are deleted with it.
+    /// Returns true if the snapshot was deleted.
+    pub fn delete_snapshot(&self, snapshot_id: i64) -> Result<bool> {
+        let mut index = self.index.lock().unwrap();
+        let tx = index.transaction()?;
+        let mut orphaned = Vec::new();
+        let deleted = delete_snapshot_tx(&tx, snapshot_id, &mut orphaned)?;
+        tx.commit()?;
+        self.delete_chunks(&mut index, &orphaned)?;
+        Ok(deleted)
+    }
+
+    /// Delete a site and all of its snapshots, current one included, in one
+    /// transaction. Chunks no other snapshot references are freed. Returns
+    /// false if there's no such site.
+    pub fn delete_site(&self, hostname: &str) -> Result<bool> {
+        let mut index = self.index.lock().unwrap();
+        let tx = index.transaction()?;
+
+        let site_id: Option<i64> = tx
+            .query_row(
+                "SELECT id FROM sites WHERE hostname = ?1",
+                params![hostname],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(site_id) = site_id else {
+            return Ok(false);
+        };
+
+        let snapshot_ids: Vec<i64> = {
+            let mut stmt = tx.prepare("SELECT id FROM snapshots WHERE site_id = ?1")?;
+            let ids = stmt
+                .query_map(params![site_id], |row| row.get(0))?
+                .collect::<std::result::Result<Vec<i64>, _>>()?;
+            ids
+        };
+        tx.execute(
+            "UPDATE snapshots SET is_current = 0 WHERE site_id = ?1",
+            params![site_id],
+        )?;
+        let mut orphaned = Vec::new();
+        for id in snapshot_ids {
+            delete_snapshot_tx(&tx, id, &mut orphaned)?;
+        }
+        tx.execute("DELETE FROM sites WHERE id = ?1", params![site_id])?;
+        tx.commit()?;
+        self.delete_chunks(&mut index, &orphaned)?;
+
+        Ok(true)
+    }
+
+    /// Delete a site's snapshots beyond the `keep` most recent, in one
+    /// transaction. The current snapshot is kept even if it's older.
+    /// Returns the IDs of deleted snapshots.
+    pub fn prune_snapshots(&self, hostname: &str, keep: usize) -> Result<Vec<i64>> {
+        let mut index = self.index.lock().unwrap();
+        let tx = index.transaction()?;
+
+        let old: Vec<i64> = {
+            let mut stmt = tx.prepare(
+                r#"
+                SELECT s.id
+                FROM snapshots s
+                JOIN sites si ON s.site_id = si.id
+                WHERE si.hostname = ?1
+                ORDER BY s.id DESC
+                "#,
+            )?;
+            let ids = stmt
+                .query_map(params![hostname], |row| row.get(0))?
+                .collect::<std::result::Result<Vec<i64>, _>>()?;
+            ids.into_iter().skip(keep).collect()
+        };
+
+        let mut deleted = Vec::new();
+        let mut orphaned = Vec::new();
+        for id in old {
+            if delete_snapshot_tx(&tx, id, &mut orphaned)? {
+                deleted.push(id);
+            }
+        }
+        tx.commit()?;
+        self.delete_chunks(&mut index, &orphaned)?;
+
+        Ok(deleted)
+    }
+
+    /// Find sites whose snapshots violate the single-current-snapshot invariant.
+    /// Returns (hostname, number of current snapshots) for each broken site.
+    pub fn find_broken_current(&self) -> Result<Vec<(String, usize)>> {
+        let index = self.index.lock().unwrap();
+
+        let mut stmt = index.prepare(
+            r#"
+            SELECT si.hostname, SUM(s.is_current != 0)
+            FROM sites si
+            JOIN snapshots s ON s.site_id = si.id
+            GROUP BY si.id
+            HAVING SUM(s.is_current != 0) != 1
+            ORDER BY si.hostname
+            "#,
+        )?;
+
+        let broken: Vec<(String, usize)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        Ok(broken)
+    }
+
+    /// Restore the invariant that a site has exactly one current snapshot.
+    /// If the site has zero or several current snapshots, the highest-id
+    /// snapshot becomes current and all others are unset.
+    /// Returns true if a repair was made.
+    pub fn repair_current(&self, hostname: &str) -> Result<bool> {
+        let mut index = self.index.lock().unwrap();
+        let tx = index.transaction()?;
+
+        let state: Option<(i64, i64, i64)> = tx
+            .query_row(
+                r#"
+                SELECT si.id, SUM(s.is_current != 0), MAX(s.id)
+                FROM sites si
+                JOIN snapshots s ON s.site_id = si.id
+                WHERE si.hostname = ?1
+                GROUP BY si.id
+                "#,
+                params![hostname],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        let Some((site_id, current_count, latest_id)) = state else {
+            return Ok(false);
+        };
+
+        if current_count == 1 {
+            return Ok(false);
+        }
+
+        tx.execute(
+            "UPDATE snapshots SET is_current = (id = ?2) WHERE site_id = ?1",
+            params![site_id, latest_id],
+        )?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    /// Collect the hashes of all chunks referenced by any existing snapshot
+    fn referenced_chunks(&self) -> Result<HashSet<[u8; 32]>> {
+        let index = self.index.lock().unwrap();
+        let mut stmt = index.prepare("SELECT hash FROM chunk_refs WHERE count > 0")?;
+        let referenced = stmt
+            .query_map([], |row| blob_hash(0, &row.get::<_, Vec<u8>>(0)?))?
+            .collect::<std::result::Result<HashSet<_>, _>>()?;
+        Ok(referenced)
+    }
+
+    /// Number of snapshots referencing a chunk
+    pub fn chunk_refs(&self, hash: &[u8; 32]) -> Result<u64> {
+        let index = self.readers.get()?;
+        let count: Option<i64> = index
+            .query_row(
+                "SELECT count FROM chunk_refs WHERE hash = ?1",
+                params![hash.as_slice()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(count.unwrap_or(0) as u64)
+    }
+
+    /// Delete chunks no snapshot references any more, in one transaction
+    /// per chunk database. References are checked again under the index's
+    /// write lock, held until the chunks are gone, so a commit from another
+    /// process can't claim one in between.
+    fn delete_chunks(&self, index: &mut Connection, hashes: &[[u8; 32]]) -> Result<()> {
+        let locked = index.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut unreferenced = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            if !is_referenced(&locked, hash)? {
+                unreferenced.push(hash);
+            }
+        }
+        let dbs = self.chunk_dbs.read().unwrap();
+        let mut by_shard: BTreeMap<u8, Vec<_>> = BTreeMap::new();
+        for hash in unreferenced {
+            by_shard
+                .entry(dbs.layout.shard(hash))
+                .or_default()
+                .push(hash);
+        }
+
+        for (shard, hashes) in by_shard {
+            self.with_shard(&dbs, shard, |conn| {
+                let tx = conn.transaction()?;
+                {
+                    let mut stmt = tx.prepare_cached("DELETE FROM chunks WHERE hash = ?1")?;
+                    for hash in hashes {
+                        stmt.execute(params![hash.as_slice()])?;
+                    }
+                }
+                tx.commit()?;
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Delete chunks not referenced by any snapshot, then compact the
+    /// affected shard files. Chunks uploaded for a deploy that hasn't been
+    /// committed yet are unreferenced too, so run this between deploys; a
+    /// deploy that loses chunks this way uploads them again at commit.
+    /// Deleting snapshots already removes the chunks they alone referenced;
+    /// this catches uploads that were never committed.
+    ///
+    /// Safe to run from another process while a server commits: each
+    /// shard's candidates are checked again and deleted under the index's
+    /// write lock, which commits hold from their chunk check until their
+    /// references are recorded.
+    pub fn gc(&self) -> Result<GcStats> {
+        let referenced = self.referenced_chunks()?;
+        let mut stats = GcStats::default();
+
+        // Lock order is always index, then chunk databases. A relayout
+        // moving chunks between files ends the pass early; the next gc
+        // collects what is left.
+        let layout = self.chunk_layout();
+        for shard in self.existing_shards(layout) {
+            let candidates: Vec<(Vec<u8>, i64)> = {
+                let dbs = self.chunk_dbs.read().unwrap();
+                if dbs.layout != layout {
+                    break;
+                }
+                self.with_shard(&dbs, shard, |conn| {
+                    let mut stmt = conn.prepare("SELECT hash, length(data) FROM chunks")?;
+                    let rows = stmt
+                        .query_map([], |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get(1)?)))?
+                        .collect::<std::result::Result<Vec<_>, _>>()?;
+                    Ok(rows
+                        .into_iter()
+                        .filter(|(hash, _)| {
+                            <[u8; 32]>::try_from(hash.as_slice())
+                                .map_or(true, |hash| !referenced.contains(&hash))
+                        })
+                        .collect())
+                })?
+            };
+            if candidates.is_empty() {
+                continue;
+            }
+
+            // A commit since `referenced` was read may have claimed some
+            let mut index = self.index.lock().unwrap();
+            let locked = index.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let dbs = self.chunk_dbs.read().unwrap();
+            if dbs.layout != layout {
+                break;
+            }
+            let mut unreferenced = Vec::with_capacity(candidates.len());
+            for (hash, size) in candidates {
+                if !is_referenced(&locked, &hash)? {
+                    unreferenced.push((hash, size));
+                }
+            }
+            self.with_shard(&dbs, shard, |conn| {
+                let tx = conn.transaction()?;
+                for (hash, size) in &unreferenced {
+                    tx.execute("DELETE FROM chunks WHERE hash = ?1", params![hash])?;
+                    stats.chunks_deleted += 1;
+                    stats.bytes_freed += *size as u64;
+                }
+                tx.commit()?;
+                Ok(())
+            })?;
+            drop(locked);
+            drop(index);
+
+            // Return freed pages to the filesystem
+            if !unreferenced.is_empty() {
+                self.with_shard(&dbs, shard, |conn| Ok(conn.execute_batch("VACUUM")?))?;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// The current chunk layout
+    pub fn chunk_layout(&self) -> ChunkLayout {
+        self.chunk_dbs.read().unwrap().layout
+    }
+
+    /// Total number of stored chunks
+    pub fn chunk_count(&self) -> Result<u64> {
+        let dbs = self.chunk_dbs.read().unwrap();
+        let mut count = 0;
+        for shard in self.existing_shards(dbs.layout) {
+            count += self.with_shard(&dbs, shard, |conn| {
+                Ok(conn.query_row("SELECT COUNT(*) FROM chunks", [], |row| {
+                    row.get::<_, i64>(0)
+                })? as u64)
+            })?;
+        }
+        Ok(count)
+    }
+
+    /// Merge all shard files into a single database if the store holds at
+    /// most `threshold` chunks. Returns true if the layout changed.
+    pub fn compact(&self, threshold: u64) -> Result<bool> {
+        if self.chunk_layout() != ChunkLayout::Sharded || self.chunk_count()? > threshold {
+            return Ok(false);
+        }
+        self.relayout(ChunkLayout::Single)?;
+        Ok(true)
+    }
+
+    /// Split a compacted store back into one database per hash prefix,
+    /// for when it has grown. Returns true if the layout changed.
+    pub fn rebalance(&self) -> Result<bool> {
+        if self.chunk_layout() != ChunkLayout::Single {
+            return Ok(false);
+        }
+        self.relayout(ChunkLayout::Sharded)?;
+        Ok(true)
+    }
+
+    /// Move every chunk into the database files of another layout. Chunks
+    /// are copied and the new layout recorded before old files are removed,
+    /// so an interrupted move loses nothing.
+    fn relayout(&self, to: ChunkLayout) -> Result<()> {
+        // Lock order is always index, then chunk databases
+        let index = self.index.lock().unwrap();
+        let mut dbs = self.chunk_dbs.write().unwrap();
+        let from = dbs.layout;
+        let old_shards = self.existing_shards(from);
+
+        let new_dbs = ChunkDbs::new(&self.base_path, to, self.options);
+        for &shard in &old_shards {
+            let by_shard = self.with_shard(&dbs, shard, |conn| {
+                let mut stmt = conn.prepare("SELECT hash, data FROM chunks")?;
+                let rows = stmt.query_map([], |row| {
+                    Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?))
+                })?;
+
+                let mut by_shard: HashMap<u8, Vec<Chunk>> = HashMap::new();
+                for row in rows {
+                    let (hash, data) = row?;
+                    let Ok(hash) = <[u8; 32]>::try_from(hash.as_slice()) else {
+                        continue;
+                    };
+                    by_shard
+                        .entry(to.shard(&hash))
+                        .or_default()
+                        .push(Chunk { hash, data });
+                }
+                Ok(by_shard)
+            })?;
+
+            for (new_shard, chunks) in by_shard {
+                self.with_shard(&new_dbs, new_shard, |conn| {
+                    let tx = conn.transaction()?;
+                    for chunk in chunks {
+                        tx.execute(
+                            "INSERT OR REPLACE INTO chunks (hash, data) VALUES (?1, ?2)",
+                            params![chunk.hash.as_slice(), chunk.data],
+                        )?;
+                    }
+                    tx.commit()?;
+                    Ok(())
+                })?;
+            }
+        }
+
+        index.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES ('chunk_layout', ?1)",
+            params![to.as_str()],
+        )?;
+        *dbs = new_dbs;
+
+        for shard in old_shards {
+            fs::remove_file(self.shard_path(from, shard))?;
+        }
+        Ok(())
+    }
+}
+
+/// Path of a chunk database file under a storage directory
+fn shard_path(base_path: &Path, layout: ChunkLayout, shard: u8) -> PathBuf {
+    let name = match layout {
+        ChunkLayout::Sharded => format!("{:02x}.db", shard),
+        ChunkLayout::Single => "chunks.db".to_string(),
+    };
+    base_path.join("chunks").join(name)
+}
+
+/// Set up a new chunk database connection, creating the table if needed
+fn init_chunk_db(conn: &Connection, options: &StorageOptions) -> rusqlite::Result<()> {
+    options.configure(conn)?;
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS chunks (
+            hash BLOB PRIMARY KEY,
+            data BLOB NOT NULL
+        );
+        "#,
+    )
+}
+
+/// Add a column to an existing table if it isn't there yet
+fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+            table
+        ))?
+        .exists(params![column])?;
+    if !exists {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, decl
+        ))?;
+    }
+    Ok(())
+}
+
+/// Hex BLAKE3 hash of a token, which is all the index stores of it
+fn hash_token(token: &str) -> String {
+    blake3::hash(token.as_bytes()).to_hex().to_string()
+}
+
+/// Replace plaintext tokens left by older versions with their hashes.
+/// Rows without a prefix are the ones still holding the plaintext.
+fn hash_plaintext_tokens(index: &mut Connection) -> Result<()> {
+    let tx = index.transaction()?;
+    {
+        let plaintext: Vec<(i64, String)> = tx
+            .prepare("SELECT id, token FROM tokens WHERE prefix IS NULL")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<_, _>>()?;
+        let mut update = tx.prepare("UPDATE tokens SET token = ?1, prefix = ?2 WHERE id = ?3")?;
+        for (id, token) in plaintext {
+            let prefix: String = token.chars().take(TOKEN_PREFIX_LEN).collect();
+            update.execute(params![hash_token(&token), prefix, id])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Current time in seconds since the Unix epoch
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Delete a snapshot unless it is current, within an open transaction.
+/// Chunks left with no references are added to `orphaned`.
+fn delete_snapshot_tx(
+    tx: &rusqlite::Transaction,
+    snapshot_id: i64,
+    orphaned: &mut Vec<[u8; 32]>,
+) -> Result<bool> {
+    let tree_data: Option<Vec<u8>> = tx
+        .query_row(
+            "SELECT tree_data FROM snapshots WHERE id = ?1 AND is_current = 0",
+            params![snapshot_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let Some(tree_data) = tree_data else {
+        return Ok(false);
+    };
+
+    tx.execute(
+        "DELETE FROM files WHERE snapshot_id = ?1",
+        params![snapshot_id],
+    )?;
+    tx.execute("DELETE FROM snapshots WHERE id = ?1", params![snapshot_id])?;
+    orphaned.extend(add_chunk_refs(tx, &decode_tree(&tree_data)?, -1)?);
+    Ok(true)
+}
+
+/// Whether any snapshot references a chunk
+fn is_referenced(conn: &Connection, hash: &[u8]) -> Result<bool> {
+    let count: Option<i64> = conn
+        .prepare_cached("SELECT count FROM chunk_refs WHERE hash = ?1")?
+        .query_row(params![hash], |row| row.get(0))
+        .optional()?;
+    Ok(count.unwrap_or(0) > 0)
+}
+
+/// Add `delta` to the reference count of each distinct chunk in a tree.
+/// Counts that drop to zero are removed, and those chunks returned.
+fn add_chunk_refs(conn: &Connection, tree: &Node, delta: i64) -> Result<Vec<[u8; 32]>> {
+    let mut chunks = HashSet::new();
+    collect_chunks(tree, &mut chunks);
+
+    let mut update = conn.prepare_cached(
+        r#"
+        INSERT INTO chunk_refs (hash, count) VALUES (?1, ?2)
+        ON CONFLICT (hash) DO UPDATE SET count = count + excluded.count
+        RETURNING count
+        "#,
+    )?;
+    let mut remove = conn.prepare_cached("DELETE FROM chunk_refs WHERE hash = ?1")?;
+    let mut orphaned = Vec::new();
+    for hash in chunks {
+        let count: i64 = update.query_row(params![hash.as_slice(), delta], |row| row.get(0))?;
+        if count <= 0 {
+            remove.execute(params![hash.as_slice()])?;
+            orphaned.push(hash);
+        }
+    }
+    Ok(orphaned)
+}
+
+/// Rebuild chunk reference counts from every snapshot's tree
+fn count_all_chunk_refs(index: &mut Connection) -> Result<()> {
+    let tx = index.transaction()?;
+    tx.execute("DELETE FROM chunk_refs", [])?;
+    let trees: Vec<Vec<u8>> = {
+        let mut stmt = tx.prepare("SELECT tree_data FROM snapshots")?;
+        let rows = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        rows
+    };
+    for tree_data in trees {
+        add_chunk_refs(&tx, &decode_tree(&tree_data)?, 1)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Convert a hash column value, failing if it isn't 32 bytes
+fn blob_hash(column: usize, bytes: &[u8]) -> rusqlite::Result<[u8; 32]> {
+    bytes.try_into().map_err(|_| {
+        rusqlite::Error::FromSqlConversionFailure(
+            column,
+            rusqlite::types::Type::Blob,
+            format!("expected a 32-byte hash, got {} bytes", bytes.len()).into(),
+        )
+    })
+}
+
+fn decode_tree(tree_data: &[u8]) -> Result<Node> {
+    rmp_serde::from_slice(tree_data).map_err(|e| StorageError::Serialization(e.to_string()))
+}
+
+/// A request path as stored in the files table: no leading, trailing or
+/// repeated slashes, and empty for the root
+fn normalize_path(path: &str) -> String {
+    path.split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Record every node of a snapshot tree in the files table
+fn index_snapshot(conn: &Connection, snapshot_id: i64, tree: &Node) -> Result<()> {
+    let mut stmt = conn.prepare(
+        r#"
+        INSERT OR REPLACE INTO files
+            (snapshot_id, path, parent, is_dir, permissions, size, hash, chunks)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+        "#,
+    )?;
+    index_node(&mut stmt, snapshot_id, tree, "", None)
+}
+
+fn index_node(
+    stmt: &mut rusqlite::Statement,
+    snapshot_id: i64,
+    node: &Node,
+    path: &str,
+    parent: Option<&str>,
+) -> Result<()> {
+    match node {
+        Node::File {
+            permissions,
+            size,
+            chunks,
+            hash,
+            ..
+        } => {
+            stmt.execute(params![
+                snapshot_id,
+                path,
+                parent,
+                false,
+                permissions,
+                *size as i64,
+                hash.as_slice(),
+                chunks.concat(),
+            ])?;
+        }
+        Node::Directory {
+            permissions,
+            children,
+            hash,
+            ..
+        } => {
+            stmt.execute(params![
+                snapshot_id,
+                path,
+                parent,
+                true,
+                permissions,
+                0i64,
+                hash.as_slice(),
+                Vec::<u8>::new(),
+            ])?;
+            for child in children {
+                let child_path = if path.is_empty() {
+                    child.name().to_string()
+                } else {
+                    format!("{}/{}", path, child.name())
+                };
+                index_node(stmt, snapshot_id, child, &child_path, Some(path))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Populate the files table for snapshots that have no entries yet
+fn index_unindexed_snapshots(index: &mut Connection) -> Result<()> {
+    let tx = index.transaction()?;
+    let pending: Vec<(i64, Vec<u8>)> = {
+        let mut stmt = tx.prepare(
+            r#"
+            SELECT id, tree_data FROM snapshots
+            WHERE id NOT IN (SELECT DISTINCT snapshot_id FROM files)
+            "#,
+        )?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        rows
+    };
+    for (snapshot_id, tree_data) in pending {
+        index_snapshot(&tx, snapshot_id, &decode_tree(&tree_data)?)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Check that every file under `node` is as long as its chunks together.
+/// All of the chunks must be in `sizes`.
+fn check_file_sizes(node: &Node, prefix: &str, sizes: &HashMap<[u8; 32], u64>) -> Result<()> {
+    let path = format!("{}/{}", prefix, node.name());
+    match node {
+        Node::File { size, chunks, .. } => {
+            let total: u64 = chunks.iter().map(|hash| sizes[hash]).sum();
+            if total != *size {
+                return Err(StorageError::SizeMismatch(path));
+            }
+        }
+        Node::Directory { children, .. } => {
+            let prefix = path.trim_end_matches('/');
+            for child in children {
+                check_file_sizes(child, prefix, sizes)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn collect_chunks(node: &Node, out: &mut HashSet<[u8; 32]>) {
+    match node {
+        Node::File { chunks, .. } => out.extend(chunks.iter().copied()),
+        Node::Directory { children, .. } => {
+            for child in children {
+                collect_chunks(child, out);
+            }
+        }
+    }
 }
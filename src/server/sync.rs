@@ -1,13 +1,201 @@
-use crate::protocol::{ClientMessage, ServerMessage};
-use crate::server::storage::Storage;
+use crate::merkle;
+use crate::protocol::{
+    self, ClientMessage, ProtocolError, ServerMessage, MAX_MISSING_CHUNKS,
+    MISSING_CHUNKS_PROTOCOL_VERSION, PROTOCOL_VERSION, TAG_PROTOCOL_VERSION,
+    TIMESTAMP_PROTOCOL_VERSION, UNCHANGED_PROTOCOL_VERSION,
+};
+use crate::server::auth::{
+    Authenticator, TokenAuthenticator, SCOPE_ADMIN, SCOPE_DEPLOY, SCOPE_READ,
+};
+use crate::server::http::find_node;
+use crate::server::metrics::Metrics;
+use crate::server::rate_limit::{AuthLimiter, DEFAULT_AUTH_FAILURES_PER_MINUTE};
+use crate::server::storage::{Storage, StorageError};
+use crate::server::tls;
+use crate::timestamp::format_utc;
 use crate::Node;
+use axum_server::tls_rustls::RustlsConfig;
 use futures_util::{SinkExt, StreamExt};
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{accept_async_with_config, WebSocketStream};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
-pub async fn handle_connection(stream: TcpStream, storage: Arc<Storage>, keep: usize) {
-    let ws_stream = match accept_async(stream).await {
+/// Which file permission bits a committed tree may contain.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PermissionPolicy {
+    /// Accept any permissions
+    #[default]
+    Permissive,
+    /// Reject setuid, setgid, and world-writable files and directories
+    Strict,
+}
+
+impl PermissionPolicy {
+    /// Paths in a tree whose permissions the policy forbids.
+    pub fn violations(&self, tree: &Node) -> Vec<String> {
+        let mut paths = Vec::new();
+        if *self == PermissionPolicy::Strict {
+            collect_violations(tree, "", &mut paths);
+        }
+        paths
+    }
+}
+
+/// Setuid, setgid, and world-writable bits
+const DANGEROUS_MODE_BITS: u32 = 0o4000 | 0o2000 | 0o002;
+
+/// Longest snapshot tag accepted
+const MAX_TAG_LEN: usize = 64;
+
+/// Bytes of file contents sent per `FileData` frame.
+const FILE_FRAME_BYTES: usize = 4 * 1024 * 1024;
+
+/// How long a sync connection may go without a client message.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Largest client message accepted, in bytes. Chunk batches are 4 MiB, so
+/// this leaves room for the trees of large sites.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+fn collect_violations(node: &Node, prefix: &str, paths: &mut Vec<String>) {
+    let path = match node.name() {
+        "" => String::new(),
+        name => format!("{}/{}", prefix, name),
+    };
+    if node.permissions() & DANGEROUS_MODE_BITS != 0 {
+        paths.push(if path.is_empty() {
+            "/".to_string()
+        } else {
+            path.clone()
+        });
+    }
+    if let Node::Directory { children, .. } = node {
+        for child in children {
+            collect_violations(child, &path, paths);
+        }
+    }
+}
+
+/// Shared configuration and services for sync connections.
+pub struct SyncState {
+    pub storage: Arc<Storage>,
+    pub authenticator: Arc<dyn Authenticator>,
+    /// Number of snapshots to keep per site
+    pub keep: usize,
+    /// Permission bits allowed in committed trees
+    pub permission_policy: PermissionPolicy,
+    /// Free space in bytes to leave on the data disk; uploads that would
+    /// go below it are rejected
+    pub min_free_space: u64,
+    /// Counters for deploys, shared with the HTTP server
+    pub metrics: Arc<Metrics>,
+    /// Connections sending no message for this long are closed
+    pub idle_timeout: Duration,
+    /// Connections sending a larger message are closed
+    pub max_message_size: usize,
+    /// Failed authentications allowed per client IP
+    pub auth_limiter: AuthLimiter,
+}
+
+/// Check that storing `needed` more bytes leaves at least `min_free` of
+/// `available` free. Returns the reason to reject the deploy if not.
+pub fn check_disk_space(available: u64, needed: u64, min_free: u64) -> Result<(), String> {
+    if available < needed.saturating_add(min_free) {
+        Err(format!(
+            "insufficient disk space: {} bytes needed plus {} reserved, {} available",
+            needed, min_free, available
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+impl SyncState {
+    /// Sync state using storage tokens for authentication.
+    pub fn new(storage: Arc<Storage>, keep: usize) -> Self {
+        SyncState {
+            authenticator: Arc::new(TokenAuthenticator::new(storage.clone())),
+            storage,
+            keep,
+            permission_policy: PermissionPolicy::default(),
+            min_free_space: 0,
+            metrics: Arc::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            auth_limiter: AuthLimiter::per_minute(DEFAULT_AUTH_FAILURES_PER_MINUTE),
+        }
+    }
+}
+
+/// Accept sync connections, over TLS when given a config, until `shutdown`
+/// is cancelled. Then stop accepting and wait up to `grace` for open
+/// sessions to finish, so deploys in flight can commit.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<SyncState>,
+    tls: Option<RustlsConfig>,
+    shutdown: CancellationToken,
+    grace: Duration,
+) {
+    let sessions = TaskTracker::new();
+    loop {
+        let (stream, addr) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Failed to accept sync connection: {}", e);
+                    continue;
+                }
+            },
+        };
+        println!("Sync connection from {}", addr);
+        match &tls {
+            Some(config) => sessions.spawn(handle_tls_connection(
+                stream,
+                tls::acceptor(config),
+                state.clone(),
+            )),
+            None => sessions.spawn(handle_connection(stream, addr.ip(), state.clone())),
+        };
+    }
+    drop(listener);
+
+    sessions.close();
+    if !sessions.is_empty() {
+        println!("Waiting for {} sync sessions to finish", sessions.len());
+    }
+    if tokio::time::timeout(grace, sessions.wait()).await.is_err() {
+        eprintln!(
+            "Closing {} sync sessions still open after {:?}",
+            sessions.len(),
+            grace
+        );
+    }
+}
+
+/// Handle a sync session on a connection from `peer`, plain or already
+/// wrapped in TLS.
+pub async fn handle_connection<S>(stream: S, peer: IpAddr, state: Arc<SyncState>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Clients send each message as a single frame, so both get the same cap
+    let config = WebSocketConfig {
+        max_message_size: Some(state.max_message_size),
+        max_frame_size: Some(state.max_message_size),
+        ..Default::default()
+    };
+    let ws_stream = match accept_async_with_config(stream, Some(config)).await {
         Ok(ws) => ws,
         Err(e) => {
             eprintln!("WebSocket handshake failed: {}", e);
@@ -15,129 +203,531 @@ pub async fn handle_connection(stream: TcpStream, storage: Arc<Storage>, keep: u
         }
     };
 
-    if let Err(e) = handle_sync(ws_stream, storage, keep).await {
+    if let Err(e) = handle_sync(ws_stream, peer, state).await {
         eprintln!("Sync error: {}", e);
     }
 }
 
-async fn handle_sync(
-    mut ws: WebSocketStream<TcpStream>,
-    storage: Arc<Storage>,
-    keep: usize,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// Complete a TLS handshake on a raw connection, then handle it as a
+/// `wss://` sync session.
+pub async fn handle_tls_connection(
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+    state: Arc<SyncState>,
+) {
+    let peer = match stream.peer_addr() {
+        Ok(addr) => addr.ip(),
+        Err(e) => {
+            eprintln!("Failed to read sync peer address: {}", e);
+            return;
+        }
+    };
+    match acceptor.accept(stream).await {
+        Ok(stream) => handle_connection(stream, peer, state).await,
+        Err(e) => eprintln!("TLS handshake failed: {}", e),
+    }
+}
+
+/// The next message from the client, or None once the connection closes.
+/// A client that stays silent for `idle` or sends an oversized message is
+/// told why in a close frame, and the session ends with an error.
+async fn next_message<S>(
+    ws: &mut WebSocketStream<S>,
+    idle: Duration,
+) -> Result<Option<Message>, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (code, reason) = match tokio::time::timeout(idle, ws.next()).await {
+        Ok(None) => return Ok(None),
+        Ok(Some(Err(tungstenite::Error::Capacity(e)))) => (CloseCode::Size, e.to_string()),
+        Ok(Some(msg)) => return Ok(Some(msg?)),
+        Err(_) => (
+            CloseCode::Policy,
+            format!("No message for {} seconds", idle.as_secs()),
+        ),
+    };
+    close(ws, code, &reason).await;
+    Err(reason.into())
+}
+
+/// Tell the client why its session is ending.
+async fn close<S>(ws: &mut WebSocketStream<S>, code: CloseCode, reason: &str)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let frame = CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    };
+    // The client may already be gone; the session ends either way
+    let _ = ws.close(Some(frame)).await;
+}
+
+async fn handle_sync<S>(
+    mut ws: WebSocketStream<S>,
+    peer: IpAddr,
+    state: Arc<SyncState>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let storage = &state.storage;
+
+    // Refuse IPs with too many recent failed authentications
+    if let Err(retry) = state.auth_limiter.check(peer) {
+        let reason = format!(
+            "Too many failed authentications, retry in {} seconds",
+            retry.as_secs() + 1
+        );
+        close(&mut ws, CloseCode::Policy, &reason).await;
+        return Err(format!("{} from {}", reason, peer).into());
+    }
+
     // Wait for auth
-    let msg = ws.next().await.ok_or("Connection closed")??;
+    let msg = next_message(&mut ws, state.idle_timeout)
+        .await?
+        .ok_or("Connection closed")?;
     let client_msg: ClientMessage = match msg {
-        Message::Binary(data) => rmp_serde::from_slice(&data)?,
+        Message::Binary(data) => protocol::decode(&data)?,
         _ => return Err("Expected binary message".into()),
     };
 
-    let token = match client_msg {
-        ClientMessage::Auth { token } => token,
+    let (token, protocol_version) = match client_msg {
+        ClientMessage::Auth {
+            token,
+            protocol_version,
+        } => (token, protocol_version),
         _ => return Err("Expected Auth message".into()),
     };
 
-    if !storage.verify_token(&token)? {
-        let response = rmp_serde::to_vec(&ServerMessage::AuthFailed)?;
-        ws.send(Message::Binary(response)).await?;
-        return Err("Invalid token".into());
+    if !protocol::is_compatible(protocol_version) {
+        send(
+            &mut ws,
+            &ServerMessage::VersionMismatch {
+                server_version: PROTOCOL_VERSION,
+            },
+        )
+        .await?;
+        return Err(format!("unsupported client protocol version {}", protocol_version).into());
     }
 
-    let response = rmp_serde::to_vec(&ServerMessage::AuthOk)?;
-    ws.send(Message::Binary(response)).await?;
+    let auth = match state.authenticator.authenticate(&token).await {
+        Ok(auth) => auth,
+        Err(e) => {
+            state.auth_limiter.record_failure(peer);
+            send(&mut ws, &ServerMessage::AuthFailed).await?;
+            return Err(e.into());
+        }
+    };
+
+    send(&mut ws, &ServerMessage::AuthOk).await?;
+
+    // Trees staged since BeginBatch, by hostname
+    let mut batch: Option<Vec<(String, Node)>> = None;
 
     // Handle sync messages
-    while let Some(msg) = ws.next().await {
-        let msg = msg?;
+    while let Some(msg) = next_message(&mut ws, state.idle_timeout).await? {
         let data = match msg {
             Message::Binary(data) => data,
             Message::Close(_) => break,
             _ => continue,
         };
 
-        let client_msg: ClientMessage = rmp_serde::from_slice(&data)?;
+        let client_msg: ClientMessage = match protocol::decode(&data) {
+            Ok(msg) => msg,
+            Err(e @ ProtocolError::Unsupported { .. }) => {
+                // Reply so the client doesn't wait for an answer that never comes
+                eprintln!("Refusing {}", e);
+                send(
+                    &mut ws,
+                    &ServerMessage::Denied {
+                        reason: e.to_string(),
+                    },
+                )
+                .await?;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if let Some(scope) = required_scope(&client_msg) {
+            if !auth.has_scope(scope) {
+                send(
+                    &mut ws,
+                    &ServerMessage::Denied {
+                        reason: format!("{} scope required", scope),
+                    },
+                )
+                .await?;
+                continue;
+            }
+        }
 
         match client_msg {
-            ClientMessage::HaveChunks { hashes } => {
+            ClientMessage::HaveChunks { hashes, sizes } => {
                 let have = storage.has_chunks(&hashes)?;
-                let need: Vec<[u8; 32]> =
-                    hashes.into_iter().filter(|h| !have.contains(h)).collect();
+                let sized = sizes.len() == hashes.len();
+                let mut need = Vec::new();
+                let mut needed_bytes = 0;
+                for (i, hash) in hashes.into_iter().enumerate() {
+                    if !have.contains(&hash) {
+                        need.push(hash);
+                        needed_bytes += sizes.get(i).copied().unwrap_or(0) as u64;
+                    }
+                }
 
-                let response = rmp_serde::to_vec(&ServerMessage::NeedChunks { hashes: need })?;
-                ws.send(Message::Binary(response)).await?;
+                // Reject before any upload if the chunks won't fit. Clients
+                // before SIZES_PROTOCOL_VERSION don't send sizes, so their
+                // chunks are only checked as they arrive.
+                let fits = match sized {
+                    true => check_disk_space(
+                        storage.available_space()?,
+                        needed_bytes,
+                        state.min_free_space,
+                    ),
+                    false => Ok(()),
+                };
+                if let Err(reason) = fits {
+                    send(
+                        &mut ws,
+                        &ServerMessage::CommitFailed {
+                            reason,
+                            missing: Vec::new(),
+                        },
+                    )
+                    .await?;
+                    continue;
+                }
+
+                send(&mut ws, &ServerMessage::NeedChunks { hashes: need }).await?;
             }
             ClientMessage::ChunkData { hash, data } => {
+                if let Err(reason) = verify_chunk(&hash, &data) {
+                    send(&mut ws, &ServerMessage::ChunkRejected { hash, reason }).await?;
+                    continue;
+                }
+                if let Err(reason) = check_disk_space(
+                    storage.available_space()?,
+                    data.len() as u64,
+                    state.min_free_space,
+                ) {
+                    send(
+                        &mut ws,
+                        &ServerMessage::CommitFailed {
+                            reason,
+                            missing: Vec::new(),
+                        },
+                    )
+                    .await?;
+                    continue;
+                }
+
                 storage.store_chunk(&hash, &data)?;
 
-                let response = rmp_serde::to_vec(&ServerMessage::ChunkAck { hash })?;
-                ws.send(Message::Binary(response)).await?;
+                send(&mut ws, &ServerMessage::ChunkAck { hash }).await?;
+            }
+            ClientMessage::ChunkBatch { chunks } => {
+                // One bad chunk rejects the whole batch
+                if let Some((hash, reason)) = chunks
+                    .iter()
+                    .find_map(|(hash, data)| verify_chunk(hash, data).err().map(|r| (*hash, r)))
+                {
+                    send(&mut ws, &ServerMessage::ChunkRejected { hash, reason }).await?;
+                    continue;
+                }
+                let size = chunks.iter().map(|(_, data)| data.len() as u64).sum();
+                if let Err(reason) =
+                    check_disk_space(storage.available_space()?, size, state.min_free_space)
+                {
+                    send(
+                        &mut ws,
+                        &ServerMessage::CommitFailed {
+                            reason,
+                            missing: Vec::new(),
+                        },
+                    )
+                    .await?;
+                    continue;
+                }
+
+                storage.store_chunks(&chunks)?;
+
+                let hashes = chunks.into_iter().map(|(hash, _)| hash).collect();
+                send(&mut ws, &ServerMessage::BatchAck { hashes }).await?;
             }
             ClientMessage::CommitTree { hostname, tree } => {
-                // Verify all chunks exist
-                if let Err(missing) = verify_tree_chunks(&tree, &storage) {
-                    let response = rmp_serde::to_vec(&ServerMessage::CommitFailed {
-                        reason: format!("Missing {} chunks", missing),
-                    })?;
-                    ws.send(Message::Binary(response)).await?;
+                let violations = state.permission_policy.violations(&tree);
+                if !violations.is_empty() {
+                    send(
+                        &mut ws,
+                        &ServerMessage::CommitFailed {
+                            reason: format!("Forbidden permissions on: {}", violations.join(", ")),
+                            missing: Vec::new(),
+                        },
+                    )
+                    .await?;
+                    // A batch with a bad tree is dropped whole
+                    batch = None;
                     continue;
                 }
 
-                let snapshot_id = storage.create_snapshot(&hostname, &tree)?;
+                // The server never trusts the hashes a client computed
+                if let Err(e) = merkle::verify_tree(&tree) {
+                    send(
+                        &mut ws,
+                        &ServerMessage::CommitFailed {
+                            reason: format!("Invalid tree: {}", e),
+                            missing: Vec::new(),
+                        },
+                    )
+                    .await?;
+                    // A batch with a bad tree is dropped whole
+                    batch = None;
+                    continue;
+                }
 
-                // Cleanup old snapshots
-                cleanup_old_snapshots(&storage, &hostname, keep)?;
+                // Inside a batch the tree waits for CommitBatch
+                if let Some(staged) = &mut batch {
+                    staged.push((hostname.clone(), tree));
+                    send(&mut ws, &ServerMessage::TreeStaged { hostname }).await?;
+                    continue;
+                }
 
-                let response = rmp_serde::to_vec(&ServerMessage::CommitOk {
-                    snapshot_id: snapshot_id as u64,
-                })?;
-                ws.send(Message::Binary(response)).await?;
+                // Fails if any chunk is missing or a file's size is wrong
+                let commit = match storage.commit_snapshot(&hostname, &tree) {
+                    Ok(commit) => commit,
+                    Err(e) => {
+                        send(&mut ws, &commit_failed(e, protocol_version)?).await?;
+                        continue;
+                    }
+                };
+
+                if commit.unchanged {
+                    println!(
+                        "{} unchanged, keeping snapshot {}",
+                        hostname, commit.snapshot_id
+                    );
+                } else {
+                    state.metrics.record_commit(&hostname);
 
-                println!("Deployed {} snapshot {}", hostname, snapshot_id);
+                    // Cleanup old snapshots
+                    cleanup_old_snapshots(storage, &hostname, state.keep)?;
+
+                    println!("Deployed {} snapshot {}", hostname, commit.snapshot_id);
+                }
+
+                send(
+                    &mut ws,
+                    &ServerMessage::CommitOk {
+                        snapshot_id: commit.snapshot_id as u64,
+                        // Older clients can't decode the flag
+                        unchanged: commit.unchanged
+                            && protocol_version >= UNCHANGED_PROTOCOL_VERSION,
+                    },
+                )
+                .await?;
+            }
+            ClientMessage::BeginBatch => {
+                batch = Some(Vec::new());
+                send(&mut ws, &ServerMessage::BatchStarted).await?;
+            }
+            ClientMessage::CommitBatch => {
+                let Some(staged) = batch.take() else {
+                    send(
+                        &mut ws,
+                        &ServerMessage::CommitFailed {
+                            reason: "No batch to commit".to_string(),
+                            missing: Vec::new(),
+                        },
+                    )
+                    .await?;
+                    continue;
+                };
+
+                // All sites flip to their new snapshots together, or none do
+                let trees: Vec<(&str, &Node)> = staged
+                    .iter()
+                    .map(|(hostname, tree)| (hostname.as_str(), tree))
+                    .collect();
+                let commits = match storage.commit_snapshots(&trees) {
+                    Ok(commits) => commits,
+                    Err(e) => {
+                        send(&mut ws, &commit_failed(e, protocol_version)?).await?;
+                        continue;
+                    }
+                };
+
+                let mut snapshots = Vec::with_capacity(staged.len());
+                for ((hostname, _), commit) in staged.iter().zip(commits) {
+                    if !commit.unchanged {
+                        state.metrics.record_commit(hostname);
+                        cleanup_old_snapshots(storage, hostname, state.keep)?;
+                        println!("Deployed {} snapshot {}", hostname, commit.snapshot_id);
+                    }
+                    snapshots.push((hostname.clone(), commit.snapshot_id as u64));
+                }
+                send(&mut ws, &ServerMessage::BatchCommitted { snapshots }).await?;
+            }
+            ClientMessage::DeleteSite { hostname } => {
+                let reply = if storage.delete_site(&hostname)? {
+                    println!("Deleted site {}", hostname);
+                    ServerMessage::SiteDeleted
+                } else {
+                    ServerMessage::DeleteSiteFailed {
+                        reason: "Site not found".to_string(),
+                    }
+                };
+                send(&mut ws, &reply).await?;
+            }
+            ClientMessage::TagSnapshot {
+                hostname,
+                snapshot_id,
+                tag,
+            } => {
+                let reply = match check_tag(&tag) {
+                    Err(reason) => ServerMessage::TagFailed { reason },
+                    Ok(()) if storage.tag_snapshot(&hostname, snapshot_id as i64, &tag)? => {
+                        println!("Tagged {} snapshot {} as {}", hostname, snapshot_id, tag);
+                        ServerMessage::SnapshotTagged
+                    }
+                    Ok(()) => ServerMessage::TagFailed {
+                        reason: "Snapshot not found".to_string(),
+                    },
+                };
+                send(&mut ws, &reply).await?;
             }
             ClientMessage::ListSnapshots { hostname } => {
                 let snapshots = storage.list_snapshots(&hostname)?;
-                // Convert from (i64, bool, String) to (u64, String, bool)
-                let snapshots: Vec<(u64, String, bool)> = snapshots
+                // Older clients take creation times as strings, and know no tags
+                let reply = if protocol_version >= TIMESTAMP_PROTOCOL_VERSION {
+                    let tags = match protocol_version >= TAG_PROTOCOL_VERSION {
+                        true => snapshots
+                            .iter()
+                            .filter_map(|(id, _, _, tag)| Some((*id as u64, tag.clone()?)))
+                            .collect(),
+                        false => Vec::new(),
+                    };
+                    ServerMessage::Snapshots {
+                        snapshots: snapshots
+                            .into_iter()
+                            .map(|(id, is_current, created_at, _)| {
+                                (id as u64, created_at, is_current)
+                            })
+                            .collect(),
+                        tags,
+                    }
+                } else {
+                    ServerMessage::SnapshotList {
+                        snapshots: snapshots
+                            .into_iter()
+                            .map(|(id, is_current, created_at, _)| {
+                                (id as u64, format_utc(created_at), is_current)
+                            })
+                            .collect(),
+                    }
+                };
+                send(&mut ws, &reply).await?;
+            }
+            ClientMessage::GetFile { hostname, path } => {
+                let chunks = match storage.get_current_snapshot(&hostname)? {
+                    Some((_, tree)) => resolve_file(&tree, &path),
+                    None => Err("Site not found"),
+                };
+                let chunks = match chunks {
+                    Ok(chunks) => chunks,
+                    Err(reason) => {
+                        let reply = ServerMessage::GetFileFailed {
+                            reason: reason.to_string(),
+                        };
+                        send(&mut ws, &reply).await?;
+                        continue;
+                    }
+                };
+
+                // Sent in frames of about FILE_FRAME_BYTES as chunks are read
+                let mut data = Vec::new();
+                let mut reply = None;
+                for hash in &chunks {
+                    let Some(chunk) = storage.get_chunk(hash)? else {
+                        reply = Some(ServerMessage::GetFileFailed {
+                            reason: "Missing chunk".to_string(),
+                        });
+                        break;
+                    };
+                    data.extend_from_slice(&chunk);
+                    if data.len() >= FILE_FRAME_BYTES {
+                        let data = std::mem::take(&mut data);
+                        send(&mut ws, &ServerMessage::FileData { data, done: false }).await?;
+                    }
+                }
+                let reply = reply.unwrap_or(ServerMessage::FileData { data, done: true });
+                send(&mut ws, &reply).await?;
+            }
+            ClientMessage::ListSites => {
+                let sites = storage
+                    .list_sites()?
                     .into_iter()
-                    .map(|(id, is_current, created_at)| (id as u64, created_at, is_current))
+                    .map(|(hostname, count, current)| {
+                        (hostname, count, current.map(|id| id as u64))
+                    })
                     .collect();
-                let response = rmp_serde::to_vec(&ServerMessage::SnapshotList { snapshots })?;
-                ws.send(Message::Binary(response)).await?;
+                send(&mut ws, &ServerMessage::SiteList { sites }).await?;
             }
             ClientMessage::Rollback {
                 hostname,
                 snapshot_id,
+                tag,
             } => {
-                // If no snapshot_id given, use previous (second most recent)
-                let snapshots = storage.list_snapshots(&hostname)?;
-                let target_id = match snapshot_id {
-                    Some(id) => id as i64,
-                    None => {
-                        // Find previous (second in list since list is sorted by id DESC)
-                        if snapshots.len() < 2 {
-                            let response = rmp_serde::to_vec(&ServerMessage::RollbackFailed {
-                                reason: "No previous snapshot to rollback to".to_string(),
-                            })?;
-                            ws.send(Message::Binary(response)).await?;
+                let target_id =
+                    match rollback_target(storage, &hostname, snapshot_id, tag.as_deref())? {
+                        Ok(id) => id,
+                        Err(reason) => {
+                            send(&mut ws, &ServerMessage::RollbackFailed { reason }).await?;
                             continue;
                         }
-                        snapshots[1].0 // Second snapshot (previous)
-                    }
-                };
+                    };
 
                 if storage.set_current_snapshot(&hostname, target_id)? {
-                    let response = rmp_serde::to_vec(&ServerMessage::RollbackOk {
-                        snapshot_id: target_id as u64,
-                    })?;
-                    ws.send(Message::Binary(response)).await?;
+                    send(
+                        &mut ws,
+                        &ServerMessage::RollbackOk {
+                            snapshot_id: target_id as u64,
+                        },
+                    )
+                    .await?;
                     println!("Rolled back {} to snapshot {}", hostname, target_id);
                 } else {
-                    let response = rmp_serde::to_vec(&ServerMessage::RollbackFailed {
-                        reason: "Snapshot not found".to_string(),
-                    })?;
-                    ws.send(Message::Binary(response)).await?;
+                    send(
+                        &mut ws,
+                        &ServerMessage::RollbackFailed {
+                            reason: "Snapshot not found".to_string(),
+                        },
+                    )
+                    .await?;
                 }
             }
+            ClientMessage::GetSnapshotTree {
+                hostname,
+                snapshot_id,
+            } => {
+                let snapshot = match snapshot_id {
+                    Some(id) => storage
+                        .get_snapshot(&hostname, id as i64)?
+                        .map(|tree| (id as i64, tree)),
+                    None => storage.get_current_snapshot(&hostname)?,
+                };
+                let reply = match snapshot {
+                    Some((id, tree)) => ServerMessage::SnapshotTree {
+                        snapshot_id: id as u64,
+                        tree,
+                    },
+                    None => ServerMessage::SnapshotTreeFailed {
+                        reason: "Snapshot not found".to_string(),
+                    },
+                };
+                send(&mut ws, &reply).await?;
+            }
             _ => {}
         }
     }
@@ -145,30 +735,127 @@ async fn handle_sync(
     Ok(())
 }
 
-fn verify_tree_chunks(tree: &Node, storage: &Storage) -> Result<(), usize> {
-    let mut missing = 0;
-    verify_node_chunks(tree, storage, &mut missing);
-    if missing > 0 {
-        Err(missing)
+/// The scope a client needs to send a message, if any.
+fn required_scope(msg: &ClientMessage) -> Option<&'static str> {
+    match msg {
+        ClientMessage::Auth { .. } => None,
+        ClientMessage::HaveChunks { .. }
+        | ClientMessage::ChunkData { .. }
+        | ClientMessage::ChunkBatch { .. }
+        | ClientMessage::CommitTree { .. }
+        | ClientMessage::BeginBatch
+        | ClientMessage::CommitBatch
+        | ClientMessage::Rollback { .. }
+        | ClientMessage::TagSnapshot { .. } => Some(SCOPE_DEPLOY),
+        ClientMessage::ListSnapshots { .. }
+        | ClientMessage::GetSnapshotTree { .. }
+        | ClientMessage::ListSites
+        | ClientMessage::GetFile { .. } => Some(SCOPE_READ),
+        ClientMessage::DeleteSite { .. } => Some(SCOPE_ADMIN),
+    }
+}
+
+async fn send<S>(
+    ws: &mut WebSocketStream<S>,
+    msg: &ServerMessage,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    ws.send(Message::Binary(protocol::encode(msg)?)).await?;
+    Ok(())
+}
+
+/// Check that chunk data hashes to the hash the client claims for it, so a
+/// buggy or malicious client can't store data under someone else's hash.
+pub fn verify_chunk(hash: &[u8; 32], data: &[u8]) -> Result<(), String> {
+    let actual = blake3::hash(data);
+    if actual.as_bytes() == hash {
+        Ok(())
+    } else {
+        Err(format!(
+            "data hashes to {}, not {}",
+            actual.to_hex(),
+            hex::encode(hash)
+        ))
+    }
+}
+
+/// The snapshot a rollback goes to: the one with the given ID or tag, or
+/// else the one before the newest. The inner error is the reason to report
+/// when there is none.
+pub fn rollback_target(
+    storage: &Storage,
+    hostname: &str,
+    snapshot_id: Option<u64>,
+    tag: Option<&str>,
+) -> Result<Result<i64, String>, StorageError> {
+    match (snapshot_id, tag) {
+        (Some(id), _) => Ok(Ok(id as i64)),
+        (None, Some(tag)) => Ok(storage
+            .find_tagged_snapshot(hostname, tag)?
+            .ok_or_else(|| format!("No snapshot tagged {}", tag))),
+        (None, None) => {
+            // Snapshots are listed newest first
+            let snapshots = storage.list_snapshots(hostname)?;
+            Ok(snapshots
+                .get(1)
+                .map(|snapshot| snapshot.0)
+                .ok_or_else(|| "No previous snapshot to rollback to".to_string()))
+        }
+    }
+}
+
+/// Check that a tag can name a snapshot. Tags that are numbers would read
+/// as snapshot IDs on the command line.
+fn check_tag(tag: &str) -> Result<(), String> {
+    if tag.is_empty() {
+        Err("Tag is empty".to_string())
+    } else if tag.len() > MAX_TAG_LEN {
+        Err(format!("Tag is longer than {} bytes", MAX_TAG_LEN))
+    } else if tag.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Err("Tag contains whitespace".to_string())
+    } else if tag.bytes().all(|b| b.is_ascii_digit()) {
+        Err("Tag is a number".to_string())
     } else {
         Ok(())
     }
 }
 
-fn verify_node_chunks(node: &Node, storage: &Storage, missing: &mut usize) {
+/// The chunks of the file at `path` in a tree. A directory resolves to its
+/// index.html, as in HTTP serving.
+fn resolve_file(tree: &Node, path: &str) -> Result<Vec<[u8; 32]>, &'static str> {
+    let node = match find_node(tree, path) {
+        Some(Node::Directory { children, .. }) => children
+            .iter()
+            .find(|child| child.name() == "index.html")
+            .ok_or("Is a directory")?,
+        Some(node) => node,
+        None => return Err("File not found"),
+    };
     match node {
-        Node::File { chunks, .. } => {
-            for hash in chunks {
-                if storage.get_chunk(hash).ok().flatten().is_none() {
-                    *missing += 1;
-                }
-            }
-        }
-        Node::Directory { children, .. } => {
-            for child in children {
-                verify_node_chunks(child, storage, missing);
-            }
+        Node::File { chunks, .. } => Ok(chunks.clone()),
+        Node::Directory { .. } => Err("Is a directory"),
+    }
+}
+
+/// The reply to a commit that storage refused, or the error if it failed
+/// for another reason.
+fn commit_failed(e: StorageError, protocol_version: u32) -> Result<ServerMessage, StorageError> {
+    match e {
+        StorageError::MissingChunks(hashes) => {
+            let reason = format!("Missing {} chunks", hashes.len());
+            // Listed, when few enough, so the client can upload them and retry
+            let listed = protocol_version >= MISSING_CHUNKS_PROTOCOL_VERSION
+                && hashes.len() <= MAX_MISSING_CHUNKS;
+            let missing = if listed { hashes } else { Vec::new() };
+            Ok(ServerMessage::CommitFailed { reason, missing })
         }
+        e @ StorageError::SizeMismatch(_) => Ok(ServerMessage::CommitFailed {
+            reason: e.to_string(),
+            missing: Vec::new(),
+        }),
+        e => Err(e),
     }
 }
 
@@ -177,10 +864,9 @@ fn cleanup_old_snapshots(
     hostname: &str,
     keep: usize,
 ) -> crate::server::storage::Result<()> {
-    let snapshots = storage.list_snapshots(hostname)?;
-    if snapshots.len() > keep {
-        // TODO: Delete old snapshots (keeping `keep` most recent)
-        // For now, just leave them - GC will clean up chunks
+    let deleted = storage.prune_snapshots(hostname, keep)?;
+    if !deleted.is_empty() {
+        println!("Pruned {} old snapshots of {}", deleted.len(), hostname);
     }
     Ok(())
 }
@@ -0,0 +1,83 @@
+use axum::{
+    extract::{Host, OriginalUri},
+    http::{header, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio_rustls::TlsAcceptor;
+
+/// Load a certificate chain and private key from PEM files.
+pub async fn load_config(cert: &Path, key: &Path) -> io::Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(cert, key).await
+}
+
+/// A TLS acceptor using a config's current certificate, for wrapping raw
+/// connections such as the sync server's. Create one per connection so
+/// reloaded certificates take effect.
+pub fn acceptor(config: &RustlsConfig) -> TlsAcceptor {
+    TlsAcceptor::from(config.get_inner())
+}
+
+/// Reload the certificate and key into a running server's config whenever
+/// the process receives SIGHUP, so renewed certificates apply without a
+/// restart. A failed reload keeps the previous certificate.
+#[cfg(unix)]
+pub fn reload_on_sighup(config: RustlsConfig, cert: PathBuf, key: PathBuf) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match config.reload_from_pem_file(&cert, &key).await {
+                Ok(()) => println!("Reloaded TLS certificate from {}", cert.display()),
+                Err(e) => eprintln!("Failed to reload TLS certificate: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Certificates are only reloaded on SIGHUP, which this platform lacks.
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_config: RustlsConfig, _cert: PathBuf, _key: PathBuf) -> io::Result<()> {
+    Ok(())
+}
+
+/// A router answering every request with a 301 to the same host and path
+/// over HTTPS on the given port.
+pub fn redirect_router(https_port: u16) -> Router {
+    Router::new().fallback(
+        move |Host(host): Host, OriginalUri(uri): OriginalUri| async move {
+            redirect_to_https(&host, &uri, https_port)
+        },
+    )
+}
+
+fn redirect_to_https(host: &str, uri: &Uri, https_port: u16) -> Response {
+    let hostname = strip_port(host);
+    let authority = if https_port == 443 {
+        hostname.to_string()
+    } else {
+        format!("{}:{}", hostname, https_port)
+    };
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+
+    let location = format!("https://{}{}", authority, path);
+    (
+        StatusCode::MOVED_PERMANENTLY,
+        [(header::LOCATION, location)],
+    )
+        .into_response()
+}
+
+/// A Host header without its port. IPv6 literals keep their brackets, and
+/// only a port after the closing one is dropped.
+fn strip_port(host: &str) -> &str {
+    match host.rfind(']') {
+        Some(end) => &host[..=end],
+        None => host.rsplit_once(':').map_or(host, |(hostname, _)| hostname),
+    }
+}
@@ -0,0 +1,109 @@
+/// Format seconds since the Unix epoch as an RFC 3339 UTC timestamp, e.g.
+/// `2024-05-01T12:30:00Z`.
+pub fn format_utc(secs: i64) -> String {
+    let (days, time) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+/// Parse a UTC timestamp into seconds since the Unix epoch. Accepts RFC 3339
+/// with a `Z` offset, as written by [`format_utc`], and SQLite's
+/// `CURRENT_TIMESTAMP` form `2024-05-01 12:30:00`.
+pub fn parse_utc(s: &str) -> Option<i64> {
+    let s = s.strip_suffix('Z').unwrap_or(s);
+    let bytes = s.as_bytes();
+    let separators = [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')];
+    if bytes.len() != 19
+        || !matches!(bytes[10], b'T' | b' ')
+        || separators.iter().any(|&(i, c)| bytes[i] != c)
+    {
+        return None;
+    }
+
+    let field = |start: usize, len: usize| -> Option<i64> {
+        let digits = &s[start..start + len];
+        digits
+            .bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| digits.parse().ok())?
+    };
+    let (year, month, day) = (field(0, 4)?, field(5, 2)?, field(8, 2)?);
+    let (hour, minute, second) = (field(11, 2)?, field(14, 2)?, field(17, 2)?);
+    if !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 86_400 + hour * 3_600 + minute * 60 + second)
+}
+
+/// Describe an expiry relative to `now`, both in seconds since the Unix
+/// epoch, rounded up to whole seconds, minutes, hours or days: the largest
+/// unit the rounded time fills, so 3599s is `1h` rather than `60m`.
+pub fn format_expiry(expires_at: i64, now: i64) -> String {
+    let remaining = expires_at - now;
+    if remaining <= 0 {
+        return "expired".to_string();
+    }
+    let (mut value, mut unit) = (remaining as u64, "s");
+    for (next, size) in [("m", 60), ("h", 60), ("d", 24)] {
+        if value < size {
+            break;
+        }
+        value = value.div_ceil(size);
+        unit = next;
+    }
+    format!("expires in {}{}", value, unit)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Years counted from March, so the leap day ends the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Proleptic Gregorian date of a number of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,168 @@
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use tower::ServiceExt;
+use webpub::server::access_log::{subscriber, AccessEntry, LogFormat};
+use webpub::server::http::{create_router_with, RouterOptions};
+use webpub::server::storage::Storage;
+use webpub::{build_tree, scan_directory};
+
+fn entry() -> AccessEntry {
+    AccessEntry {
+        host: "example.com".to_string(),
+        method: "GET".to_string(),
+        path: "/docs/\"quoted\".html".to_string(),
+        status: 404,
+        bytes: 1234,
+        duration_ms: 0.75,
+    }
+}
+
+/// Log output collected in memory.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(data)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Buffer {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// The lines `f` logs in `format`.
+fn logged(format: LogFormat, f: impl FnOnce()) -> Vec<String> {
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    tracing::subscriber::with_default(subscriber(format, move || writer.clone()), f);
+    buffer.lines()
+}
+
+#[test]
+fn test_log_format_parse() {
+    assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+    assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+    assert!("xml".parse::<LogFormat>().is_err());
+}
+
+#[test]
+fn test_text_line() {
+    let lines = logged(LogFormat::Text, || entry().record());
+    assert_eq!(lines.len(), 1);
+    let line = &lines[0];
+    assert!(line.contains(" INFO webpub::access: request "), "{}", line);
+    for field in [
+        "host=example.com",
+        "method=GET",
+        "path=/docs/\"quoted\".html",
+        "status=404",
+        "bytes=1234",
+        "duration_ms=0.75",
+    ] {
+        assert!(line.contains(field), "{} not in {}", field, line);
+    }
+}
+
+#[test]
+fn test_json_line() {
+    let lines = logged(LogFormat::Json, || entry().record());
+    assert_eq!(lines.len(), 1);
+    let value: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(value["level"], "INFO");
+    assert_eq!(value["target"], "webpub::access");
+    assert_eq!(value["host"], "example.com");
+    assert_eq!(value["method"], "GET");
+    assert_eq!(value["path"], "/docs/\"quoted\".html");
+    assert_eq!(value["status"], 404);
+    assert_eq!(value["bytes"], 1234);
+    assert_eq!(value["duration_ms"], 0.75);
+    assert!(value["timestamp"].is_string());
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_router_logs_requests() {
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+    let options = RouterOptions {
+        access_log: true,
+        ..RouterOptions::default()
+    };
+    let app = create_router_with(storage, options);
+
+    // The current-thread runtime keeps the request on this thread's subscriber
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let _guard =
+        tracing::subscriber::set_default(subscriber(LogFormat::Json, move || writer.clone()));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/missing.html")
+                .header(header::HOST, "nowhere.example")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let lines = buffer.lines();
+    assert_eq!(lines.len(), 1, "{:?}", lines);
+    let value: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(value["host"], "nowhere.example");
+    assert_eq!(value["path"], "/missing.html");
+    assert_eq!(value["status"], 404);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_internal_errors_logged_with_request() {
+    // A snapshot whose chunks were never stored
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    std::fs::create_dir(&site).unwrap();
+    std::fs::write(site.join("photo.png"), vec![7u8; 1000]).unwrap();
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let (tree, _) = build_tree(scan_directory(&site).unwrap().next().unwrap());
+    storage.create_snapshot("example.com", &tree).unwrap();
+    let app = create_router_with(storage, RouterOptions::default());
+
+    let buffer = Buffer::default();
+    let writer = buffer.clone();
+    let _guard =
+        tracing::subscriber::set_default(subscriber(LogFormat::Json, move || writer.clone()));
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/photo.png")
+                .header(header::HOST, "example.com:8080")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    // The detail goes to the log, tied to the request it failed
+    let lines = buffer.lines();
+    assert_eq!(lines.len(), 1, "{:?}", lines);
+    let value: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(value["level"], "ERROR");
+    assert_eq!(value["message"], "internal error");
+    assert_eq!(value["error"], "Missing chunk");
+    assert_eq!(value["span"]["host"], "example.com:8080");
+    assert_eq!(value["span"]["path"], "/photo.png");
+}
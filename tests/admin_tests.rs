@@ -0,0 +1,220 @@
+use async_trait::async_trait;
+use axum::body::Body;
+use axum::http::{header, Method, Request, StatusCode};
+use axum::Router;
+use serde_json::Value;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tower::ServiceExt;
+use webpub::server::admin::admin_router;
+use webpub::server::auth::{AuthContext, AuthError, Authenticator, TokenAuthenticator, SCOPE_READ};
+use webpub::server::preview_link::preview_path;
+use webpub::server::storage::Storage;
+use webpub::Node;
+
+/// Grants read scope to the credential "reader" only.
+struct ReadOnlyAuthenticator;
+
+#[async_trait]
+impl Authenticator for ReadOnlyAuthenticator {
+    async fn authenticate(&self, credential: &str) -> Result<AuthContext, AuthError> {
+        match credential {
+            "reader" => Ok(AuthContext {
+                subject: "reader".to_string(),
+                scopes: vec![SCOPE_READ.to_string()],
+            }),
+            _ => Err(AuthError::Denied),
+        }
+    }
+}
+
+fn tree(content: &str) -> Node {
+    Node::Directory {
+        name: String::new(),
+        permissions: 0o755,
+        children: vec![Node::File {
+            name: "index.html".to_string(),
+            hash: *blake3::hash(content.as_bytes()).as_bytes(),
+            content_hash: *blake3::hash(content.as_bytes()).as_bytes(),
+            size: content.len() as u64,
+            chunks: Vec::new(),
+            permissions: 0o644,
+            mtime: 0,
+        }],
+        hash: *blake3::hash(content.as_bytes()).as_bytes(),
+    }
+}
+
+/// Storage with three snapshots of example.com, the newest current, and an
+/// admin router authenticating with its tokens.
+fn setup(temp: &TempDir) -> (Arc<Storage>, Router, String) {
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+    for version in ["v1", "v2", "v3"] {
+        storage
+            .create_snapshot("example.com", &tree(version))
+            .unwrap();
+    }
+    let token = storage.add_token().unwrap();
+    let authenticator = Arc::new(TokenAuthenticator::new(storage.clone()));
+    let router = admin_router(storage.clone(), authenticator);
+    (storage, router, token)
+}
+
+/// Send a request with an optional bearer token and JSON body, returning the
+/// status and the parsed body.
+async fn call(
+    router: &Router,
+    method: Method,
+    path: &str,
+    token: Option<&str>,
+    body: &str,
+) -> (StatusCode, Value) {
+    let mut request = Request::builder().method(method).uri(path);
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let response = router
+        .clone()
+        .oneshot(request.body(Body::from(body.to_string())).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+#[tokio::test]
+async fn test_list_sites_and_snapshots() {
+    let temp = TempDir::new().unwrap();
+    let (storage, router, token) = setup(&temp);
+
+    let (status, sites) = call(&router, Method::GET, "/api/sites", Some(&token), "").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(sites[0]["hostname"], "example.com");
+    assert_eq!(sites[0]["snapshots"], 3);
+    assert_eq!(sites[0]["current_snapshot_id"], 3);
+
+    let (status, snapshots) = call(
+        &router,
+        Method::GET,
+        "/api/sites/example.com/snapshots",
+        Some(&token),
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let snapshots = snapshots.as_array().unwrap();
+    assert_eq!(snapshots.len(), 3);
+    assert_eq!(snapshots[0]["id"], 3);
+    assert_eq!(snapshots[0]["current"], true);
+    assert_eq!(snapshots[0]["tag"], Value::Null);
+    assert!(snapshots[0]["created_at"].as_str().unwrap().ends_with('Z'));
+    assert_eq!(snapshots[2]["current"], false);
+    let secret = storage.preview_secret().unwrap();
+    assert_eq!(
+        snapshots[2]["preview_path"],
+        preview_path(&secret, "example.com", snapshots[2]["id"].as_i64().unwrap())
+    );
+}
+
+#[tokio::test]
+async fn test_rollback() {
+    let temp = TempDir::new().unwrap();
+    let (storage, router, token) = setup(&temp);
+    let path = "/api/sites/example.com/rollback";
+    let current = || {
+        storage
+            .get_current_snapshot("example.com")
+            .unwrap()
+            .unwrap()
+            .0
+    };
+
+    // Without a body, to the previous snapshot
+    let (status, body) = call(&router, Method::POST, path, Some(&token), "").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["snapshot_id"], 2);
+    assert_eq!(current(), 2);
+
+    // To a given snapshot, or a tagged one
+    let (status, _) = call(
+        &router,
+        Method::POST,
+        path,
+        Some(&token),
+        r#"{"snapshot_id": 3}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(current(), 3);
+    storage.tag_snapshot("example.com", 1, "stable").unwrap();
+    let (status, body) = call(
+        &router,
+        Method::POST,
+        path,
+        Some(&token),
+        r#"{"tag": "stable"}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["snapshot_id"], 1);
+    assert_eq!(current(), 1);
+
+    // Unknown targets and malformed bodies leave the site alone
+    for (body, expected) in [
+        (r#"{"snapshot_id": 99}"#, StatusCode::NOT_FOUND),
+        (r#"{"tag": "missing"}"#, StatusCode::NOT_FOUND),
+        ("{not json", StatusCode::BAD_REQUEST),
+    ] {
+        let (status, reply) = call(&router, Method::POST, path, Some(&token), body).await;
+        assert_eq!(status, expected, "{}", body);
+        assert!(reply["error"].is_string(), "{}", body);
+        assert_eq!(current(), 1);
+    }
+    let (status, _) = call(
+        &router,
+        Method::POST,
+        "/api/sites/other.com/rollback",
+        Some(&token),
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_requires_token_and_scope() {
+    let temp = TempDir::new().unwrap();
+    let (storage, router, _token) = setup(&temp);
+
+    for token in [None, Some("wrong")] {
+        let (status, body) = call(&router, Method::GET, "/api/sites", token, "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "Invalid or missing token");
+    }
+
+    // Reading needs the read scope, rolling back the deploy scope
+    let router = admin_router(storage.clone(), Arc::new(ReadOnlyAuthenticator));
+    let (status, _) = call(&router, Method::GET, "/api/sites", Some("reader"), "").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = call(
+        &router,
+        Method::POST,
+        "/api/sites/example.com/rollback",
+        Some("reader"),
+        "",
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"], "deploy scope required");
+    assert_eq!(
+        storage
+            .get_current_snapshot("example.com")
+            .unwrap()
+            .unwrap()
+            .0,
+        3
+    );
+}
@@ -1,8 +1,14 @@
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
 use std::fs;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use tempfile::TempDir;
-use webpub::archive::{read_archive, write_archive, MAGIC};
-use webpub::merkle::build_tree;
+use webpub::archive::{
+    extract_path, list_archive, read_archive, read_chunk, read_index, to_tar, verify_archive,
+    write_archive, write_archive_with, ArchiveError, ArchiveIndex, ArchiveReader, ChunkEntry,
+    Compression, Corruption, ExtractOptions, MAGIC, VERSION,
+};
+use webpub::merkle::{build_tree, diff, DiffEntry};
 use webpub::scanner::scan_directory;
 
 #[test]
@@ -41,7 +47,7 @@ fn test_write_archive_version() {
     file.seek(SeekFrom::Start(8)).unwrap();
     let mut version = [0u8; 1];
     file.read_exact(&mut version).unwrap();
-    assert_eq!(version[0], 1);
+    assert_eq!(version[0], VERSION);
 }
 
 #[test]
@@ -114,3 +120,843 @@ fn test_roundtrip_empty_directory() {
     // Verify empty dir exists
     assert!(extract_path.join("empty").is_dir());
 }
+
+#[test]
+fn test_diff_archives() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    fs::write(site.join("about.html"), "About").unwrap();
+
+    let archive_a = temp.path().join("a.webpub");
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    write_archive(&archive_a, &tree, &chunks).unwrap();
+
+    // Compared to itself: no differences
+    let a = read_index(&archive_a).unwrap();
+    let same = read_index(&archive_a).unwrap();
+    assert!(diff(&a.tree, &same.tree).is_empty());
+
+    // Modify one file
+    fs::write(site.join("index.html"), "<h1>Changed</h1>").unwrap();
+    let archive_b = temp.path().join("b.webpub");
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    write_archive(&archive_b, &tree, &chunks).unwrap();
+
+    let b = read_index(&archive_b).unwrap();
+    assert_eq!(
+        diff(&a.tree, &b.tree),
+        vec![DiffEntry::Modified("index.html".to_string())]
+    );
+    assert_ne!(
+        a.chunks.keys().collect::<std::collections::HashSet<_>>(),
+        b.chunks.keys().collect::<std::collections::HashSet<_>>()
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_extract_long_names_error() {
+    use webpub::Node;
+
+    let file = |name: String| Node::File {
+        name,
+        permissions: 0o644,
+        size: 0,
+        chunks: vec![],
+        hash: [0u8; 32],
+        content_hash: [0u8; 32],
+        mtime: 0,
+    };
+    let temp = TempDir::new().unwrap();
+
+    // A single over-long file name
+    let long_name = "n".repeat(300);
+    let tree = Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![file(long_name.clone())],
+        hash: [0u8; 32],
+    };
+    let archive_path = temp.path().join("name.webpub");
+    write_archive(&archive_path, &tree, &[]).unwrap();
+
+    let err = read_archive(&archive_path, &temp.path().join("out1")).unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains(&long_name), "{}", message);
+    assert!(message.contains("file name is 300 bytes"), "{}", message);
+
+    // Deep nesting whose total path exceeds the limit
+    let mut node = file("leaf.txt".to_string());
+    for i in 0..20 {
+        node = Node::Directory {
+            name: format!("{}{}", "d".repeat(240), i),
+            permissions: 0o755,
+            children: vec![node],
+            hash: [0u8; 32],
+        };
+    }
+    let tree = Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![node],
+        hash: [0u8; 32],
+    };
+    let archive_path = temp.path().join("deep.webpub");
+    write_archive(&archive_path, &tree, &[]).unwrap();
+
+    let err = read_archive(&archive_path, &temp.path().join("out2")).unwrap_err();
+    assert!(err.to_string().contains("byte limit"), "{}", err);
+}
+
+#[test]
+fn test_extract_rejects_unsafe_names() {
+    use webpub::Node;
+
+    let temp = TempDir::new().unwrap();
+    for (i, name) in [
+        "..",
+        "../evil.txt",
+        "/tmp/evil.txt",
+        "a/../../evil.txt",
+        ".",
+        "",
+    ]
+    .iter()
+    .enumerate()
+    {
+        let tree = Node::Directory {
+            name: "".to_string(),
+            permissions: 0o755,
+            children: vec![Node::Directory {
+                name: "site".to_string(),
+                permissions: 0o755,
+                children: vec![Node::File {
+                    name: name.to_string(),
+                    permissions: 0o644,
+                    size: 0,
+                    chunks: vec![],
+                    hash: [0u8; 32],
+                    content_hash: [0u8; 32],
+                    mtime: 0,
+                }],
+                hash: [0u8; 32],
+            }],
+            hash: [0u8; 32],
+        };
+        let archive_path = temp.path().join(format!("{}.webpub", i));
+        write_archive(&archive_path, &tree, &[]).unwrap();
+
+        let out = temp.path().join(format!("out{}", i));
+        let err = read_archive(&archive_path, &out).unwrap_err();
+        assert!(matches!(err, ArchiveError::UnsafeName(_)), "{:?}", err);
+
+        let err = to_tar(&archive_path, Vec::new()).unwrap_err();
+        assert!(matches!(err, ArchiveError::UnsafeName(_)), "{:?}", err);
+    }
+    assert!(!temp.path().join("evil.txt").exists());
+}
+
+#[test]
+fn test_extract_existing_files() {
+    let temp = TempDir::new().unwrap();
+    let (archive_path, _) = small_archive(&temp);
+    let out = temp.path().join("out");
+    fs::create_dir(&out).unwrap();
+    fs::write(out.join("index.html"), "local edits").unwrap();
+
+    let err = read_archive(&archive_path, &out).unwrap_err();
+    assert!(matches!(err, ArchiveError::AlreadyExists(_)), "{:?}", err);
+    assert!(err.to_string().contains("index.html"), "{}", err);
+    assert_eq!(
+        fs::read_to_string(out.join("index.html")).unwrap(),
+        "local edits"
+    );
+
+    let options = ExtractOptions { overwrite: true };
+    ArchiveReader::open(&archive_path)
+        .unwrap()
+        .extract_with("", &out, &options)
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(out.join("index.html")).unwrap(),
+        "<h1>Hello</h1>"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_extract_does_not_follow_symlinks() {
+    let temp = TempDir::new().unwrap();
+    let (archive_path, _) = small_archive(&temp);
+    let outside = temp.path().join("outside.html");
+    fs::write(&outside, "keep me").unwrap();
+    let out = temp.path().join("out");
+    fs::create_dir(&out).unwrap();
+    std::os::unix::fs::symlink(&outside, out.join("index.html")).unwrap();
+
+    let err = read_archive(&archive_path, &out).unwrap_err();
+    assert!(matches!(err, ArchiveError::AlreadyExists(_)), "{:?}", err);
+
+    // Overwriting replaces the link rather than writing through it
+    let options = ExtractOptions { overwrite: true };
+    ArchiveReader::open(&archive_path)
+        .unwrap()
+        .extract_with("", &out, &options)
+        .unwrap();
+    assert_eq!(fs::read_to_string(&outside).unwrap(), "keep me");
+    let metadata = fs::symlink_metadata(out.join("index.html")).unwrap();
+    assert!(metadata.is_file());
+    assert_eq!(
+        fs::read_to_string(out.join("index.html")).unwrap(),
+        "<h1>Hello</h1>"
+    );
+}
+
+/// Build a small archive and return its path and the byte offset of its index.
+/// The archive stores chunks uncompressed, so it can be rewritten in older formats.
+fn small_archive(temp: &TempDir) -> (std::path::PathBuf, u64) {
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let archive_path = temp.path().join("test.webpub");
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    write_archive_with(&archive_path, &tree, &chunks, Compression::None).unwrap();
+
+    let bytes = fs::read(&archive_path).unwrap();
+    let index_offset = u64::from_le_bytes(bytes[9..17].try_into().unwrap());
+    (archive_path, index_offset)
+}
+
+#[test]
+fn test_corrupted_index_detected() {
+    let temp = TempDir::new().unwrap();
+    let (archive_path, index_offset) = small_archive(&temp);
+
+    let mut bytes = fs::read(&archive_path).unwrap();
+    bytes[index_offset as usize + 1] ^= 0xff;
+    fs::write(&archive_path, &bytes).unwrap();
+
+    let err = read_archive(&archive_path, &temp.path().join("out")).unwrap_err();
+    assert!(matches!(err, ArchiveError::CorruptIndex), "{:?}", err);
+    assert!(
+        err.to_string().contains("archive index corrupted"),
+        "{}",
+        err
+    );
+}
+
+/// Encode an index in the version 1/2 layout: tree and hash -> (offset, size)
+/// of uncompressed chunks, with offsets shifted by `shift` bytes.
+fn legacy_index_bytes(index: &ArchiveIndex, shift: u64) -> Vec<u8> {
+    let chunk_offsets: HashMap<[u8; 32], (u64, u64)> = index
+        .chunks
+        .iter()
+        .map(|(hash, entry)| (*hash, (entry.offset - shift, entry.size)))
+        .collect();
+    rmp_serde::to_vec(&(&index.tree, chunk_offsets)).unwrap()
+}
+
+#[test]
+fn test_read_version_1_archive() {
+    let temp = TempDir::new().unwrap();
+    let (archive_path, index_offset) = small_archive(&temp);
+
+    // Rewrite as version 1: 25-byte header, no index checksum
+    let bytes = fs::read(&archive_path).unwrap();
+    let index = read_index(&archive_path).unwrap();
+    let index_bytes = legacy_index_bytes(&index, 32);
+    let chunk_region = &bytes[57..index_offset as usize];
+
+    let mut v1 = Vec::new();
+    v1.extend_from_slice(MAGIC);
+    v1.push(1);
+    v1.extend_from_slice(&(25 + chunk_region.len() as u64).to_le_bytes());
+    v1.extend_from_slice(&(index_bytes.len() as u64).to_le_bytes());
+    v1.extend_from_slice(chunk_region);
+    v1.extend_from_slice(&index_bytes);
+    let v1_path = temp.path().join("v1.webpub");
+    fs::write(&v1_path, v1).unwrap();
+
+    let out = temp.path().join("out");
+    read_archive(&v1_path, &out).unwrap();
+    assert_eq!(
+        fs::read_to_string(out.join("index.html")).unwrap(),
+        "<h1>Hello</h1>"
+    );
+}
+
+#[test]
+fn test_read_version_2_archive() {
+    let temp = TempDir::new().unwrap();
+    let (archive_path, index_offset) = small_archive(&temp);
+
+    // Same header as version 3, but with the uncompressed index layout
+    let bytes = fs::read(&archive_path).unwrap();
+    let index = read_index(&archive_path).unwrap();
+    let index_bytes = legacy_index_bytes(&index, 0);
+
+    let mut v2 = bytes[..index_offset as usize].to_vec();
+    v2[8] = 2;
+    v2[17..25].copy_from_slice(&(index_bytes.len() as u64).to_le_bytes());
+    v2[25..57].copy_from_slice(blake3::hash(&index_bytes).as_bytes());
+    v2.extend_from_slice(&index_bytes);
+    let v2_path = temp.path().join("v2.webpub");
+    fs::write(&v2_path, v2).unwrap();
+
+    let out = temp.path().join("out");
+    read_archive(&v2_path, &out).unwrap();
+    assert_eq!(
+        fs::read_to_string(out.join("index.html")).unwrap(),
+        "<h1>Hello</h1>"
+    );
+}
+
+#[test]
+fn test_zstd_compression_roundtrip() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    let html = "<p>Lorem ipsum dolor sit amet</p>\n".repeat(5000);
+    fs::write(site.join("index.html"), &html).unwrap();
+    // Random bytes don't compress and are stored as-is
+    let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+    let noise: Vec<u8> = (0..20_000).map(|_| rng.gen()).collect();
+    fs::write(site.join("noise.bin"), &noise).unwrap();
+
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+
+    let compressed = temp.path().join("zstd.webpub");
+    let raw = temp.path().join("none.webpub");
+    write_archive(&compressed, &tree, &chunks).unwrap();
+    write_archive_with(&raw, &tree, &chunks, Compression::None).unwrap();
+    assert!(fs::metadata(&compressed).unwrap().len() * 4 < fs::metadata(&raw).unwrap().len());
+
+    let index = read_index(&compressed).unwrap();
+    assert!(index
+        .chunks
+        .values()
+        .any(|entry| entry.compression == Compression::Zstd && entry.size < entry.raw_size));
+    assert!(read_index(&raw)
+        .unwrap()
+        .chunks
+        .values()
+        .all(|entry| entry.compression == Compression::None));
+
+    for archive in [&compressed, &raw] {
+        let out = temp.path().join(archive.file_stem().unwrap());
+        read_archive(archive, &out).unwrap();
+        assert_eq!(fs::read_to_string(out.join("index.html")).unwrap(), html);
+        assert_eq!(fs::read(out.join("noise.bin")).unwrap(), noise);
+    }
+}
+
+#[test]
+fn test_list_archive() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("css")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    fs::write(site.join("css/style.css"), "h1 {}").unwrap();
+
+    let archive_path = temp.path().join("test.webpub");
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    write_archive(&archive_path, &tree, &chunks).unwrap();
+
+    let entries = list_archive(&archive_path).unwrap();
+    let summary: Vec<(&str, usize, bool, u64, usize)> = entries
+        .iter()
+        .map(|e| (e.path.as_str(), e.depth, e.is_dir, e.size, e.chunks))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("css", 0, true, 0, 0),
+            ("css/style.css", 1, false, 5, 1),
+            ("index.html", 0, false, 14, 1),
+        ]
+    );
+}
+
+#[test]
+fn test_extract_path() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("css/themes")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    fs::write(site.join("css/style.css"), "h1 {}").unwrap();
+    fs::write(site.join("css/themes/dark.css"), "body {}").unwrap();
+
+    let archive_path = temp.path().join("test.webpub");
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    write_archive(&archive_path, &tree, &chunks).unwrap();
+
+    // A single file
+    let out = temp.path().join("file");
+    extract_path(&archive_path, "css/style.css", &out).unwrap();
+    assert_eq!(fs::read_to_string(out.join("style.css")).unwrap(), "h1 {}");
+    assert_eq!(fs::read_dir(&out).unwrap().count(), 1);
+
+    // A subdirectory and everything under it
+    let out = temp.path().join("dir");
+    extract_path(&archive_path, "/css", &out).unwrap();
+    assert_eq!(
+        fs::read_to_string(out.join("css/themes/dark.css")).unwrap(),
+        "body {}"
+    );
+    assert!(!out.join("index.html").exists());
+
+    let err = extract_path(&archive_path, "css/missing.css", &temp.path().join("x")).unwrap_err();
+    assert!(matches!(err, ArchiveError::NotFound(_)), "{:?}", err);
+    assert!(err.to_string().contains("css/missing.css"), "{}", err);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_to_tar() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("bin")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    fs::write(site.join("bin/run.sh"), "#!/bin/sh").unwrap();
+    fs::set_permissions(site.join("bin/run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    // Several chunks, to exercise streaming across chunk boundaries
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1011);
+    let large: Vec<u8> = (0..200_000).map(|_| rng.gen()).collect();
+    fs::write(site.join("large.bin"), &large).unwrap();
+
+    let archive_path = temp.path().join("test.webpub");
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    write_archive(&archive_path, &tree, &chunks).unwrap();
+
+    let mut buffer = Vec::new();
+    to_tar(&archive_path, &mut buffer).unwrap();
+
+    let mut entries = HashMap::new();
+    for entry in tar::Archive::new(buffer.as_slice()).entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().to_string_lossy().into_owned();
+        let mode = entry.header().mode().unwrap();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).unwrap();
+        entries.insert(path, (mode, data));
+    }
+
+    let mut names: Vec<_> = entries.keys().cloned().collect();
+    names.sort();
+    assert_eq!(names, vec!["bin/", "bin/run.sh", "index.html", "large.bin"]);
+    assert_eq!(entries["index.html"].1, b"<h1>Hello</h1>");
+    assert_eq!(entries["large.bin"].1, large);
+    assert_eq!(entries["bin/run.sh"].0, 0o755);
+    assert_eq!(
+        entries["index.html"].0,
+        fs::metadata(site.join("index.html"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o7777
+    );
+}
+
+#[test]
+fn test_bad_magic_error() {
+    let temp = TempDir::new().unwrap();
+    let path = temp.path().join("not-an-archive.webpub");
+    fs::write(&path, b"<html>definitely not an archive</html>").unwrap();
+
+    let err = read_archive(&path, &temp.path().join("out")).unwrap_err();
+    assert!(matches!(err, ArchiveError::BadMagic), "{:?}", err);
+}
+
+#[test]
+fn test_unsupported_version_error() {
+    let temp = TempDir::new().unwrap();
+    let (archive_path, _) = small_archive(&temp);
+
+    let mut bytes = fs::read(&archive_path).unwrap();
+    bytes[8] = VERSION + 1;
+    fs::write(&archive_path, &bytes).unwrap();
+
+    let err = read_index(&archive_path).unwrap_err();
+    assert!(
+        matches!(err, ArchiveError::UnsupportedVersion(v) if v == VERSION + 1),
+        "{:?}",
+        err
+    );
+}
+
+#[test]
+fn test_missing_chunk_error() {
+    use webpub::Node;
+
+    let temp = TempDir::new().unwrap();
+    let chunk = [7u8; 32];
+    let tree = Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![Node::File {
+            name: "a.txt".to_string(),
+            permissions: 0o644,
+            size: 1,
+            chunks: vec![chunk],
+            hash: chunk,
+            content_hash: chunk,
+            mtime: 0,
+        }],
+        hash: [0u8; 32],
+    };
+    let archive_path = temp.path().join("test.webpub");
+    write_archive(&archive_path, &tree, &[]).unwrap();
+
+    let err = read_archive(&archive_path, &temp.path().join("out")).unwrap_err();
+    assert!(
+        matches!(err, ArchiveError::MissingChunk(h) if h == chunk),
+        "{:?}",
+        err
+    );
+}
+
+#[test]
+fn test_verify_archive() {
+    let temp = TempDir::new().unwrap();
+    let (archive_path, index_offset) = small_archive(&temp);
+
+    let report = verify_archive(&archive_path).unwrap();
+    assert_eq!(report.chunks_checked, 1);
+    assert_eq!(report.files_checked, 1);
+    assert_eq!(report.corruption, None);
+
+    // Flip a byte of chunk data
+    let original = fs::read(&archive_path).unwrap();
+    let mut bytes = original.clone();
+    bytes[60] ^= 0xff;
+    fs::write(&archive_path, &bytes).unwrap();
+    match verify_archive(&archive_path).unwrap().corruption {
+        Some(Corruption::Chunk {
+            offset,
+            expected,
+            actual,
+        }) => {
+            assert_eq!(offset, 57);
+            assert_ne!(expected, actual);
+        }
+        other => panic!("expected chunk corruption, got {:?}", other),
+    }
+
+    // Truncated in the middle of the index
+    fs::write(&archive_path, &original[..index_offset as usize + 4]).unwrap();
+    assert!(verify_archive(&archive_path).is_err());
+}
+
+#[test]
+fn test_verify_archive_file_hash() {
+    use webpub::Node;
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("css")).unwrap();
+    fs::write(site.join("css/style.css"), "h1 {}").unwrap();
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (mut tree, chunks) = build_tree(entry);
+
+    // Tamper with the recorded file hash
+    if let Node::Directory { children, .. } = &mut tree {
+        if let Node::Directory { children, .. } = &mut children[0] {
+            if let Node::File { hash, .. } = &mut children[0] {
+                hash[0] ^= 0xff;
+            }
+        }
+    }
+    let archive_path = temp.path().join("test.webpub");
+    write_archive(&archive_path, &tree, &chunks).unwrap();
+
+    match verify_archive(&archive_path).unwrap().corruption {
+        Some(Corruption::File { path, .. }) => assert_eq!(path, "/css/style.css"),
+        other => panic!("expected file corruption, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_archive_omits_mtimes() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("old.txt"), "old").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(site.join("old.txt"))
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(3600))
+        .unwrap();
+
+    let (tree, chunks) = build_tree(scan_directory(&site).unwrap().next().unwrap());
+    let archive_path = temp.path().join("site.webpub");
+    write_archive(&archive_path, &tree, &chunks).unwrap();
+
+    let mut expected = tree.clone();
+    expected.clear_mtimes();
+    assert_ne!(tree, expected);
+    assert_eq!(read_index(&archive_path).unwrap().tree, expected);
+}
+
+#[test]
+fn test_archive_reader_random_access() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("css")).unwrap();
+    let large: Vec<u8> = (0..300_000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    fs::write(site.join("css/style.css"), "body {}").unwrap();
+    fs::write(site.join("large.bin"), &large).unwrap();
+
+    let archive_path = temp.path().join("test.webpub");
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    write_archive(&archive_path, &tree, &chunks).unwrap();
+
+    // Files read in any order, repeatedly, from one open archive
+    let mut archive = ArchiveReader::open(&archive_path).unwrap();
+    assert_eq!(archive.tree().hash(), tree.hash());
+    assert_eq!(archive.read_file("css/style.css").unwrap(), b"body {}");
+    assert_eq!(archive.read_file("/large.bin").unwrap(), large);
+    assert_eq!(archive.read_file("index.html").unwrap(), b"<h1>Hello</h1>");
+    assert_eq!(archive.read_file("css/style.css").unwrap(), b"body {}");
+    for chunk in &chunks {
+        assert_eq!(archive.chunk(&chunk.hash).unwrap(), chunk.data);
+    }
+
+    assert!(matches!(
+        archive.read_file("missing.txt"),
+        Err(ArchiveError::NotFound(_))
+    ));
+    assert!(matches!(
+        archive.read_file("css"),
+        Err(ArchiveError::IsDirectory(_))
+    ));
+    assert!(matches!(
+        archive.chunk(&[0u8; 32]),
+        Err(ArchiveError::MissingChunk(_))
+    ));
+
+    // Extraction from the same reader
+    archive.extract("css", &temp.path().join("out")).unwrap();
+    assert_eq!(
+        fs::read(temp.path().join("out/css/style.css")).unwrap(),
+        b"body {}"
+    );
+}
+
+#[test]
+fn test_archive_reader_detects_corrupt_chunk() {
+    let temp = TempDir::new().unwrap();
+    let (archive_path, _) = small_archive(&temp);
+
+    // Flip a byte of the only chunk, stored uncompressed after the header
+    let mut bytes = fs::read(&archive_path).unwrap();
+    bytes[57] ^= 0xff;
+    fs::write(&archive_path, &bytes).unwrap();
+
+    let mut archive = ArchiveReader::open(&archive_path).unwrap();
+    assert!(matches!(
+        archive.read_file("index.html"),
+        Err(ArchiveError::CorruptChunk(_))
+    ));
+}
+
+#[test]
+fn test_archive_checksum_trailer() {
+    let temp = TempDir::new().unwrap();
+    let (archive_path, _) = small_archive(&temp);
+    let original = fs::read(&archive_path).unwrap();
+
+    // The trailer hashes the chunk region, between the header and the index
+    let index_offset = u64::from_le_bytes(original[9..17].try_into().unwrap()) as usize;
+    let trailer = &original[original.len() - 32..];
+    assert_eq!(
+        trailer,
+        blake3::hash(&original[57..index_offset]).as_bytes()
+    );
+
+    let report = verify_archive(&archive_path).unwrap();
+    assert!(report.checksum_checked);
+    assert_eq!(report.corruption, None);
+    ArchiveReader::open(&archive_path)
+        .unwrap()
+        .verify_checksum()
+        .unwrap();
+
+    // A damaged trailer is reported once every chunk checks out
+    let mut bytes = original.clone();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&archive_path, &bytes).unwrap();
+    assert!(matches!(
+        verify_archive(&archive_path).unwrap().corruption,
+        Some(Corruption::Checksum { .. })
+    ));
+    assert!(matches!(
+        ArchiveReader::open(&archive_path)
+            .unwrap()
+            .verify_checksum(),
+        Err(ArchiveError::ChecksumMismatch)
+    ));
+}
+
+#[test]
+fn test_archive_size_checked_at_open() {
+    let temp = TempDir::new().unwrap();
+    let (archive_path, _) = small_archive(&temp);
+    let original = fs::read(&archive_path).unwrap();
+
+    // Losing the tail of the trailer, or gaining bytes, fails before any chunk is read
+    let truncated = &original[..original.len() - 1];
+    let mut appended = original.clone();
+    appended.extend_from_slice(b"junk");
+    for bytes in [truncated, &appended[..]] {
+        fs::write(&archive_path, bytes).unwrap();
+        let err = ArchiveReader::open(&archive_path).err().unwrap();
+        assert!(
+            matches!(err, ArchiveError::WrongSize { expected, actual }
+                if expected == original.len() as u64 && actual == bytes.len() as u64),
+            "{:?}",
+            err
+        );
+        assert!(read_index(&archive_path).is_err());
+    }
+}
+
+#[test]
+fn test_read_version_3_archive() {
+    let temp = TempDir::new().unwrap();
+    let (archive_path, _) = small_archive(&temp);
+
+    // Version 3 archives end at the index, without a checksum
+    let mut bytes = fs::read(&archive_path).unwrap();
+    bytes.truncate(bytes.len() - 32);
+    bytes[8] = 3;
+    fs::write(&archive_path, &bytes).unwrap();
+
+    let report = verify_archive(&archive_path).unwrap();
+    assert!(!report.checksum_checked);
+    assert_eq!(report.corruption, None);
+    let mut archive = ArchiveReader::open(&archive_path).unwrap();
+    assert!(!archive.has_checksum());
+    archive.verify_checksum().unwrap();
+    assert_eq!(archive.read_file("index.html").unwrap(), b"<h1>Hello</h1>");
+}
+
+/// Rewrite a small archive as version 3 with `edit` applied to its index.
+fn crafted_archive(temp: &TempDir, edit: impl FnOnce(&mut ArchiveIndex)) -> std::path::PathBuf {
+    let (archive_path, index_offset) = small_archive(temp);
+    let mut index = read_index(&archive_path).unwrap();
+    edit(&mut index);
+    let index_bytes = rmp_serde::to_vec(&index).unwrap();
+
+    let mut bytes = fs::read(&archive_path).unwrap();
+    bytes.truncate(index_offset as usize);
+    bytes[8] = 3;
+    bytes[17..25].copy_from_slice(&(index_bytes.len() as u64).to_le_bytes());
+    bytes[25..57].copy_from_slice(blake3::hash(&index_bytes).as_bytes());
+    bytes.extend_from_slice(&index_bytes);
+    fs::write(&archive_path, &bytes).unwrap();
+    archive_path
+}
+
+#[test]
+fn test_out_of_bounds_chunk_entry_rejected() {
+    let edits: [fn(&mut ChunkEntry); 4] = [
+        // Past the start of the index
+        |entry| entry.offset += 1000,
+        // Wrapping around
+        |entry| entry.size = u64::MAX,
+        // Larger than any chunk could decompress to
+        |entry| {
+            entry.compression = Compression::Zstd;
+            entry.raw_size = u64::MAX;
+        },
+        // Stored bytes that can't be the whole chunk
+        |entry| entry.raw_size += 1,
+    ];
+    for edit in edits {
+        let temp = TempDir::new().unwrap();
+        let archive_path = crafted_archive(&temp, |index| {
+            index.chunks.values_mut().for_each(edit);
+        });
+        let err = read_index(&archive_path).unwrap_err();
+        assert!(matches!(err, ArchiveError::BadChunkEntry(_)), "{:?}", err);
+        assert!(ArchiveReader::open(&archive_path).is_err());
+    }
+}
+
+#[test]
+fn test_read_chunk_bounded_by_raw_size() {
+    // A zstd frame that expands far past the size its entry records
+    let compressed = zstd::bulk::compress(&vec![0u8; 1 << 20], 3).unwrap();
+    let mut entry = ChunkEntry {
+        offset: 0,
+        size: compressed.len() as u64,
+        compression: Compression::Zstd,
+        raw_size: 100,
+    };
+    let err = read_chunk(&mut Cursor::new(&compressed), &entry).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // And a claimed size far past what the frame holds
+    entry.raw_size = u32::MAX as u64;
+    let err = read_chunk(&mut Cursor::new(&compressed), &entry).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    entry.raw_size = 1 << 20;
+    let data = read_chunk(&mut Cursor::new(&compressed), &entry).unwrap();
+    assert_eq!(data.len(), 1 << 20);
+}
+
+#[test]
+fn test_roundtrip_empty_file() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("empty.txt"), "").unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let archive_path = temp.path().join("test.webpub");
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    write_archive(&archive_path, &tree, &chunks).unwrap();
+    assert_eq!(verify_archive(&archive_path).unwrap().corruption, None);
+
+    let extracted = temp.path().join("extracted");
+    read_archive(&archive_path, &extracted).unwrap();
+    let metadata = fs::metadata(extracted.join("empty.txt")).unwrap();
+    assert!(metadata.is_file());
+    assert_eq!(metadata.len(), 0);
+
+    let mut archive = ArchiveReader::open(&archive_path).unwrap();
+    assert_eq!(archive.read_file("empty.txt").unwrap(), b"");
+
+    // An empty tar entry
+    let mut tar_bytes = Vec::new();
+    to_tar(&archive_path, &mut tar_bytes).unwrap();
+    let mut tar = tar::Archive::new(&tar_bytes[..]);
+    let entry = tar
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .find(|entry| entry.path().unwrap().to_str() == Some("empty.txt"))
+        .unwrap();
+    assert_eq!(entry.header().size().unwrap(), 0);
+}
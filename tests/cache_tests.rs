@@ -0,0 +1,77 @@
+use mime_guess::mime;
+use webpub::server::cache::{
+    cache_control, glob_match, is_fingerprinted, CacheRule, IMMUTABLE, REVALIDATE,
+};
+
+#[test]
+fn test_glob_match() {
+    // Globs without a slash match the file name anywhere
+    assert!(glob_match("*.js", "/assets/app.js"));
+    assert!(glob_match("*.js", "/app.js"));
+    assert!(!glob_match("*.js", "/app.json"));
+    assert!(glob_match("app.??", "/x/app.js"));
+
+    // Globs with a slash match the whole path; `*` stays within a segment
+    assert!(glob_match("/assets/*", "/assets/app.js"));
+    assert!(glob_match("assets/*", "/assets/app.js"));
+    assert!(!glob_match("/assets/*", "/assets/img/logo.png"));
+    assert!(!glob_match("/assets/*", "/static/assets/app.js"));
+
+    // `**` crosses segments, including none
+    assert!(glob_match("/assets/**", "/assets/img/logo.png"));
+    assert!(glob_match("/assets/**/*.png", "/assets/logo.png"));
+    assert!(glob_match("/assets/**/*.png", "/assets/img/icons/logo.png"));
+    assert!(!glob_match("/assets/**/*.png", "/assets/img/logo.svg"));
+}
+
+#[test]
+fn test_is_fingerprinted() {
+    assert!(is_fingerprinted("/assets/app.3f2a9c1b.js"));
+    assert!(is_fingerprinted("/assets/index-BxK3z9aQ.css"));
+    assert!(is_fingerprinted("/main.a1b2c3d4e5f6.chunk.js"));
+
+    assert!(!is_fingerprinted("/app.js"));
+    assert!(!is_fingerprinted("/photo-20240101.jpg"));
+    assert!(!is_fingerprinted("/jquery-migrate.js"));
+    assert!(!is_fingerprinted("/3f2a9c1b"));
+}
+
+#[test]
+fn test_cache_control_rules() {
+    let html = mime::TEXT_HTML;
+    let js = mime_guess::from_path("app.js").first_or_octet_stream();
+
+    // Defaults
+    assert_eq!(cache_control(&[], "/index.html", &html), Some(REVALIDATE));
+    assert_eq!(cache_control(&[], "/app.3f2a9c1b.js", &js), Some(IMMUTABLE));
+    assert_eq!(cache_control(&[], "/app.js", &js), None);
+
+    // Configured rules win in order, and an empty value sends nothing
+    let rules = vec![
+        CacheRule {
+            glob: "/static/**".to_string(),
+            value: "public, max-age=3600".to_string(),
+        },
+        CacheRule {
+            glob: "*.html".to_string(),
+            value: String::new(),
+        },
+        CacheRule {
+            glob: "*.js".to_string(),
+            value: "no-store".to_string(),
+        },
+    ];
+    assert_eq!(
+        cache_control(&rules, "/static/page.html", &html),
+        Some("public, max-age=3600")
+    );
+    assert_eq!(cache_control(&rules, "/index.html", &html), None);
+    assert_eq!(
+        cache_control(&rules, "/app.3f2a9c1b.js", &js),
+        Some("no-store")
+    );
+    assert_eq!(
+        cache_control(&rules, "/logo.1a2b3c4d.png", &mime::IMAGE_PNG),
+        Some(IMMUTABLE)
+    );
+}
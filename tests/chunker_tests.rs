@@ -1,9 +1,9 @@
-use webpub::chunker::{chunk_data, Chunk};
+use webpub::chunker::{chunk_data_default, Chunk};
 
 #[test]
 fn test_chunk_small_data() {
     let data = b"Hello, world!";
-    let chunks: Vec<Chunk> = chunk_data(data).collect();
+    let chunks: Vec<Chunk> = chunk_data_default(data).collect();
 
     // Small data should produce one chunk
     assert_eq!(chunks.len(), 1);
@@ -17,8 +17,8 @@ fn test_chunk_small_data() {
 #[test]
 fn test_chunk_deterministic() {
     let data = b"Some test data that we chunk";
-    let chunks1: Vec<Chunk> = chunk_data(data).collect();
-    let chunks2: Vec<Chunk> = chunk_data(data).collect();
+    let chunks1: Vec<Chunk> = chunk_data_default(data).collect();
+    let chunks2: Vec<Chunk> = chunk_data_default(data).collect();
 
     assert_eq!(chunks1.len(), chunks2.len());
     for (c1, c2) in chunks1.iter().zip(chunks2.iter()) {
@@ -31,7 +31,7 @@ fn test_chunk_large_data() {
     // Create data large enough to produce multiple chunks
     // fastcdc default min is 16KB, avg 32KB, max 64KB
     let data: Vec<u8> = (0..200_000).map(|i| (i % 256) as u8).collect();
-    let chunks: Vec<Chunk> = chunk_data(&data).collect();
+    let chunks: Vec<Chunk> = chunk_data_default(&data).collect();
 
     // Should produce multiple chunks
     assert!(chunks.len() > 1);
@@ -40,3 +40,217 @@ fn test_chunk_large_data() {
     let reconstructed: Vec<u8> = chunks.iter().flat_map(|c| c.data.iter().copied()).collect();
     assert_eq!(reconstructed, data);
 }
+
+#[test]
+fn test_chunk_custom_sizes() {
+    use webpub::chunker::{chunk_data, ChunkConfig};
+
+    let data: Vec<u8> = (0..200_000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let config = ChunkConfig::new(1024, 2048, 4096).unwrap();
+    let chunks: Vec<Chunk> = chunk_data(&data, &config).collect();
+
+    // Smaller sizes produce more chunks, all within the bounds
+    assert!(chunks.len() > chunk_data_default(&data).count());
+    for chunk in &chunks[..chunks.len() - 1] {
+        assert!(
+            (1024..=4096).contains(&chunk.data.len()),
+            "{}",
+            chunk.data.len()
+        );
+    }
+    let reconstructed: Vec<u8> = chunks.iter().flat_map(|c| c.data.iter().copied()).collect();
+    assert_eq!(reconstructed, data);
+
+    // The default config matches the default chunker
+    let defaults: Vec<[u8; 32]> = chunk_data(&data, &ChunkConfig::default())
+        .map(|c| c.hash)
+        .collect();
+    let expected: Vec<[u8; 32]> = chunk_data_default(&data).map(|c| c.hash).collect();
+    assert_eq!(defaults, expected);
+}
+
+#[test]
+fn test_chunk_config_validation() {
+    use webpub::chunker::{ChunkConfig, ChunkConfigError};
+
+    assert!(ChunkConfig::new(16 * 1024, 32 * 1024, 64 * 1024).is_ok());
+    assert!(ChunkConfig::new(4096, 4096, 4096).is_ok());
+
+    assert_eq!(
+        ChunkConfig::new(8192, 4096, 16384),
+        Err(ChunkConfigError::Order {
+            min: 8192,
+            avg: 4096,
+            max: 16384
+        })
+    );
+    let err = ChunkConfig::new(16, 4096, 16384).unwrap_err();
+    assert!(
+        matches!(err, ChunkConfigError::OutOfRange { name: "min", .. }),
+        "{}",
+        err
+    );
+    let err = ChunkConfig::new(4096, 8192, u32::MAX).unwrap_err();
+    assert!(err.to_string().starts_with("max chunk size"), "{}", err);
+}
+
+#[test]
+fn test_chunk_small_files_match_fastcdc() {
+    use fastcdc::v2020::FastCDC;
+    use webpub::chunker::{AVG_SIZE, MAX_SIZE, MIN_SIZE};
+    use webpub::{build_tree, Node, ScannedEntry};
+
+    let min = MIN_SIZE as usize;
+    for len in [0, 1, 200, min - 1, min, min + 1] {
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+
+        // Same chunks as running FastCDC directly
+        let expected: Vec<[u8; 32]> = FastCDC::new(&data, MIN_SIZE, AVG_SIZE, MAX_SIZE)
+            .map(|c| *blake3::hash(&data[c.offset..c.offset + c.length]).as_bytes())
+            .collect();
+        let chunks: Vec<Chunk> = chunk_data_default(&data).collect();
+        assert_eq!(
+            chunks.iter().map(|c| c.hash).collect::<Vec<_>>(),
+            expected,
+            "{} bytes",
+            len
+        );
+
+        // ...and the same file hash: BLAKE3 of the concatenated chunk hashes
+        let (tree, _) = build_tree(ScannedEntry::File {
+            name: "style.css".to_string(),
+            permissions: 0o644,
+            size: len as u64,
+            mtime: 0,
+            data: data.clone(),
+        });
+        let mut hasher = blake3::Hasher::new();
+        for hash in &expected {
+            hasher.update(hash);
+        }
+        let Node::File { hash, .. } = tree else {
+            panic!("expected a file node");
+        };
+        assert_eq!(hash, *hasher.finalize().as_bytes(), "{} bytes", len);
+    }
+}
+
+#[test]
+fn test_streaming_chunker_matches_chunk_data() {
+    use std::io::Write;
+    use webpub::chunker::{chunk_data, ChunkConfig, StreamingChunker};
+
+    let config = ChunkConfig::new(1024, 2048, 4096).unwrap();
+    let data: Vec<u8> = (0..100_000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+
+    for len in [0, 100, 1024, 4096, 4097, 50_000, data.len()] {
+        let data = &data[..len];
+        let expected: Vec<[u8; 32]> = chunk_data(data, &config).map(|c| c.hash).collect();
+
+        // Writes of every size, including ones spanning several chunks
+        for write_size in [1, 7, 1000, 4096, 30_000] {
+            let mut hashes = Vec::new();
+            let mut chunker = StreamingChunker::new(config, |chunk| {
+                hashes.push(chunk.hash);
+                Ok(())
+            });
+            for piece in data.chunks(write_size) {
+                chunker.write_all(piece).unwrap();
+            }
+            chunker.finish().unwrap();
+            assert_eq!(
+                hashes, expected,
+                "{} bytes in writes of {}",
+                len, write_size
+            );
+        }
+    }
+}
+
+#[test]
+fn test_streaming_chunker_emits_as_it_goes() {
+    use std::io::{self, Write};
+    use webpub::chunker::{ChunkConfig, StreamingChunker};
+
+    let config = ChunkConfig::new(1024, 2048, 4096).unwrap();
+    let mut emitted = 0;
+    let mut chunker = StreamingChunker::new(config, |chunk| {
+        emitted += chunk.data.len();
+        if emitted > 20_000 {
+            return Err(io::Error::other("sink full"));
+        }
+        Ok(())
+    });
+
+    // Chunks are handed over during writes, and their errors stop the stream
+    let block = vec![0xa5u8; 1000];
+    let mut result = Ok(());
+    for _ in 0..100 {
+        result = chunker.write_all(&block);
+        if result.is_err() {
+            break;
+        }
+    }
+    assert_eq!(result.unwrap_err().to_string(), "sink full");
+}
+
+#[test]
+fn test_chunk_empty_data() {
+    use std::io::Write;
+    use webpub::chunker::{ChunkConfig, StreamingChunker};
+
+    assert_eq!(chunk_data_default(&[]).count(), 0);
+
+    // Nor does an empty stream, with or without empty writes
+    let mut count = 0;
+    let mut chunker = StreamingChunker::new(ChunkConfig::default(), |_| {
+        count += 1;
+        Ok(())
+    });
+    chunker.write_all(&[]).unwrap();
+    chunker.finish().unwrap();
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn test_chunk_fixed_size() {
+    use std::io::Write;
+    use webpub::chunker::{chunk_data, ChunkConfig, ChunkConfigError, StreamingChunker};
+
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let config = ChunkConfig::fixed(4096).unwrap();
+
+    // Blocks of exactly the size, the last one short
+    let chunks: Vec<Chunk> = chunk_data(&data, &config).collect();
+    let sizes: Vec<usize> = chunks.iter().map(|c| c.data.len()).collect();
+    assert_eq!(sizes, vec![4096, 4096, 1808]);
+    for (chunk, block) in chunks.iter().zip(data.chunks(4096)) {
+        assert_eq!(chunk.data, block);
+        assert_eq!(chunk.hash, *blake3::hash(block).as_bytes());
+    }
+    assert_eq!(chunk_data(&[], &config).count(), 0);
+    assert_eq!(chunk_data(&data[..10], &config).count(), 1);
+
+    // The same streamed
+    let expected: Vec<[u8; 32]> = chunks.iter().map(|c| c.hash).collect();
+    let mut streamed = Vec::new();
+    let mut chunker = StreamingChunker::new(config, |chunk| {
+        streamed.push(chunk.hash);
+        Ok(())
+    });
+    for piece in data.chunks(1000) {
+        chunker.write_all(piece).unwrap();
+    }
+    chunker.finish().unwrap();
+    assert_eq!(streamed, expected);
+
+    assert_eq!(ChunkConfig::fixed(1).unwrap().max, 1);
+    assert!(matches!(
+        ChunkConfig::fixed(0),
+        Err(ChunkConfigError::OutOfRange { name: "fixed", .. })
+    ));
+}
@@ -20,20 +20,31 @@ fn test_cli_archive_and_extract() {
     fs::write(source.join("subdir/world.txt"), "World!").unwrap();
 
     // Archive
-    let status = webpub_cmd()
+    let output = webpub_cmd()
         .args([
             "archive",
             source.to_str().unwrap(),
             archive.to_str().unwrap(),
         ])
-        .status()
+        .output()
         .unwrap();
-    assert!(status.success());
+    assert!(output.status.success());
     assert!(archive.exists());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("Files: 12 B, distinct chunks: 12 B (1.00x dedup)"),
+        "{}",
+        stdout
+    );
 
-    // Extract
+    // Extract, checking the archive's checksum first
     let status = webpub_cmd()
-        .args(["extract", archive.to_str().unwrap(), dest.to_str().unwrap()])
+        .args([
+            "extract",
+            "--verify",
+            archive.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ])
         .status()
         .unwrap();
     assert!(status.success());
@@ -48,3 +59,289 @@ fn test_cli_archive_and_extract() {
         "World!"
     );
 }
+
+#[test]
+fn test_cli_token_policy() {
+    let temp = TempDir::new().unwrap();
+    let data = temp.path().join("data");
+    let data = data.to_str().unwrap();
+
+    let output = webpub_cmd()
+        .args(["token", "--data", data, "policy", "--default-ttl", "90d"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap().trim(),
+        "Default token TTL: 90d"
+    );
+
+    let status = webpub_cmd()
+        .args(["token", "--data", data, "add", "--expires-never"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = webpub_cmd()
+        .args(["token", "--data", data, "policy", "--default-ttl", "3x"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_cli_token_ttl() {
+    let temp = TempDir::new().unwrap();
+    let data = temp.path().join("data");
+    let data = data.to_str().unwrap();
+
+    for (label, ttl) in [("hourly", "1h"), ("stale", "0s")] {
+        let status = webpub_cmd()
+            .args([
+                "token", "--data", data, "add", "--label", label, "--ttl", ttl,
+            ])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    let output = webpub_cmd()
+        .args(["token", "--data", data, "list"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let line = |label: &str| {
+        stdout
+            .lines()
+            .find(|line| line.contains(label))
+            .unwrap_or_else(|| panic!("no {} token in {}", label, stdout))
+            .to_string()
+    };
+    assert!(line("hourly").ends_with("expires in 1h"), "{}", stdout);
+    assert!(line("stale").ends_with("expired"), "{}", stdout);
+
+    // A TTL and --expires-never contradict each other
+    let output = webpub_cmd()
+        .args([
+            "token",
+            "--data",
+            data,
+            "add",
+            "--ttl",
+            "1h",
+            "--expires-never",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("cannot be used with"));
+
+    // An unknown unit is rejected
+    let output = webpub_cmd()
+        .args(["token", "--data", data, "add", "--ttl", "1y"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_cli_extract_verify_rejects_damaged_archive() {
+    let temp = TempDir::new().unwrap();
+    let source = temp.path().join("source");
+    let archive = temp.path().join("test.webpub");
+    fs::create_dir(&source).unwrap();
+    fs::write(source.join("hello.txt"), "Hello!").unwrap();
+
+    let status = webpub_cmd()
+        .args([
+            "archive",
+            source.to_str().unwrap(),
+            archive.to_str().unwrap(),
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // Damage the checksum trailer
+    let mut bytes = fs::read(&archive).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&archive, &bytes).unwrap();
+
+    let output = webpub_cmd()
+        .args([
+            "extract",
+            "--verify",
+            archive.to_str().unwrap(),
+            temp.path().join("dest").to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .to_lowercase()
+        .contains("checksum"));
+    assert!(!temp.path().join("dest").exists());
+}
+
+#[test]
+fn test_cli_extract_force() {
+    let temp = TempDir::new().unwrap();
+    let source = temp.path().join("source");
+    let archive = temp.path().join("test.webpub");
+    let dest = temp.path().join("dest");
+    fs::create_dir(&source).unwrap();
+    fs::write(source.join("hello.txt"), "Hello!").unwrap();
+    fs::create_dir(&dest).unwrap();
+    fs::write(dest.join("hello.txt"), "Local").unwrap();
+
+    let status = webpub_cmd()
+        .args([
+            "archive",
+            source.to_str().unwrap(),
+            archive.to_str().unwrap(),
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let extract = |force: bool| {
+        let mut cmd = webpub_cmd();
+        cmd.args(["extract", archive.to_str().unwrap(), dest.to_str().unwrap()]);
+        if force {
+            cmd.arg("--force");
+        }
+        cmd.output().unwrap()
+    };
+
+    let output = extract(false);
+    assert!(!output.status.success());
+    assert_eq!(fs::read_to_string(dest.join("hello.txt")).unwrap(), "Local");
+
+    let output = extract(true);
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(dest.join("hello.txt")).unwrap(),
+        "Hello!"
+    );
+}
+
+#[test]
+fn test_cli_archive_max_file_size() {
+    let temp = TempDir::new().unwrap();
+    let source = temp.path().join("source");
+    let archive = temp.path().join("test.webpub");
+    fs::create_dir(&source).unwrap();
+    fs::write(source.join("index.html"), "Hello!").unwrap();
+    fs::write(source.join("dump.sql"), vec![b'x'; 3000]).unwrap();
+
+    let archive_cmd = |extra: &[&str]| {
+        webpub_cmd()
+            .args([
+                "archive",
+                source.to_str().unwrap(),
+                archive.to_str().unwrap(),
+                "--max-file-size",
+                "2KB",
+            ])
+            .args(extra)
+            .output()
+            .unwrap()
+    };
+
+    let output = archive_cmd(&["--fail-on-large-files"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("/dump.sql is too large"));
+    assert!(!archive.exists());
+
+    let output = archive_cmd(&[]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Skipping /dump.sql: 3000 bytes, over the 2000 byte limit"));
+    let output = webpub_cmd()
+        .args(["list-archive", archive.to_str().unwrap()])
+        .output()
+        .unwrap();
+    let listing = String::from_utf8(output.stdout).unwrap();
+    assert!(listing.contains("index.html"), "{}", listing);
+    assert!(!listing.contains("dump.sql"), "{}", listing);
+
+    let output = webpub_cmd()
+        .args([
+            "archive",
+            source.to_str().unwrap(),
+            archive.to_str().unwrap(),
+            "--max-file-size",
+            "2XB",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown unit"));
+}
+
+#[test]
+fn test_cli_serve_bind_addresses() {
+    use std::io::{BufRead, BufReader};
+    use std::net::{SocketAddr, TcpStream};
+    use std::process::Stdio;
+
+    let temp = TempDir::new().unwrap();
+    let data = temp.path().join("data");
+
+    // A port in the address and a port flag contradict each other
+    let output = webpub_cmd()
+        .args([
+            "serve",
+            "--data",
+            data.to_str().unwrap(),
+            "--http-addr",
+            "127.0.0.1:0",
+            "--http-port",
+            "8080",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--http-port"));
+
+    let mut server = webpub_cmd()
+        .args([
+            "serve",
+            "--data",
+            data.to_str().unwrap(),
+            "--http-addr",
+            "127.0.0.1:0",
+            "--sync-addr",
+            "127.0.0.1",
+            "--sync-port",
+            "0",
+            "--metrics-addr",
+            "127.0.0.1:0",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // Every listener reports the loopback address it bound
+    let mut addrs = Vec::new();
+    let mut lines = BufReader::new(server.stdout.take().unwrap()).lines();
+    while addrs.len() < 3 {
+        let line = lines.next().unwrap().unwrap();
+        if let Some((_, addr)) = line.split_once(" listening on ") {
+            addrs.push(addr.parse::<SocketAddr>().unwrap());
+        }
+    }
+    for addr in &addrs {
+        assert!(addr.ip().is_loopback(), "{}", addr);
+        assert_ne!(addr.port(), 0);
+        TcpStream::connect(addr).unwrap();
+    }
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
@@ -0,0 +1,63 @@
+use axum::body::Bytes;
+use webpub::server::file_cache::FileCache;
+
+fn file(byte: u8, len: usize) -> ([u8; 32], Bytes) {
+    ([byte; 32], Bytes::from(vec![byte; len]))
+}
+
+#[test]
+fn test_evicts_least_recently_used() {
+    let cache = FileCache::new(300);
+    let (a, a_data) = file(1, 100);
+    let (b, b_data) = file(2, 100);
+    let (c, c_data) = file(3, 100);
+    let (d, d_data) = file(4, 100);
+
+    cache.insert(a, a_data.clone());
+    cache.insert(b, b_data);
+    cache.insert(c, c_data);
+    assert_eq!(cache.size(), 300);
+
+    // Using a makes b the oldest
+    assert_eq!(cache.get(&a), Some(a_data));
+    cache.insert(d, d_data);
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.size(), 300);
+    assert!(cache.get(&b).is_none());
+    assert!(cache.get(&a).is_some());
+    assert!(cache.get(&c).is_some());
+    assert!(cache.get(&d).is_some());
+}
+
+#[test]
+fn test_size_bounds() {
+    let cache = FileCache::new(250);
+
+    // Larger than the whole cache: not kept
+    let (big, big_data) = file(1, 251);
+    cache.insert(big, big_data);
+    assert!(cache.is_empty());
+
+    // One large file can push out several small ones
+    for byte in 2..7 {
+        let (hash, data) = file(byte, 50);
+        cache.insert(hash, data);
+    }
+    assert_eq!(cache.size(), 250);
+    let (large, large_data) = file(7, 200);
+    cache.insert(large, large_data);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.size(), 250);
+    assert!(cache.get(&[6; 32]).is_some());
+
+    // Re-inserting a file doesn't count it twice
+    let (same, same_data) = file(7, 200);
+    cache.insert(same, same_data);
+    assert_eq!(cache.size(), 250);
+
+    // A zero-sized cache keeps nothing
+    let disabled = FileCache::new(0);
+    let (hash, data) = file(1, 1);
+    disabled.insert(hash, data);
+    assert!(disabled.is_empty());
+}
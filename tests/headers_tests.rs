@@ -0,0 +1,57 @@
+use axum::http::{header, HeaderMap};
+use webpub::server::headers::{apply_headers, parse_headers};
+
+#[test]
+fn test_parse_headers() {
+    let rules = parse_headers(
+        "# Security headers\n\
+         /*\n\
+         \x20 X-Frame-Options: DENY\n\
+         \x20 Content-Length: 5\n\
+         \x20 not a header\n\
+         \n\
+         \x20 X-Content-Type-Options: nosniff\n\
+         assets/*\n\
+         \x20 X-Ignored: yes\n",
+    );
+    assert_eq!(rules.len(), 1);
+
+    let mut headers = HeaderMap::new();
+    apply_headers(&rules, "/index.html", &mut headers);
+    assert_eq!(headers.len(), 2);
+    assert_eq!(headers["x-frame-options"], "DENY");
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+}
+
+#[test]
+fn test_header_precedence() {
+    let rules = parse_headers(
+        "/*\n\
+         \x20 Cache-Control: no-cache\n\
+         \x20 X-Frame-Options: DENY\n\
+         /assets/*\n\
+         \x20 Cache-Control: public, max-age=31536000\n\
+         \x20 Link: </a.css>; rel=preload\n\
+         \x20 Link: </b.css>; rel=preload\n\
+         /blog/:slug\n\
+         \x20 X-Robots-Tag: noindex\n",
+    );
+
+    // Later rules replace what the server and earlier rules set
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CACHE_CONTROL, "max-age=60".parse().unwrap());
+    apply_headers(&rules, "/assets/app.js", &mut headers);
+    assert_eq!(headers[header::CACHE_CONTROL], "public, max-age=31536000");
+    assert_eq!(headers["x-frame-options"], "DENY");
+    // Repeats within one rule are all sent
+    assert_eq!(headers.get_all(header::LINK).iter().count(), 2);
+
+    let mut headers = HeaderMap::new();
+    apply_headers(&rules, "/blog/hello", &mut headers);
+    assert_eq!(headers["x-robots-tag"], "noindex");
+    assert_eq!(headers[header::CACHE_CONTROL], "no-cache");
+
+    let mut headers = HeaderMap::new();
+    apply_headers(&rules, "/blog/hello/comments", &mut headers);
+    assert!(!headers.contains_key("x-robots-tag"));
+}
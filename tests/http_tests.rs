@@ -1,5 +1,72 @@
-use webpub::server::http::find_node;
-use webpub::Node;
+use axum::body::Body;
+use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+use axum::Router;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tower::ServiceExt;
+use webpub::server::http::{
+    create_router, create_router_with, decode_path, find_node, normalize_path, RouterOptions,
+};
+use webpub::server::metrics::{metrics_router, Metrics};
+use webpub::server::preview_link::preview_path;
+use webpub::server::storage::Storage;
+use webpub::{build_tree, scan_directory, Node};
+
+/// Store a directory as the current snapshot for a host.
+fn publish(storage: &Storage, host: &str, dir: &Path) {
+    let entry = scan_directory(dir).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    for chunk in &chunks {
+        storage.store_chunk(&chunk.hash, &chunk.data).unwrap();
+    }
+    storage.create_snapshot(host, &tree).unwrap();
+}
+
+/// Send a GET request for a host and path, returning status, headers and body.
+async fn get(router: &Router, host: &str, path: &str) -> (StatusCode, HeaderMap, Vec<u8>) {
+    get_with(router, host, path, &[]).await
+}
+
+/// Send a GET request with extra request headers.
+async fn get_with(
+    router: &Router,
+    host: &str,
+    path: &str,
+    headers: &[(header::HeaderName, &str)],
+) -> (StatusCode, HeaderMap, Vec<u8>) {
+    send(router, Method::GET, host, path, headers).await
+}
+
+/// Send a request with the given method and extra request headers.
+async fn send(
+    router: &Router,
+    method: Method,
+    host: &str,
+    path: &str,
+    headers: &[(header::HeaderName, &str)],
+) -> (StatusCode, HeaderMap, Vec<u8>) {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(header::HOST, host);
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+    let response = router
+        .clone()
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap()
+        .to_vec();
+    (status, headers, body)
+}
 
 #[test]
 fn test_find_node_in_tree() {
@@ -13,6 +80,8 @@ fn test_find_node_in_tree() {
                 size: 100,
                 chunks: vec![[1u8; 32]],
                 hash: [2u8; 32],
+                content_hash: [2u8; 32],
+                mtime: 0,
             },
             Node::Directory {
                 name: "css".to_string(),
@@ -23,6 +92,8 @@ fn test_find_node_in_tree() {
                     size: 50,
                     chunks: vec![[3u8; 32]],
                     hash: [4u8; 32],
+                    content_hash: [4u8; 32],
+                    mtime: 0,
                 }],
                 hash: [5u8; 32],
             },
@@ -48,3 +119,1441 @@ fn test_find_node_in_tree() {
     let node = find_node(&tree, "/missing.txt");
     assert!(node.is_none());
 }
+
+#[tokio::test]
+async fn test_csp_nonce_injection() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("webpub.json"), r#"{"csp_nonce": true}"#).unwrap();
+    fs::write(
+        site.join("index.html"),
+        "<html><head><script>var a = 1;</script><style>p{}</style></head>\
+         <body><script nonce=\"fixed\">var b;</script></body></html>",
+    )
+    .unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage);
+
+    let (status, headers, body) = get(&router, "example.com", "/").await;
+    assert_eq!(status, StatusCode::OK);
+
+    let csp = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+    let nonce = csp
+        .split("'nonce-")
+        .nth(1)
+        .and_then(|rest| rest.split('\'').next())
+        .unwrap();
+
+    let html = String::from_utf8(body).unwrap();
+    assert!(html.contains(&format!("<script nonce=\"{}\">var a = 1;", nonce)));
+    assert!(html.contains(&format!("<style nonce=\"{}\">", nonce)));
+    // Existing nonce attributes are left alone
+    assert!(html.contains("<script nonce=\"fixed\">"));
+
+    // Each response gets a fresh nonce
+    let (_, headers, _) = get(&router, "example.com", "/").await;
+    assert_ne!(
+        headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap(),
+        csp
+    );
+}
+
+#[tokio::test]
+async fn test_gzip_only_asset() {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let script = b"console.log('hello from a gzip-only asset');";
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(script).unwrap();
+    let gzipped = encoder.finish().unwrap();
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("app.js.gz"), &gzipped).unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage);
+
+    // Client accepting gzip gets the compressed bytes
+    let (status, headers, body) = get_with(
+        &router,
+        "example.com",
+        "/app.js",
+        &[(header::ACCEPT_ENCODING, "gzip, deflate")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+    assert!(headers[header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .contains("javascript"));
+    assert_eq!(body, gzipped);
+
+    // Client without gzip support gets decompressed content
+    let (status, headers, body) = get(&router, "example.com", "/app.js").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers.get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(body, script);
+
+    // Explicitly refusing gzip also gets decompressed content
+    let (_, headers, body) = get_with(
+        &router,
+        "example.com",
+        "/app.js",
+        &[(header::ACCEPT_ENCODING, "gzip;q=0")],
+    )
+    .await;
+    assert!(headers.get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(body, script);
+}
+
+#[tokio::test]
+async fn test_missing_root_index_message() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("docs")).unwrap();
+    fs::write(site.join("about.html"), "About").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage);
+
+    let (status, _, body) = get(&router, "example.com", "/").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let body = String::from_utf8(body).unwrap();
+    assert!(body.contains("no index.html found at root"), "{}", body);
+    assert!(body.contains("about.html, docs/"), "{}", body);
+
+    // Other missing paths keep the plain message
+    let (status, _, body) = get(&router, "example.com", "/missing.html").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body, b"Not found");
+}
+
+#[tokio::test]
+async fn test_range_requests() {
+    use rand::{Rng, SeedableRng};
+
+    // Large enough to span several chunks
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1011);
+    let video: Vec<u8> = (0..200_000).map(|_| rng.gen()).collect();
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("video.mp4"), &video).unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage);
+
+    // Without a Range header: the whole file, advertising range support
+    let (status, headers, body) = get(&router, "example.com", "/video.mp4").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::ACCEPT_RANGES], "bytes");
+    assert_eq!(body, video);
+
+    let range = |value: &'static str| {
+        let router = router.clone();
+        async move {
+            get_with(
+                &router,
+                "example.com",
+                "/video.mp4",
+                &[(header::RANGE, value)],
+            )
+            .await
+        }
+    };
+
+    // A range crossing chunk boundaries
+    let (status, headers, body) = range("bytes=1000-120000").await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(headers[header::CONTENT_RANGE], "bytes 1000-120000/200000");
+    assert_eq!(body, &video[1000..=120000]);
+
+    // Open-ended and suffix ranges
+    let (_, headers, body) = range("bytes=199990-").await;
+    assert_eq!(headers[header::CONTENT_RANGE], "bytes 199990-199999/200000");
+    assert_eq!(body, &video[199990..]);
+    let (_, _, body) = range("bytes=-10").await;
+    assert_eq!(body, &video[199990..]);
+
+    // Past the end of the file
+    let (status, headers, _) = range("bytes=200000-").await;
+    assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(headers[header::CONTENT_RANGE], "bytes */200000");
+
+    // Multiple ranges fall back to the whole file
+    let (status, _, body) = range("bytes=0-1,5-6").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, video);
+}
+
+#[tokio::test]
+async fn test_head_requests() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("photo.png"), vec![7u8; 100_000]).unwrap();
+    fs::write(site.join("app.js"), "console.log('hello');\n".repeat(200)).unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage.clone());
+
+    // Same headers as GET, with the file's size and no body
+    let (_, get_headers, _) = get(&router, "example.com", "/photo.png").await;
+    let (status, headers, body) =
+        send(&router, Method::HEAD, "example.com", "/photo.png", &[]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.is_empty());
+    assert_eq!(headers[header::CONTENT_LENGTH], "100000");
+    for name in [header::CONTENT_TYPE, header::ETAG, header::ACCEPT_RANGES] {
+        assert_eq!(headers[&name], get_headers[&name]);
+    }
+
+    // Conditional and range requests resolve as they do for GET
+    let etag = get_headers[header::ETAG].to_str().unwrap();
+    let (status, _, _) = send(
+        &router,
+        Method::HEAD,
+        "example.com",
+        "/photo.png",
+        &[(header::IF_NONE_MATCH, etag)],
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_MODIFIED);
+    let (status, headers, _) = send(
+        &router,
+        Method::HEAD,
+        "example.com",
+        "/photo.png",
+        &[(header::RANGE, "bytes=0-99")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(headers[header::CONTENT_LENGTH], "100");
+    assert_eq!(headers[header::CONTENT_RANGE], "bytes 0-99/100000");
+
+    // A compressed body's length isn't known without building it
+    let (status, headers, _) = send(
+        &router,
+        Method::HEAD,
+        "example.com",
+        "/app.js",
+        &[(header::ACCEPT_ENCODING, "gzip")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+    assert!(!headers.contains_key(header::CONTENT_LENGTH));
+
+    let (status, _, _) = send(&router, Method::HEAD, "example.com", "/missing.png", &[]).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_head_does_not_read_chunks() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("photo.png"), vec![7u8; 100_000]).unwrap();
+
+    // Publish the tree without storing any of its chunks
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, _) = build_tree(entry);
+    storage.create_snapshot("example.com", &tree).unwrap();
+    let router = create_router(storage);
+
+    let (status, headers, _) = send(&router, Method::HEAD, "example.com", "/photo.png", &[]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_LENGTH], "100000");
+
+    let (status, _, body) = get(&router, "example.com", "/photo.png").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body, b"Internal Server Error");
+}
+
+#[tokio::test]
+async fn test_storage_errors_are_not_exposed() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Home</h1>").unwrap();
+
+    let data = temp.path().join("data");
+    let storage = Arc::new(Storage::open(&data).unwrap());
+    publish(&storage, "example.com", &site);
+    let options = RouterOptions {
+        health_host: Some("health.internal".to_string()),
+        ..Default::default()
+    };
+    let router = create_router_with(storage, options);
+
+    // Break the index behind the server's back
+    let conn = rusqlite::Connection::open(data.join("index.db")).unwrap();
+    conn.execute_batch("ALTER TABLE snapshots RENAME TO broken")
+        .unwrap();
+
+    let (status, _, body) = get(&router, "example.com", "/").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body, b"Internal Server Error");
+
+    let (status, _, body) = get(&router, "health.internal", "/healthz").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body, b"Service Unavailable");
+}
+
+#[tokio::test]
+async fn test_snapshot_preview() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("docs")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Draft</h1>").unwrap();
+    fs::write(site.join("docs/a b.html"), "Draft docs").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    fs::write(site.join("index.html"), "<h1>Live</h1>").unwrap();
+    publish(&storage, "example.com", &site);
+    let draft = storage
+        .list_snapshots("example.com")
+        .unwrap()
+        .into_iter()
+        .find(|(_, current, _, _)| !current)
+        .unwrap()
+        .0;
+
+    let secret = storage.preview_secret().unwrap();
+    assert_eq!(storage.preview_secret().unwrap(), secret);
+    let link = preview_path(&secret, "example.com", draft);
+
+    // Without previews enabled the prefix is ordinary content
+    let router = create_router(storage.clone());
+    let (status, headers, _) = get(&router, "example.com", &link).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(headers.get(header::SET_COOKIE).is_none());
+
+    let options = RouterOptions {
+        preview_secret: Some(secret),
+        ..Default::default()
+    };
+    let router = create_router_with(storage.clone(), options);
+
+    // The link sets the cookie and redirects into the site
+    let (status, headers, _) =
+        get(&router, "example.com", &format!("{}docs/a%20b.html", link)).await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(headers[header::LOCATION], "/docs/a%20b.html");
+    let set_cookie = headers[header::SET_COOKIE].to_str().unwrap();
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    assert!(cookie.starts_with("webpub_preview="), "{}", set_cookie);
+    assert!(set_cookie.contains("HttpOnly"), "{}", set_cookie);
+
+    // With the cookie the site is served from the previewed snapshot
+    let (status, headers, body) =
+        get_with(&router, "example.com", "/", &[(header::COOKIE, &cookie)]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"<h1>Draft</h1>");
+    assert_eq!(headers[header::CACHE_CONTROL], "private, no-store");
+    assert_eq!(headers["x-webpub-preview"], draft.to_string().as_str());
+    let (_, _, body) = get(&router, "example.com", "/").await;
+    assert_eq!(body, b"<h1>Live</h1>");
+
+    // Keys are bound to their host and can't be forged from an ID
+    let (status, _, _) = get(&router, "other.com", &link).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let forged = format!("{}-{}", draft, "0".repeat(64));
+    let (status, _, _) = get(&router, "example.com", &format!("/_preview/{}/", forged)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let forged_cookie = format!("webpub_preview={}", forged);
+    let (_, _, body) = get_with(
+        &router,
+        "example.com",
+        "/",
+        &[(header::COOKIE, &forged_cookie)],
+    )
+    .await;
+    assert_eq!(body, b"<h1>Live</h1>");
+
+    // Exiting clears the cookie
+    let (status, headers, _) = get(&router, "example.com", "/_preview/exit").await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(headers[header::LOCATION], "/");
+    assert!(headers[header::SET_COOKIE]
+        .to_str()
+        .unwrap()
+        .contains("Max-Age=0"));
+
+    // A deleted snapshot's link stops working
+    storage.delete_snapshot(draft).unwrap();
+    let (status, _, _) = get(&router, "example.com", &link).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, _, body) = get_with(&router, "example.com", "/", &[(header::COOKIE, &cookie)]).await;
+    assert_eq!(body, b"<h1>Live</h1>");
+}
+
+#[tokio::test]
+async fn test_response_compression() {
+    use std::io::Read;
+
+    let css = "body { margin: 0; padding: 0; }\n".repeat(100);
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("style.css"), &css).unwrap();
+    fs::write(site.join("small.css"), "a {}").unwrap();
+    fs::write(site.join("font.woff2"), css.as_bytes()).unwrap();
+    fs::write(site.join("photo.png"), css.as_bytes()).unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage);
+    let get_encoded = |path: &'static str, accept: &'static str| {
+        let router = router.clone();
+        async move {
+            get_with(
+                &router,
+                "example.com",
+                path,
+                &[(header::ACCEPT_ENCODING, accept)],
+            )
+            .await
+        }
+    };
+
+    // Brotli is preferred when accepted
+    let (status, headers, body) = get_encoded("/style.css", "gzip, deflate, br").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_ENCODING], "br");
+    assert_eq!(headers[header::VARY], "accept-encoding");
+    assert!(body.len() < css.len());
+    let mut decoded = String::new();
+    brotli::Decompressor::new(body.as_slice(), 4096)
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, css);
+
+    // Then gzip
+    let (_, headers, body) = get_encoded("/style.css", "gzip").await;
+    assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(body.as_slice())
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, css);
+
+    // No acceptable encoding
+    let (_, headers, body) = get_encoded("/style.css", "br;q=0, identity").await;
+    assert!(headers.get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(body, css.as_bytes());
+
+    // Small bodies and already-compressed types are sent as-is
+    for path in ["/small.css", "/font.woff2", "/photo.png"] {
+        let (status, headers, _) = get_encoded(path, "br, gzip").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.get(header::CONTENT_ENCODING).is_none(), "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn test_mime_sniffing() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("about"), "<!DOCTYPE html><h1>About</h1>").unwrap();
+    fs::write(site.join("data"), r#"{"items": [1, 2]}"#).unwrap();
+    fs::write(site.join("blob"), [0u8, 1, 2, 3]).unwrap();
+
+    // Off by default
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage.clone());
+    let (_, headers, _) = get(&router, "example.com", "/about").await;
+    assert_eq!(headers[header::CONTENT_TYPE], "application/octet-stream");
+
+    fs::write(site.join("webpub.json"), r#"{"sniff_mime": true}"#).unwrap();
+    publish(&storage, "example.com", &site);
+
+    let (status, headers, body) = get(&router, "example.com", "/about").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+    assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    assert_eq!(body, b"<!DOCTYPE html><h1>About</h1>");
+
+    let (_, headers, _) = get(&router, "example.com", "/data").await;
+    assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+
+    // Unrecognized content stays octet-stream
+    let (_, headers, _) = get(&router, "example.com", "/blob").await;
+    assert_eq!(headers[header::CONTENT_TYPE], "application/octet-stream");
+    assert!(headers.get(header::X_CONTENT_TYPE_OPTIONS).is_none());
+}
+
+#[test]
+fn test_sniff_content_type() {
+    use webpub::server::sniff::sniff_content_type;
+
+    assert_eq!(
+        sniff_content_type(b"\xef\xbb\xbf  <html lang=en>", true),
+        Some("text/html")
+    );
+    assert_eq!(
+        sniff_content_type(b"\x89PNG\r\n\x1a\n\0\0", true),
+        Some("image/png")
+    );
+    assert_eq!(
+        sniff_content_type(b"<svg xmlns=", true),
+        Some("image/svg+xml")
+    );
+    assert_eq!(sniff_content_type(b"hello\n", true), Some("text/plain"));
+
+    // A truncated JSON sample is still JSON; a complete invalid one is text
+    assert_eq!(
+        sniff_content_type(b"{\"a\": [1,", false),
+        Some("application/json")
+    );
+    assert_eq!(sniff_content_type(b"{\"a\": [1,", true), Some("text/plain"));
+
+    // A sample may end mid-character unless it is the whole file
+    let cut = &"caf\u{e9}".as_bytes()[..4];
+    assert_eq!(sniff_content_type(cut, false), Some("text/plain"));
+    assert_eq!(sniff_content_type(cut, true), None);
+    assert_eq!(sniff_content_type(&[0, 159, 146, 150], true), None);
+}
+
+#[tokio::test]
+async fn test_etag_and_conditional_requests() {
+    let css = "body { margin: 0; padding: 0; }\n".repeat(100);
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    fs::write(site.join("style.css"), &css).unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage.clone());
+
+    let (status, headers, _) = get(&router, "example.com", "/index.html").await;
+    assert_eq!(status, StatusCode::OK);
+    let etag = headers[header::ETAG].to_str().unwrap().to_string();
+    let (_, tree) = storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .unwrap();
+    let hash = hex::encode(find_node(&tree, "/index.html").unwrap().hash());
+    assert_eq!(etag, format!("\"{}\"", hash));
+
+    // A matching If-None-Match gets 304 with no body
+    for value in [etag.as_str(), "\"other\", W/\"x\"", "*"] {
+        let value = value.replace("x", &hash);
+        let (status, headers, body) = get_with(
+            &router,
+            "example.com",
+            "/index.html",
+            &[(header::IF_NONE_MATCH, &value)],
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED, "{}", value);
+        assert_eq!(headers[header::ETAG], etag.as_str());
+        assert!(body.is_empty());
+    }
+    let (status, _, _) = get_with(
+        &router,
+        "example.com",
+        "/index.html",
+        &[(header::IF_NONE_MATCH, "\"stale\"")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // The ETag only depends on content, so it survives a restart
+    let router = create_router(Arc::new(Storage::open(&temp.path().join("data")).unwrap()));
+    let (_, headers, _) = get(&router, "example.com", "/index.html").await;
+    assert_eq!(headers[header::ETAG], etag.as_str());
+
+    // Compressed bodies are different bytes and get their own ETag
+    let (_, plain, _) = get(&router, "example.com", "/style.css").await;
+    let (_, brotli, _) = get_with(
+        &router,
+        "example.com",
+        "/style.css",
+        &[(header::ACCEPT_ENCODING, "br")],
+    )
+    .await;
+    assert_ne!(plain[header::ETAG], brotli[header::ETAG]);
+    let (status, _, _) = get_with(
+        &router,
+        "example.com",
+        "/style.css",
+        &[
+            (header::ACCEPT_ENCODING, "br"),
+            (header::IF_NONE_MATCH, plain[header::ETAG].to_str().unwrap()),
+        ],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_cache_control_headers() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("assets")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    fs::write(site.join("assets/app.3f2a9c1b.js"), "let a = 1;").unwrap();
+    fs::write(site.join("robots.txt"), "User-agent: *").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+
+    // The same site again, with a rule for text files
+    fs::write(
+        site.join("webpub.json"),
+        r#"{"cache_control": [{"glob": "*.txt", "value": "public, max-age=60"}]}"#,
+    )
+    .unwrap();
+    publish(&storage, "configured.com", &site);
+    let router = create_router(storage);
+
+    let cache = |host: &'static str, path: &'static str| {
+        let router = router.clone();
+        async move {
+            let (status, headers, _) = get(&router, host, path).await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+            headers
+                .get(header::CACHE_CONTROL)
+                .map(|value| value.to_str().unwrap().to_string())
+        }
+    };
+
+    // Defaults: revalidate HTML, cache fingerprinted assets for good
+    assert_eq!(cache("example.com", "/").await.as_deref(), Some("no-cache"));
+    assert_eq!(
+        cache("example.com", "/assets/app.3f2a9c1b.js")
+            .await
+            .as_deref(),
+        Some("public, max-age=31536000, immutable")
+    );
+    assert_eq!(cache("example.com", "/robots.txt").await, None);
+
+    // Configured rules come first, defaults still cover the rest
+    assert_eq!(
+        cache("configured.com", "/robots.txt").await.as_deref(),
+        Some("public, max-age=60")
+    );
+    assert_eq!(
+        cache("configured.com", "/index.html").await.as_deref(),
+        Some("no-cache")
+    );
+}
+
+#[tokio::test]
+async fn test_redirects_file() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    fs::write(site.join("old.html"), "<h1>Old</h1>").unwrap();
+    fs::write(
+        site.join("_redirects"),
+        "/old.html /index.html\n/blog/:slug /posts/:slug 302\n",
+    )
+    .unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage);
+
+    // Redirects win over files that exist
+    let (status, headers, _) = get(&router, "example.com", "/old.html").await;
+    assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(headers[header::LOCATION], "/index.html");
+
+    let (status, headers, _) = get(&router, "example.com", "/blog/hello").await;
+    assert_eq!(status, StatusCode::FOUND);
+    assert_eq!(headers[header::LOCATION], "/posts/hello");
+
+    let (status, _, _) = get(&router, "example.com", "/").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_clean_urls() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("docs")).unwrap();
+    fs::write(site.join("about.html"), "<h1>About</h1>").unwrap();
+    fs::write(site.join("docs/index.html"), "<h1>Docs</h1>").unwrap();
+
+    // Off by default
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage.clone());
+    let (status, _, _) = get(&router, "example.com", "/about").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    fs::write(site.join("webpub.json"), r#"{"clean_urls": true}"#).unwrap();
+    publish(&storage, "example.com", &site);
+
+    // Served in place, without a redirect
+    for path in ["/about", "/about/"] {
+        let (status, headers, body) = get(&router, "example.com", path).await;
+        assert_eq!(status, StatusCode::OK, "{}", path);
+        assert!(!headers.contains_key(header::LOCATION));
+        assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+        assert_eq!(body, b"<h1>About</h1>");
+    }
+    let (_, _, body) = get(&router, "example.com", "/docs").await;
+    assert_eq!(body, b"<h1>Docs</h1>");
+
+    // Paths with an extension aren't rewritten
+    for path in ["/about.htm", "/contact"] {
+        let (status, _, _) = get(&router, "example.com", path).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn test_cors() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("data.json"), r#"{"a": 1}"#).unwrap();
+
+    // Off by default: no headers, and no preflight
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage.clone());
+    let origin = [(header::ORIGIN, "https://app.example.org")];
+    let (_, headers, _) = get_with(&router, "example.com", "/data.json", &origin).await;
+    assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    let (status, _, _) = send(
+        &router,
+        Method::OPTIONS,
+        "example.com",
+        "/data.json",
+        &origin,
+    )
+    .await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+
+    fs::write(
+        site.join("webpub.json"),
+        r#"{"cors": {
+            "origins": ["https://app.example.org"],
+            "methods": ["GET", "HEAD", "OPTIONS"],
+            "headers": ["Content-Type"],
+            "max_age": 600
+        }}"#,
+    )
+    .unwrap();
+    publish(&storage, "example.com", &site);
+
+    let (status, headers, _) = get_with(&router, "example.com", "/data.json", &origin).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://app.example.org"
+    );
+    assert!(headers
+        .get_all(header::VARY)
+        .iter()
+        .any(|value| value == "origin"));
+
+    let (status, headers, body) = send(
+        &router,
+        Method::OPTIONS,
+        "example.com",
+        "/data.json",
+        &origin,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(body.is_empty());
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+        "GET, HEAD, OPTIONS"
+    );
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+        "Content-Type"
+    );
+    assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+    // Other origins get nothing
+    let other = [(header::ORIGIN, "https://evil.example")];
+    let (_, headers, _) = get_with(&router, "example.com", "/data.json", &other).await;
+    assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    let (status, headers, _) = send(
+        &router,
+        Method::OPTIONS,
+        "example.com",
+        "/data.json",
+        &other,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+
+    // A wildcard allows every origin
+    fs::write(site.join("webpub.json"), r#"{"cors": {"origins": ["*"]}}"#).unwrap();
+    publish(&storage, "example.com", &site);
+    let (_, headers, _) = get_with(&router, "example.com", "/data.json", &other).await;
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+}
+
+#[tokio::test]
+async fn test_headers_file() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    fs::write(
+        site.join("_headers"),
+        "/*\n  X-Frame-Options: DENY\n  Cache-Control: max-age=60\n",
+    )
+    .unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage);
+
+    // Custom headers replace the server's own, and reach 404s too
+    let (status, headers, _) = get(&router, "example.com", "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+    assert_eq!(headers["x-frame-options"], "DENY");
+    assert_eq!(headers[header::CACHE_CONTROL], "max-age=60");
+
+    let (status, headers, _) = get(&router, "example.com", "/missing").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(headers["x-frame-options"], "DENY");
+}
+
+#[tokio::test]
+async fn test_health_endpoint() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("healthz"), "site content").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let options = RouterOptions {
+        health_host: Some("health.internal".to_string()),
+        ..Default::default()
+    };
+    let router = create_router_with(storage, options);
+
+    // Without a Host header, and on the health host
+    let request = Request::builder()
+        .uri("/healthz")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let health: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(health["status"], "ok");
+    assert_eq!(health["sites"], 1);
+    assert!(health["uptime_seconds"].is_u64());
+
+    let (status, headers, _) = get(&router, "health.internal:8080", "/healthz").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+
+    // Sites keep their own /healthz
+    let (status, _, body) = get(&router, "example.com", "/healthz").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"site content");
+    let (status, _, _) = get(&router, "other.com", "/healthz").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let metrics = Arc::new(Metrics::default());
+    let options = RouterOptions {
+        metrics: metrics.clone(),
+        ..Default::default()
+    };
+    let router = create_router_with(storage.clone(), options);
+
+    let mut sent = 0;
+    for path in ["/", "/", "/missing"] {
+        let (_, _, body) = get(&router, "example.com", path).await;
+        sent += body.len();
+    }
+    metrics.record_commit("example.com");
+
+    let (status, headers, body) = get(
+        &metrics_router(metrics, storage.clone()),
+        "admin",
+        "/metrics",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers[header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    let text = String::from_utf8(body).unwrap();
+    assert!(text.contains("# TYPE webpub_http_requests_total counter"));
+    assert!(text.contains("webpub_http_requests_total{status=\"200\"} 2\n"));
+    assert!(text.contains("webpub_http_requests_total{status=\"404\"} 1\n"));
+    assert!(text.contains(&format!("webpub_http_bytes_total {}\n", sent)));
+    assert!(text.contains(&format!(
+        "webpub_chunk_reads_total {}\n",
+        storage.chunk_reads()
+    )));
+    assert!(text.contains("webpub_sync_commits_total{hostname=\"example.com\"} 1\n"));
+}
+
+#[tokio::test]
+async fn test_file_cache() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    let video = vec![9u8; 200_000];
+    fs::write(site.join("video.mp4"), &video).unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let metrics = Arc::new(Metrics::default());
+    let options = RouterOptions {
+        metrics: metrics.clone(),
+        ..Default::default()
+    };
+    let router = create_router_with(storage.clone(), options);
+
+    // The first request reads chunks; repeats and ranges don't
+    let (_, _, body) = get(&router, "example.com", "/video.mp4").await;
+    assert_eq!(body, video);
+    let reads = storage.chunk_reads();
+    assert!(reads > 0);
+    let (_, _, body) = get(&router, "example.com", "/video.mp4").await;
+    assert_eq!(body, video);
+    let (status, _, body) = get_with(
+        &router,
+        "example.com",
+        "/video.mp4",
+        &[(header::RANGE, "bytes=100-199")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body, &video[100..200]);
+    assert_eq!(storage.chunk_reads(), reads);
+
+    let text = metrics.render(&storage);
+    assert!(text.contains("webpub_file_cache_hits_total 2\n"));
+    assert!(text.contains("webpub_file_cache_misses_total 1\n"));
+
+    // Without a cache every request reads chunks
+    let options = RouterOptions {
+        cache_size: 0,
+        ..Default::default()
+    };
+    let router = create_router_with(storage.clone(), options);
+    get(&router, "example.com", "/video.mp4").await;
+    let reads = storage.chunk_reads();
+    get(&router, "example.com", "/video.mp4").await;
+    assert!(storage.chunk_reads() > reads);
+}
+
+#[tokio::test]
+async fn test_spa_fallback() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("assets")).unwrap();
+    fs::write(site.join("index.html"), "<div id=app></div>").unwrap();
+    fs::write(site.join("assets/app.js"), "render()").unwrap();
+
+    // Off by default
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage.clone());
+    let (status, _, _) = get(&router, "example.com", "/app/settings").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    fs::write(site.join("webpub.json"), r#"{"spa_fallback": true}"#).unwrap();
+    publish(&storage, "example.com", &site);
+
+    for path in ["/app/settings", "/app/settings/", "/login"] {
+        let (status, headers, body) = get(&router, "example.com", path).await;
+        assert_eq!(status, StatusCode::OK, "{}", path);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+        assert_eq!(body, b"<div id=app></div>");
+    }
+
+    // Existing files are still served, and missing assets still 404
+    let (_, _, body) = get(&router, "example.com", "/assets/app.js").await;
+    assert_eq!(body, b"render()");
+    let (status, _, _) = get(&router, "example.com", "/assets/missing.js").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_custom_404_page() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("docs")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Home</h1>").unwrap();
+    fs::write(site.join("docs/guide.html"), "<h1>Guide</h1>").unwrap();
+
+    // Without a 404.html, plain text as before
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage.clone());
+    let (status, _, body) = get(&router, "example.com", "/missing").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body, b"Not found");
+
+    fs::write(site.join("404.html"), "<h1>Lost?</h1>").unwrap();
+    publish(&storage, "example.com", &site);
+
+    // Both a missing path and a directory without index.html
+    for path in ["/missing", "/docs/missing.html", "/docs/"] {
+        let (status, headers, body) = get(&router, "example.com", path).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", path);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+        assert_eq!(body, b"<h1>Lost?</h1>");
+    }
+}
+
+#[tokio::test]
+async fn test_autoindex() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("docs/api ref")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Home</h1>").unwrap();
+    fs::write(site.join("docs/a&b <1>.txt"), "12345").unwrap();
+
+    // Off by default
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage.clone());
+    let (status, _, _) = get(&router, "example.com", "/docs/").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    fs::write(site.join("webpub.json"), r#"{"autoindex": true}"#).unwrap();
+    publish(&storage, "example.com", &site);
+
+    let (status, headers, body) = get(&router, "example.com", "/docs/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers[header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let html = String::from_utf8(body).unwrap();
+    assert!(html.contains("Index of /docs/"), "{}", html);
+    assert!(html.contains("<a href=\"../\">"), "{}", html);
+    assert!(
+        html.contains("<a href=\"a%26b%20%3C1%3E.txt\">a&amp;b &lt;1&gt;.txt</a></td><td>5</td>"),
+        "{}",
+        html
+    );
+    assert!(
+        html.contains("<a href=\"api%20ref/\">api ref/</a>"),
+        "{}",
+        html
+    );
+
+    // Without a trailing slash, links include the directory name
+    let (_, _, body) = get(&router, "example.com", "/docs").await;
+    let html = String::from_utf8(body).unwrap();
+    assert!(html.contains("<a href=\"docs/api%20ref/\">"), "{}", html);
+
+    // Directories with an index.html are unaffected
+    let (_, _, body) = get(&router, "example.com", "/").await;
+    assert_eq!(body, b"<h1>Home</h1>");
+}
+
+#[tokio::test]
+async fn test_serve_without_loading_tree() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("docs")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Home</h1>").unwrap();
+    fs::write(site.join("docs/index.html"), "<h1>Docs</h1>").unwrap();
+    fs::write(site.join("docs/guide.txt"), "guide").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage.clone());
+
+    let (status, _, body) = get(&router, "example.com", "/docs/guide.txt").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"guide");
+    let (_, _, body) = get(&router, "example.com", "/docs/").await;
+    assert_eq!(body, b"<h1>Docs</h1>");
+    let (status, _, _) = get(&router, "example.com", "/docs/missing.txt").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    assert_eq!(storage.tree_loads(), 0);
+}
+
+/// Build a flat tree of `count` files sharing one chunk.
+fn large_tree(count: usize, chunk: [u8; 32]) -> Node {
+    let children = (0..count)
+        .map(|i| Node::File {
+            name: format!("page-{}.html", i),
+            permissions: 0o644,
+            size: 4,
+            chunks: vec![chunk],
+            hash: chunk,
+            content_hash: chunk,
+            mtime: 0,
+        })
+        .collect();
+    Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children,
+        hash: [0u8; 32],
+    }
+}
+
+#[tokio::test]
+#[ignore] // Benchmark; run with --ignored --nocapture
+async fn bench_serve_large_tree() {
+    use std::time::Instant;
+
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+    let chunk = *blake3::hash(b"page").as_bytes();
+    storage.store_chunk(&chunk, b"page").unwrap();
+    storage
+        .create_snapshot("example.com", &large_tree(20_000, chunk))
+        .unwrap();
+    let router = create_router(storage.clone());
+
+    let requests = 1000;
+    let start = Instant::now();
+    for i in 0..requests {
+        let path = format!("/page-{}.html", i * 37 % 20_000);
+        let (status, _, _) = get(&router, "example.com", &path).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let by_path = start.elapsed() / requests;
+
+    // The previous approach: deserialize the tree and walk it per request
+    let loads = 10;
+    let start = Instant::now();
+    for i in 0..loads {
+        let (_, tree) = storage
+            .get_current_snapshot("example.com")
+            .unwrap()
+            .unwrap();
+        let path = format!("/page-{}.html", i * 37 % 20_000);
+        assert!(find_node(&tree, &path).is_some());
+    }
+    let by_tree = start.elapsed() / loads;
+
+    println!(
+        "20000-file site, per request: path lookup {:?}, tree load {:?}",
+        by_path, by_tree
+    );
+}
+
+#[tokio::test]
+async fn test_deleted_site_not_found() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(&site).unwrap();
+    fs::write(site.join("index.html"), "Hello").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage.clone());
+
+    let (status, _, body) = get(&router, "example.com", "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"Hello");
+
+    assert!(storage.delete_site("example.com").unwrap());
+    let (status, _, _) = get(&router, "example.com", "/").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_content_length() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("photo.png"), vec![7u8; 100_000]).unwrap();
+    fs::write(site.join("app.js"), "console.log('hello');\n".repeat(200)).unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage.clone());
+
+    let (status, headers, body) = get(&router, "example.com", "/photo.png").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_LENGTH], "100000");
+    assert_eq!(body.len(), 100_000);
+    let (_, headers, body) = get_with(
+        &router,
+        "example.com",
+        "/photo.png",
+        &[(header::RANGE, "bytes=0-99")],
+    )
+    .await;
+    assert_eq!(headers[header::CONTENT_LENGTH], "100");
+    assert_eq!(body.len(), 100);
+
+    // Transformed bodies are measured once built
+    let (_, headers, body) = get_with(
+        &router,
+        "example.com",
+        "/app.js",
+        &[(header::ACCEPT_ENCODING, "gzip")],
+    )
+    .await;
+    assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+    assert_eq!(headers[header::CONTENT_LENGTH], body.len().to_string());
+
+    // A file whose chunks don't add up to its recorded size is corrupt
+    let data = b"short";
+    let hash = *blake3::hash(data).as_bytes();
+    storage.store_chunk(&hash, data).unwrap();
+    let tree = Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![Node::File {
+            name: "broken.txt".to_string(),
+            permissions: 0o644,
+            size: 10,
+            chunks: vec![hash],
+            hash,
+            content_hash: hash,
+            mtime: 0,
+        }],
+        hash: [0u8; 32],
+    };
+    storage.create_snapshot("broken.com", &tree).unwrap();
+    for _ in 0..2 {
+        let (status, _, body) = get(&router, "broken.com", "/broken.txt").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body, b"File size mismatch");
+    }
+}
+
+#[tokio::test]
+async fn test_method_not_allowed() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    let api = temp.path().join("api");
+    fs::create_dir(&api).unwrap();
+    fs::write(api.join("data.json"), r#"{"a": 1}"#).unwrap();
+    fs::write(api.join("webpub.json"), r#"{"cors": {"origins": ["*"]}}"#).unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    publish(&storage, "api.example.com", &api);
+    let router = create_router(storage);
+
+    for method in [Method::POST, Method::PUT, Method::DELETE, Method::PATCH] {
+        for path in ["/", "/index.html", "/missing"] {
+            let (status, headers, body) =
+                send(&router, method.clone(), "example.com", path, &[]).await;
+            assert_eq!(
+                status,
+                StatusCode::METHOD_NOT_ALLOWED,
+                "{} {}",
+                method,
+                path
+            );
+            assert_eq!(headers[header::ALLOW], "GET, HEAD");
+            assert!(body.is_empty());
+        }
+    }
+
+    // Without cors, OPTIONS isn't allowed either
+    let (status, headers, _) = send(&router, Method::OPTIONS, "example.com", "/", &[]).await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(headers[header::ALLOW], "GET, HEAD");
+
+    // Sites with cors answer preflights
+    let (status, headers, _) =
+        send(&router, Method::POST, "api.example.com", "/data.json", &[]).await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(headers[header::ALLOW], "GET, HEAD, OPTIONS");
+}
+
+#[test]
+fn test_normalize_path() {
+    for (path, normalized) in [
+        ("/", "/"),
+        ("/index.html", "/index.html"),
+        ("//css///style.css", "/css/style.css"),
+        ("/css/", "/css/"),
+        ("/./css/./style.css", "/css/style.css"),
+        ("/css/../index.html", "/index.html"),
+        ("/css/..", "/"),
+        ("/docs/guide/..", "/docs/"),
+        ("/docs/.", "/docs/"),
+        ("/a/b/../../c", "/c"),
+    ] {
+        assert_eq!(
+            normalize_path(path).as_deref(),
+            Some(normalized),
+            "{}",
+            path
+        );
+    }
+    for path in ["/..", "/../etc/passwd", "/css/../../secret", "/a/./../.."] {
+        assert_eq!(normalize_path(path), None, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn test_encoded_and_traversal_paths() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("css")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Home</h1>").unwrap();
+    fs::write(site.join("my file.txt"), "spaced").unwrap();
+    fs::write(site.join("css/style.css"), "body {}").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage);
+
+    for (path, body) in [
+        ("/my%20file.txt", &b"spaced"[..]),
+        ("/css/../my%20file.txt", b"spaced"),
+        ("/css/%2e%2e/index.html", b"<h1>Home</h1>"),
+        ("/./css/./style.css", b"body {}"),
+        ("/css/..", b"<h1>Home</h1>"),
+    ] {
+        let (status, _, got) = get(&router, "example.com", path).await;
+        assert_eq!(status, StatusCode::OK, "{}", path);
+        assert_eq!(got, body, "{}", path);
+    }
+
+    for path in [
+        "/..",
+        "/css/../../secret",
+        "/%2e%2e/etc/passwd",
+        "/css/%2e%2e/%2e%2e/x",
+    ] {
+        let (status, _, _) = get(&router, "example.com", path).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+    }
+}
+
+#[test]
+fn test_decode_path() {
+    for (path, decoded) in [
+        ("/index.html", "/index.html"),
+        ("/my%20file.txt", "/my file.txt"),
+        ("/caf%C3%A9.txt", "/caf\u{e9}.txt"),
+        ("/caf%c3%a9.txt", "/caf\u{e9}.txt"),
+        ("/a+b.txt", "/a+b.txt"),
+        ("/100%25.txt", "/100%.txt"),
+        ("/%2e%2e/x", "/../x"),
+    ] {
+        assert_eq!(decode_path(path).as_deref(), Some(decoded), "{}", path);
+    }
+    for path in ["/%", "/%2", "/%zz", "/100%.txt", "/%ff", "/%C3", "/%+1"] {
+        assert_eq!(decode_path(path), None, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn test_percent_encoded_names() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("d\u{e9}j\u{e0} vu")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Home</h1>").unwrap();
+    fs::write(site.join("caf\u{e9}.txt"), "coffee").unwrap();
+    fs::write(site.join("\u{65e5}\u{672c}.txt"), "japan").unwrap();
+    fs::write(site.join("a+b.txt"), "plus").unwrap();
+    fs::write(site.join("d\u{e9}j\u{e0} vu/notes.txt"), "again").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage);
+
+    for (path, body) in [
+        ("/caf%C3%A9.txt", &b"coffee"[..]),
+        ("/%E6%97%A5%E6%9C%AC.txt", b"japan"),
+        ("/a+b.txt", b"plus"),
+        ("/a%2Bb.txt", b"plus"),
+        ("/d%C3%A9j%C3%A0%20vu/notes.txt", b"again"),
+    ] {
+        let (status, _, got) = get(&router, "example.com", path).await;
+        assert_eq!(status, StatusCode::OK, "{}", path);
+        assert_eq!(got, body, "{}", path);
+    }
+
+    // Invalid encodings are rejected rather than served literally or as root
+    for path in ["/%zz", "/caf%C3.txt", "/%ff", "/100%.txt", "/%"] {
+        let (status, _, got) = get(&router, "example.com", path).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+        assert_eq!(got, b"Invalid percent-encoding in path", "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn test_empty_file() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("empty.txt"), "").unwrap();
+    fs::write(site.join("empty.css"), "").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    publish(&storage, "example.com", &site);
+    let router = create_router(storage);
+
+    for path in ["/empty.txt", "/empty.css"] {
+        let (status, headers, body) = get_with(
+            &router,
+            "example.com",
+            path,
+            &[(header::ACCEPT_ENCODING, "br, gzip")],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{}", path);
+        assert_eq!(headers[header::CONTENT_LENGTH], "0", "{}", path);
+        assert!(body.is_empty(), "{}", path);
+
+        let (status, headers, _) = send(&router, Method::HEAD, "example.com", path, &[]).await;
+        assert_eq!(status, StatusCode::OK, "{}", path);
+        assert_eq!(headers[header::CONTENT_LENGTH], "0", "{}", path);
+    }
+
+    // No byte of an empty file can be asked for
+    let (status, headers, _) = get_with(
+        &router,
+        "example.com",
+        "/empty.txt",
+        &[(header::RANGE, "bytes=0-")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(headers[header::CONTENT_RANGE], "bytes */0");
+}
@@ -68,4 +68,5 @@ async fn test_push_and_serve() {
 
     // Cleanup
     server.kill().unwrap();
+    server.wait().unwrap();
 }
@@ -1,7 +1,12 @@
+use std::collections::HashSet;
 use std::fs;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
-use webpub::merkle::build_tree;
-use webpub::scanner::scan_directory;
+use webpub::merkle::{build_tree, build_tree_with_stats};
+use webpub::scanner::{
+    scan_directory, scan_directory_incremental, scan_directory_with, ScanOptions,
+};
+use webpub::server::http::find_node;
 use webpub::Node;
 
 #[test]
@@ -76,3 +81,234 @@ fn test_build_tree_empty_dir_preserved() {
     // No chunks for empty directory
     assert!(chunks.is_empty());
 }
+
+#[test]
+fn test_build_tree_reuses_identical_files() {
+    let temp = TempDir::new().unwrap();
+    fs::create_dir(temp.path().join("img")).unwrap();
+    fs::write(temp.path().join("LICENSE"), "MIT license text").unwrap();
+    fs::write(temp.path().join("img/LICENSE"), "MIT license text").unwrap();
+    fs::write(temp.path().join("other.txt"), "different").unwrap();
+
+    let entry = scan_directory(temp.path()).unwrap().next().unwrap();
+    let (tree, chunks, stats) = build_tree_with_stats(entry);
+
+    assert_eq!(stats.files_chunked, 2);
+    assert_eq!(stats.files_reused, 1);
+    assert_eq!(chunks.len(), 2);
+    assert_eq!(stats.file_bytes, 16 + 16 + 9);
+    assert_eq!(stats.chunk_bytes, 16 + 9);
+    assert_eq!(stats.dedup_ratio(), Some(41.0 / 25.0));
+
+    let file_chunks = |path: &str| match find_node(&tree, path) {
+        Some(Node::File { chunks, .. }) => chunks.clone(),
+        _ => panic!("Expected file at {}", path),
+    };
+    assert_eq!(file_chunks("/LICENSE"), file_chunks("/img/LICENSE"));
+}
+
+#[test]
+fn test_build_tree_returns_distinct_chunks() {
+    let temp = TempDir::new().unwrap();
+    // Runs of zeros split into identical maximum-size chunks, within one
+    // file and across two files whose contents differ
+    let zeros = vec![0u8; 1024 * 1024];
+    fs::write(temp.path().join("a.bin"), &zeros).unwrap();
+    fs::write(temp.path().join("b.bin"), [&zeros[..], b"tail"].concat()).unwrap();
+
+    let entry = scan_directory(temp.path()).unwrap().next().unwrap();
+    let (tree, chunks, stats) = build_tree_with_stats(entry);
+    assert_eq!(stats.files_chunked, 2);
+
+    let mut referenced = HashSet::new();
+    for path in ["/a.bin", "/b.bin"] {
+        match find_node(&tree, path) {
+            Some(Node::File { chunks, .. }) => referenced.extend(chunks.iter().copied()),
+            _ => panic!("Expected file at {}", path),
+        }
+    }
+    let returned: Vec<[u8; 32]> = chunks.iter().map(|c| c.hash).collect();
+    assert_eq!(returned.len(), referenced.len());
+    assert_eq!(returned.into_iter().collect::<HashSet<_>>(), referenced);
+    assert!(chunks.len() < 4, "{} chunks", chunks.len());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_build_tree_normalized_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Same content checked out on two machines with different modes
+    let temp = TempDir::new().unwrap();
+    for (dir, file_mode, dir_mode) in [("a", 0o644, 0o755), ("b", 0o664, 0o775)] {
+        let root = temp.path().join(dir);
+        fs::create_dir_all(root.join("css")).unwrap();
+        fs::write(root.join("index.html"), "<h1>Hello</h1>").unwrap();
+        fs::write(root.join("css/style.css"), "h1 {}").unwrap();
+        for file in ["index.html", "css/style.css"] {
+            fs::set_permissions(root.join(file), fs::Permissions::from_mode(file_mode)).unwrap();
+        }
+        fs::set_permissions(root.join("css"), fs::Permissions::from_mode(dir_mode)).unwrap();
+    }
+
+    let tree_hash = |dir: &str, normalize_permissions: bool| {
+        let options = ScanOptions {
+            normalize_permissions,
+            ..Default::default()
+        };
+        let entry = scan_directory_with(&temp.path().join(dir), &options)
+            .unwrap()
+            .next()
+            .unwrap();
+        let (tree, _) = build_tree(entry);
+        match find_node(&tree, "/css/style.css") {
+            Some(Node::File { permissions, .. }) if normalize_permissions => {
+                assert_eq!(*permissions, 0o644)
+            }
+            Some(_) => {}
+            None => panic!("style.css missing"),
+        }
+        *tree.hash()
+    };
+
+    assert_ne!(tree_hash("a", false), tree_hash("b", false));
+    assert_eq!(tree_hash("a", true), tree_hash("b", true));
+}
+
+#[test]
+fn test_build_tree_content_hash_independent_of_chunking() {
+    use webpub::chunker::ChunkConfig;
+    use webpub::merkle::build_tree_with;
+
+    let temp = TempDir::new().unwrap();
+    let data: Vec<u8> = (0..200_000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    fs::write(temp.path().join("data.bin"), &data).unwrap();
+
+    let file_hashes = |config: &ChunkConfig| {
+        let entry = scan_directory(temp.path()).unwrap().next().unwrap();
+        let (tree, _) = build_tree_with(entry, config);
+        match find_node(&tree, "/data.bin") {
+            Some(Node::File {
+                hash, content_hash, ..
+            }) => (*hash, *content_hash),
+            _ => panic!("Expected file"),
+        }
+    };
+
+    let (default_hash, default_content) = file_hashes(&ChunkConfig::default());
+    let (small_hash, small_content) = file_hashes(&ChunkConfig::new(1024, 2048, 4096).unwrap());
+
+    // The merkle hash depends on the chunks; the content hash does not
+    assert_ne!(default_hash, small_hash);
+    assert_eq!(default_content, *blake3::hash(&data).as_bytes());
+    assert_eq!(small_content, default_content);
+}
+
+#[test]
+fn test_build_tree_incremental_matches_full() {
+    let temp = TempDir::new().unwrap();
+    fs::create_dir(temp.path().join("docs")).unwrap();
+    fs::write(temp.path().join("index.html"), "<h1>Home</h1>").unwrap();
+    fs::write(temp.path().join("docs/guide.md"), "# Guide".repeat(5000)).unwrap();
+    let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    for file in ["index.html", "docs/guide.md"] {
+        fs::File::options()
+            .write(true)
+            .open(temp.path().join(file))
+            .unwrap()
+            .set_modified(hour_ago)
+            .unwrap();
+    }
+
+    let full = || scan_directory(temp.path()).unwrap().next().unwrap();
+    let (previous, _) = build_tree(full());
+    fs::write(temp.path().join("new.txt"), "new").unwrap();
+
+    let entry = scan_directory_incremental(temp.path(), &ScanOptions::default(), &previous)
+        .unwrap()
+        .next()
+        .unwrap();
+    let (tree, chunks, stats) = build_tree_with_stats(entry);
+    let (expected, _) = build_tree(full());
+
+    assert_eq!(tree, expected);
+    assert_eq!(stats.files_unchanged, 2);
+    assert_eq!(stats.files_chunked, 1);
+    // Only the new file's chunk; the rest are on the server already
+    assert_eq!(chunks.len(), 1);
+    match find_node(&tree, "/index.html") {
+        Some(Node::File { mtime, .. }) => assert_ne!(*mtime, 0),
+        other => panic!("unexpected node {:?}", other),
+    }
+}
+
+#[test]
+fn test_build_tree_empty_file() {
+    use webpub::chunker::ChunkConfig;
+    use webpub::merkle::build_tree_with;
+
+    let temp = TempDir::new().unwrap();
+    fs::write(temp.path().join("empty.txt"), "").unwrap();
+    fs::write(temp.path().join("blank.txt"), "").unwrap();
+
+    let entry = scan_directory(temp.path()).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    assert!(chunks.is_empty());
+
+    // An empty file has no chunks, and both its hashes are BLAKE3 of nothing,
+    // whatever the chunk sizes
+    let empty_hash = *blake3::hash(b"").as_bytes();
+    assert_eq!(
+        hex::encode(empty_hash),
+        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+    );
+    let small = ChunkConfig::new(1024, 2048, 4096).unwrap();
+    let entry = scan_directory(temp.path()).unwrap().next().unwrap();
+    let (small_tree, _) = build_tree_with(entry, &small);
+    assert_eq!(small_tree.hash(), tree.hash());
+    for name in ["empty.txt", "blank.txt"] {
+        match find_node(&tree, name) {
+            Some(Node::File {
+                size,
+                chunks,
+                hash,
+                content_hash,
+                ..
+            }) => {
+                assert_eq!(*size, 0);
+                assert!(chunks.is_empty());
+                assert_eq!(*hash, empty_hash);
+                assert_eq!(*content_hash, empty_hash);
+            }
+            other => panic!("unexpected node {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_build_tree_fixed_chunks() {
+    use webpub::chunker::ChunkConfig;
+    use webpub::merkle::build_tree_with;
+
+    let temp = TempDir::new().unwrap();
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(temp.path().join("data.bin"), &data).unwrap();
+
+    let fixed = ChunkConfig::fixed(30_000).unwrap();
+    let entry = scan_directory(temp.path()).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree_with(entry, &fixed);
+    let sizes: Vec<usize> = chunks.iter().map(|c| c.data.len()).collect();
+    assert_eq!(sizes, vec![30_000, 30_000, 30_000, 10_000]);
+
+    // The content hash doesn't depend on how the file was split
+    let entry = scan_directory(temp.path()).unwrap().next().unwrap();
+    let (cdc_tree, _) = build_tree(entry);
+    let content_hash = |tree: &Node| match find_node(tree, "data.bin") {
+        Some(Node::File { content_hash, .. }) => *content_hash,
+        other => panic!("unexpected node {:?}", other),
+    };
+    assert_eq!(content_hash(&tree), content_hash(&cdc_tree));
+    assert_ne!(tree.hash(), cdc_tree.hash());
+}
@@ -1,3 +1,4 @@
+use webpub::merkle::{diff, DiffEntry};
 use webpub::Node;
 
 #[test]
@@ -8,6 +9,8 @@ fn test_file_node_roundtrip() {
         size: 100,
         chunks: vec![[0u8; 32], [1u8; 32]],
         hash: [2u8; 32],
+        content_hash: [2u8; 32],
+        mtime: 0,
     };
 
     let bytes = rmp_serde::to_vec(&node).unwrap();
@@ -24,6 +27,8 @@ fn test_directory_node_roundtrip() {
         size: 50,
         chunks: vec![[3u8; 32]],
         hash: [4u8; 32],
+        content_hash: [4u8; 32],
+        mtime: 0,
     };
 
     let node = Node::Directory {
@@ -38,3 +43,188 @@ fn test_directory_node_roundtrip() {
 
     assert_eq!(node, decoded);
 }
+
+#[test]
+fn test_diff_nested_changes() {
+    let file = |name: &str, hash: u8| Node::File {
+        name: name.to_string(),
+        permissions: 0o644,
+        size: 1,
+        chunks: vec![[hash; 32]],
+        hash: [hash; 32],
+        content_hash: [hash; 32],
+        mtime: 0,
+    };
+    let dir = |name: &str, children: Vec<Node>, hash: u8| Node::Directory {
+        name: name.to_string(),
+        permissions: 0o755,
+        children,
+        hash: [hash; 32],
+    };
+
+    let old = dir(
+        "",
+        vec![
+            dir("css", vec![file("a.css", 1), file("b.css", 2)], 10),
+            file("index.html", 3),
+            file("old.txt", 4),
+        ],
+        20,
+    );
+    let new = dir(
+        "",
+        vec![
+            dir("css", vec![file("a.css", 1), file("b.css", 5)], 11),
+            file("index.html", 3),
+            dir("new", vec![], 12),
+        ],
+        21,
+    );
+
+    assert_eq!(
+        diff(&old, &new),
+        vec![
+            DiffEntry::Modified("css/b.css".to_string()),
+            DiffEntry::Added("new".to_string()),
+            DiffEntry::Removed("old.txt".to_string()),
+        ]
+    );
+    assert!(diff(&old, &old).is_empty());
+}
+
+#[test]
+fn test_file_node_decodes_without_content_hash() {
+    use serde::Serialize;
+
+    // A file node as serialized before content_hash was added
+    #[derive(Serialize)]
+    enum OldNode {
+        File {
+            name: String,
+            permissions: u32,
+            size: u64,
+            chunks: Vec<[u8; 32]>,
+            hash: [u8; 32],
+        },
+    }
+
+    let bytes = rmp_serde::to_vec(&OldNode::File {
+        name: "old.txt".to_string(),
+        permissions: 0o644,
+        size: 10,
+        chunks: vec![[1u8; 32]],
+        hash: [2u8; 32],
+    })
+    .unwrap();
+    let decoded: Node = rmp_serde::from_slice(&bytes).unwrap();
+
+    assert_eq!(
+        decoded,
+        Node::File {
+            name: "old.txt".to_string(),
+            permissions: 0o644,
+            size: 10,
+            chunks: vec![[1u8; 32]],
+            hash: [2u8; 32],
+            content_hash: [0u8; 32],
+            mtime: 0,
+        }
+    );
+}
+
+#[test]
+fn test_file_node_without_mtime_encodes_as_before() {
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    enum OldNode {
+        File {
+            name: String,
+            permissions: u32,
+            size: u64,
+            chunks: Vec<[u8; 32]>,
+            hash: [u8; 32],
+            content_hash: [u8; 32],
+        },
+    }
+
+    let old = rmp_serde::to_vec(&OldNode::File {
+        name: "a.txt".to_string(),
+        permissions: 0o644,
+        size: 10,
+        chunks: vec![[1u8; 32]],
+        hash: [2u8; 32],
+        content_hash: [3u8; 32],
+    })
+    .unwrap();
+    let mut node = Node::File {
+        name: "a.txt".to_string(),
+        permissions: 0o644,
+        size: 10,
+        chunks: vec![[1u8; 32]],
+        hash: [2u8; 32],
+        content_hash: [3u8; 32],
+        mtime: 1_700_000_000_000_000_000,
+    };
+    assert_ne!(rmp_serde::to_vec(&node).unwrap(), old);
+    assert_eq!(
+        rmp_serde::from_slice::<Node>(&rmp_serde::to_vec(&node).unwrap()).unwrap(),
+        node
+    );
+
+    // Without an mtime, older versions can read the node
+    node.clear_mtimes();
+    assert_eq!(rmp_serde::to_vec(&node).unwrap(), old);
+}
+
+#[test]
+fn test_verify_tree() {
+    use std::fs;
+    use webpub::merkle::{build_tree, verify_tree, VerifyError};
+    use webpub::scanner::scan_directory;
+
+    let temp = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(temp.path().join("css")).unwrap();
+    fs::write(temp.path().join("index.html"), "<h1>Hi</h1>").unwrap();
+    fs::write(temp.path().join("css/site.css"), "body {}").unwrap();
+    let (tree, _) = build_tree(scan_directory(temp.path()).unwrap().next().unwrap());
+    assert_eq!(verify_tree(&tree), Ok(()));
+
+    let mismatch = |path: &str| {
+        Err(VerifyError {
+            path: path.to_string(),
+        })
+    };
+    let Node::Directory { children, .. } = &tree else {
+        unreachable!()
+    };
+
+    // A file whose chunk list was swapped, keeping the old hash
+    let mut tampered = tree.clone();
+    if let Node::Directory { children, .. } = &mut tampered {
+        if let Node::Directory { children, .. } = &mut children[0] {
+            if let Node::File { chunks, .. } = &mut children[0] {
+                chunks[0] = [9u8; 32];
+            }
+        }
+    }
+    assert_eq!(verify_tree(&tampered), mismatch("/css/site.css"));
+
+    // A child's permissions changed without rehashing its parent
+    let mut tampered = tree.clone();
+    if let Node::Directory { children, .. } = &mut tampered {
+        if let Node::File { permissions, .. } = &mut children[1] {
+            *permissions = 0o4755;
+        }
+    }
+    assert_eq!(verify_tree(&tampered), mismatch("/"));
+
+    // A subtree dropped from the root
+    let tampered = Node::Directory {
+        name: String::new(),
+        permissions: tree.permissions(),
+        children: children[1..].to_vec(),
+        hash: *tree.hash(),
+    };
+    assert_eq!(verify_tree(&tampered), mismatch("/"));
+}
@@ -0,0 +1,69 @@
+use rusqlite::Connection;
+use std::sync::mpsc;
+use std::time::Duration;
+use tempfile::TempDir;
+use webpub::server::pool::Pool;
+
+fn init(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("CREATE TABLE IF NOT EXISTS t (x INTEGER)")
+}
+
+#[test]
+fn test_pool_opens_lazily() {
+    let temp = TempDir::new().unwrap();
+    let path = temp.path().join("a.db");
+    let pool = Pool::new(&path, 2, init);
+    assert!(!path.exists());
+
+    pool.get()
+        .unwrap()
+        .execute("INSERT INTO t VALUES (1)", [])
+        .unwrap();
+    assert!(path.exists());
+}
+
+#[test]
+fn test_pool_reuses_connections() {
+    let temp = TempDir::new().unwrap();
+    let pool = Pool::new(&temp.path().join("a.db"), 2, init);
+
+    // Temp tables are private to one connection
+    pool.get()
+        .unwrap()
+        .execute_batch("CREATE TEMP TABLE mine (x INTEGER)")
+        .unwrap();
+    let conn = pool.get().unwrap();
+    assert!(conn.prepare("SELECT * FROM mine").is_ok());
+}
+
+#[test]
+fn test_pool_waits_when_exhausted() {
+    let temp = TempDir::new().unwrap();
+    let pool = Pool::new(&temp.path().join("a.db"), 1, init);
+    let held = pool.get().unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let conn = pool.get().unwrap();
+            tx.send(
+                conn.query_row("SELECT 1", [], |row| row.get::<_, i64>(0))
+                    .unwrap(),
+            )
+            .unwrap();
+        });
+
+        // The second checkout blocks until the first is returned
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(held);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+    });
+}
+
+#[test]
+fn test_pool_failed_open_frees_slot() {
+    let temp = TempDir::new().unwrap();
+    let pool = Pool::new(&temp.path().join("missing/a.db"), 1, init);
+    assert!(pool.get().is_err());
+    assert!(pool.get().is_err());
+}
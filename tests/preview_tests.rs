@@ -0,0 +1,108 @@
+use axum::body::Body;
+use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+use axum::Router;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tower::ServiceExt;
+use webpub::archive::write_archive;
+use webpub::server::preview::archive_router;
+use webpub::{build_tree, scan_directory};
+
+/// Archive a directory next to it, returning the archive's path.
+fn archive(dir: &Path) -> PathBuf {
+    let entry = scan_directory(dir).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    let path = dir.with_extension("webpub");
+    write_archive(&path, &tree, &chunks).unwrap();
+    path
+}
+
+/// Send a request for a path, returning status, headers and body.
+async fn send(router: &Router, method: Method, path: &str) -> (StatusCode, HeaderMap, Vec<u8>) {
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap()
+        .to_vec();
+    (status, headers, body)
+}
+
+#[tokio::test]
+async fn test_serve_archive() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("docs")).unwrap();
+    fs::create_dir_all(site.join("assets")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Home</h1>").unwrap();
+    fs::write(site.join("docs/index.html"), "<h1>Docs</h1>").unwrap();
+    fs::write(site.join("assets/app.js"), "console.log(1);").unwrap();
+    let photo: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(site.join("assets/photo.png"), &photo).unwrap();
+    let router = archive_router(&archive(&site)).unwrap();
+
+    let (status, headers, body) = send(&router, Method::GET, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+    assert_eq!(body, b"<h1>Home</h1>");
+
+    let (_, _, body) = send(&router, Method::GET, "/docs/").await;
+    assert_eq!(body, b"<h1>Docs</h1>");
+
+    let (status, headers, body) = send(&router, Method::GET, "/assets/photo.png").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+    assert_eq!(headers[header::CONTENT_LENGTH], "300000");
+    assert_eq!(body, photo);
+
+    let (status, headers, body) = send(&router, Method::HEAD, "/assets/app.js").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "text/javascript");
+    assert!(body.is_empty());
+
+    // A directory without an index.html is listed
+    let (status, _, body) = send(&router, Method::GET, "/assets/").await;
+    assert_eq!(status, StatusCode::OK);
+    let listing = String::from_utf8(body).unwrap();
+    assert!(listing.contains("app.js"), "{}", listing);
+    assert!(listing.contains("photo.png"), "{}", listing);
+
+    let (status, _, body) = send(&router, Method::GET, "/missing.html").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body, b"Not found");
+
+    // Paths are normalized as on the server
+    let (_, _, body) = send(&router, Method::GET, "/assets/../docs/").await;
+    assert_eq!(body, b"<h1>Docs</h1>");
+    let (status, _, _) = send(&router, Method::GET, "/assets/../../secret").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_serve_archive_not_found_page() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Home</h1>").unwrap();
+    fs::write(site.join("404.html"), "<h1>Lost</h1>").unwrap();
+    let router = archive_router(&archive(&site)).unwrap();
+
+    let (status, _, body) = send(&router, Method::GET, "/missing").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body, b"<h1>Lost</h1>");
+}
+
+#[test]
+fn test_serve_archive_rejects_non_archive() {
+    let temp = TempDir::new().unwrap();
+    let path = temp.path().join("not.webpub");
+    fs::write(&path, "hello").unwrap();
+    assert!(archive_router(&path).is_err());
+}
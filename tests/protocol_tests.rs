@@ -4,12 +4,19 @@ use webpub::protocol::*;
 fn test_auth_message_roundtrip() {
     let msg = ClientMessage::Auth {
         token: "secret123".to_string(),
+        protocol_version: PROTOCOL_VERSION,
     };
     let bytes = rmp_serde::to_vec(&msg).unwrap();
     let decoded: ClientMessage = rmp_serde::from_slice(&bytes).unwrap();
 
     match decoded {
-        ClientMessage::Auth { token } => assert_eq!(token, "secret123"),
+        ClientMessage::Auth {
+            token,
+            protocol_version,
+        } => {
+            assert_eq!(token, "secret123");
+            assert_eq!(protocol_version, PROTOCOL_VERSION);
+        }
         _ => panic!("Wrong variant"),
     }
 }
@@ -18,18 +25,54 @@ fn test_auth_message_roundtrip() {
 fn test_have_chunks_message() {
     let msg = ClientMessage::HaveChunks {
         hashes: vec![[1u8; 32], [2u8; 32]],
+        sizes: vec![1000, 2000],
     };
     let bytes = rmp_serde::to_vec(&msg).unwrap();
     let decoded: ClientMessage = rmp_serde::from_slice(&bytes).unwrap();
 
     match decoded {
-        ClientMessage::HaveChunks { hashes } => {
+        ClientMessage::HaveChunks { hashes, sizes } => {
             assert_eq!(hashes.len(), 2);
+            assert_eq!(sizes, vec![1000, 2000]);
         }
         _ => panic!("Wrong variant"),
     }
 }
 
+#[test]
+fn test_have_chunks_without_sizes() {
+    // Client messages before SIZES_PROTOCOL_VERSION, up to HaveChunks
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    enum LegacyMessage {
+        Auth,
+        HaveChunks { hashes: Vec<[u8; 32]> },
+    }
+
+    // Older clients send no sizes
+    let bytes = rmp_serde::to_vec(&LegacyMessage::HaveChunks {
+        hashes: vec![[1u8; 32]],
+    })
+    .unwrap();
+    match rmp_serde::from_slice::<ClientMessage>(&bytes).unwrap() {
+        ClientMessage::HaveChunks { hashes, sizes } => {
+            assert_eq!(hashes, vec![[1u8; 32]]);
+            assert!(sizes.is_empty());
+        }
+        other => panic!("Wrong variant {:?}", other),
+    }
+
+    // And without them, the message is what older servers expect
+    let bytes = rmp_serde::to_vec(&ClientMessage::HaveChunks {
+        hashes: vec![[1u8; 32]],
+        sizes: Vec::new(),
+    })
+    .unwrap();
+    match rmp_serde::from_slice::<LegacyMessage>(&bytes).unwrap() {
+        LegacyMessage::HaveChunks { hashes } => assert_eq!(hashes, vec![[1u8; 32]]),
+        other => panic!("Wrong variant {:?}", other),
+    }
+}
+
 #[test]
 fn test_server_messages() {
     let msg = ServerMessage::NeedChunks {
@@ -38,7 +81,225 @@ fn test_server_messages() {
     let bytes = rmp_serde::to_vec(&msg).unwrap();
     let _: ServerMessage = rmp_serde::from_slice(&bytes).unwrap();
 
-    let msg = ServerMessage::CommitOk { snapshot_id: 42 };
+    let msg = ServerMessage::CommitOk {
+        snapshot_id: 42,
+        unchanged: false,
+    };
     let bytes = rmp_serde::to_vec(&msg).unwrap();
     let _: ServerMessage = rmp_serde::from_slice(&bytes).unwrap();
 }
+
+#[test]
+fn test_envelope_roundtrip() {
+    let bytes = encode(&ClientMessage::ListSnapshots {
+        hostname: "example.com".to_string(),
+    })
+    .unwrap();
+
+    let envelope: Envelope<ClientMessage> = rmp_serde::from_slice(&bytes).unwrap();
+    assert_eq!(envelope.version, PROTOCOL_VERSION);
+
+    match decode::<ClientMessage>(&bytes).unwrap() {
+        ClientMessage::ListSnapshots { hostname } => assert_eq!(hostname, "example.com"),
+        _ => panic!("Wrong variant"),
+    }
+}
+
+#[test]
+fn test_decode_unknown_future_variant() {
+    #[derive(serde::Serialize)]
+    enum FutureMessage {
+        Teleport { destination: String },
+    }
+
+    let bytes = rmp_serde::to_vec(&Envelope {
+        version: PROTOCOL_VERSION + 1,
+        message: FutureMessage::Teleport {
+            destination: "mars".to_string(),
+        },
+    })
+    .unwrap();
+
+    match decode::<ClientMessage>(&bytes) {
+        Err(ProtocolError::Unsupported { version }) => assert_eq!(version, PROTOCOL_VERSION + 1),
+        other => panic!("Expected unsupported error, got {:?}", other),
+    }
+
+    // Garbage is still reported as malformed
+    assert!(matches!(
+        decode::<ClientMessage>(b"\xc1"),
+        Err(ProtocolError::Malformed(_))
+    ));
+}
+
+#[test]
+fn test_auth_without_protocol_version() {
+    // Auth as sent by clients that predate version negotiation
+    #[derive(serde::Serialize)]
+    enum LegacyMessage {
+        Auth { token: String },
+    }
+
+    let bytes = rmp_serde::to_vec(&Envelope {
+        version: 1,
+        message: LegacyMessage::Auth {
+            token: "secret123".to_string(),
+        },
+    })
+    .unwrap();
+
+    match decode::<ClientMessage>(&bytes).unwrap() {
+        ClientMessage::Auth {
+            protocol_version, ..
+        } => {
+            assert_eq!(protocol_version, 1);
+            assert!(is_compatible(protocol_version));
+        }
+        _ => panic!("Wrong variant"),
+    }
+
+    assert!(is_compatible(PROTOCOL_VERSION));
+    assert!(!is_compatible(MIN_PROTOCOL_VERSION - 1));
+    assert!(!is_compatible(PROTOCOL_VERSION + 1));
+}
+
+#[test]
+fn test_commit_failed_missing_chunks() {
+    // The server messages of clients before MISSING_CHUNKS_PROTOCOL_VERSION,
+    // up to CommitFailed
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    enum LegacyMessage {
+        AuthOk,
+        AuthFailed,
+        NeedChunks { hashes: Vec<[u8; 32]> },
+        ChunkAck { hash: [u8; 32] },
+        BatchAck { hashes: Vec<[u8; 32]> },
+        ChunkRejected { hash: [u8; 32], reason: String },
+        CommitOk { snapshot_id: u64 },
+        CommitFailed { reason: String },
+    }
+
+    // Without a list the message is what older clients expect
+    let bytes = rmp_serde::to_vec(&ServerMessage::CommitFailed {
+        reason: "Missing 1 chunks".to_string(),
+        missing: vec![],
+    })
+    .unwrap();
+    match rmp_serde::from_slice::<LegacyMessage>(&bytes).unwrap() {
+        LegacyMessage::CommitFailed { reason } => assert_eq!(reason, "Missing 1 chunks"),
+        other => panic!("Wrong variant {:?}", other),
+    }
+
+    let bytes = rmp_serde::to_vec(&LegacyMessage::CommitFailed {
+        reason: "Missing 1 chunks".to_string(),
+    })
+    .unwrap();
+    match rmp_serde::from_slice::<ServerMessage>(&bytes).unwrap() {
+        ServerMessage::CommitFailed { missing, .. } => assert!(missing.is_empty()),
+        other => panic!("Wrong variant {:?}", other),
+    }
+
+    // A listed hash is only understood by newer clients
+    let bytes = rmp_serde::to_vec(&ServerMessage::CommitFailed {
+        reason: "Missing 1 chunks".to_string(),
+        missing: vec![[1u8; 32]],
+    })
+    .unwrap();
+    assert!(rmp_serde::from_slice::<LegacyMessage>(&bytes).is_err());
+    match rmp_serde::from_slice::<ServerMessage>(&bytes).unwrap() {
+        ServerMessage::CommitFailed { missing, .. } => assert_eq!(missing, vec![[1u8; 32]]),
+        other => panic!("Wrong variant {:?}", other),
+    }
+}
+
+#[test]
+fn test_commit_ok_unchanged() {
+    // Server messages before UNCHANGED_PROTOCOL_VERSION, up to CommitOk;
+    // only the position of the others matters here
+    #[derive(Debug, serde::Deserialize)]
+    enum LegacyMessage {
+        AuthOk,
+        AuthFailed,
+        NeedChunks,
+        ChunkAck,
+        BatchAck,
+        ChunkRejected,
+        CommitOk { snapshot_id: u64 },
+    }
+
+    // A changed tree's reply is what older clients expect
+    let bytes = rmp_serde::to_vec(&ServerMessage::CommitOk {
+        snapshot_id: 7,
+        unchanged: false,
+    })
+    .unwrap();
+    match rmp_serde::from_slice::<LegacyMessage>(&bytes).unwrap() {
+        LegacyMessage::CommitOk { snapshot_id } => assert_eq!(snapshot_id, 7),
+        other => panic!("Wrong variant {:?}", other),
+    }
+
+    let bytes = rmp_serde::to_vec(&ServerMessage::CommitOk {
+        snapshot_id: 7,
+        unchanged: true,
+    })
+    .unwrap();
+    match rmp_serde::from_slice::<ServerMessage>(&bytes).unwrap() {
+        ServerMessage::CommitOk { unchanged, .. } => assert!(unchanged),
+        other => panic!("Wrong variant {:?}", other),
+    }
+}
+
+#[test]
+fn test_rollback_tag() {
+    // Client messages before TAG_PROTOCOL_VERSION, up to Rollback; only the
+    // position of the others matters here
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    enum LegacyMessage {
+        Auth,
+        HaveChunks,
+        ChunkData,
+        ChunkBatch,
+        CommitTree,
+        ListSnapshots,
+        Rollback {
+            hostname: String,
+            snapshot_id: Option<u64>,
+        },
+    }
+
+    // Older clients' rollbacks carry no tag
+    let bytes = rmp_serde::to_vec(&LegacyMessage::Rollback {
+        hostname: "example.com".to_string(),
+        snapshot_id: Some(3),
+    })
+    .unwrap();
+    match rmp_serde::from_slice::<ClientMessage>(&bytes).unwrap() {
+        ClientMessage::Rollback {
+            snapshot_id, tag, ..
+        } => assert_eq!((snapshot_id, tag), (Some(3), None)),
+        other => panic!("Wrong variant {:?}", other),
+    }
+
+    // And without one, a rollback is what older servers expect
+    let bytes = rmp_serde::to_vec(&ClientMessage::Rollback {
+        hostname: "example.com".to_string(),
+        snapshot_id: None,
+        tag: None,
+    })
+    .unwrap();
+    match rmp_serde::from_slice::<LegacyMessage>(&bytes).unwrap() {
+        LegacyMessage::Rollback { snapshot_id, .. } => assert_eq!(snapshot_id, None),
+        other => panic!("Wrong variant {:?}", other),
+    }
+
+    let bytes = rmp_serde::to_vec(&ClientMessage::Rollback {
+        hostname: "example.com".to_string(),
+        snapshot_id: None,
+        tag: Some("stable".to_string()),
+    })
+    .unwrap();
+    match rmp_serde::from_slice::<ClientMessage>(&bytes).unwrap() {
+        ClientMessage::Rollback { tag, .. } => assert_eq!(tag.as_deref(), Some("stable")),
+        other => panic!("Wrong variant {:?}", other),
+    }
+}
@@ -0,0 +1,145 @@
+use rand::{Rng, SeedableRng};
+use tempfile::TempDir;
+use webpub::server::range::{chunk_slices, ChunkSlice};
+use webpub::server::storage::Storage;
+
+/// Store a file split into chunks of the given sizes, returning its
+/// contents and chunk hashes.
+fn store_file(storage: &Storage, rng: &mut impl Rng, sizes: &[usize]) -> (Vec<u8>, Vec<[u8; 32]>) {
+    let mut content = Vec::new();
+    let mut hashes = Vec::new();
+    for &size in sizes {
+        let data: Vec<u8> = (0..size).map(|_| rng.gen()).collect();
+        let hash = *blake3::hash(&data).as_bytes();
+        storage.store_chunk(&hash, &data).unwrap();
+        content.extend_from_slice(&data);
+        hashes.push(hash);
+    }
+    (content, hashes)
+}
+
+#[test]
+fn test_chunk_slices_boundaries() {
+    let sizes = [10, 10, 5];
+
+    // Zero-length ranges select nothing, including at a boundary and at EOF
+    assert!(chunk_slices(&sizes, 0..0).is_empty());
+    assert!(chunk_slices(&sizes, 10..10).is_empty());
+    assert!(chunk_slices(&sizes, 25..25).is_empty());
+
+    // Exactly one chunk
+    assert_eq!(
+        chunk_slices(&sizes, 10..20),
+        vec![ChunkSlice {
+            index: 1,
+            range: 0..10
+        }]
+    );
+
+    // Spanning exactly one boundary
+    assert_eq!(
+        chunk_slices(&sizes, 9..11),
+        vec![
+            ChunkSlice {
+                index: 0,
+                range: 9..10
+            },
+            ChunkSlice {
+                index: 1,
+                range: 0..1
+            },
+        ]
+    );
+
+    // Ending at EOF, and past it
+    let to_eof = vec![
+        ChunkSlice {
+            index: 1,
+            range: 5..10,
+        },
+        ChunkSlice {
+            index: 2,
+            range: 0..5,
+        },
+    ];
+    assert_eq!(chunk_slices(&sizes, 15..25), to_eof);
+    assert_eq!(chunk_slices(&sizes, 15..100), to_eof);
+    assert!(chunk_slices(&sizes, 30..40).is_empty());
+}
+
+#[test]
+fn test_read_range_matches_full_file() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1006);
+
+    for _ in 0..50 {
+        let sizes: Vec<usize> = (0..rng.gen_range(1..8))
+            .map(|_| rng.gen_range(1..200))
+            .collect();
+        let (content, hashes) = store_file(&storage, &mut rng, &sizes);
+        let len = content.len() as u64;
+
+        // Every chunk boundary, plus random points and the ends of the file
+        let mut points = vec![0, len];
+        let mut offset = 0;
+        for size in &sizes {
+            offset += *size as u64;
+            points.extend([offset.saturating_sub(1), offset, offset + 1]);
+        }
+        for _ in 0..10 {
+            points.push(rng.gen_range(0..=len));
+        }
+        points.retain(|&p| p <= len);
+
+        for &start in &points {
+            for &end in &points {
+                if start > end {
+                    continue;
+                }
+                let data = storage.read_range(&hashes, start..end).unwrap().unwrap();
+                assert_eq!(
+                    data,
+                    &content[start as usize..end as usize],
+                    "range {}..{} of chunks {:?}",
+                    start,
+                    end,
+                    sizes
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_read_range_missing_chunk() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+
+    let (_, mut hashes) = store_file(&storage, &mut rng, &[10, 10]);
+    hashes.push([0u8; 32]);
+    assert_eq!(storage.read_range(&hashes, 0..5).unwrap(), None);
+}
+
+#[test]
+fn test_parse_range() {
+    use webpub::server::range::{parse_range, RangeRequest::*};
+
+    assert_eq!(parse_range("bytes=0-99", 1000), Partial(0..100));
+    assert_eq!(parse_range("bytes=990-2000", 1000), Partial(990..1000));
+    assert_eq!(parse_range("bytes=500-", 1000), Partial(500..1000));
+    assert_eq!(parse_range("bytes=-100", 1000), Partial(900..1000));
+    assert_eq!(parse_range("bytes=-5000", 1000), Partial(0..1000));
+    assert_eq!(parse_range("bytes=999-999", 1000), Partial(999..1000));
+
+    assert_eq!(parse_range("bytes=1000-", 1000), Unsatisfiable);
+    assert_eq!(parse_range("bytes=-0", 1000), Unsatisfiable);
+    assert_eq!(parse_range("bytes=0-", 0), Unsatisfiable);
+
+    // Unsupported or malformed ranges are ignored
+    assert_eq!(parse_range("bytes=0-1,5-6", 1000), Full);
+    assert_eq!(parse_range("bytes=10-5", 1000), Full);
+    assert_eq!(parse_range("items=0-5", 1000), Full);
+    assert_eq!(parse_range("bytes=a-b", 1000), Full);
+}
@@ -0,0 +1,46 @@
+use std::net::IpAddr;
+use std::thread::sleep;
+use std::time::Duration;
+use webpub::server::rate_limit::AuthLimiter;
+
+#[test]
+fn test_limits_failures_per_ip() {
+    let limiter = AuthLimiter::per_minute(3);
+    let attacker: IpAddr = "10.0.0.1".parse().unwrap();
+    let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+    for _ in 0..3 {
+        assert!(limiter.check(attacker).is_ok());
+        limiter.record_failure(attacker);
+    }
+    let retry = limiter.check(attacker).unwrap_err();
+    assert!(retry > Duration::ZERO && retry <= Duration::from_secs(20));
+
+    // Other clients are unaffected
+    assert!(limiter.check(other).is_ok());
+}
+
+#[test]
+fn test_failures_refill_over_period() {
+    let limiter = AuthLimiter::new(2, Duration::from_millis(200));
+    let ip: IpAddr = "::1".parse().unwrap();
+    limiter.record_failure(ip);
+    limiter.record_failure(ip);
+    assert!(limiter.check(ip).is_err());
+
+    // One token refills every 100ms
+    sleep(Duration::from_millis(150));
+    assert!(limiter.check(ip).is_ok());
+    limiter.record_failure(ip);
+    assert!(limiter.check(ip).is_err());
+}
+
+#[test]
+fn test_zero_disables_limit() {
+    let limiter = AuthLimiter::per_minute(0);
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    for _ in 0..100 {
+        limiter.record_failure(ip);
+    }
+    assert!(limiter.check(ip).is_ok());
+}
@@ -0,0 +1,56 @@
+use axum::http::StatusCode;
+use webpub::server::redirects::{find_redirect, parse_redirects, RedirectRule};
+
+#[test]
+fn test_parse_redirects() {
+    let rules = parse_redirects(
+        "# Moved pages\n\
+         /old /new\n\
+         \n\
+         /temp /elsewhere 302\n\
+         /forced /target 301!\n\
+         /bad\n\
+         /rewrite /index.html 200\n\
+         relative /x\n",
+    );
+    assert_eq!(rules.len(), 3);
+    assert_eq!(
+        rules[1],
+        RedirectRule::parse("/temp   /elsewhere   302").unwrap()
+    );
+    assert!(RedirectRule::parse("/a /b 404").is_err());
+}
+
+#[test]
+fn test_find_redirect() {
+    let rules = parse_redirects(
+        "/old /new\n\
+         /blog/:year/:slug /posts/:slug?year=:year 302\n\
+         /docs/* https://docs.example.com/:splat\n\
+         /users/:id/:identifier /u/:identifier/:id\n\
+         /* /fallback\n",
+    );
+    let redirect = |path| find_redirect(&rules, path);
+
+    assert_eq!(
+        redirect("/old"),
+        Some(("/new".to_string(), StatusCode::MOVED_PERMANENTLY))
+    );
+    // Trailing slashes don't matter
+    assert_eq!(redirect("/old/").unwrap().0, "/new");
+
+    assert_eq!(
+        redirect("/blog/2024/hello"),
+        Some(("/posts/hello?year=2024".to_string(), StatusCode::FOUND))
+    );
+    assert_eq!(
+        redirect("/docs/guide/intro.html").unwrap().0,
+        "https://docs.example.com/guide/intro.html"
+    );
+    assert_eq!(redirect("/docs").unwrap().0, "https://docs.example.com/");
+    assert_eq!(redirect("/users/7/x").unwrap().0, "/u/x/7");
+
+    // Rules apply in order; the catch-all only gets what's left
+    assert_eq!(redirect("/blog/2024").unwrap().0, "/fallback");
+    assert_eq!(find_redirect(&rules[..4], "/blog/2024"), None);
+}
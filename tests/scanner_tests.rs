@@ -1,6 +1,11 @@
 use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
-use webpub::scanner::{scan_directory, ScannedEntry};
+use webpub::build_tree;
+use webpub::scanner::{
+    scan_directory, scan_directory_incremental, scan_directory_with, ScanOptions, ScannedEntry,
+};
 
 #[test]
 fn test_scan_empty_directory() {
@@ -103,3 +108,252 @@ fn test_scan_ignores_symlinks() {
         _ => panic!("Expected directory"),
     }
 }
+
+#[test]
+fn test_path_length_problem() {
+    use webpub::scanner::{path_length_problem, MAX_NAME_LEN, MAX_PATH_LEN};
+
+    assert!(path_length_problem("index.html", 11).is_none());
+
+    let long_name = "n".repeat(MAX_NAME_LEN + 1);
+    let problem = path_length_problem(&long_name, long_name.len()).unwrap();
+    assert!(problem.contains("file name"));
+
+    let problem = path_length_problem("leaf.txt", MAX_PATH_LEN + 1).unwrap();
+    assert!(problem.contains("path is"));
+}
+
+/// Collect the relative paths of every file under a scanned entry.
+fn file_paths(entry: &ScannedEntry, prefix: &str, out: &mut Vec<String>) {
+    match entry {
+        ScannedEntry::File { name, .. } | ScannedEntry::Unchanged { name, .. } => {
+            out.push(format!("{}{}", prefix, name))
+        }
+        ScannedEntry::Directory { name, children, .. } => {
+            let prefix = if name.is_empty() {
+                String::new()
+            } else {
+                format!("{}{}/", prefix, name)
+            };
+            for child in children {
+                file_paths(child, &prefix, out);
+            }
+        }
+    }
+}
+
+fn scan_paths(root: &std::path::Path, ignore: &[&str]) -> Vec<String> {
+    let options = ScanOptions {
+        ignore: ignore.iter().map(|p| p.to_string()).collect(),
+        ..Default::default()
+    };
+    let entry = scan_directory_with(root, &options).unwrap().next().unwrap();
+    let mut paths = Vec::new();
+    file_paths(&entry, "", &mut paths);
+    paths
+}
+
+#[test]
+fn test_scan_ignore_patterns() {
+    let temp = TempDir::new().unwrap();
+    fs::create_dir_all(temp.path().join("node_modules/pkg")).unwrap();
+    fs::create_dir_all(temp.path().join("assets/cache")).unwrap();
+    fs::write(temp.path().join("node_modules/pkg/index.js"), "x").unwrap();
+    fs::write(temp.path().join("index.html"), "<html>").unwrap();
+    fs::write(temp.path().join("debug.log"), "log").unwrap();
+    fs::write(temp.path().join("assets/app.js"), "js").unwrap();
+    fs::write(temp.path().join("assets/cache/a.bin"), "bin").unwrap();
+    // A file named like an ignored directory is kept by a dir-only pattern
+    fs::write(temp.path().join("assets/node_modules"), "file").unwrap();
+
+    let paths = scan_paths(temp.path(), &["node_modules/", "*.log", "**/cache"]);
+    assert_eq!(
+        paths,
+        vec!["assets/app.js", "assets/node_modules", "index.html"]
+    );
+}
+
+#[test]
+fn test_scan_webpubignore_file() {
+    let temp = TempDir::new().unwrap();
+    fs::create_dir(temp.path().join(".git")).unwrap();
+    fs::write(temp.path().join(".git/HEAD"), "ref").unwrap();
+    fs::write(temp.path().join("index.html"), "<html>").unwrap();
+    fs::write(temp.path().join("draft.md"), "wip").unwrap();
+    fs::write(temp.path().join("keep.md"), "ok").unwrap();
+    fs::write(
+        temp.path().join(".webpubignore"),
+        "# source files\n.git/\n*.md\n!keep.md\n.webpubignore\n",
+    )
+    .unwrap();
+
+    let paths = scan_paths(temp.path(), &[]);
+    assert_eq!(paths, vec!["index.html", "keep.md"]);
+}
+
+#[test]
+fn test_scan_skips_push_manifest() {
+    use webpub::client::manifest::MANIFEST_FILE;
+
+    let temp = TempDir::new().unwrap();
+    fs::create_dir(temp.path().join("docs")).unwrap();
+    fs::write(temp.path().join("index.html"), "<html>").unwrap();
+    fs::write(temp.path().join(MANIFEST_FILE), "key\n").unwrap();
+    // Only the manifest at the root is skipped
+    fs::write(temp.path().join("docs").join(MANIFEST_FILE), "page").unwrap();
+
+    let paths = scan_paths(temp.path(), &[]);
+    assert_eq!(
+        paths,
+        vec![format!("docs/{}", MANIFEST_FILE), "index.html".to_string()]
+    );
+}
+
+#[test]
+fn test_scan_max_file_size() {
+    let temp = TempDir::new().unwrap();
+    fs::create_dir(temp.path().join("logs")).unwrap();
+    fs::write(temp.path().join("index.html"), "<html>").unwrap();
+    fs::write(temp.path().join("logs/huge.log"), vec![b'x'; 2000]).unwrap();
+    fs::write(temp.path().join("logs/exact.log"), vec![b'x'; 1000]).unwrap();
+
+    // Larger files are skipped; one exactly at the limit is kept
+    let mut options = ScanOptions {
+        max_file_size: Some(1000),
+        ..Default::default()
+    };
+    let entry = scan_directory_with(temp.path(), &options)
+        .unwrap()
+        .next()
+        .unwrap();
+    let mut paths = Vec::new();
+    file_paths(&entry, "", &mut paths);
+    assert_eq!(paths, vec!["index.html", "logs/exact.log"]);
+
+    options.fail_on_large_files = true;
+    let err = match scan_directory_with(temp.path(), &options) {
+        Ok(_) => panic!("scan should fail on huge.log"),
+        Err(e) => e,
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::FileTooLarge);
+    assert!(err.to_string().contains("/logs/huge.log"), "{}", err);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_scan_follow_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let temp = TempDir::new().unwrap();
+    let root = temp.path().join("site");
+    fs::create_dir_all(root.join("v2")).unwrap();
+    fs::write(root.join("v2/app.js"), "js").unwrap();
+    symlink(root.join("v2"), root.join("latest")).unwrap();
+    // A cycle back to the root and a dangling link are skipped
+    symlink(&root, root.join("v2/loop")).unwrap();
+    symlink(root.join("missing"), root.join("broken")).unwrap();
+
+    let options = ScanOptions {
+        follow_symlinks: true,
+        ..Default::default()
+    };
+    let entry = scan_directory_with(&root, &options)
+        .unwrap()
+        .next()
+        .unwrap();
+    let mut paths = Vec::new();
+    file_paths(&entry, "", &mut paths);
+    assert_eq!(paths, vec!["latest/app.js", "v2/app.js"]);
+
+    // Without the option, symlinks are not followed
+    assert_eq!(scan_paths(&root, &[]), vec!["v2/app.js"]);
+}
+
+/// Write a file with a modification time an hour ago.
+fn write_old(path: &Path, contents: &str) {
+    fs::write(path, contents).unwrap();
+    let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(hour_ago)
+        .unwrap();
+}
+
+/// Names of a scanned directory's children with whether each was read.
+fn read_children(entry: &ScannedEntry) -> Vec<(String, bool)> {
+    match entry {
+        ScannedEntry::Directory { children, .. } => children
+            .iter()
+            .map(|child| {
+                let read = !matches!(child, ScannedEntry::Unchanged { .. });
+                (child.name().to_string(), read)
+            })
+            .collect(),
+        _ => panic!("Expected directory"),
+    }
+}
+
+#[test]
+fn test_scan_incremental_skips_unchanged_files() {
+    let temp = TempDir::new().unwrap();
+    let root = temp.path();
+    fs::create_dir(root.join("css")).unwrap();
+    write_old(&root.join("a.txt"), "alpha");
+    write_old(&root.join("b.txt"), "bravo");
+    write_old(&root.join("css/site.css"), "body {}");
+    fs::write(root.join("fresh.txt"), "just written").unwrap();
+
+    let (previous, _) = build_tree(scan_directory(root).unwrap().next().unwrap());
+
+    // b.txt grows, c.txt is new, the rest is untouched
+    write_old(&root.join("b.txt"), "bravo, longer");
+    write_old(&root.join("c.txt"), "charlie");
+    let entry = scan_directory_incremental(root, &ScanOptions::default(), &previous)
+        .unwrap()
+        .next()
+        .unwrap();
+
+    assert_eq!(
+        read_children(&entry),
+        vec![
+            ("a.txt".to_string(), false),
+            ("b.txt".to_string(), true),
+            ("c.txt".to_string(), true),
+            ("css".to_string(), true),
+            // Too recent to trust its mtime
+            ("fresh.txt".to_string(), true),
+        ]
+    );
+    let ScannedEntry::Directory { children, .. } = &entry else {
+        unreachable!()
+    };
+    assert_eq!(
+        read_children(&children[3]),
+        vec![("site.css".to_string(), false)]
+    );
+}
+
+#[test]
+fn test_scan_incremental_rereads_on_mtime_change() {
+    let temp = TempDir::new().unwrap();
+    write_old(&temp.path().join("a.txt"), "alpha");
+    let (previous, _) = build_tree(scan_directory(temp.path()).unwrap().next().unwrap());
+
+    // Same size, but rewritten since
+    let path = temp.path().join("a.txt");
+    fs::write(&path, "ALPHA").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(1800))
+        .unwrap();
+
+    let entry = scan_directory_incremental(temp.path(), &ScanOptions::default(), &previous)
+        .unwrap()
+        .next()
+        .unwrap();
+    assert_eq!(read_children(&entry), vec![("a.txt".to_string(), true)]);
+}
@@ -1,5 +1,8 @@
+use std::time::{SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
-use webpub::server::storage::Storage;
+use webpub::server::storage::{
+    ChunkLayout, DirectoryEntry, SnapshotEntry, Storage, StorageError, StorageOptions, Synchronous,
+};
 use webpub::Node;
 
 #[test]
@@ -47,6 +50,64 @@ fn test_storage_has_chunks() {
     assert_eq!(have, vec![hash1, hash2]);
 }
 
+#[test]
+fn test_storage_has_chunks_batched() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    // More hashes than fit one query, all in shard 0x07, every third stored
+    let hashes: Vec<[u8; 32]> = (0..1200u32)
+        .map(|i| {
+            let mut hash = [7u8; 32];
+            hash[1..5].copy_from_slice(&i.to_be_bytes());
+            hash
+        })
+        .collect();
+    let stored: Vec<_> = hashes.iter().step_by(3).map(|h| (*h, vec![1])).collect();
+    storage.store_chunks(&stored).unwrap();
+
+    // Input order across shards is kept, repeats included
+    let mut query: Vec<[u8; 32]> = hashes.iter().rev().copied().collect();
+    query.insert(1, [200u8; 32]);
+    query.push(hashes[0]);
+    let expected: Vec<[u8; 32]> = query
+        .iter()
+        .filter(|hash| stored.iter().any(|(stored, _)| stored == *hash))
+        .copied()
+        .collect();
+    assert_eq!(expected.len(), 401);
+    assert_eq!(storage.has_chunks(&query).unwrap(), expected);
+    assert!(storage.has_chunks(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn test_storage_store_chunks() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    // Hashes spread across shards, with two sharing one
+    let chunks: Vec<([u8; 32], Vec<u8>)> = [[1u8; 32], [2u8; 32], [200u8; 32]]
+        .into_iter()
+        .chain([{
+            let mut hash = [1u8; 32];
+            hash[31] = 9;
+            hash
+        }])
+        .map(|hash| (hash, format!("data {}", hash[31]).into_bytes()))
+        .collect();
+    storage.store_chunks(&chunks).unwrap();
+
+    for (hash, data) in &chunks {
+        assert_eq!(storage.get_chunk(hash).unwrap().as_ref(), Some(data));
+    }
+    assert_eq!(storage.chunk_count().unwrap(), 4);
+
+    // Storing again is harmless
+    storage.store_chunks(&chunks).unwrap();
+    storage.store_chunks(&[]).unwrap();
+    assert_eq!(storage.chunk_count().unwrap(), 4);
+}
+
 #[test]
 fn test_storage_tokens() {
     let temp = TempDir::new().unwrap();
@@ -85,3 +146,1286 @@ fn test_storage_snapshots() {
     let list = storage.list_snapshots("example.com").unwrap();
     assert_eq!(list.len(), 1);
 }
+
+#[test]
+fn test_storage_repair_current() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    let tree = Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![],
+        hash: [0u8; 32],
+    };
+
+    storage.create_snapshot("example.com", &tree).unwrap();
+    let newer = Node::Directory {
+        name: "".to_string(),
+        permissions: 0o700,
+        children: vec![],
+        hash: [1u8; 32],
+    };
+    let latest = storage.create_snapshot("example.com", &newer).unwrap();
+    assert!(storage.find_broken_current().unwrap().is_empty());
+    assert!(!storage.repair_current("example.com").unwrap());
+
+    // Break the invariant: no current snapshot
+    let conn = rusqlite::Connection::open(temp.path().join("index.db")).unwrap();
+    conn.execute("UPDATE snapshots SET is_current = 0", [])
+        .unwrap();
+    assert!(storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        storage.find_broken_current().unwrap(),
+        vec![("example.com".to_string(), 0)]
+    );
+
+    assert!(storage.repair_current("example.com").unwrap());
+    assert_eq!(
+        storage
+            .get_current_snapshot("example.com")
+            .unwrap()
+            .unwrap()
+            .0,
+        latest
+    );
+
+    // Break the invariant: every snapshot current
+    conn.execute("UPDATE snapshots SET is_current = 1", [])
+        .unwrap();
+    assert_eq!(
+        storage.find_broken_current().unwrap(),
+        vec![("example.com".to_string(), 2)]
+    );
+
+    assert!(storage.repair_current("example.com").unwrap());
+    assert!(storage.find_broken_current().unwrap().is_empty());
+    let list = storage.list_snapshots("example.com").unwrap();
+    let current: Vec<i64> = list.iter().filter(|s| s.1).map(|s| s.0).collect();
+    assert_eq!(current, vec![latest]);
+}
+
+#[test]
+fn test_storage_gc() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    let kept = [1u8; 32];
+    let old = [2u8; 32];
+    let orphan = [3u8; 32];
+    storage.store_chunk(&kept, b"kept").unwrap();
+    storage.store_chunk(&old, b"old").unwrap();
+    storage.store_chunk(&orphan, b"orphaned data").unwrap();
+
+    let file = |chunk: [u8; 32]| Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![Node::File {
+            name: "index.html".to_string(),
+            permissions: 0o644,
+            size: 4,
+            chunks: vec![chunk],
+            hash: chunk,
+            content_hash: chunk,
+            mtime: 0,
+        }],
+        hash: chunk,
+    };
+
+    // Chunks of a non-current snapshot are still referenced
+    storage.create_snapshot("example.com", &file(old)).unwrap();
+    storage.create_snapshot("example.com", &file(kept)).unwrap();
+
+    let stats = storage.gc().unwrap();
+    assert_eq!(stats.chunks_deleted, 1);
+    assert_eq!(stats.bytes_freed, b"orphaned data".len() as u64);
+
+    assert!(storage.get_chunk(&kept).unwrap().is_some());
+    assert!(storage.get_chunk(&old).unwrap().is_some());
+    assert!(storage.get_chunk(&orphan).unwrap().is_none());
+
+    // Nothing left to collect
+    assert_eq!(storage.gc().unwrap().chunks_deleted, 0);
+}
+
+#[test]
+fn test_storage_gc_rechecks_refs_under_write_lock() {
+    let temp = TempDir::new().unwrap();
+    let claimed = [4u8; 32];
+    let orphan = [5u8; 32];
+    {
+        let server = Storage::open(temp.path()).unwrap();
+        server.store_chunk(&claimed, b"just uploaded").unwrap();
+        server.store_chunk(&orphan, b"never committed").unwrap();
+    }
+
+    // Another process is committing a snapshot of `claimed`: it holds the
+    // index's write lock while gc, run separately, looks for garbage
+    let mut commit = rusqlite::Connection::open(temp.path().join("index.db")).unwrap();
+    let tx = commit
+        .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+        .unwrap();
+    let data = temp.path().to_path_buf();
+    let gc = std::thread::spawn(move || Storage::open(&data).unwrap().gc().unwrap());
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    tx.execute(
+        "INSERT INTO chunk_refs (hash, count) VALUES (?1, 1)",
+        [claimed.as_slice()],
+    )
+    .unwrap();
+    tx.commit().unwrap();
+
+    // gc waited for the commit and only deleted what is still unreferenced
+    let stats = gc.join().unwrap();
+    assert_eq!(stats.chunks_deleted, 1);
+    let storage = Storage::open(temp.path()).unwrap();
+    assert!(storage.get_chunk(&claimed).unwrap().is_some());
+    assert!(storage.get_chunk(&orphan).unwrap().is_none());
+}
+
+#[test]
+fn test_storage_prune_snapshots() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    let tree = |chunk: [u8; 32]| Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![Node::File {
+            name: "index.html".to_string(),
+            permissions: 0o644,
+            size: 1,
+            chunks: vec![chunk],
+            hash: chunk,
+            content_hash: chunk,
+            mtime: 0,
+        }],
+        hash: chunk,
+    };
+
+    let mut ids = Vec::new();
+    for i in 1..=4u8 {
+        storage.store_chunk(&[i; 32], &[i]).unwrap();
+        ids.push(
+            storage
+                .create_snapshot("example.com", &tree([i; 32]))
+                .unwrap(),
+        );
+    }
+
+    // The current snapshot can't be deleted directly
+    assert!(!storage.delete_snapshot(ids[3]).unwrap());
+
+    // Roll back to the oldest, then prune to 2: the old current one survives
+    assert!(storage.set_current_snapshot("example.com", ids[0]).unwrap());
+    let deleted = storage.prune_snapshots("example.com", 2).unwrap();
+    assert_eq!(deleted, vec![ids[1]]);
+
+    let remaining: Vec<i64> = storage
+        .list_snapshots("example.com")
+        .unwrap()
+        .into_iter()
+        .map(|s| s.0)
+        .collect();
+    assert_eq!(remaining, vec![ids[3], ids[2], ids[0]]);
+
+    // The pruned snapshot's chunk went with it, leaving nothing to collect
+    assert!(storage.get_chunk(&[2u8; 32]).unwrap().is_none());
+    assert!(storage.get_chunk(&[3u8; 32]).unwrap().is_some());
+    assert_eq!(storage.gc().unwrap().chunks_deleted, 0);
+}
+
+#[test]
+fn test_storage_chunk_refcounts() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    let (shared, a, b) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+    for hash in [shared, a, b] {
+        storage.store_chunk(&hash, &hash[..4]).unwrap();
+    }
+    let file = |name: &str, chunks: Vec<[u8; 32]>| Node::File {
+        name: name.to_string(),
+        permissions: 0o644,
+        size: 4 * chunks.len() as u64,
+        hash: chunks[0],
+        content_hash: chunks[0],
+        mtime: 0,
+        chunks,
+    };
+    let tree = |only: [u8; 32]| Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        // The shared chunk appears twice but counts once per snapshot
+        children: vec![
+            file("a.html", vec![shared, only]),
+            file("b.html", vec![shared]),
+        ],
+        hash: only,
+    };
+
+    let first = storage.create_snapshot("example.com", &tree(a)).unwrap();
+    let second = storage.create_snapshot("example.com", &tree(b)).unwrap();
+    assert_eq!(storage.chunk_refs(&shared).unwrap(), 2);
+    assert_eq!(storage.chunk_refs(&a).unwrap(), 1);
+
+    // Deleting a snapshot drops its references and its now-unused chunks
+    assert!(storage.delete_snapshot(first).unwrap());
+    assert_eq!(storage.chunk_refs(&shared).unwrap(), 1);
+    assert_eq!(storage.chunk_refs(&a).unwrap(), 0);
+    assert!(storage.get_chunk(&a).unwrap().is_none());
+    assert!(storage.get_chunk(&shared).unwrap().is_some());
+
+    // A deploy can't commit a tree whose chunks are gone
+    let err = storage
+        .commit_snapshot("example.com", &tree(a))
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Missing 1 chunks");
+    assert_eq!(storage.chunk_refs(&shared).unwrap(), 1);
+    storage.store_chunk(&a, &a[..4]).unwrap();
+    storage.commit_snapshot("example.com", &tree(a)).unwrap();
+    assert_eq!(storage.chunk_refs(&shared).unwrap(), 2);
+
+    // Counts are rebuilt for stores that predate them
+    drop(storage);
+    let conn = rusqlite::Connection::open(temp.path().join("index.db")).unwrap();
+    conn.execute("DROP TABLE chunk_refs", []).unwrap();
+    drop(conn);
+    let storage = Storage::open(temp.path()).unwrap();
+    assert_eq!(storage.chunk_refs(&shared).unwrap(), 2);
+    assert_eq!(storage.chunk_refs(&b).unwrap(), 1);
+    assert!(storage.delete_snapshot(second).unwrap());
+    assert!(storage.get_chunk(&b).unwrap().is_none());
+}
+
+#[test]
+fn test_storage_default_token_ttl() {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    // No policy: tokens never expire
+    assert_eq!(storage.default_token_ttl().unwrap(), None);
+    let permanent = storage.add_token().unwrap();
+    assert_eq!(storage.token_expires_at(&permanent).unwrap(), None);
+
+    // With a default policy, new tokens expire
+    let ninety_days = Duration::from_secs(90 * 24 * 60 * 60);
+    storage.set_default_token_ttl(Some(ninety_days)).unwrap();
+    assert_eq!(storage.default_token_ttl().unwrap(), Some(ninety_days));
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let expiring = storage.add_token().unwrap();
+    let expires_at = storage.token_expires_at(&expiring).unwrap().unwrap();
+    assert!((expires_at - now - ninety_days.as_secs() as i64).abs() <= 5);
+    assert!(storage.verify_token(&expiring).unwrap());
+
+    // Explicit opt-out overrides the policy; existing tokens are untouched
+    let never = storage.add_token_with_ttl(None).unwrap();
+    assert_eq!(storage.token_expires_at(&never).unwrap(), None);
+    assert_eq!(storage.token_expires_at(&permanent).unwrap(), None);
+
+    // Expired tokens are rejected
+    let expired = storage.add_token_with_ttl(Some(Duration::ZERO)).unwrap();
+    assert!(!storage.verify_token(&expired).unwrap());
+}
+
+#[test]
+fn test_storage_token_labels() {
+    use webpub::server::storage::StorageError;
+
+    let temp = TempDir::new().unwrap();
+
+    // A store whose tokens table predates labels
+    let conn = rusqlite::Connection::open(temp.path().join("index.db")).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE tokens (
+            id INTEGER PRIMARY KEY,
+            token TEXT UNIQUE NOT NULL,
+            created_at TEXT DEFAULT CURRENT_TIMESTAMP
+        );
+        INSERT INTO tokens (token) VALUES ('legacy');",
+    )
+    .unwrap();
+    drop(conn);
+
+    let storage = Storage::open(temp.path()).unwrap();
+    assert!(storage.verify_token("legacy").unwrap());
+
+    let ci = storage.add_labeled_token("github-ci", None).unwrap();
+    let plain = storage.add_token().unwrap();
+    assert!(storage.verify_token(&ci).unwrap());
+
+    let tokens: Vec<(String, Option<String>)> = storage
+        .list_tokens()
+        .unwrap()
+        .into_iter()
+        .map(|t| (t.prefix, t.label))
+        .collect();
+    assert_eq!(
+        tokens,
+        vec![
+            ("legacy".to_string(), None),
+            (ci[..8].to_string(), Some("github-ci".to_string())),
+            (plain[..8].to_string(), None),
+        ]
+    );
+
+    // Labels are unique
+    assert!(matches!(
+        storage.add_labeled_token("github-ci", None),
+        Err(StorageError::LabelInUse(label)) if label == "github-ci"
+    ));
+
+    // Revoke by label or by token
+    assert!(storage.revoke_token("github-ci").unwrap());
+    assert!(!storage.verify_token(&ci).unwrap());
+    assert!(storage.verify_token(&plain).unwrap());
+    assert!(!storage.revoke_token("github-ci").unwrap());
+    assert!(storage.revoke_token(&plain).unwrap());
+
+    // The label is free again once its token is revoked
+    storage.add_labeled_token("github-ci", None).unwrap();
+}
+
+#[test]
+fn test_storage_tokens_hashed_at_rest() {
+    let temp = TempDir::new().unwrap();
+    let stored_tokens = || -> Vec<String> {
+        let conn = rusqlite::Connection::open(temp.path().join("index.db")).unwrap();
+        let mut stmt = conn.prepare("SELECT token FROM tokens").unwrap();
+        let tokens = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        tokens
+    };
+
+    // A plaintext token from before tokens were hashed
+    let legacy = "ab".repeat(32);
+    {
+        let _storage = Storage::open(temp.path()).unwrap();
+    }
+    let conn = rusqlite::Connection::open(temp.path().join("index.db")).unwrap();
+    conn.execute("INSERT INTO tokens (token) VALUES (?1)", [&legacy])
+        .unwrap();
+    drop(conn);
+
+    let storage = Storage::open(temp.path()).unwrap();
+    let token = storage.add_token().unwrap();
+
+    // Neither secret is in the index, yet both still verify
+    let stored = stored_tokens();
+    assert_eq!(stored.len(), 2);
+    assert!(!stored.contains(&legacy));
+    assert!(!stored.contains(&token));
+    assert!(storage.verify_token(&legacy).unwrap());
+    assert!(storage.verify_token(&token).unwrap());
+
+    // The stored hash itself is not a credential
+    assert!(!storage.verify_token(&stored[1]).unwrap());
+
+    let prefixes: Vec<String> = storage
+        .list_tokens()
+        .unwrap()
+        .into_iter()
+        .map(|t| t.prefix)
+        .collect();
+    assert_eq!(
+        prefixes,
+        vec![legacy[..8].to_string(), token[..8].to_string()]
+    );
+
+    // Reopening doesn't hash the hashes again
+    drop(storage);
+    let storage = Storage::open(temp.path()).unwrap();
+    assert!(storage.verify_token(&legacy).unwrap());
+    assert!(storage.revoke_token(&token).unwrap());
+    assert_eq!(stored_tokens().len(), 1);
+}
+
+#[test]
+fn test_storage_compact_and_rebalance() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+    // Database files only, not their WAL files
+    let db_files = || {
+        std::fs::read_dir(temp.path().join("chunks"))
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("db".as_ref()))
+            .count()
+    };
+
+    let hashes: Vec<[u8; 32]> = (0..20u8).map(|i| [i * 12; 32]).collect();
+    for hash in &hashes {
+        storage.store_chunk(hash, &hash[..4]).unwrap();
+    }
+    assert_eq!(db_files(), 20);
+
+    // Too many chunks for the threshold
+    assert!(!storage.compact(10).unwrap());
+    assert_eq!(storage.chunk_layout(), ChunkLayout::Sharded);
+
+    assert!(storage.compact(100).unwrap());
+    assert_eq!(storage.chunk_layout(), ChunkLayout::Single);
+    assert_eq!(db_files(), 1);
+    for hash in &hashes {
+        assert_eq!(storage.get_chunk(hash).unwrap(), Some(hash[..4].to_vec()));
+    }
+
+    // New chunks go to the single database, and the layout survives reopening
+    let extra = [255u8; 32];
+    storage.store_chunk(&extra, b"extra").unwrap();
+    drop(storage);
+    let storage = Storage::open(temp.path()).unwrap();
+    assert_eq!(storage.chunk_layout(), ChunkLayout::Single);
+    assert_eq!(db_files(), 1);
+    assert_eq!(storage.chunk_count().unwrap(), 21);
+
+    assert!(storage.rebalance().unwrap());
+    assert_eq!(storage.chunk_layout(), ChunkLayout::Sharded);
+    assert_eq!(db_files(), 21);
+    for hash in hashes.iter().chain([&extra]) {
+        assert!(storage.get_chunk(hash).unwrap().is_some());
+    }
+    assert!(!storage.rebalance().unwrap());
+}
+
+#[test]
+fn test_storage_gc_during_relayout() {
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+
+    // gc keeps finding orphans while the layout flips back and forth; with
+    // the locks taken in different orders the two deadlock
+    let (done_tx, done_rx) = mpsc::channel();
+    let collector = {
+        let storage = storage.clone();
+        let done = done_tx.clone();
+        std::thread::spawn(move || {
+            for round in 0..30u8 {
+                for i in 0..8u8 {
+                    storage
+                        .store_chunk(&[i * 32 + round; 32], b"orphan")
+                        .unwrap();
+                }
+                storage.gc().unwrap();
+            }
+            done.send(()).unwrap();
+        })
+    };
+    let mover = {
+        let storage = storage.clone();
+        std::thread::spawn(move || {
+            for _ in 0..30 {
+                storage.compact(1000).unwrap();
+                storage.rebalance().unwrap();
+            }
+            done_tx.send(()).unwrap();
+        })
+    };
+    for _ in 0..2 {
+        done_rx
+            .recv_timeout(Duration::from_secs(60))
+            .expect("gc and relayout deadlocked");
+    }
+    collector.join().unwrap();
+    mover.join().unwrap();
+
+    // Anything a pass skipped because the layout moved is collected now
+    storage.gc().unwrap();
+    assert_eq!(storage.chunk_count().unwrap(), 0);
+}
+
+fn site_tree() -> Node {
+    Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![
+            Node::File {
+                name: "index.html".to_string(),
+                permissions: 0o644,
+                size: 64,
+                chunks: vec![[1u8; 32], [2u8; 32]],
+                hash: [3u8; 32],
+                content_hash: [3u8; 32],
+                mtime: 0,
+            },
+            Node::Directory {
+                name: "css".to_string(),
+                permissions: 0o755,
+                children: vec![Node::File {
+                    name: "style.css".to_string(),
+                    permissions: 0o600,
+                    size: 10,
+                    chunks: vec![[4u8; 32]],
+                    hash: [5u8; 32],
+                    content_hash: [5u8; 32],
+                    mtime: 0,
+                }],
+                hash: [6u8; 32],
+            },
+        ],
+        hash: [7u8; 32],
+    }
+}
+
+#[test]
+fn test_lookup_path() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+    let id = storage
+        .create_snapshot("example.com", &site_tree())
+        .unwrap();
+
+    assert_eq!(
+        storage.lookup_path(id, "/css//style.css").unwrap(),
+        Some(SnapshotEntry::File {
+            permissions: 0o600,
+            size: 10,
+            chunks: vec![[4u8; 32]],
+            hash: [5u8; 32],
+        })
+    );
+    assert_eq!(
+        storage.lookup_path(id, "/css/").unwrap(),
+        Some(SnapshotEntry::Directory { permissions: 0o755 })
+    );
+    assert_eq!(
+        storage.lookup_path(id, "/").unwrap(),
+        Some(SnapshotEntry::Directory { permissions: 0o755 })
+    );
+    assert_eq!(storage.lookup_path(id, "/missing").unwrap(), None);
+    assert_eq!(storage.lookup_path(id + 1, "/index.html").unwrap(), None);
+
+    let entry = |name: &str, is_dir, size| DirectoryEntry {
+        name: name.to_string(),
+        is_dir,
+        size,
+    };
+    assert_eq!(
+        storage.list_directory(id, "/").unwrap(),
+        vec![entry("css", true, 0), entry("index.html", false, 64)]
+    );
+    assert_eq!(
+        storage.list_directory(id, "css").unwrap(),
+        vec![entry("style.css", false, 10)]
+    );
+    assert_eq!(storage.tree_loads(), 0);
+}
+
+#[test]
+fn test_lookup_path_indexes_existing_snapshots() {
+    let temp = TempDir::new().unwrap();
+    let id = {
+        let storage = Storage::open(temp.path()).unwrap();
+        storage
+            .create_snapshot("example.com", &site_tree())
+            .unwrap()
+    };
+
+    // Simulate a store created before snapshots were indexed by path
+    let conn = rusqlite::Connection::open(temp.path().join("index.db")).unwrap();
+    conn.execute("DELETE FROM files", []).unwrap();
+    drop(conn);
+
+    let storage = Storage::open(temp.path()).unwrap();
+    assert!(matches!(
+        storage.lookup_path(id, "index.html").unwrap(),
+        Some(SnapshotEntry::File { size: 64, .. })
+    ));
+
+    // Deleting a snapshot removes its entries
+    let mut tree = site_tree();
+    if let Node::Directory { hash, .. } = &mut tree {
+        hash[0] = 9;
+    }
+    let newer = storage.create_snapshot("example.com", &tree).unwrap();
+    assert!(storage.delete_snapshot(id).unwrap());
+    assert_eq!(storage.lookup_path(id, "index.html").unwrap(), None);
+    assert!(storage.lookup_path(newer, "index.html").unwrap().is_some());
+}
+
+#[test]
+#[ignore] // Benchmark; run with --release --ignored --nocapture
+fn bench_concurrent_get_chunk() {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+
+    // 16KB chunks spread over every shard
+    let hashes: Vec<[u8; 32]> = (0..4096u32)
+        .map(|i| {
+            let data = vec![i as u8; 16 * 1024];
+            let mut hash = *blake3::hash(&i.to_le_bytes()).as_bytes();
+            hash[0] = (i % 256) as u8;
+            storage.store_chunk(&hash, &data).unwrap();
+            hash
+        })
+        .collect();
+    let hashes = Arc::new(hashes);
+
+    let reads_per_thread = 20_000;
+    for threads in [1, 2, 4, 8] {
+        let start = Instant::now();
+        let workers: Vec<_> = (0..threads)
+            .map(|t| {
+                let storage = storage.clone();
+                let hashes = hashes.clone();
+                std::thread::spawn(move || {
+                    for i in 0..reads_per_thread {
+                        let hash = &hashes[(i * 7 + t * 997) % hashes.len()];
+                        assert!(storage.get_chunk(hash).unwrap().is_some());
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let elapsed = start.elapsed();
+        let reads = (threads * reads_per_thread) as f64;
+        println!(
+            "{} threads: {:.0} reads/s",
+            threads,
+            reads / elapsed.as_secs_f64()
+        );
+    }
+}
+
+#[test]
+fn test_storage_reads_during_write() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open_with(
+        temp.path(),
+        StorageOptions {
+            pool_size: 1,
+            ..StorageOptions::default()
+        },
+    )
+    .unwrap();
+    let hash = [7u8; 32];
+    storage.store_chunk(&hash, b"before").unwrap();
+
+    // An uncommitted write to the same shard from another connection
+    let writer = rusqlite::Connection::open(temp.path().join("chunks/07.db")).unwrap();
+    writer
+        .execute_batch("BEGIN IMMEDIATE; UPDATE chunks SET data = x'00';")
+        .unwrap();
+
+    assert_eq!(storage.get_chunk(&hash).unwrap(), Some(b"before".to_vec()));
+    writer.execute_batch("COMMIT").unwrap();
+    assert_eq!(storage.get_chunk(&hash).unwrap(), Some(vec![0]));
+}
+
+#[test]
+fn test_storage_databases_use_wal() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+    storage.store_chunk(&[3u8; 32], b"data").unwrap();
+
+    for db in ["index.db", "chunks/03.db"] {
+        let conn = rusqlite::Connection::open(temp.path().join(db)).unwrap();
+        let mode: String = conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal", "{}", db);
+    }
+}
+
+#[test]
+fn test_storage_open_with_pragmas() {
+    assert_eq!("FULL".parse::<Synchronous>().unwrap(), Synchronous::Full);
+    assert_eq!("off".parse::<Synchronous>().unwrap(), Synchronous::Off);
+    assert!("sometimes".parse::<Synchronous>().is_err());
+
+    let temp = TempDir::new().unwrap();
+    let options = StorageOptions {
+        synchronous: Synchronous::Full,
+        mmap_size: Some(1 << 20),
+        ..StorageOptions::default()
+    };
+    let storage = Storage::open_with(temp.path(), options).unwrap();
+    storage.store_chunk(&[5u8; 32], b"durable").unwrap();
+    assert_eq!(
+        storage.get_chunk(&[5u8; 32]).unwrap(),
+        Some(b"durable".to_vec())
+    );
+}
+
+#[test]
+fn test_storage_writes_while_reading() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+    let read_hash = [9u8; 32];
+    storage.store_chunk(&read_hash, b"read me").unwrap();
+
+    // A deploy writing to the shard that requests read from
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            for i in 0..50u8 {
+                let mut hash = [9u8; 32];
+                hash[1] = 100 + i;
+                storage.store_chunks(&[(hash, vec![i; 1024])]).unwrap();
+            }
+        });
+        for _ in 0..200 {
+            assert_eq!(
+                storage.get_chunk(&read_hash).unwrap(),
+                Some(b"read me".to_vec())
+            );
+        }
+    });
+}
+
+#[test]
+fn test_storage_get_snapshot() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+    let tree = Node::Directory {
+        name: String::new(),
+        permissions: 0o755,
+        hash: [1u8; 32],
+        children: vec![],
+    };
+    let id = storage.create_snapshot("example.com", &tree).unwrap();
+
+    assert_eq!(storage.get_snapshot("example.com", id).unwrap(), Some(tree));
+    // Another site's snapshot IDs aren't visible
+    assert_eq!(storage.get_snapshot("other.com", id).unwrap(), None);
+    assert_eq!(storage.get_snapshot("example.com", id + 1).unwrap(), None);
+}
+
+#[test]
+fn test_storage_commit_checks_file_sizes() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    let (a, b) = ([1u8; 32], [2u8; 32]);
+    storage.store_chunk(&a, b"hello ").unwrap();
+    storage.store_chunk(&b, b"world").unwrap();
+    assert_eq!(
+        storage.chunk_sizes(&[a, b, [3u8; 32]]).unwrap(),
+        [(a, 6), (b, 5)].into_iter().collect()
+    );
+
+    let tree = |size: u64| Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![Node::Directory {
+            name: "docs".to_string(),
+            permissions: 0o755,
+            children: vec![Node::File {
+                name: "index.html".to_string(),
+                permissions: 0o644,
+                size,
+                hash: a,
+                content_hash: a,
+                mtime: 0,
+                chunks: vec![a, b, a],
+            }],
+            hash: b,
+        }],
+        hash: a,
+    };
+
+    let err = storage
+        .commit_snapshot("example.com", &tree(11))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "File size doesn't match its chunks: /docs/index.html"
+    );
+    assert!(storage.list_snapshots("example.com").unwrap().is_empty());
+    assert_eq!(storage.chunk_refs(&a).unwrap(), 0);
+
+    storage.commit_snapshot("example.com", &tree(17)).unwrap();
+    assert_eq!(storage.list_snapshots("example.com").unwrap().len(), 1);
+}
+
+#[test]
+fn test_storage_commit_check_reads_no_chunk_data() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    // Chunks spread over every shard, one of them never uploaded
+    let hashes: Vec<[u8; 32]> = (0..=255u8).map(|i| [i; 32]).collect();
+    for hash in &hashes[1..] {
+        storage.store_chunk(hash, &hash[..1]).unwrap();
+    }
+    let tree = Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![Node::File {
+            name: "big.bin".to_string(),
+            permissions: 0o644,
+            size: hashes.len() as u64,
+            hash: [9u8; 32],
+            content_hash: [9u8; 32],
+            mtime: 0,
+            chunks: hashes.clone(),
+        }],
+        hash: [9u8; 32],
+    };
+
+    let reads = storage.chunk_reads();
+    match storage.commit_snapshot("example.com", &tree) {
+        Err(StorageError::MissingChunks(missing)) => assert_eq!(missing, vec![hashes[0]]),
+        other => panic!("expected missing chunks, got {:?}", other),
+    }
+    storage.store_chunk(&hashes[0], &[0]).unwrap();
+    storage.commit_snapshot("example.com", &tree).unwrap();
+    assert_eq!(storage.chunk_reads(), reads);
+}
+
+#[test]
+fn test_storage_commit_snapshots_together() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    let (a, b) = ([1u8; 32], [2u8; 32]);
+    storage.store_chunk(&a, b"docs").unwrap();
+    let tree = |chunk: [u8; 32]| Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![Node::File {
+            name: "index.html".to_string(),
+            permissions: 0o644,
+            size: 4,
+            hash: chunk,
+            content_hash: chunk,
+            mtime: 0,
+            chunks: vec![chunk],
+        }],
+        hash: chunk,
+    };
+    let (docs, blog) = (tree(a), tree(b));
+
+    // One missing chunk keeps every site from being committed
+    let err = storage
+        .commit_snapshots(&[("docs.example.com", &docs), ("blog.example.com", &blog)])
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Missing 1 chunks");
+    assert!(storage
+        .list_snapshots("docs.example.com")
+        .unwrap()
+        .is_empty());
+    assert_eq!(storage.chunk_refs(&a).unwrap(), 0);
+
+    storage.store_chunk(&b, b"blog").unwrap();
+    let ids = storage
+        .commit_snapshots(&[("docs.example.com", &docs), ("blog.example.com", &blog)])
+        .unwrap();
+    assert_eq!(ids.len(), 2);
+    assert_eq!(
+        storage.get_current_snapshot_id("docs.example.com").unwrap(),
+        Some(ids[0].snapshot_id)
+    );
+    assert_eq!(
+        storage.get_current_snapshot_id("blog.example.com").unwrap(),
+        Some(ids[1].snapshot_id)
+    );
+}
+
+#[test]
+fn test_storage_delete_site() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    let (shared, own, other) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+    for hash in [shared, own, other] {
+        storage.store_chunk(&hash, &hash[..4]).unwrap();
+    }
+    let tree = |chunk: [u8; 32]| Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![Node::File {
+            name: "index.html".to_string(),
+            permissions: 0o644,
+            size: 8,
+            hash: chunk,
+            content_hash: chunk,
+            mtime: 0,
+            chunks: vec![shared, chunk],
+        }],
+        hash: chunk,
+    };
+    storage
+        .create_snapshot("old.example.com", &tree(own))
+        .unwrap();
+    storage
+        .create_snapshot("old.example.com", &tree(own))
+        .unwrap();
+    storage
+        .create_snapshot("example.com", &tree(other))
+        .unwrap();
+
+    // Every snapshot goes, current included, with chunks only it used
+    assert!(storage.delete_site("old.example.com").unwrap());
+    assert!(storage
+        .list_snapshots("old.example.com")
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        storage.get_current_snapshot_id("old.example.com").unwrap(),
+        None
+    );
+    assert_eq!(storage.count_sites().unwrap(), 1);
+    assert!(storage.get_chunk(&own).unwrap().is_none());
+    assert_eq!(storage.chunk_refs(&shared).unwrap(), 1);
+
+    // Other sites keep their chunks through a later gc
+    assert_eq!(storage.gc().unwrap().chunks_deleted, 0);
+    assert!(storage.get_chunk(&shared).unwrap().is_some());
+    assert!(storage
+        .get_current_snapshot_id("example.com")
+        .unwrap()
+        .is_some());
+
+    assert!(!storage.delete_site("old.example.com").unwrap());
+}
+
+#[test]
+fn test_storage_list_sites() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+    assert!(storage.list_sites().unwrap().is_empty());
+
+    let tree = site_tree();
+    let mut newer = site_tree();
+    if let Node::Directory { hash, .. } = &mut newer {
+        hash[0] = 9;
+    }
+    storage.create_snapshot("example.com", &tree).unwrap();
+    let current = storage.create_snapshot("example.com", &newer).unwrap();
+    let docs = storage.create_snapshot("docs.example.com", &tree).unwrap();
+
+    // A failed commit still registers the site, without snapshots
+    assert!(storage.commit_snapshot("new.example.com", &tree).is_err());
+
+    assert_eq!(
+        storage.list_sites().unwrap(),
+        vec![
+            ("docs.example.com".to_string(), 1, Some(docs)),
+            ("example.com".to_string(), 2, Some(current)),
+            ("new.example.com".to_string(), 0, None),
+        ]
+    );
+}
+
+#[test]
+fn test_storage_identical_tree_keeps_current() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    let tree = |hash: u8| Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![],
+        hash: [hash; 32],
+    };
+    let first = storage.commit_snapshot("example.com", &tree(1)).unwrap();
+    assert!(!first.unchanged);
+
+    let again = storage.commit_snapshot("example.com", &tree(1)).unwrap();
+    assert_eq!(again.snapshot_id, first.snapshot_id);
+    assert!(again.unchanged);
+    assert_eq!(storage.list_snapshots("example.com").unwrap().len(), 1);
+    assert_eq!(
+        storage.create_snapshot("example.com", &tree(1)).unwrap(),
+        first.snapshot_id
+    );
+
+    // Only the current tree counts: after a rollback to it, the newer tree
+    // is a new snapshot again
+    let second = storage.commit_snapshot("example.com", &tree(2)).unwrap();
+    assert!(storage
+        .set_current_snapshot("example.com", first.snapshot_id)
+        .unwrap());
+    let third = storage.commit_snapshot("example.com", &tree(2)).unwrap();
+    assert!(!third.unchanged);
+    assert!(third.snapshot_id > second.snapshot_id);
+    assert_eq!(storage.list_snapshots("example.com").unwrap().len(), 3);
+}
+
+#[test]
+fn test_storage_snapshot_timestamps() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    let tree = |hash: u8| Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![],
+        hash: [hash; 32],
+    };
+    let before = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let old = storage.create_snapshot("example.com", &tree(1)).unwrap();
+    let new = storage.create_snapshot("example.com", &tree(2)).unwrap();
+    let after = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    // Stored as explicit UTC
+    let conn = rusqlite::Connection::open(temp.path().join("index.db")).unwrap();
+    let stored: String = conn
+        .query_row(
+            "SELECT created_at FROM snapshots WHERE id = ?1",
+            [new],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(stored.ends_with('Z'), "{}", stored);
+
+    // Rows written by older servers hold SQLite's CURRENT_TIMESTAMP
+    conn.execute(
+        "UPDATE snapshots SET created_at = '2024-05-01 12:30:00' WHERE id = ?1",
+        [old],
+    )
+    .unwrap();
+
+    let list = storage.list_snapshots("example.com").unwrap();
+    assert_eq!(list[1], (old, false, 1_714_566_600, None));
+    assert_eq!((list[0].0, list[0].1), (new, true));
+    assert!((before..=after).contains(&list[0].2), "{:?}", list[0]);
+}
+
+#[test]
+fn test_storage_tag_snapshot() {
+    let temp = TempDir::new().unwrap();
+    let storage = Storage::open(temp.path()).unwrap();
+
+    let tree = |hash: u8| Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![],
+        hash: [hash; 32],
+    };
+    let first = storage.create_snapshot("example.com", &tree(1)).unwrap();
+    let second = storage.create_snapshot("example.com", &tree(2)).unwrap();
+    let other = storage.create_snapshot("other.com", &tree(3)).unwrap();
+
+    assert!(storage
+        .tag_snapshot("example.com", first, "stable")
+        .unwrap());
+    assert!(storage.tag_snapshot("other.com", other, "stable").unwrap());
+    assert_eq!(
+        storage
+            .find_tagged_snapshot("example.com", "stable")
+            .unwrap(),
+        Some(first)
+    );
+    let list = storage.list_snapshots("example.com").unwrap();
+    assert_eq!(list[1].3.as_deref(), Some("stable"));
+    assert_eq!(list[0].3, None);
+
+    // A tag names one snapshot per site, so tagging another moves it
+    assert!(storage
+        .tag_snapshot("example.com", second, "stable")
+        .unwrap());
+    assert_eq!(
+        storage
+            .find_tagged_snapshot("example.com", "stable")
+            .unwrap(),
+        Some(second)
+    );
+    assert_eq!(
+        storage.find_tagged_snapshot("other.com", "stable").unwrap(),
+        Some(other)
+    );
+    assert_eq!(storage.list_snapshots("example.com").unwrap()[1].3, None);
+
+    // Another site's snapshot can't be tagged through this one
+    assert!(!storage.tag_snapshot("example.com", other, "beta").unwrap());
+    assert_eq!(
+        storage.find_tagged_snapshot("example.com", "beta").unwrap(),
+        None
+    );
+}
+
+#[test]
+fn test_storage_import_archive() {
+    use std::fs;
+    use webpub::archive::{
+        read_index, write_archive, write_archive_with, ArchiveError, Compression,
+    };
+    use webpub::{build_tree, scan_directory};
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("docs")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Home</h1>").unwrap();
+    let guide: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(site.join("docs/guide.bin"), &guide).unwrap();
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    let archive = temp.path().join("site.webpub");
+    write_archive(&archive, &tree, &chunks).unwrap();
+
+    let storage = Storage::open(&temp.path().join("data")).unwrap();
+    let commit = storage.import_archive(&archive, "example.com").unwrap();
+    assert!(!commit.unchanged);
+    let (id, stored) = storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .unwrap();
+    assert_eq!(id, commit.snapshot_id);
+    assert_eq!(stored.hash(), tree.hash());
+    match storage.lookup_path(id, "/docs/guide.bin").unwrap() {
+        Some(SnapshotEntry::File { chunks, .. }) => {
+            assert_eq!(storage.read_file(&chunks).unwrap().unwrap(), guide)
+        }
+        other => panic!("unexpected entry {:?}", other),
+    }
+
+    // Importing the same archive again changes nothing
+    let again = storage.import_archive(&archive, "example.com").unwrap();
+    assert!(again.unchanged);
+    assert_eq!(again.snapshot_id, commit.snapshot_id);
+
+    let err = storage
+        .import_archive(&temp.path().join("missing.webpub"), "example.com")
+        .unwrap_err();
+    assert!(matches!(err, StorageError::Archive(_)), "{}", err);
+
+    // A damaged chunk fails the import before anything is committed
+    let raw = temp.path().join("raw.webpub");
+    write_archive_with(&raw, &tree, &chunks, Compression::None).unwrap();
+    let offset = read_index(&raw)
+        .unwrap()
+        .chunks
+        .values()
+        .next()
+        .unwrap()
+        .offset as usize;
+    let mut bytes = fs::read(&raw).unwrap();
+    bytes[offset] ^= 0xff;
+    fs::write(&raw, bytes).unwrap();
+    let err = storage.import_archive(&raw, "other.com").unwrap_err();
+    assert!(
+        matches!(err, StorageError::Archive(ArchiveError::CorruptChunk(_))),
+        "{}",
+        err
+    );
+    assert!(storage.list_snapshots("other.com").unwrap().is_empty());
+}
+
+#[test]
+fn test_storage_export_archive() {
+    use std::fs;
+    use webpub::archive::{read_archive, write_archive};
+    use webpub::{build_tree, scan_directory};
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("docs")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Home</h1>").unwrap();
+    let guide: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(site.join("docs/guide.bin"), &guide).unwrap();
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    let archive = temp.path().join("site.webpub");
+    write_archive(&archive, &tree, &chunks).unwrap();
+
+    let storage = Storage::open(&temp.path().join("data")).unwrap();
+    assert_eq!(
+        storage
+            .export_archive("example.com", &temp.path().join("none.webpub"))
+            .unwrap(),
+        None
+    );
+
+    // Import and export round-trip the site's files
+    let commit = storage.import_archive(&archive, "example.com").unwrap();
+    let exported = temp.path().join("exported.webpub");
+    assert_eq!(
+        storage.export_archive("example.com", &exported).unwrap(),
+        Some(commit.snapshot_id)
+    );
+    let out = temp.path().join("out");
+    read_archive(&exported, &out).unwrap();
+    assert_eq!(fs::read(out.join("index.html")).unwrap(), b"<h1>Home</h1>");
+    assert_eq!(fs::read(out.join("docs/guide.bin")).unwrap(), guide);
+
+    // And into another store, which serves the same tree
+    let other = Storage::open(&temp.path().join("other")).unwrap();
+    other.import_archive(&exported, "example.com").unwrap();
+    let (_, moved) = other.get_current_snapshot("example.com").unwrap().unwrap();
+    assert_eq!(moved.hash(), tree.hash());
+
+    // A snapshot whose chunks are gone can't be exported
+    let broken = Node::Directory {
+        name: "".to_string(),
+        permissions: 0o755,
+        children: vec![Node::File {
+            name: "lost.txt".to_string(),
+            permissions: 0o644,
+            size: 4,
+            chunks: vec![[9u8; 32]],
+            hash: [9u8; 32],
+            content_hash: [9u8; 32],
+            mtime: 0,
+        }],
+        hash: [8u8; 32],
+    };
+    storage.create_snapshot("broken.com", &broken).unwrap();
+    let err = storage
+        .export_archive("broken.com", &temp.path().join("broken.webpub"))
+        .unwrap_err();
+    assert!(matches!(err, StorageError::MissingChunks(ref hashes) if hashes == &vec![[9u8; 32]]));
+}
+
+#[test]
+fn test_storage_empty_file() {
+    use std::fs;
+    use webpub::archive::{read_archive, write_archive};
+    use webpub::{build_tree, scan_directory};
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("empty.txt"), "").unwrap();
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    let archive = temp.path().join("site.webpub");
+    write_archive(&archive, &tree, &chunks).unwrap();
+
+    // Imported, it's a file with no chunks that reads back empty
+    let storage = Storage::open(&temp.path().join("data")).unwrap();
+    let commit = storage.import_archive(&archive, "example.com").unwrap();
+    match storage
+        .lookup_path(commit.snapshot_id, "/empty.txt")
+        .unwrap()
+    {
+        Some(SnapshotEntry::File { chunks, size, .. }) => {
+            assert_eq!(size, 0);
+            assert!(chunks.is_empty());
+            assert_eq!(storage.read_file(&chunks).unwrap(), Some(Vec::new()));
+        }
+        other => panic!("unexpected entry {:?}", other),
+    }
+
+    // ...and exports back to an empty file
+    let exported = temp.path().join("exported.webpub");
+    storage.export_archive("example.com", &exported).unwrap();
+    let out = temp.path().join("out");
+    read_archive(&exported, &out).unwrap();
+    assert_eq!(fs::read(out.join("empty.txt")).unwrap(), b"");
+}
@@ -0,0 +1,1714 @@
+use rand::{Rng, SeedableRng};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use webpub::client::diff::diff;
+use webpub::client::list::list;
+use webpub::client::push::{push, PushOptions};
+use webpub::merkle::DiffEntry;
+use webpub::server::auth::{AuthContext, AuthError, Authenticator, SCOPE_READ};
+use webpub::server::http::find_node;
+use webpub::server::metrics::Metrics;
+use webpub::server::rate_limit::AuthLimiter;
+use webpub::server::storage::Storage;
+use webpub::server::sync::{
+    check_disk_space, handle_connection, serve, PermissionPolicy, SyncState,
+};
+use webpub::Node;
+
+/// Run a sync server on an ephemeral port, returning its WebSocket URL.
+async fn start_server(storage: Arc<Storage>) -> String {
+    start_server_with(SyncState::new(storage, 5)).await
+}
+
+async fn start_server_with(state: SyncState) -> String {
+    let state = Arc::new(state);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = listener.accept().await.unwrap();
+            tokio::spawn(handle_connection(stream, addr.ip(), state.clone()));
+        }
+    });
+    format!("ws://{}", addr)
+}
+
+/// Assert that every file under `dir` is stored byte-for-byte in `tree`.
+fn assert_stored(storage: &Storage, tree: &Node, dir: &Path, prefix: &str) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let path = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type().unwrap().is_dir() {
+            assert_stored(storage, tree, &entry.path(), &path);
+            continue;
+        }
+        match find_node(tree, &path) {
+            Some(Node::File { chunks, .. }) => {
+                let data = storage.read_file(chunks).unwrap().unwrap();
+                assert_eq!(data, fs::read(entry.path()).unwrap(), "{}", path);
+            }
+            _ => panic!("{} missing from tree", path),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_push_uploads_content_intact() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("media")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    // Large enough to span several chunks; duplicated to exercise shared chunks
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+    let video: Vec<u8> = (0..300_000).map(|_| rng.gen()).collect();
+    fs::write(site.join("media/video.bin"), &video).unwrap();
+    fs::write(site.join("media/copy.bin"), &video).unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let metrics = Arc::new(Metrics::default());
+    let url = start_server_with(SyncState {
+        metrics: metrics.clone(),
+        ..SyncState::new(storage.clone(), 5)
+    })
+    .await;
+
+    let snapshot_id = push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+    assert!(metrics
+        .render(&storage)
+        .contains("webpub_sync_commits_total{hostname=\"example.com\"} 1\n"));
+
+    let (current_id, tree) = storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .unwrap();
+    assert_eq!(current_id as u64, snapshot_id);
+    assert_stored(&storage, &tree, &site, "");
+}
+
+/// Grants read-only access to "reader" and denies everything else.
+struct ReadOnlyAuthenticator;
+
+#[async_trait::async_trait]
+impl Authenticator for ReadOnlyAuthenticator {
+    async fn authenticate(&self, credential: &str) -> Result<AuthContext, AuthError> {
+        match credential {
+            "reader" => Ok(AuthContext {
+                subject: "reader".to_string(),
+                scopes: vec![SCOPE_READ.to_string()],
+            }),
+            _ => Err(AuthError::Denied),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_custom_authenticator() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let url = start_server_with(SyncState {
+        authenticator: Arc::new(ReadOnlyAuthenticator),
+        ..SyncState::new(storage.clone(), 5)
+    })
+    .await;
+
+    // Unknown credential is rejected, even if it is a valid storage token
+    let token = storage.add_token().unwrap();
+    let err = list(&url, "example.com", &token).await.unwrap_err();
+    assert!(err.to_string().contains("Authentication failed"));
+
+    // Read scope allows listing
+    assert!(list(&url, "example.com", "reader")
+        .await
+        .unwrap()
+        .is_empty());
+
+    // ...but not deploying
+    let err = push(
+        &site,
+        &url,
+        "example.com",
+        "reader",
+        &PushOptions::default(),
+    )
+    .await
+    .unwrap_err();
+    assert!(err.to_string().contains("Permission denied"), "{}", err);
+    assert!(storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_failed_auths_rate_limited() {
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server_with(SyncState {
+        auth_limiter: AuthLimiter::per_minute(2),
+        ..SyncState::new(storage.clone(), 5)
+    })
+    .await;
+
+    for _ in 0..2 {
+        let err = list(&url, "example.com", "guess").await.unwrap_err();
+        assert!(err.to_string().contains("Authentication failed"), "{}", err);
+    }
+
+    // Further attempts are refused, even with a valid token
+    let err = list(&url, "example.com", &token).await.unwrap_err();
+    assert!(
+        err.to_string().contains("Too many failed authentications"),
+        "{}",
+        err
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_strict_permissions_reject_setuid() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("bin")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    fs::write(site.join("bin/tool"), "#!/bin/sh").unwrap();
+    fs::set_permissions(site.join("bin/tool"), fs::Permissions::from_mode(0o4755)).unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server_with(SyncState {
+        permission_policy: PermissionPolicy::Strict,
+        ..SyncState::new(storage.clone(), 5)
+    })
+    .await;
+
+    let err = push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("/bin/tool"), "{}", err);
+    assert!(!err.to_string().contains("index.html"), "{}", err);
+    assert!(storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .is_none());
+
+    // The default policy accepts the same tree
+    let url = start_server(storage.clone()).await;
+    push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_check_disk_space() {
+    assert!(check_disk_space(1000, 400, 500).is_ok());
+    assert!(check_disk_space(1000, 500, 500).is_ok());
+    let reason = check_disk_space(1000, 501, 500).unwrap_err();
+    assert!(reason.starts_with("insufficient disk space"), "{}", reason);
+    assert!(check_disk_space(1000, 1, u64::MAX).is_err());
+}
+
+#[tokio::test]
+async fn test_deploy_rejected_without_disk_space() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server_with(SyncState {
+        min_free_space: u64::MAX,
+        ..SyncState::new(storage.clone(), 5)
+    })
+    .await;
+
+    let err = push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("insufficient disk space"),
+        "{}",
+        err
+    );
+    // Rejected before any chunk was uploaded
+    assert_eq!(storage.chunk_count().unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_disk_space_estimated_from_chunk_sizes() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::protocol::{decode, encode, ClientMessage, ServerMessage, PROTOCOL_VERSION};
+
+    // Room for about 1GB beyond the reserve, give or take what other
+    // writes to the disk use meanwhile
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server_with(SyncState {
+        min_free_space: storage.available_space().unwrap().saturating_sub(1 << 30),
+        ..SyncState::new(storage.clone(), 5)
+    })
+    .await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    async fn roundtrip(
+        ws: &mut tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >,
+        msg: ClientMessage,
+    ) -> ServerMessage {
+        ws.send(Message::Binary(encode(&msg).unwrap()))
+            .await
+            .unwrap();
+        match ws.next().await.unwrap().unwrap() {
+            Message::Binary(data) => decode(&data).unwrap(),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+
+    let auth = ClientMessage::Auth {
+        token,
+        protocol_version: PROTOCOL_VERSION,
+    };
+    assert!(matches!(
+        roundtrip(&mut ws, auth).await,
+        ServerMessage::AuthOk
+    ));
+
+    // Small chunks fit
+    let hashes: Vec<[u8; 32]> = (0..100u8).map(|i| [i; 32]).collect();
+    let reply = roundtrip(
+        &mut ws,
+        ClientMessage::HaveChunks {
+            hashes: hashes.clone(),
+            sizes: vec![100; 100],
+        },
+    )
+    .await;
+    assert!(
+        matches!(&reply, ServerMessage::NeedChunks { hashes: need } if need == &hashes),
+        "{:?}",
+        reply
+    );
+
+    // A single chunk far larger than the default sizes doesn't, and is
+    // turned away before it's sent
+    let reply = roundtrip(
+        &mut ws,
+        ClientMessage::HaveChunks {
+            hashes: vec![[200u8; 32]],
+            sizes: vec![u32::MAX],
+        },
+    )
+    .await;
+    match reply {
+        ServerMessage::CommitFailed { reason, .. } => assert!(
+            reason.contains(&format!("{} bytes needed", u32::MAX)),
+            "{}",
+            reason
+        ),
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+/// A sync server that holds back acks until four chunks have arrived, so a
+/// client that waits for each ack before sending the next chunk would stall.
+/// It speaks protocol version 2, so chunks arrive one `ChunkData` at a time.
+async fn start_batching_server() -> String {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::protocol::{decode, encode, ClientMessage, Envelope, ServerMessage};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let (mut needed, mut received) = (0, 0);
+        let mut pending = Vec::new();
+
+        while let Some(Ok(Message::Binary(data))) = ws.next().await {
+            let replies = match decode::<ClientMessage>(&data).unwrap() {
+                ClientMessage::Auth { .. } => {
+                    let auth_ok = Envelope {
+                        version: 2,
+                        message: ServerMessage::AuthOk,
+                    };
+                    ws.send(Message::Binary(rmp_serde::to_vec(&auth_ok).unwrap()))
+                        .await
+                        .unwrap();
+                    Vec::new()
+                }
+                ClientMessage::HaveChunks { hashes, .. } => {
+                    needed += hashes.len();
+                    vec![ServerMessage::NeedChunks { hashes }]
+                }
+                ClientMessage::ChunkData { hash, .. } => {
+                    received += 1;
+                    pending.push(ServerMessage::ChunkAck { hash });
+                    if pending.len() == 4 || received == needed {
+                        std::mem::take(&mut pending)
+                    } else {
+                        Vec::new()
+                    }
+                }
+                ClientMessage::CommitTree { .. } => vec![ServerMessage::CommitOk {
+                    snapshot_id: received as u64,
+                    unchanged: false,
+                }],
+                _ => panic!("unexpected message"),
+            };
+            for reply in replies {
+                ws.send(Message::Binary(encode(&reply).unwrap()))
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+    format!("ws://{}", addr)
+}
+
+#[tokio::test]
+async fn test_push_pipelines_chunk_uploads() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1019);
+    let data: Vec<u8> = (0..500_000).map(|_| rng.gen()).collect();
+    fs::write(site.join("data.bin"), &data).unwrap();
+
+    let url = start_batching_server().await;
+    let options = PushOptions {
+        concurrency: 4,
+        ..PushOptions::default()
+    };
+    let acked = tokio::time::timeout(
+        std::time::Duration::from_secs(10),
+        push(&site, &url, "example.com", "token", &options),
+    )
+    .await
+    .expect("push stalled waiting for acks")
+    .unwrap();
+
+    let chunks = webpub::chunker::chunk_data_default(&data).count() as u64;
+    assert!(chunks > 4, "{} chunks", chunks);
+    // Every chunk was acked before the commit
+    assert_eq!(acked, chunks);
+}
+
+#[tokio::test]
+async fn test_protocol_version_mismatch() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::protocol::{decode, encode, ClientMessage, ServerMessage, PROTOCOL_VERSION};
+
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage).await;
+
+    // A client from the future is turned away before authenticating
+    let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    let auth = ClientMessage::Auth {
+        token,
+        protocol_version: PROTOCOL_VERSION + 1,
+    };
+    ws.send(Message::Binary(encode(&auth).unwrap()))
+        .await
+        .unwrap();
+    match ws.next().await.unwrap().unwrap() {
+        Message::Binary(data) => match decode::<ServerMessage>(&data).unwrap() {
+            ServerMessage::VersionMismatch { server_version } => {
+                assert_eq!(server_version, PROTOCOL_VERSION)
+            }
+            other => panic!("unexpected reply {:?}", other),
+        },
+        other => panic!("unexpected frame {:?}", other),
+    }
+
+    // A server that rejects our version surfaces a clear client error
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        ws.next().await.unwrap().unwrap();
+        let reply = ServerMessage::VersionMismatch { server_version: 99 };
+        ws.send(Message::Binary(encode(&reply).unwrap()))
+            .await
+            .unwrap();
+    });
+    let err = list(&format!("ws://{}", addr), "example.com", "token")
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("Protocol version mismatch"),
+        "{}",
+        err
+    );
+    assert!(
+        err.to_string().contains("server speaks version 99"),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn test_push_batches_chunk_uploads() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::protocol::{decode, encode, ClientMessage, ServerMessage};
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1025);
+    let data: Vec<u8> = (0..6_000_000).map(|_| rng.gen()).collect();
+    fs::write(site.join("data.bin"), &data).unwrap();
+
+    // Records the size of each ChunkBatch frame and acks it in bulk
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let mut batches = Vec::new();
+        while let Some(Ok(Message::Binary(data))) = ws.next().await {
+            let reply = match decode::<ClientMessage>(&data).unwrap() {
+                ClientMessage::Auth { .. } => ServerMessage::AuthOk,
+                ClientMessage::GetSnapshotTree { .. } => ServerMessage::SnapshotTreeFailed {
+                    reason: "Snapshot not found".to_string(),
+                },
+                ClientMessage::HaveChunks { hashes, .. } => ServerMessage::NeedChunks { hashes },
+                ClientMessage::ChunkBatch { chunks } => {
+                    batches.push(chunks.len());
+                    ServerMessage::BatchAck {
+                        hashes: chunks.into_iter().map(|(hash, _)| hash).collect(),
+                    }
+                }
+                ClientMessage::CommitTree { .. } => {
+                    ws.send(Message::Binary(
+                        encode(&ServerMessage::CommitOk {
+                            snapshot_id: 1,
+                            unchanged: false,
+                        })
+                        .unwrap(),
+                    ))
+                    .await
+                    .unwrap();
+                    break;
+                }
+                _ => panic!("unexpected message"),
+            };
+            ws.send(Message::Binary(encode(&reply).unwrap()))
+                .await
+                .unwrap();
+        }
+        batches
+    });
+
+    push(
+        &site,
+        &format!("ws://{}", addr),
+        "example.com",
+        "token",
+        &PushOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    // About 6MB of chunks fits in two frames of up to 4MB each
+    let batches = server.await.unwrap();
+    let chunks = webpub::chunker::chunk_data_default(&data).count();
+    assert_eq!(batches.len(), 2, "{:?}", batches);
+    assert_eq!(batches.iter().sum::<usize>(), chunks);
+}
+
+#[tokio::test]
+async fn test_push_resume_skips_acked_chunks() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::client::manifest::MANIFEST_FILE;
+    use webpub::protocol::{decode, encode, ClientMessage, ServerMessage};
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1026);
+    let data: Vec<u8> = (0..6_000_000).map(|_| rng.gen()).collect();
+    fs::write(site.join("data.bin"), &data).unwrap();
+
+    // Serves pushes in turn, reporting the chunks offered in each. The
+    // first two connections are dropped right after acking one batch.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let (offered_tx, mut offered_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for connection in 0.. {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut offered = Vec::new();
+            while let Some(Ok(Message::Binary(data))) = ws.next().await {
+                let reply = match decode::<ClientMessage>(&data).unwrap() {
+                    ClientMessage::Auth { .. } => ServerMessage::AuthOk,
+                    ClientMessage::GetSnapshotTree { .. } => ServerMessage::SnapshotTreeFailed {
+                        reason: "Snapshot not found".to_string(),
+                    },
+                    ClientMessage::HaveChunks { hashes, .. } => {
+                        offered.extend(hashes.iter().copied());
+                        ServerMessage::NeedChunks { hashes }
+                    }
+                    ClientMessage::ChunkBatch { chunks } => ServerMessage::BatchAck {
+                        hashes: chunks.into_iter().map(|(hash, _)| hash).collect(),
+                    },
+                    ClientMessage::CommitTree { .. } => ServerMessage::CommitOk {
+                        snapshot_id: 1,
+                        unchanged: false,
+                    },
+                    _ => panic!("unexpected message"),
+                };
+                let ack = matches!(reply, ServerMessage::BatchAck { .. });
+                ws.send(Message::Binary(encode(&reply).unwrap()))
+                    .await
+                    .unwrap();
+                if connection < 2 && ack {
+                    break;
+                }
+            }
+            offered_tx.send(offered).unwrap();
+        }
+    });
+
+    let options = PushOptions {
+        concurrency: 1,
+        ..PushOptions::default()
+    };
+    let all = webpub::chunker::chunk_data_default(&data).count();
+
+    let untouched = || {
+        let mut names: Vec<_> = fs::read_dir(&site)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names == ["data.bin"]
+    };
+
+    // Without --resume an interrupted push leaves the source dir untouched
+    assert!(push(&site, &url, "example.com", "token", &options)
+        .await
+        .is_err());
+    assert_eq!(offered_rx.recv().await.unwrap().len(), all);
+    assert!(untouched());
+
+    // With --resume it leaves a manifest behind
+    let resume = PushOptions {
+        resume: true,
+        ..options.clone()
+    };
+    assert!(push(&site, &url, "example.com", "token", &resume)
+        .await
+        .is_err());
+    assert_eq!(offered_rx.recv().await.unwrap().len(), all);
+    assert!(site.join(MANIFEST_FILE).exists());
+
+    // Resuming offers only the chunks that were never acked
+    push(&site, &url, "example.com", "token", &resume)
+        .await
+        .unwrap();
+    let remaining = offered_rx.recv().await.unwrap().len();
+    assert!(remaining > 0 && remaining < all, "{} of {}", remaining, all);
+    assert!(untouched());
+
+    // Without --resume everything is offered again, and nothing is written
+    push(&site, &url, "example.com", "token", &options)
+        .await
+        .unwrap();
+    assert_eq!(offered_rx.recv().await.unwrap().len(), all);
+    assert!(untouched());
+}
+
+#[tokio::test]
+async fn test_mismatched_chunk_rejected() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::protocol::{decode, encode, ClientMessage, ServerMessage, PROTOCOL_VERSION};
+
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    async fn roundtrip(
+        ws: &mut tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >,
+        msg: ClientMessage,
+    ) -> ServerMessage {
+        ws.send(Message::Binary(encode(&msg).unwrap()))
+            .await
+            .unwrap();
+        match ws.next().await.unwrap().unwrap() {
+            Message::Binary(data) => decode(&data).unwrap(),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+
+    let auth = ClientMessage::Auth {
+        token,
+        protocol_version: PROTOCOL_VERSION,
+    };
+    assert!(matches!(
+        roundtrip(&mut ws, auth).await,
+        ServerMessage::AuthOk
+    ));
+
+    let good = b"honest data".to_vec();
+    let good_hash = *blake3::hash(&good).as_bytes();
+    let forged = [7u8; 32];
+
+    // A single chunk whose data doesn't match its hash
+    let reply = roundtrip(
+        &mut ws,
+        ClientMessage::ChunkData {
+            hash: forged,
+            data: b"poison".to_vec(),
+        },
+    )
+    .await;
+    match reply {
+        ServerMessage::ChunkRejected { hash, reason } => {
+            assert_eq!(hash, forged);
+            assert!(reason.contains(&hex::encode(forged)), "{}", reason);
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+
+    // A batch with one bad chunk stores none of them
+    let reply = roundtrip(
+        &mut ws,
+        ClientMessage::ChunkBatch {
+            chunks: vec![(good_hash, good.clone()), (forged, b"poison".to_vec())],
+        },
+    )
+    .await;
+    assert!(matches!(
+        reply,
+        ServerMessage::ChunkRejected { hash, .. } if hash == forged
+    ));
+    assert_eq!(storage.chunk_count().unwrap(), 0);
+
+    // Honest chunks are still accepted on the same connection
+    let reply = roundtrip(
+        &mut ws,
+        ClientMessage::ChunkData {
+            hash: good_hash,
+            data: good.clone(),
+        },
+    )
+    .await;
+    assert!(matches!(reply, ServerMessage::ChunkAck { hash } if hash == good_hash));
+    assert_eq!(storage.get_chunk(&good_hash).unwrap(), Some(good));
+}
+
+#[tokio::test]
+async fn test_unknown_message_denied() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::protocol::{
+        decode, encode, ClientMessage, Envelope, ServerMessage, PROTOCOL_VERSION,
+    };
+
+    // A message from a newer client that this server doesn't know
+    #[derive(serde::Serialize)]
+    enum FutureMessage {
+        Teleport { destination: String },
+    }
+
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    async fn reply(
+        ws: &mut tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >,
+        bytes: Vec<u8>,
+    ) -> ServerMessage {
+        ws.send(Message::Binary(bytes)).await.unwrap();
+        // Well within the idle timeout that would otherwise close the socket
+        let frame = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("no reply to unknown message");
+        match frame.unwrap().unwrap() {
+            Message::Binary(data) => decode(&data).unwrap(),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+
+    let auth = ClientMessage::Auth {
+        token,
+        protocol_version: PROTOCOL_VERSION,
+    };
+    assert!(matches!(
+        reply(&mut ws, encode(&auth).unwrap()).await,
+        ServerMessage::AuthOk
+    ));
+
+    let unknown = rmp_serde::to_vec(&Envelope {
+        version: PROTOCOL_VERSION + 1,
+        message: FutureMessage::Teleport {
+            destination: "mars".to_string(),
+        },
+    })
+    .unwrap();
+    match reply(&mut ws, unknown).await {
+        ServerMessage::Denied { reason } => {
+            assert!(reason.contains("unsupported message"), "{}", reason);
+            assert!(
+                reason.contains(&(PROTOCOL_VERSION + 1).to_string()),
+                "{}",
+                reason
+            );
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+
+    // The connection stays usable
+    let have = ClientMessage::HaveChunks {
+        hashes: Vec::new(),
+        sizes: Vec::new(),
+    };
+    assert!(matches!(
+        reply(&mut ws, encode(&have).unwrap()).await,
+        ServerMessage::NeedChunks { .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_sync_connection_limits() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::protocol::{encode, ClientMessage, PROTOCOL_VERSION};
+
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server_with(SyncState {
+        idle_timeout: Duration::from_millis(200),
+        max_message_size: 1024,
+        ..SyncState::new(storage.clone(), 5)
+    })
+    .await;
+    let auth = ClientMessage::Auth {
+        token,
+        protocol_version: PROTOCOL_VERSION,
+    };
+
+    // A client that goes quiet after authenticating is closed
+    let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    ws.send(Message::Binary(encode(&auth).unwrap()))
+        .await
+        .unwrap();
+    assert!(matches!(ws.next().await, Some(Ok(Message::Binary(_)))));
+    match ws.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => {
+            assert_eq!(frame.code, CloseCode::Policy);
+            assert!(frame.reason.contains("No message"), "{}", frame.reason);
+        }
+        other => panic!("expected close frame, got {:?}", other),
+    }
+
+    // So is one that sends a message over the size limit
+    let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    ws.send(Message::Binary(encode(&auth).unwrap()))
+        .await
+        .unwrap();
+    assert!(matches!(ws.next().await, Some(Ok(Message::Binary(_)))));
+    let chunk = ClientMessage::ChunkData {
+        hash: [0u8; 32],
+        data: vec![0u8; 2048],
+    };
+    ws.send(Message::Binary(encode(&chunk).unwrap()))
+        .await
+        .unwrap();
+    match ws.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => {
+            assert_eq!(frame.code, CloseCode::Size);
+        }
+        other => panic!("expected close frame, got {:?}", other),
+    }
+    assert_eq!(storage.chunk_count().unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_serve_shutdown_waits_for_sessions() {
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let state = Arc::new(SyncState::new(storage.clone(), 5));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(serve(
+        listener,
+        state.clone(),
+        None,
+        shutdown.clone(),
+        Duration::from_secs(10),
+    ));
+
+    // A session open when shutdown starts keeps the server running
+    let (session, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+        .await
+        .unwrap();
+    shutdown.cancel();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!server.is_finished());
+
+    // New connections are no longer accepted
+    assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+
+    // Once it ends, the server stops
+    drop(session);
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Sessions still open after the grace period don't hold it up
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn(serve(
+        listener,
+        state,
+        None,
+        shutdown.clone(),
+        Duration::from_millis(100),
+    ));
+    let (_session, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+        .await
+        .unwrap();
+    shutdown.cancel();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_diff_snapshots() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("css")).unwrap();
+    fs::write(site.join("index.html"), "<h1>v1</h1>").unwrap();
+    fs::write(site.join("about.html"), "about").unwrap();
+    fs::write(site.join("css/site.css"), "body {}").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+    let options = PushOptions::default();
+
+    let first = push(&site, &url, "example.com", &token, &options)
+        .await
+        .unwrap();
+    fs::write(site.join("index.html"), "<h1>v2</h1>").unwrap();
+    fs::remove_file(site.join("about.html")).unwrap();
+    fs::write(site.join("contact.html"), "contact").unwrap();
+    let second = push(&site, &url, "example.com", &token, &options)
+        .await
+        .unwrap();
+
+    // The current snapshot against the one before it
+    let changes = diff(&url, "example.com", &token, None, None).await.unwrap();
+    assert_eq!((changes.old_id, changes.new_id), (first, second));
+    assert_eq!(
+        changes.changes,
+        vec![
+            DiffEntry::Removed("about.html".to_string()),
+            DiffEntry::Added("contact.html".to_string()),
+            DiffEntry::Modified("index.html".to_string()),
+        ]
+    );
+
+    // Explicit IDs, in either direction
+    let reverse = diff(&url, "example.com", &token, Some(second), Some(first))
+        .await
+        .unwrap();
+    assert_eq!(reverse.changes.len(), 3);
+    assert!(reverse
+        .changes
+        .contains(&DiffEntry::Added("about.html".to_string())));
+
+    let err = diff(&url, "example.com", &token, None, Some(first))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("No snapshot before"), "{}", err);
+    let err = diff(&url, "example.com", &token, Some(999), None)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("Snapshot 999"), "{}", err);
+    let err = diff(&url, "other.com", &token, None, None)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("No current snapshot"), "{}", err);
+}
+
+#[tokio::test]
+async fn test_push_incremental_reuses_current_tree() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(&site).unwrap();
+    let old = std::time::SystemTime::now() - Duration::from_secs(3600);
+    for (name, contents) in [("index.html", "<h1>Hello</h1>"), ("about.html", "about")] {
+        fs::write(site.join(name), contents).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(site.join(name))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+    }
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+    push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+    let (_, first) = storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .unwrap();
+
+    // The server keeps mtimes for the next push to compare against
+    match find_node(&first, "/index.html") {
+        Some(Node::File { mtime, .. }) => assert_ne!(*mtime, 0),
+        other => panic!("unexpected node {:?}", other),
+    }
+
+    // An unchanged file whose contents differ only on disk is taken from
+    // the current tree, which --full-scan would have read
+    fs::write(site.join("about.html"), "ABOUT").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(site.join("about.html"))
+        .unwrap()
+        .set_modified(old)
+        .unwrap();
+    fs::write(site.join("new.html"), "new").unwrap();
+    push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+    let (_, second) = storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        find_node(&second, "/about.html"),
+        find_node(&first, "/about.html")
+    );
+    assert!(find_node(&second, "/new.html").is_some());
+
+    let full = PushOptions {
+        incremental: false,
+        ..PushOptions::default()
+    };
+    push(&site, &url, "example.com", &token, &full)
+        .await
+        .unwrap();
+    let (_, third) = storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .unwrap();
+    assert_ne!(
+        find_node(&third, "/about.html"),
+        find_node(&first, "/about.html")
+    );
+    assert_stored(&storage, &third, &site, "");
+}
+
+#[tokio::test]
+async fn test_push_custom_chunking_rereads_unchanged_files() {
+    use webpub::chunker::{chunk_data, ChunkConfig};
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(&site).unwrap();
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(site.join("data.bin"), &data).unwrap();
+    fs::File::options()
+        .write(true)
+        .open(site.join("data.bin"))
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() - Duration::from_secs(3600))
+        .unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+    push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+
+    // The file is unchanged, but the new push asks for different chunks
+    let fixed = PushOptions {
+        chunking: ChunkConfig::fixed(1000).unwrap(),
+        ..PushOptions::default()
+    };
+    push(&site, &url, "example.com", &token, &fixed)
+        .await
+        .unwrap();
+    let (_, tree) = storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .unwrap();
+    let expected: Vec<[u8; 32]> = chunk_data(&data, &fixed.chunking).map(|c| c.hash).collect();
+    match find_node(&tree, "/data.bin") {
+        Some(Node::File { chunks, .. }) => assert_eq!(chunks, &expected),
+        other => panic!("unexpected node {:?}", other),
+    }
+    assert_eq!(expected.len(), 10);
+    assert_stored(&storage, &tree, &site, "");
+}
+
+#[tokio::test]
+async fn test_commit_rejects_tampered_tree() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::protocol::{decode, encode, ClientMessage, ServerMessage, PROTOCOL_VERSION};
+
+    let temp = TempDir::new().unwrap();
+    let storage = Arc::new(Storage::open(temp.path()).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    let auth = ClientMessage::Auth {
+        token,
+        protocol_version: PROTOCOL_VERSION,
+    };
+    ws.send(Message::Binary(encode(&auth).unwrap()))
+        .await
+        .unwrap();
+    ws.next().await.unwrap().unwrap();
+
+    // A file node claiming a hash its (empty) chunk list doesn't produce
+    let tree = Node::Directory {
+        name: String::new(),
+        permissions: 0o755,
+        children: vec![Node::File {
+            name: "index.html".to_string(),
+            permissions: 0o644,
+            size: 0,
+            chunks: vec![],
+            hash: [7u8; 32],
+            content_hash: [7u8; 32],
+            mtime: 0,
+        }],
+        hash: [8u8; 32],
+    };
+    let commit = ClientMessage::CommitTree {
+        hostname: "example.com".to_string(),
+        tree,
+    };
+    ws.send(Message::Binary(encode(&commit).unwrap()))
+        .await
+        .unwrap();
+    match ws.next().await.unwrap().unwrap() {
+        Message::Binary(data) => match decode::<ServerMessage>(&data).unwrap() {
+            ServerMessage::CommitFailed { reason, .. } => {
+                assert_eq!(reason, "Invalid tree: hash mismatch at /index.html")
+            }
+            other => panic!("unexpected reply {:?}", other),
+        },
+        other => panic!("unexpected frame {:?}", other),
+    }
+    assert!(storage.list_snapshots("example.com").unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_commit_rejects_wrong_file_size() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::protocol::{decode, encode, ClientMessage, ServerMessage, PROTOCOL_VERSION};
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+    push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+
+    // Valid hashes and stored chunks, but a size the chunks don't add up to
+    let (_, mut tree) = storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .unwrap();
+    if let Node::Directory { children, .. } = &mut tree {
+        if let Node::File { size, .. } = &mut children[0] {
+            *size += 1;
+        }
+    }
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    let auth = ClientMessage::Auth {
+        token,
+        protocol_version: PROTOCOL_VERSION,
+    };
+    ws.send(Message::Binary(encode(&auth).unwrap()))
+        .await
+        .unwrap();
+    ws.next().await.unwrap().unwrap();
+
+    let commit = ClientMessage::CommitTree {
+        hostname: "example.com".to_string(),
+        tree,
+    };
+    ws.send(Message::Binary(encode(&commit).unwrap()))
+        .await
+        .unwrap();
+    match ws.next().await.unwrap().unwrap() {
+        Message::Binary(data) => match decode::<ServerMessage>(&data).unwrap() {
+            ServerMessage::CommitFailed { reason, .. } => {
+                assert_eq!(reason, "File size doesn't match its chunks: /index.html")
+            }
+            other => panic!("unexpected reply {:?}", other),
+        },
+        other => panic!("unexpected frame {:?}", other),
+    }
+    assert_eq!(storage.list_snapshots("example.com").unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_push_uploads_chunks_missing_at_commit() {
+    use webpub::client::manifest::{manifest_key, PushManifest};
+    use webpub::merkle::build_tree;
+    use webpub::scanner::scan_directory;
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("docs")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+    fs::write(site.join("docs/guide.html"), "<p>Guide</p>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+
+    // A manifest claiming every chunk was uploaded before, which this
+    // server never saw, so resuming offers none of them
+    let (tree, chunks) = build_tree(scan_directory(&site).unwrap().next().unwrap());
+    let key = manifest_key(&url, "example.com", tree.hash());
+    let mut manifest = PushManifest::open(&site, &key, true).unwrap();
+    let hashes: Vec<[u8; 32]> = chunks.iter().map(|c| c.hash).collect();
+    manifest.record(&hashes).unwrap();
+
+    let options = PushOptions {
+        resume: true,
+        ..PushOptions::default()
+    };
+    push(&site, &url, "example.com", &token, &options)
+        .await
+        .unwrap();
+
+    let (_, stored) = storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .unwrap();
+    match find_node(&stored, "/docs/guide.html") {
+        Some(Node::File { chunks, .. }) => {
+            assert_eq!(storage.read_file(chunks).unwrap().unwrap(), b"<p>Guide</p>")
+        }
+        other => panic!("unexpected node {:?}", other),
+    }
+    assert_eq!(storage.has_chunks(&hashes).unwrap().len(), hashes.len());
+}
+
+#[tokio::test]
+async fn test_push_sites_commits_together() {
+    use webpub::client::push::push_sites;
+
+    let temp = TempDir::new().unwrap();
+    let mut sites = Vec::new();
+    for (hostname, contents) in [("docs.example.com", "docs"), ("blog.example.com", "blog")] {
+        let dir = temp.path().join(hostname);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("index.html"), contents).unwrap();
+        sites.push((hostname.to_string(), dir));
+    }
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+
+    let snapshots = push_sites(&sites, &url, &token, &PushOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(snapshots.len(), 2);
+    for ((hostname, dir), (committed, snapshot_id)) in sites.iter().zip(&snapshots) {
+        assert_eq!(hostname, committed);
+        let (current, tree) = storage.get_current_snapshot(hostname).unwrap().unwrap();
+        assert_eq!(current as u64, *snapshot_id);
+        match find_node(&tree, "/index.html") {
+            Some(Node::File { chunks, .. }) => assert_eq!(
+                storage.read_file(chunks).unwrap().unwrap(),
+                fs::read(dir.join("index.html")).unwrap()
+            ),
+            other => panic!("unexpected node {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_batch_with_invalid_tree_commits_nothing() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::protocol::{decode, encode, ClientMessage, ServerMessage, PROTOCOL_VERSION};
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+    push(
+        &site,
+        &url,
+        "docs.example.com",
+        &token,
+        &PushOptions::default(),
+    )
+    .await
+    .unwrap();
+    let (_, valid) = storage
+        .get_current_snapshot("docs.example.com")
+        .unwrap()
+        .unwrap();
+    let mut tampered = valid.clone();
+    if let Node::Directory { hash, .. } = &mut tampered {
+        hash[0] ^= 1;
+    }
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    let messages = [
+        ClientMessage::Auth {
+            token,
+            protocol_version: PROTOCOL_VERSION,
+        },
+        ClientMessage::BeginBatch,
+        ClientMessage::CommitTree {
+            hostname: "blog.example.com".to_string(),
+            tree: valid,
+        },
+        ClientMessage::CommitTree {
+            hostname: "shop.example.com".to_string(),
+            tree: tampered,
+        },
+        ClientMessage::CommitBatch,
+    ];
+    let mut replies = Vec::new();
+    for message in messages {
+        ws.send(Message::Binary(encode(&message).unwrap()))
+            .await
+            .unwrap();
+        match ws.next().await.unwrap().unwrap() {
+            Message::Binary(data) => replies.push(decode::<ServerMessage>(&data).unwrap()),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+
+    assert!(matches!(replies[1], ServerMessage::BatchStarted));
+    match &replies[2] {
+        ServerMessage::TreeStaged { hostname } => assert_eq!(hostname, "blog.example.com"),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    match &replies[3] {
+        ServerMessage::CommitFailed { reason, .. } => {
+            assert_eq!(reason, "Invalid tree: hash mismatch at /")
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+    // The failed tree dropped the batch, including the valid tree
+    match &replies[4] {
+        ServerMessage::CommitFailed { reason, .. } => assert_eq!(reason, "No batch to commit"),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert!(storage
+        .list_snapshots("blog.example.com")
+        .unwrap()
+        .is_empty());
+    assert!(storage
+        .list_snapshots("shop.example.com")
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_delete_site() {
+    use webpub::client::delete_site::delete_site;
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+    push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+
+    // Deleting needs the admin scope
+    let read_only = start_server_with(SyncState {
+        authenticator: Arc::new(ReadOnlyAuthenticator),
+        ..SyncState::new(storage.clone(), 5)
+    })
+    .await;
+    let err = delete_site(&read_only, "example.com", "reader")
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("admin scope required"), "{}", err);
+    assert_eq!(storage.list_snapshots("example.com").unwrap().len(), 1);
+
+    delete_site(&url, "example.com", &token).await.unwrap();
+    assert!(storage.list_snapshots("example.com").unwrap().is_empty());
+    assert_eq!(storage.count_sites().unwrap(), 0);
+
+    let err = delete_site(&url, "example.com", &token).await.unwrap_err();
+    assert_eq!(err.to_string(), "Delete failed: Site not found");
+}
+
+#[tokio::test]
+async fn test_list_sites() {
+    use webpub::client::list::list_sites;
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+    for (hostname, contents) in [
+        ("example.com", "v1"),
+        ("docs.example.com", "v1"),
+        ("example.com", "v2"),
+    ] {
+        fs::write(site.join("index.html"), contents).unwrap();
+        push(&site, &url, hostname, &token, &PushOptions::default())
+            .await
+            .unwrap();
+    }
+    let current = |hostname| {
+        storage
+            .get_current_snapshot_id(hostname)
+            .unwrap()
+            .map(|id| id as u64)
+    };
+
+    // Listing only needs the read scope
+    let read_only = start_server_with(SyncState {
+        authenticator: Arc::new(ReadOnlyAuthenticator),
+        ..SyncState::new(storage.clone(), 5)
+    })
+    .await;
+    assert_eq!(
+        list_sites(&read_only, "reader").await.unwrap(),
+        vec![
+            (
+                "docs.example.com".to_string(),
+                1,
+                current("docs.example.com")
+            ),
+            ("example.com".to_string(), 2, current("example.com")),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_push_unchanged_tree_keeps_snapshot() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+
+    let first = push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+    let again = push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(again, first);
+    assert_eq!(storage.list_snapshots("example.com").unwrap().len(), 1);
+
+    // A changed tree is a new snapshot
+    fs::write(site.join("index.html"), "<h1>Changed</h1>").unwrap();
+    let changed = push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+    assert_ne!(changed, first);
+    assert_eq!(storage.list_snapshots("example.com").unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_list_snapshot_timestamps() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use webpub::protocol::{
+        decode, encode, ClientMessage, ServerMessage, TIMESTAMP_PROTOCOL_VERSION,
+    };
+    use webpub::timestamp::format_utc;
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+    push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+
+    let snapshots = list(&url, "example.com", &token).await.unwrap();
+    assert_eq!(snapshots.len(), 1);
+    let (id, created_at, is_current, _) = snapshots[0];
+    assert!(is_current);
+    assert!(created_at > 1_700_000_000, "{}", created_at);
+
+    // Older clients still get the creation time as a string
+    let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    let requests = [
+        ClientMessage::Auth {
+            token,
+            protocol_version: TIMESTAMP_PROTOCOL_VERSION - 1,
+        },
+        ClientMessage::ListSnapshots {
+            hostname: "example.com".to_string(),
+        },
+    ];
+    let mut replies = Vec::new();
+    for request in &requests {
+        ws.send(Message::Binary(encode(request).unwrap()))
+            .await
+            .unwrap();
+        match ws.next().await.unwrap().unwrap() {
+            Message::Binary(data) => replies.push(decode::<ServerMessage>(&data).unwrap()),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+    match &replies[1] {
+        ServerMessage::SnapshotList { snapshots } => {
+            assert_eq!(snapshots, &vec![(id, format_utc(created_at), true)])
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_rollback_by_tag() {
+    use webpub::client::rollback::{rollback, tag};
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+
+    let mut ids = Vec::new();
+    for version in ["v1", "v2", "v3"] {
+        fs::write(site.join("index.html"), version).unwrap();
+        ids.push(
+            push(&site, &url, "example.com", &token, &PushOptions::default())
+                .await
+                .unwrap(),
+        );
+    }
+
+    tag(&url, "example.com", &token, ids[0], "stable")
+        .await
+        .unwrap();
+    let snapshots = list(&url, "example.com", &token).await.unwrap();
+    let tags: Vec<_> = snapshots.iter().map(|s| (s.0, s.3.clone())).collect();
+    assert_eq!(
+        tags,
+        vec![
+            (ids[2], None),
+            (ids[1], None),
+            (ids[0], Some("stable".to_string()))
+        ]
+    );
+
+    let rolled_back = rollback(&url, "example.com", &token, None, Some("stable"))
+        .await
+        .unwrap();
+    assert_eq!(rolled_back, ids[0]);
+    assert_eq!(
+        storage.get_current_snapshot_id("example.com").unwrap(),
+        Some(ids[0] as i64)
+    );
+
+    let err = rollback(&url, "example.com", &token, None, Some("beta"))
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Rollback failed: No snapshot tagged beta");
+
+    // Numbers would read as snapshot IDs
+    for (bad, reason) in [("42", "Tag is a number"), ("", "Tag is empty")] {
+        let err = tag(&url, "example.com", &token, ids[1], bad)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), format!("Tag failed: {}", reason));
+    }
+    let err = tag(&url, "example.com", &token, 999, "beta")
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Tag failed: Snapshot not found");
+
+    // Tagging needs the deploy scope
+    let read_only = start_server_with(SyncState {
+        authenticator: Arc::new(ReadOnlyAuthenticator),
+        ..SyncState::new(storage.clone(), 5)
+    })
+    .await;
+    assert!(tag(&read_only, "example.com", "reader", ids[1], "beta")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_cat_file() {
+    use webpub::client::cat::cat;
+
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir_all(site.join("docs")).unwrap();
+    fs::create_dir_all(site.join("assets")).unwrap();
+    fs::write(site.join("index.html"), "<h1>Home</h1>").unwrap();
+    fs::write(site.join("docs/index.html"), "<h1>Docs</h1>").unwrap();
+    fs::write(site.join("empty.txt"), "").unwrap();
+    // Large enough to span several FileData frames
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+    let video: Vec<u8> = (0..9 * 1024 * 1024).map(|_| rng.gen()).collect();
+    fs::write(site.join("assets/video.mp4"), &video).unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+    push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+
+    let read = |path: &'static str| {
+        let (url, token) = (url.clone(), token.clone());
+        async move {
+            let mut out = Vec::new();
+            cat(&url, "example.com", path, &token, &mut out)
+                .await
+                .map(|written| {
+                    assert_eq!(written, out.len() as u64);
+                    out
+                })
+        }
+    };
+    assert_eq!(read("/assets/video.mp4").await.unwrap(), video);
+    assert_eq!(read("/").await.unwrap(), b"<h1>Home</h1>");
+    assert_eq!(read("/docs").await.unwrap(), b"<h1>Docs</h1>");
+    assert!(read("/empty.txt").await.unwrap().is_empty());
+
+    let err = read("/missing.txt").await.unwrap_err();
+    assert_eq!(err.to_string(), "/missing.txt: File not found");
+    let err = read("/assets/").await.unwrap_err();
+    assert_eq!(err.to_string(), "/assets/: Is a directory");
+    let mut out = Vec::new();
+    let err = cat(&url, "other.com", "/", &token, &mut out)
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "/: Site not found");
+
+    // Reading needs only the read scope
+    let read_only = start_server_with(SyncState {
+        authenticator: Arc::new(ReadOnlyAuthenticator),
+        ..SyncState::new(storage.clone(), 5)
+    })
+    .await;
+    let mut out = Vec::new();
+    cat(&read_only, "example.com", "/", "reader", &mut out)
+        .await
+        .unwrap();
+    assert_eq!(out, b"<h1>Home</h1>");
+}
+
+#[tokio::test]
+async fn test_push_empty_file() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("empty.txt"), "").unwrap();
+    fs::write(site.join("index.html"), "<h1>Hello</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let url = start_server(storage.clone()).await;
+
+    push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+    let (_, tree) = storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .unwrap();
+    assert_stored(&storage, &tree, &site, "");
+    match find_node(&tree, "/empty.txt") {
+        Some(Node::File { size, chunks, .. }) => {
+            assert_eq!(*size, 0);
+            assert!(chunks.is_empty());
+        }
+        other => panic!("unexpected node {:?}", other),
+    }
+
+    // A site of only an empty file deploys too
+    fs::remove_file(site.join("index.html")).unwrap();
+    push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+}
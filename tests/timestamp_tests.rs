@@ -0,0 +1,58 @@
+use webpub::timestamp::{format_expiry, format_utc, parse_utc};
+
+#[test]
+fn test_format_utc() {
+    assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+    assert_eq!(format_utc(1_714_566_600), "2024-05-01T12:30:00Z");
+    assert_eq!(format_utc(951_782_400), "2000-02-29T00:00:00Z");
+    assert_eq!(format_utc(-1), "1969-12-31T23:59:59Z");
+}
+
+#[test]
+fn test_parse_utc_roundtrip() {
+    for secs in [0, 1, 951_782_400, 1_714_566_600, 4_102_444_799, -86_400] {
+        assert_eq!(parse_utc(&format_utc(secs)), Some(secs), "{}", secs);
+    }
+}
+
+#[test]
+fn test_parse_sqlite_timestamp() {
+    // CURRENT_TIMESTAMP, as stored by older servers
+    assert_eq!(parse_utc("2024-05-01 12:30:00"), Some(1_714_566_600));
+    assert_eq!(parse_utc("2024-05-01T12:30:00"), Some(1_714_566_600));
+}
+
+#[test]
+fn test_parse_utc_rejects_invalid() {
+    for s in [
+        "",
+        "2024-05-01",
+        "2024-05-01T12:30:00+02:00",
+        "2024-13-01T00:00:00Z",
+        "2023-02-29T00:00:00Z",
+        "1900-02-29T00:00:00Z",
+        "2024-05-01T24:00:00Z",
+        "2024-05-01T12:60:00Z",
+        "2024-05-01T12:30:60Z",
+        "2024/05/01T12:30:00Z",
+        "2024-05-+1T12:30:00Z",
+        "2024-05-01T12:30:00ZZ",
+    ] {
+        assert_eq!(parse_utc(s), None, "{}", s);
+    }
+    assert_eq!(parse_utc("2000-02-29T00:00:00Z"), Some(951_782_400));
+}
+
+#[test]
+fn test_format_expiry() {
+    let now = 1_700_000_000;
+    assert_eq!(format_expiry(now, now), "expired");
+    assert_eq!(format_expiry(now - 5, now), "expired");
+    assert_eq!(format_expiry(now + 59, now), "expires in 59s");
+    assert_eq!(format_expiry(now + 60, now), "expires in 1m");
+    assert_eq!(format_expiry(now + 61, now), "expires in 2m");
+    // Rounding up can fill the next unit
+    assert_eq!(format_expiry(now + 3_599, now), "expires in 1h");
+    assert_eq!(format_expiry(now + 86_399, now), "expires in 1d");
+    assert_eq!(format_expiry(now + 86_400 + 1, now), "expires in 2d");
+}
@@ -0,0 +1,155 @@
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use std::fs;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tower::ServiceExt;
+use webpub::client::push::{push, PushOptions};
+use webpub::server::http::create_router;
+use webpub::server::storage::Storage;
+use webpub::server::sync::{handle_tls_connection, SyncState};
+use webpub::server::tls::{acceptor, load_config, redirect_router};
+use webpub::{build_tree, scan_directory};
+
+/// Write a self-signed certificate and key for localhost, returning their paths.
+fn self_signed(dir: &TempDir) -> (std::path::PathBuf, std::path::PathBuf) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_path = dir.path().join("cert.pem");
+    let key_path = dir.path().join("key.pem");
+    fs::write(&cert_path, cert.cert.pem()).unwrap();
+    fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+#[tokio::test]
+async fn test_serve_over_tls() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Secure</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let entry = scan_directory(&site).unwrap().next().unwrap();
+    let (tree, chunks) = build_tree(entry);
+    for chunk in &chunks {
+        storage.store_chunk(&chunk.hash, &chunk.data).unwrap();
+    }
+    storage.create_snapshot("localhost", &tree).unwrap();
+
+    let (cert, key) = self_signed(&temp);
+    let config = load_config(&cert, &key).await.unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = axum_server::from_tcp_rustls(listener, config)
+        .serve(create_router(storage).into_make_service());
+    tokio::spawn(server);
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!("https://localhost:{}/", port))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(response.text().await.unwrap(), "<h1>Secure</h1>");
+}
+
+#[tokio::test]
+async fn test_load_config_rejects_bad_pem() {
+    let temp = TempDir::new().unwrap();
+    let (cert, _) = self_signed(&temp);
+    let bogus = temp.path().join("bogus.pem");
+    fs::write(&bogus, "not a key").unwrap();
+
+    assert!(load_config(&cert, &bogus).await.is_err());
+    assert!(load_config(&temp.path().join("missing.pem"), &bogus)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_redirect_to_https() {
+    let redirect = |router: axum::Router, host: &'static str, uri: &'static str| async move {
+        let request = Request::builder()
+            .uri(uri)
+            .header(header::HOST, host)
+            .body(Body::empty())
+            .unwrap();
+        router.oneshot(request).await.unwrap()
+    };
+
+    let response = redirect(
+        redirect_router(443),
+        "example.com:80",
+        "/docs/page.html?q=1",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(
+        response.headers()[header::LOCATION],
+        "https://example.com/docs/page.html?q=1"
+    );
+
+    let response = redirect(redirect_router(8443), "example.com:80", "/").await;
+    assert_eq!(
+        response.headers()[header::LOCATION],
+        "https://example.com:8443/"
+    );
+
+    // IPv6 literals keep their brackets, with or without a port
+    for host in ["[::1]:80", "[::1]"] {
+        let response = redirect(redirect_router(8443), host, "/").await;
+        assert_eq!(response.headers()[header::LOCATION], "https://[::1]:8443/");
+        let response = redirect(redirect_router(443), host, "/").await;
+        assert_eq!(response.headers()[header::LOCATION], "https://[::1]/");
+    }
+}
+
+#[tokio::test]
+async fn test_push_over_wss() {
+    let temp = TempDir::new().unwrap();
+    let site = temp.path().join("site");
+    fs::create_dir(&site).unwrap();
+    fs::write(site.join("index.html"), "<h1>Deployed securely</h1>").unwrap();
+
+    let storage = Arc::new(Storage::open(&temp.path().join("data")).unwrap());
+    let token = storage.add_token().unwrap();
+    let (cert, key) = self_signed(&temp);
+    let config = load_config(&cert, &key).await.unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let state = Arc::new(SyncState::new(storage.clone(), 5));
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(handle_tls_connection(
+                stream,
+                acceptor(&config),
+                state.clone(),
+            ));
+        }
+    });
+
+    // The client trusts the platform roots, which honor SSL_CERT_FILE
+    std::env::set_var("SSL_CERT_FILE", &cert);
+    let url = format!("wss://localhost:{}", port);
+    push(&site, &url, "example.com", &token, &PushOptions::default())
+        .await
+        .unwrap();
+    assert!(storage
+        .get_current_snapshot("example.com")
+        .unwrap()
+        .is_some());
+
+    // A plain ws:// client can't talk to the TLS port
+    let url = format!("ws://localhost:{}", port);
+    assert!(
+        push(&site, &url, "example.com", &token, &PushOptions::default())
+            .await
+            .is_err()
+    );
+}